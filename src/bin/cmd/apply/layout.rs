@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::cmd::gpt::{parse_partition_type, AttributeChanges};
+use crate::utils::parse_size;
+use anyhow::Context;
+use diskutil::part::gpt::{Gpt, GptAttributes};
+use diskutil::region::Region;
+use serde::Deserialize;
+use uuid::Uuid;
+
+const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+const MAX_PARTITIONS: usize = 128;
+const MAX_NAME_LEN: usize = 36;
+
+/// Size given either as number of bytes or as string with unit, eg. "100M"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeValue {
+    Bytes(u64),
+    Text(String),
+}
+
+impl SizeValue {
+    fn to_bytes(&self) -> anyhow::Result<u64> {
+        match self {
+            Self::Bytes(x) => Ok(*x),
+            Self::Text(x) => parse_size(x).map_err(|e| anyhow!("invalid size {}: {}", x, e)),
+        }
+    }
+
+    fn is_rest(&self) -> bool {
+        matches!(self, Self::Text(x) if x == "rest")
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableType {
+    Gpt,
+    /// Recognized so that apply can reject it with a clear message
+    Mbr,
+}
+
+impl Default for TableType {
+    fn default() -> Self {
+        Self::Gpt
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    /// FAT type chosen by volume size
+    Fat,
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutFile {
+    size: SizeValue,
+    #[serde(default)]
+    table: TableType,
+    alignment: Option<SizeValue>,
+    disk_guid: Option<String>,
+    #[serde(default, rename = "partition", alias = "partitions")]
+    partitions: Vec<PartitionFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PartitionFile {
+    #[serde(default)]
+    name: String,
+    #[serde(rename = "type")]
+    partition_type: Option<String>,
+    size: SizeValue,
+    guid: Option<String>,
+    attributes: Option<String>,
+    file: Option<PathBuf>,
+    filesystem: Option<Filesystem>,
+}
+
+pub enum Content {
+    File { path: PathBuf, len: u64 },
+    Filesystem(Filesystem),
+}
+
+pub struct PartitionLayout {
+    pub name: String,
+    pub type_guid: Uuid,
+    /// None means the partition takes the rest of disk
+    pub size: Option<u64>,
+    pub unique_guid: Option<Uuid>,
+    pub attributes: GptAttributes,
+    pub content: Option<Content>,
+}
+
+/// Validated disk layout
+pub struct Layout {
+    pub size: u64,
+    pub table: TableType,
+    pub alignment: u64,
+    pub disk_guid: Option<Uuid>,
+    pub partitions: Vec<PartitionLayout>,
+}
+
+fn parse_guid(s: &str) -> anyhow::Result<Uuid> {
+    let s = s
+        .strip_prefix('{')
+        .and_then(|x| x.strip_suffix('}'))
+        .unwrap_or(s);
+    Uuid::from_str(s).with_context(|| format!("invalid GUID {}", s))
+}
+
+impl Layout {
+    /// Loads layout from TOML file or from JSON file when it has .json extension,
+    /// relative paths of fill files are resolved against layout directory
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path).context("failed to read layout file")?;
+        let file: LayoutFile = if path.extension().map_or(false, |x| x == "json") {
+            serde_json::from_str(&text).context("failed to parse layout")?
+        } else {
+            toml::from_str(&text).context("failed to parse layout")?
+        };
+
+        Self::validate(file, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    fn validate(file: LayoutFile, base_dir: &Path) -> anyhow::Result<Self> {
+        let size = file.size.to_bytes()?;
+        if size == 0 || size % 512 != 0 {
+            bail!("disk size must be non-zero multiple of 512");
+        }
+        let alignment = match &file.alignment {
+            Some(x) => x.to_bytes()?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        if alignment == 0 || alignment % 512 != 0 {
+            bail!("alignment must be non-zero multiple of 512");
+        }
+        if file.partitions.len() > MAX_PARTITIONS {
+            bail!("at most {} partitions are supported", MAX_PARTITIONS);
+        }
+
+        let mut guids = HashSet::new();
+        let mut partitions = Vec::with_capacity(file.partitions.len());
+        let count = file.partitions.len();
+        for (i, p) in file.partitions.into_iter().enumerate() {
+            let partition = Self::validate_partition(p, i + 1 == count, base_dir)
+                .with_context(|| format!("invalid partition #{}", i))?;
+            if let Some(guid) = partition.unique_guid {
+                if !guids.insert(guid) {
+                    bail!("partition #{} has duplicate GUID {}", i, guid);
+                }
+            }
+            partitions.push(partition);
+        }
+
+        Ok(Self {
+            size,
+            table: file.table,
+            alignment,
+            disk_guid: file.disk_guid.as_deref().map(parse_guid).transpose()?,
+            partitions,
+        })
+    }
+
+    fn validate_partition(
+        p: PartitionFile,
+        is_last: bool,
+        base_dir: &Path,
+    ) -> anyhow::Result<PartitionLayout> {
+        if p.name.encode_utf16().count() > MAX_NAME_LEN {
+            bail!("name is longer than {} characters", MAX_NAME_LEN);
+        }
+
+        let size = if p.size.is_rest() {
+            if !is_last {
+                bail!("only the last partition can take the rest of disk");
+            }
+            None
+        } else {
+            match p.size.to_bytes()? {
+                0 => bail!("size must be non-zero"),
+                x => Some(x),
+            }
+        };
+
+        let type_guid = match p.partition_type.as_deref() {
+            Some(x) => parse_partition_type(x).map_err(|e| anyhow!("{}: {}", x, e))?,
+            None => diskutil::part::gpt::GptPartitionType::MicrosoftBasicData.to_guid(),
+        };
+
+        let attributes = match p.attributes.as_deref() {
+            Some(x) => AttributeChanges::from_str(x)
+                .map_err(|e| anyhow!(e))?
+                .apply(GptAttributes::empty()),
+            None => GptAttributes::empty(),
+        };
+
+        let content = match (p.file, p.filesystem) {
+            (Some(_), Some(_)) => bail!("file and filesystem can't be used together"),
+            (Some(file), None) => {
+                let path = base_dir.join(file);
+                let len = fs::metadata(&path)
+                    .with_context(|| format!("can't access {}", path.display()))?
+                    .len();
+                if size.map_or(false, |x| len > x) {
+                    bail!("{} is larger than partition", path.display());
+                }
+                Some(Content::File { path, len })
+            }
+            (None, Some(fs)) => Some(Content::Filesystem(fs)),
+            (None, None) => None,
+        };
+
+        Ok(PartitionLayout {
+            name: p.name,
+            type_guid,
+            size,
+            unique_guid: p.guid.as_deref().map(parse_guid).transpose()?,
+            attributes,
+            content,
+        })
+    }
+
+    /// Computes regions of partitions in empty GPT, fails if layout doesn't fit
+    pub fn place(&self, gpt: &Gpt, sector_size: u64) -> anyhow::Result<Vec<Region<u64>>> {
+        if self.alignment % sector_size != 0 {
+            bail!("alignment is not multiple of sector size ({})", sector_size);
+        }
+        let alignment = self.alignment / sector_size;
+
+        let mut regions = Vec::with_capacity(self.partitions.len());
+        let mut next = gpt.first_usable_lba;
+        for (i, p) in self.partitions.iter().enumerate() {
+            let start = (next + alignment - 1) / alignment * alignment;
+            let end = match p.size {
+                Some(size) if size % sector_size != 0 => bail!(
+                    "size of partition #{} is not multiple of sector size ({})",
+                    i,
+                    sector_size
+                ),
+                Some(size) => start + size / sector_size - 1,
+                None => gpt.last_usable_lba,
+            };
+            if start > end || end > gpt.last_usable_lba {
+                bail!("partition #{} doesn't fit on disk", i);
+            }
+
+            let region = Region::new(start, end);
+            if let Some(Content::File { path, len }) = &p.content {
+                if *len > region.size() * sector_size {
+                    bail!("{} is larger than partition #{}", path.display(), i);
+                }
+            }
+
+            regions.push(region);
+            next = end + 1;
+        }
+
+        Ok(regions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Layout, LayoutFile, TableType};
+    use diskutil::part::gpt::{Gpt, GptAttributes, GptPartitionType};
+    use std::path::Path;
+
+    fn parse(s: &str) -> anyhow::Result<Layout> {
+        let file: LayoutFile = toml::from_str(s)?;
+        Layout::validate(file, Path::new(""))
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = parse(
+            r#"
+            size = "64M"
+
+            [[partition]]
+            name = "boot"
+            type = "esp"
+            size = "16M"
+            attributes = "legacy-boot"
+
+            [[partition]]
+            name = "root"
+            type = "linux"
+            size = "rest"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(layout.alignment, 1024 * 1024);
+        assert_eq!(
+            layout.partitions[0].type_guid,
+            GptPartitionType::EFISystemPartition.to_guid()
+        );
+        assert_eq!(
+            layout.partitions[0].attributes,
+            GptAttributes::LEGACY_BIOS_BOOTABLE
+        );
+        assert_eq!(layout.partitions[1].size, None);
+
+        let gpt = Gpt::create_for_size(layout.size, 512, 128);
+        let regions = layout.place(&gpt, 512).unwrap();
+        assert_eq!((regions[0].start(), regions[0].end()), (2048, 34815));
+        assert_eq!((regions[1].start(), regions[1].end()), (34816, 131038));
+
+        let json: LayoutFile = serde_json::from_str(
+            r#"{"size": 1048576, "alignment": 4096, "partitions": [{"size": 4096}]}"#,
+        )
+        .unwrap();
+        let layout = Layout::validate(json, Path::new("")).unwrap();
+        let gpt = Gpt::create_for_size(layout.size, 512, 128);
+        assert_eq!(layout.place(&gpt, 512).unwrap()[0].start(), 40);
+    }
+
+    #[test]
+    fn test_invalid_layout() {
+        let part = |x: &str| format!("size = \"64M\"\n[[partition]]\n{}", x);
+
+        assert!(parse(&part("size = \"rest\"\n[[partition]]\nsize = \"1M\"")).is_err());
+        assert!(parse(&part("size = \"1M\"\ntype = \"unknown\"")).is_err());
+        assert!(parse(&part("size = \"1M\"\nattributes = \"+nothing\"")).is_err());
+        assert!(parse(&part("size = \"1M\"\nfile = \"a\"\nfilesystem = \"fat\"")).is_err());
+        assert!(parse(&part("size = \"1M\"\nfilesystem = \"ext4\"")).is_err());
+        assert!(parse(&part("size = \"1M\"\nunknown = 1")).is_err());
+        assert!(parse("size = \"64M\"\ntable = \"fat\"").is_err());
+        assert_eq!(
+            parse("size = \"64M\"\ntable = \"mbr\"").unwrap().table,
+            TableType::Mbr
+        );
+
+        let layout = parse(&part("size = \"64M\"")).unwrap();
+        let gpt = Gpt::create_for_size(layout.size, 512, 128);
+        assert!(layout.place(&gpt, 512).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::cmp::max;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::cmd::create::create_disk;
+use crate::utils::{open_disk, AccessMode};
+use anyhow::Context;
+use clap::Parser;
+use diskutil::disk::vhd::CreateOptions as VhdCreateOptions;
+use diskutil::disk::{Disk, DiskFormat, DiskSlice};
+use diskutil::part::gpt::{ErrorAction, Gpt, GptPartition};
+use diskutil::part::mbr::Mbr;
+use diskutil::region::Region;
+use layout::{Content, Filesystem, Layout, TableType};
+use uuid::Uuid;
+
+mod layout;
+
+#[derive(Parser)]
+#[clap(
+    about = "Create and partition disk image according to layout file",
+    long_about = "Create and partition disk image according to TOML or JSON layout file. \
+    Image is created when it doesn't exist. Partitions whose table entry already matches \
+    the layout keep their data, only fill files are compared and rewritten when they differ, \
+    filesystems are created only in new or changed partitions and are not checked otherwise. \
+    Only GPT layouts can be applied, MBR layouts are rejected."
+)]
+pub struct Command {
+    #[clap(help = "Layout file, parsed as JSON when it has .json extension and as TOML otherwise")]
+    layout: PathBuf,
+
+    file: PathBuf,
+
+    #[clap(
+        short,
+        long,
+        help = "Disk format, new images are created as raw by default, format of existing images is detected automatically."
+    )]
+    format: Option<DiskFormat>,
+
+    #[clap(long, help = "Replace partition table that doesn't match the layout")]
+    force: bool,
+}
+
+/// Builds GPT described by layout, GUIDs of unchanged partitions are taken from old GPT
+fn build_gpt(layout: &Layout, mut gpt: Gpt, regions: &[Region<u64>], old: Option<&Gpt>) -> Gpt {
+    if let Some(guid) = layout.disk_guid {
+        gpt.disk_guid = guid;
+    } else if let Some(old) = old {
+        gpt.disk_guid = old.disk_guid;
+    }
+
+    for (i, (p, region)) in layout.partitions.iter().zip(regions.iter()).enumerate() {
+        let mut partition = GptPartition::new_ex(
+            p.type_guid,
+            &p.name,
+            region.start(),
+            region.end(),
+            Uuid::nil(),
+        );
+        partition.set_flags(p.attributes);
+
+        let old = old.and_then(|x| x.get_partition(i as u32));
+        partition.unique_guid = match (p.unique_guid, old) {
+            (Some(guid), _) => guid,
+            (None, Some(old)) if is_same_partition(old, &partition) => old.unique_guid,
+            (None, _) => Uuid::new_v4(),
+        };
+        gpt.partitions.push(Some(partition));
+    }
+
+    gpt
+}
+
+/// Compares partitions ignoring unique GUID
+fn is_same_partition(a: &GptPartition, b: &GptPartition) -> bool {
+    a.type_guid == b.type_guid
+        && a.start_lba == b.start_lba
+        && a.end_lba == b.end_lba
+        && a.attributes == b.attributes
+        && a.partition_name == b.partition_name
+}
+
+fn is_same_table(a: &Gpt, b: &Gpt) -> bool {
+    let len = max(a.partitions.len(), b.partitions.len()) as u32;
+
+    a.disk_guid == b.disk_guid
+        && a.alternate_lba == b.alternate_lba
+        && a.first_usable_lba == b.first_usable_lba
+        && a.last_usable_lba == b.last_usable_lba
+        && (0..len).all(|i| a.get_partition(i) == b.get_partition(i))
+}
+
+/// Compares beginning of the partition with fill file
+fn file_matches(slice: &mut DiskSlice, path: &Path) -> anyhow::Result<bool> {
+    let mut file = BufReader::new(
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+    );
+    let mut expected = vec![0u8; 65536];
+    let mut actual = vec![0u8; 65536];
+
+    loop {
+        let n = file
+            .read(&mut expected)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if n == 0 {
+            return Ok(true);
+        }
+        slice
+            .read_exact(&mut actual[..n])
+            .context("failed to read partition")?;
+        if expected[..n] != actual[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Writes partition content, fill file of unchanged partition is written only when
+/// it differs from partition data and filesystem is never recreated there.
+/// Returns false when nothing was written.
+fn populate(
+    disk: &mut dyn Disk,
+    partition: &GptPartition,
+    content: &Content,
+    unchanged: bool,
+) -> anyhow::Result<bool> {
+    let mut slice = DiskSlice::new(
+        disk,
+        partition.start_lba,
+        partition.end_lba - partition.start_lba + 1,
+    );
+
+    match content {
+        Content::File { path, .. } => {
+            if unchanged && file_matches(&mut slice, path)? {
+                return Ok(false);
+            }
+            slice.seek(SeekFrom::Start(0))?;
+            let mut file = BufReader::new(
+                File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+            );
+            io::copy(&mut file, &mut slice)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+        Content::Filesystem(_) if unchanged => return Ok(false),
+        Content::Filesystem(fs) => {
+            let options = fatfs::FormatVolumeOptions::new();
+            let options = match fs {
+                Filesystem::Fat => options,
+                Filesystem::Fat12 => options.fat_type(fatfs::FatType::Fat12),
+                Filesystem::Fat16 => options.fat_type(fatfs::FatType::Fat16),
+                Filesystem::Fat32 => options.fat_type(fatfs::FatType::Fat32),
+            };
+            let mut wrapper = fatfs::StdIoWrapper::from(&mut slice);
+            fatfs::format_volume(&mut wrapper, options)
+                .map_err(|e| anyhow!("failed to format partition: {:?}", e))?;
+        }
+    }
+
+    Ok(true)
+}
+
+fn apply_gpt(command: &Command, layout: &Layout) -> anyhow::Result<()> {
+    let (mut disk, old, has_table) = if command.file.exists() {
+        let mut disk = open_disk(&command.file, command.format, AccessMode::ReadWrite)?;
+        if disk.disk_size() < layout.size {
+            bail!(
+                "disk is smaller ({} bytes) than the layout ({} bytes)",
+                disk.disk_size(),
+                layout.size
+            );
+        }
+
+        let has_table = Mbr::load(disk.as_mut()).is_ok();
+        let old = Gpt::load_any(disk.as_mut(), ErrorAction::Abort)
+            .ok()
+            .map(|x| x.0);
+        (disk, old, has_table)
+    } else {
+        // validate placement before creating the image
+        layout.place(&Gpt::create_for_size(layout.size, 512, 128), 512)?;
+
+        let disk = create_disk(
+            &command.file,
+            command.format.unwrap_or(DiskFormat::RAW),
+            layout.size,
+            false,
+            &VhdCreateOptions::default(),
+        )?;
+        (disk, None, false)
+    };
+
+    let disk = disk.as_mut();
+    let gpt = Gpt::create(disk).context("failed to create GPT")?;
+    let regions = layout.place(&gpt, disk.sector_size() as u64)?;
+    let mut gpt = build_gpt(layout, gpt, &regions, old.as_ref());
+
+    if old.as_ref().map_or(false, |x| is_same_table(x, &gpt)) {
+        println!("Partition table is up to date");
+    } else {
+        if has_table && !command.force {
+            bail!("disk already contains different partition table, use --force to replace it");
+        }
+
+        Mbr::create_protective(disk)
+            .update(disk)
+            .context("failed to write MBR")?;
+        gpt.update(disk).context("failed to write GPT")?;
+    }
+
+    for (i, p) in layout.partitions.iter().enumerate() {
+        let partition = gpt.get_partition(i as u32).unwrap();
+        let unchanged = old
+            .as_ref()
+            .and_then(|x| x.get_partition(i as u32))
+            .map_or(false, |x| x == partition);
+
+        let status = match &p.content {
+            Some(content) => {
+                let written = populate(disk, partition, content, unchanged)
+                    .with_context(|| format!("failed to populate partition {}", i))?;
+                match (content, unchanged, written) {
+                    (_, false, _) => "",
+                    (_, true, true) => ", unchanged, content rewritten",
+                    (Content::File { .. }, true, false) => ", unchanged",
+                    (Content::Filesystem(_), true, false) => ", unchanged, filesystem not checked",
+                }
+            }
+            None if unchanged => ", unchanged",
+            None => "",
+        };
+        println!(
+            "Partition {} ({}): LBA {} - {}{}",
+            i, partition.partition_name, partition.start_lba, partition.end_lba, status
+        );
+    }
+
+    disk.flush().context("flush failed")
+}
+
+pub fn run(command: Command) -> anyhow::Result<()> {
+    let layout = Layout::load(&command.layout)?;
+
+    match layout.table {
+        TableType::Gpt => apply_gpt(&command, &layout),
+        TableType::Mbr => bail!("MBR layouts can't be applied yet, use table = \"gpt\""),
+    }
+}
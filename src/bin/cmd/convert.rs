@@ -0,0 +1,107 @@
+use std::cmp::min;
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::cmd::create::create_disk;
+use crate::utils::{display_progress, open_disk, AccessMode};
+use anyhow::Context;
+use clap::Parser;
+use diskutil::disk::vhd::CreateOptions as VhdCreateOptions;
+use diskutil::disk::{Disk, DiskFormat};
+
+// blocks containing only zeros are not written so sparse outputs stay sparse
+const ZERO_BLOCK_SIZE: usize = 65536;
+
+#[derive(Parser)]
+#[clap(about = "Convert disk image to another format")]
+pub struct Command {
+    #[clap(
+        short = 'f',
+        long = "format",
+        help = "Input disk format, detected automatically when not specified"
+    )]
+    input_format: Option<DiskFormat>,
+
+    #[clap(short = 'F', long = "out-format", help = "Output disk format")]
+    output_format: DiskFormat,
+
+    #[clap(
+        short = 's',
+        long = "static",
+        help = "Create statically sized output disk, by default dynamically sized disk is created if supported by disk format."
+    )]
+    statically_sized: bool,
+
+    #[clap(long)]
+    progress: bool,
+
+    input: PathBuf,
+    output: PathBuf,
+}
+
+fn create_output(command: &Command, size: u64) -> anyhow::Result<Box<dyn Disk>> {
+    match command.output_format {
+        DiskFormat::Device => bail!("converting to physical device is not supported"),
+        f => create_disk(
+            &command.output,
+            f,
+            size,
+            command.statically_sized,
+            &VhdCreateOptions::default(),
+        ),
+    }
+}
+
+pub fn run(command: Command) -> anyhow::Result<()> {
+    let mut input = open_disk(
+        command.input.as_path(),
+        command.input_format,
+        AccessMode::ReadOnly,
+    )?;
+    let length = input.disk_size();
+
+    let mut output = create_output(&command, length)?;
+    if output.disk_size() < length {
+        bail!(
+            "output disk is smaller than input disk ({} < {})",
+            output.disk_size(),
+            length
+        );
+    }
+
+    let mut buf = vec![0; min(length.try_into().unwrap_or(usize::MAX), 16777216)];
+
+    input.seek(SeekFrom::Start(0)).context("seek failed")?;
+    output.seek(SeekFrom::Start(0)).context("seek failed")?;
+
+    let mut left = length;
+    while left > 0 {
+        let start_time = Instant::now();
+        let n = min(left.try_into().unwrap_or(usize::MAX), buf.len());
+        input.read_exact(&mut buf[..n]).context("read failed")?;
+
+        for block in buf[..n].chunks(ZERO_BLOCK_SIZE) {
+            if block.iter().all(|x| *x == 0) {
+                output
+                    .seek(SeekFrom::Current(block.len() as i64))
+                    .context("seek failed")?;
+            } else {
+                output.write_all(block).context("write failed")?;
+            }
+        }
+        left -= n as u64;
+
+        if command.progress {
+            let end_time = Instant::now();
+            let duration = end_time.duration_since(start_time);
+            let bytes_per_second = n as f64 / duration.as_secs_f64();
+            display_progress(left, length, bytes_per_second);
+        }
+    }
+
+    output.flush().context("flush failed")?;
+
+    Ok(())
+}
@@ -1,13 +1,31 @@
+use std::cmp::min;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use crate::utils::parse_size;
+use crate::utils::vhd::VhdCreateArgs;
 use anyhow::Context;
-use clap::Parser;
-use diskutil::disk::vhd::{DiskType as VhdDiskType, VhdDisk};
-use diskutil::disk::DiskFormat;
+use clap::{ArgEnum, Parser};
+use diskutil::disk::qcow2::Qcow2Disk;
+use diskutil::disk::raw::RawDisk;
+use diskutil::disk::vdi::VdiDisk;
+use diskutil::disk::vhd::{
+    CreateOptions as VhdCreateOptions, DiskType as VhdDiskType, FixedVhdDisk, VhdDisk,
+};
+use diskutil::disk::vhdx::VhdxDisk;
+use diskutil::disk::vmdk::VmdkDisk;
 use diskutil::disk::FileBackend;
+use diskutil::disk::{Disk, DiskFormat, MediaType};
+use diskutil::part::gpt::Gpt;
+use diskutil::part::mbr::Mbr;
+
+#[derive(Copy, Clone, ArgEnum)]
+pub enum PartitionTableType {
+    Gpt,
+    Mbr,
+}
 
 #[derive(Parser)]
 #[clap(about = "Create disk images")]
@@ -22,42 +40,253 @@ pub struct Command {
     )]
     pub statically_sized: bool,
 
+    #[clap(
+        long,
+        help = "Fully allocate raw image, by default sparse file is created"
+    )]
+    pub preallocate: bool,
+
+    #[clap(
+        arg_enum,
+        long = "table",
+        help = "Create empty partition table on the new disk"
+    )]
+    pub partition_table: Option<PartitionTableType>,
+
+    #[clap(
+        short,
+        long,
+        help = "Create differencing disk backed by parent, size is taken from parent."
+    )]
+    pub parent: Option<PathBuf>,
+
     pub file: PathBuf,
 
-    #[clap(parse(try_from_str = parse_size))]
-    pub size: u64,
+    #[clap(parse(try_from_str = parse_size), required_unless_present = "parent")]
+    pub size: Option<u64>,
+
+    #[clap(flatten)]
+    pub vhd: VhdCreateArgs,
 }
 
-pub fn create_vhd(file: File, size: u64, disk_type: VhdDiskType) -> anyhow::Result<()> {
+/// Creates raw image as sparse file, preallocated image is filled with zeros
+pub fn create_raw(mut file: File, size: u64, preallocate: bool) -> anyhow::Result<Box<dyn Disk>> {
+    // raw disks consist of whole sectors
+    let size = (size + 511) / 512 * 512;
+
+    if preallocate {
+        let zeros = vec![0u8; 1024 * 1024];
+        let mut left = size;
+        while left > 0 {
+            let n = min(left, zeros.len() as u64) as usize;
+            file.write_all(&zeros[..n]).context("write failed")?;
+            left -= n as u64;
+        }
+        file.flush().context("flush failed")?;
+    } else {
+        file.set_len(size).context("failed to set file size")?;
+    }
+
+    let b = FileBackend::new(file).context("failed to initialize backend")?;
+    Ok(Box::new(RawDisk::open(b, 512, MediaType::HDD)))
+}
+
+pub fn create_vhd(
+    file: File,
+    size: u64,
+    disk_type: VhdDiskType,
+    options: &VhdCreateOptions,
+) -> anyhow::Result<Box<dyn Disk>> {
+    let b = FileBackend::new(file).context("failed to initialize backend")?;
+    match disk_type {
+        VhdDiskType::Dynamic => VhdDisk::create_dynamic_ex(b, size.try_into().unwrap(), options)
+            .map(|x| Box::new(x) as Box<dyn Disk>)
+            .context("failed to create VHD disk"),
+        VhdDiskType::Fixed => FixedVhdDisk::create_ex(b, size.try_into().unwrap(), options)
+            .map(|x| Box::new(x) as Box<dyn Disk>)
+            .context("failed to create VHD disk"),
+        VhdDiskType::Differencing => bail!("differencing disks can't be created this way"),
+    }
+}
+
+pub fn create_differencing_vhd(
+    file: File,
+    path: &Path,
+    parent: &Path,
+    options: &VhdCreateOptions,
+) -> anyhow::Result<Box<dyn Disk>> {
     let b = FileBackend::new(file).context("failed to initialize backend")?;
-    if disk_type == VhdDiskType::Dynamic {
-        VhdDisk::create_dynamic(b, size.try_into().unwrap())
-            .map(|_| ())
-            .context("failed to create VHD disk")
+    VhdDisk::create_differencing_ex(b, path, parent, options)
+        .map(|x| Box::new(x) as Box<dyn Disk>)
+        .context("failed to create differencing VHD disk")
+}
+
+pub fn create_vhdx(file: File, size: u64) -> anyhow::Result<Box<dyn Disk>> {
+    let b = FileBackend::new(file).context("failed to initialize backend")?;
+    VhdxDisk::create(b, size)
+        .map(|x| Box::new(x) as Box<dyn Disk>)
+        .context("failed to create VHDX disk")
+}
+
+pub fn create_qcow2(file: File, size: u64) -> anyhow::Result<Box<dyn Disk>> {
+    let b = FileBackend::new(file).context("failed to initialize backend")?;
+    Qcow2Disk::create(b, size)
+        .map(|x| Box::new(x) as Box<dyn Disk>)
+        .context("failed to create QCOW2 image")
+}
+
+pub fn create_vdi(file: File, size: u64, statically_sized: bool) -> anyhow::Result<Box<dyn Disk>> {
+    let b = FileBackend::new(file).context("failed to initialize backend")?;
+    if statically_sized {
+        VdiDisk::create_fixed(b, size)
+    } else {
+        VdiDisk::create_dynamic(b, size)
+    }
+    .map(|x| Box::new(x) as Box<dyn Disk>)
+    .context("failed to create VDI image")
+}
+
+/// Creates monolithicSparse image or monolithicFlat image when statically sized,
+/// flat extent is stored next to descriptor as <name>-flat.vmdk
+pub fn create_vmdk(
+    path: &Path,
+    size: u64,
+    statically_sized: bool,
+) -> anyhow::Result<Box<dyn Disk>> {
+    let file_name = path
+        .file_name()
+        .context("invalid file name")?
+        .to_string_lossy()
+        .into_owned();
+
+    if statically_sized {
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let extent_name = format!("{}-flat.vmdk", stem);
+        let extent = create_file(&path.with_file_name(&extent_name))?;
+        let descriptor = create_file(path)?;
+
+        VmdkDisk::create_flat(
+            FileBackend::new(descriptor).context("failed to initialize backend")?,
+            FileBackend::new(extent).context("failed to initialize backend")?,
+            &extent_name,
+            size,
+        )
+        .map(|x| Box::new(x) as Box<dyn Disk>)
+        .context("failed to create VMDK image")
     } else {
-        unimplemented!()
+        let b = FileBackend::new(create_file(path)?).context("failed to initialize backend")?;
+        VmdkDisk::create_sparse(b, size, &file_name)
+            .map(|x| Box::new(x) as Box<dyn Disk>)
+            .context("failed to create VMDK image")
     }
 }
 
+pub fn create_file(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .context("failed to create file")
+}
+
+/// Writes empty partition table, GPT is accompanied by protective MBR
+pub fn create_partition_table(
+    disk: &mut dyn Disk,
+    table: PartitionTableType,
+) -> anyhow::Result<()> {
+    match table {
+        PartitionTableType::Gpt => {
+            Mbr::create_protective(disk)
+                .update(disk)
+                .context("failed to write MBR")?;
+            Gpt::create(disk)
+                .context("failed to create GPT")?
+                .update(disk)
+                .context("failed to write GPT")?;
+        }
+        PartitionTableType::Mbr => Mbr::create_empty()
+            .update(disk)
+            .context("failed to write MBR")?,
+    }
+
+    disk.flush().context("flush failed")
+}
+
 pub fn run(command: Command) -> anyhow::Result<()> {
-    match command.format {
-        DiskFormat::RAW => {
-            unimplemented!()
+    if command.preallocate && command.format != DiskFormat::RAW {
+        bail!("--preallocate is supported only by raw images, use --static instead");
+    }
+    if command.format != DiskFormat::VHD && !command.vhd.is_empty() {
+        bail!("VHD options can't be used with {} format", command.format);
+    }
+    let vhd_options = command.vhd.to_options();
+    vhd_options.validate().context("invalid VHD options")?;
+
+    if let Some(parent) = command.parent.as_ref() {
+        if command.format != DiskFormat::VHD {
+            bail!("differencing disks are supported only by VHD");
+        }
+        if command.size.is_some() {
+            bail!("size can't be specified for differencing disk");
         }
+
+        if command.partition_table.is_some() {
+            bail!("partition table of differencing disk is inherited from parent");
+        }
+
+        return create_differencing_vhd(
+            create_file(&command.file)?,
+            &command.file,
+            parent,
+            &vhd_options,
+        )
+        .map(|_| ());
+    }
+
+    let mut disk = create_disk(
+        &command.file,
+        command.format,
+        command.size.unwrap(),
+        command.statically_sized || command.preallocate,
+        &vhd_options,
+    )?;
+
+    if let Some(table) = command.partition_table {
+        create_partition_table(disk.as_mut(), table)?;
+    }
+
+    Ok(())
+}
+
+/// Creates new disk of given format, fails if file already exists.
+/// VHD options are ignored by other formats.
+pub fn create_disk(
+    path: &Path,
+    format: DiskFormat,
+    size: u64,
+    statically_sized: bool,
+    vhd_options: &VhdCreateOptions,
+) -> anyhow::Result<Box<dyn Disk>> {
+    match format {
+        DiskFormat::RAW => create_raw(create_file(path)?, size, statically_sized),
         DiskFormat::VHD => create_vhd(
-            OpenOptions::new()
-                .read(false)
-                .write(true)
-                .create_new(true)
-                .open(command.file)
-                .context("failed to create file")?,
-            command.size,
-            if command.statically_sized {
+            create_file(path)?,
+            size,
+            if statically_sized {
                 VhdDiskType::Fixed
             } else {
                 VhdDiskType::Dynamic
             },
+            vhd_options,
         ),
+        DiskFormat::VHDX if !statically_sized => create_vhdx(create_file(path)?, size),
+        DiskFormat::QCOW2 if !statically_sized => create_qcow2(create_file(path)?, size),
+        DiskFormat::VDI => create_vdi(create_file(path)?, size, statically_sized),
+        DiskFormat::VMDK => create_vmdk(path, size, statically_sized),
+        DiskFormat::VHDX | DiskFormat::QCOW2 => {
+            bail!("statically sized {} disks are not supported", format)
+        }
         t => bail!("unsupported disk type {}", t),
     }
 }
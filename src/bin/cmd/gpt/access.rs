@@ -6,7 +6,11 @@ pub fn get_access_mode(command: &Command) -> AccessMode {
         SubCommand::Create(_)
         | SubCommand::Add(_)
         | SubCommand::Delete(_)
-        | SubCommand::Modify(_) => AccessMode::ReadWrite,
-        SubCommand::Dump | SubCommand::Info => AccessMode::ReadOnly,
+        | SubCommand::Modify(_)
+        | SubCommand::Hybrid(_)
+        | SubCommand::FixEnd(_) => AccessMode::ReadWrite,
+        SubCommand::Repair(ref opt) if opt.dry_run => AccessMode::ReadOnly,
+        SubCommand::Repair(_) => AccessMode::ReadWrite,
+        SubCommand::Dump | SubCommand::Info(_) => AccessMode::ReadOnly,
     }
 }
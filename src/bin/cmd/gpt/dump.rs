@@ -3,11 +3,17 @@ use std::mem::transmute;
 use crate::utils;
 use diskutil::disk::Disk;
 use diskutil::part::gpt::{uuid128_partition_type_guid_to_name, Gpt};
+use uuid::Uuid;
+
+pub fn partition_type_name(type_guid: &Uuid) -> Option<&'static str> {
+    // TODO: replace this with safe alternative
+    uuid128_partition_type_guid_to_name(unsafe { transmute(type_guid.as_u128()) })
+}
 
 pub fn dump(disk: &dyn Disk, gpt: &Gpt) -> anyhow::Result<()> {
     println!(
-        "{:<5} {:<8} {:<8} {:<8} {:<38} {:<45} Name",
-        "Index", "Start", "End", "Size", "Unique GUID", "Type"
+        "{:<5} {:<8} {:<8} {:<8} {:<38} {:<45} {:<24} Name",
+        "Index", "Start", "End", "Size", "Unique GUID", "Type", "Attributes"
     );
 
     for (i, p) in gpt
@@ -21,30 +27,28 @@ pub fn dump(disk: &dyn Disk, gpt: &Gpt) -> anyhow::Result<()> {
             .checked_sub(p.start_lba)
             .map(|x| (x + 1).saturating_mul(disk.sector_size().into()))
         {
-            // TODO: replace this with safe alternative
-            let t =
-                uuid128_partition_type_guid_to_name(unsafe { transmute(p.type_guid.as_u128()) });
-
-            if let Some(t) = t {
+            if let Some(t) = partition_type_name(&p.type_guid) {
                 println!(
-                    "{:<5} {:<8} {:<8} {:<8} {{{:<38X}}} {:<45} {}",
+                    "{:<5} {:<8} {:<8} {:<8} {{{:<38X}}} {:<45} {:<24} {}",
                     i,
                     p.start_lba,
                     p.end_lba,
                     utils::size_to_string(size),
                     p.unique_guid,
                     t,
+                    p.flags().to_string(),
                     &p.partition_name
                 );
             } else {
                 println!(
-                    "{:<5} {:<8} {:<8} {:<8} {{{:<38X}}} {:<45} {}",
+                    "{:<5} {:<8} {:<8} {:<8} {{{:<38X}}} {:<45} {:<24} {}",
                     i,
                     p.start_lba,
                     p.end_lba,
                     utils::size_to_string(size),
                     p.unique_guid,
                     p.type_guid.to_string(),
+                    p.flags().to_string(),
                     &p.partition_name
                 );
             }
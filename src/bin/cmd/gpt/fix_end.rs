@@ -0,0 +1,31 @@
+use super::FixEndOptions;
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::gpt::Gpt;
+
+pub fn fix_end(disk: &mut dyn Disk, gpt: &mut Gpt, opt: &FixEndOptions) -> anyhow::Result<()> {
+    let last_lba = disk.disk_size() / disk.sector_size() as u64 - 1;
+
+    if gpt.alternate_lba != last_lba {
+        let old_alternate_lba = gpt.alternate_lba;
+        gpt.move_backup_to_end(disk)
+            .context("failed to move GPT backup")?;
+        println!(
+            "Moved GPT backup header from LBA {} to LBA {}",
+            old_alternate_lba, gpt.alternate_lba
+        );
+    } else {
+        println!("GPT backup header is already at the end of disk");
+    }
+
+    if opt.grow_last {
+        if let Some(i) = gpt.grow_last_partition() {
+            gpt.update(disk).context("failed to update GPT")?;
+            println!("Partition {} now ends at LBA {}", i, gpt.last_usable_lba);
+        } else {
+            println!("There is no free space after the last partition");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use super::HybridOptions;
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::gpt::{Gpt, GptPartitionType};
+use diskutil::part::mbr::{HybridEntry, Mbr, TYPE_EFI, TYPE_LINUX, TYPE_LINUX_SWAP, TYPE_NTFS};
+use uuid::Uuid;
+
+fn guess_partition_type(type_guid: Uuid) -> u8 {
+    if type_guid == GptPartitionType::EFISystemPartition.to_guid() {
+        TYPE_EFI
+    } else if type_guid == GptPartitionType::MicrosoftBasicData.to_guid() {
+        TYPE_NTFS
+    } else if type_guid == GptPartitionType::LinuxSwap.to_guid() {
+        TYPE_LINUX_SWAP
+    } else {
+        TYPE_LINUX
+    }
+}
+
+pub fn hybrid(disk: &mut dyn Disk, gpt: &Gpt, opt: &HybridOptions) -> anyhow::Result<()> {
+    if let Some(active) = opt.active {
+        if !opt.partitions.iter().any(|x| x.index == active) {
+            bail!("active partition must be one of mirrored partitions");
+        }
+    }
+
+    let mut entries = Vec::new();
+    for p in opt.partitions.iter() {
+        let partition_type = match p.partition_type {
+            Some(x) => x,
+            None => guess_partition_type(
+                gpt.get_partition(p.index)
+                    .ok_or_else(|| anyhow!("GPT partition {} not found", p.index))?
+                    .type_guid,
+            ),
+        };
+
+        entries.push(HybridEntry {
+            gpt_index: p.index,
+            partition_type,
+            active: opt.active == Some(p.index),
+        });
+    }
+
+    let mut mbr = Mbr::create_hybrid(disk, gpt, &entries)?;
+    // keep boot code installed by boot loader
+    if let Ok(old) = Mbr::load(disk) {
+        mbr.code = old.code;
+    }
+
+    mbr.update(disk).context("failed to write MBR")
+}
@@ -0,0 +1,231 @@
+use super::dump::partition_type_name;
+use super::InfoOptions;
+use crate::utils;
+use diskutil::disk::Disk;
+use diskutil::part::gpt::{Gpt, GptCopy, GptHeaderInfo};
+use diskutil::region::Region;
+use serde::Serialize;
+
+fn yes_no(x: bool) -> &'static str {
+    if x {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn valid_invalid(x: bool) -> &'static str {
+    if x {
+        "valid"
+    } else {
+        "invalid"
+    }
+}
+
+fn print_copy(name: &str, info: &diskutil::Result<GptHeaderInfo>) {
+    match info {
+        Ok(x) => {
+            println!(
+                "{:<7} Header CRC32  : 0x{:08x} ({})",
+                name,
+                x.header_crc32,
+                valid_invalid(x.header_crc32_valid)
+            );
+            println!(
+                "{:<7} Table CRC32   : 0x{:08x} ({})",
+                name,
+                x.partition_table_crc32,
+                valid_invalid(x.partition_table_crc32_valid)
+            );
+        }
+        Err(e) => println!("{:<7} GPT           : damaged ({})", name, e),
+    }
+}
+
+/// Header fields of one GPT copy, or the reason it couldn't be read
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CopyJson {
+    Header {
+        current_lba: u64,
+        alternate_lba: u64,
+        partition_table_start: u64,
+        header_crc32: u32,
+        header_crc32_valid: bool,
+        partition_table_crc32: u32,
+        partition_table_crc32_valid: bool,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl CopyJson {
+    fn new(info: &diskutil::Result<GptHeaderInfo>) -> Self {
+        match info {
+            Ok(x) => Self::Header {
+                current_lba: x.current_lba,
+                alternate_lba: x.alternate_lba,
+                partition_table_start: x.partition_table_start,
+                header_crc32: x.header_crc32,
+                header_crc32_valid: x.header_crc32_valid,
+                partition_table_crc32: x.partition_table_crc32,
+                partition_table_crc32_valid: x.partition_table_crc32_valid,
+            },
+            Err(e) => Self::Error {
+                error: e.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FreeRegionJson {
+    start_lba: u64,
+    end_lba: u64,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct PartitionJson {
+    index: usize,
+    start_lba: u64,
+    end_lba: u64,
+    size: u64,
+    type_guid: String,
+    type_name: Option<&'static str>,
+    unique_guid: String,
+    attributes: u64,
+    flags: String,
+    name: String,
+}
+
+/// Output of --json
+#[derive(Serialize)]
+struct InfoJson {
+    revision: String,
+    disk_guid: String,
+    current_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    partition_table_start: u64,
+    partition_entries: u32,
+    partition_entry_size: u32,
+    loaded_copy: &'static str,
+    primary: CopyJson,
+    backup: CopyJson,
+    consistent: bool,
+    free_regions: Vec<FreeRegionJson>,
+    partitions: Vec<PartitionJson>,
+}
+
+fn partitions_json(gpt: &Gpt, sector_size: u64) -> Vec<PartitionJson> {
+    gpt.partitions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| x.as_ref().map(|x| (i, x)))
+        .map(|(i, p)| PartitionJson {
+            index: i,
+            start_lba: p.start_lba,
+            end_lba: p.end_lba,
+            size: (p.end_lba + 1)
+                .saturating_sub(p.start_lba)
+                .saturating_mul(sector_size),
+            type_guid: p.type_guid.to_string(),
+            type_name: partition_type_name(&p.type_guid),
+            unique_guid: p.unique_guid.to_string(),
+            attributes: p.attributes,
+            flags: p.flags().to_string(),
+            name: p.partition_name.clone(),
+        })
+        .collect()
+}
+
+pub fn info(
+    disk: &mut dyn Disk,
+    gpt: &Gpt,
+    copy: GptCopy,
+    opt: &InfoOptions,
+) -> anyhow::Result<()> {
+    let sector_size = disk.sector_size() as u64;
+    let primary = Gpt::read_header_info(disk, gpt.current_lba);
+    let backup = Gpt::read_header_info(disk, gpt.alternate_lba);
+    let consistent = match (&primary, &backup) {
+        (Ok(primary), Ok(backup)) => primary.is_consistent_with(backup),
+        _ => false,
+    };
+
+    let mut free_regions: Vec<Region<u64>> = gpt.find_free_regions();
+    free_regions.sort_by_key(|x| x.start());
+
+    let revision = format!("{}.{}", gpt.revision >> 16, gpt.revision & 0xFFFF);
+    let loaded_copy = match copy {
+        GptCopy::Primary => "primary",
+        GptCopy::Backup => "backup",
+    };
+
+    if opt.json {
+        let json = InfoJson {
+            revision,
+            disk_guid: gpt.disk_guid.to_string(),
+            current_lba: gpt.current_lba,
+            alternate_lba: gpt.alternate_lba,
+            first_usable_lba: gpt.first_usable_lba,
+            last_usable_lba: gpt.last_usable_lba,
+            partition_table_start: gpt.partition_table_start,
+            partition_entries: gpt.partition_table_entries_num,
+            partition_entry_size: gpt.partition_table_entry_size,
+            loaded_copy,
+            primary: CopyJson::new(&primary),
+            backup: CopyJson::new(&backup),
+            consistent,
+            free_regions: free_regions
+                .iter()
+                .map(|x| FreeRegionJson {
+                    start_lba: x.start(),
+                    end_lba: x.end(),
+                    size: x.size().saturating_mul(sector_size),
+                })
+                .collect(),
+            partitions: partitions_json(gpt, sector_size),
+        };
+        println!("{}", serde_json::to_string_pretty(&json)?);
+        return Ok(());
+    }
+
+    println!("Revision              : {}", revision);
+    println!("Disk GUID             : {{{}}}", gpt.disk_guid);
+    println!("Current LBA           : {}", gpt.current_lba);
+    println!("Alternate LBA         : {}", gpt.alternate_lba);
+    println!(
+        "Usable LBA Range      : {} - {}",
+        gpt.first_usable_lba, gpt.last_usable_lba
+    );
+    println!("Partition Table LBA   : {}", gpt.partition_table_start);
+    println!(
+        "Partition Entries     : {} x {} bytes",
+        gpt.partition_table_entries_num, gpt.partition_table_entry_size
+    );
+    println!("Loaded Copy           : {}", loaded_copy);
+    print_copy("Primary", &primary);
+    print_copy("Backup", &backup);
+    println!("Copies Consistent     : {}", yes_no(consistent));
+
+    println!();
+    if free_regions.is_empty() {
+        println!("No free space");
+    } else {
+        println!("{:<10} {:<10} Size", "Start", "End");
+        for x in free_regions {
+            println!(
+                "{:<10} {:<10} {}",
+                x.start(),
+                x.end(),
+                utils::size_to_string(x.size().saturating_mul(sector_size))
+            );
+        }
+    }
+
+    Ok(())
+}
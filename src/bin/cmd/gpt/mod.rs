@@ -6,7 +6,7 @@ use crate::{
 };
 use anyhow::Context;
 use clap::{ArgEnum, Parser};
-use diskutil::part::gpt::{ErrorAction, Gpt, GptPartitionType};
+use diskutil::part::gpt::{ErrorAction, Gpt, GptAttributes, GptCopy, GptPartitionType};
 use uuid::Uuid;
 
 mod access;
@@ -14,9 +14,13 @@ mod add;
 mod create;
 mod delete;
 mod dump;
+mod fix_end;
+mod hybrid;
+mod info;
 mod modify;
+mod repair;
 
-fn parse_partition_type(s: &str) -> ::std::result::Result<Uuid, String> {
+pub(crate) fn parse_partition_type(s: &str) -> ::std::result::Result<Uuid, String> {
     if s.chars().next().map_or(false, |x| x == '{')
         && s.chars().rev().next().map_or(false, |x| x == '}')
     {
@@ -28,11 +32,95 @@ fn parse_partition_type(s: &str) -> ::std::result::Result<Uuid, String> {
             "msbasic" => Ok(GptPartitionType::MicrosoftBasicData.to_guid()),
             "msreserved" => Ok(GptPartitionType::MicrosoftReserved.to_guid()),
             "efi" | "esp" => Ok(GptPartitionType::EFISystemPartition.to_guid()),
+            "linux" => Ok(GptPartitionType::LinuxFilesystem.to_guid()),
+            "swap" => Ok(GptPartitionType::LinuxSwap.to_guid()),
             _ => Err("Unknown partition type".to_owned()),
         }
     }
 }
 
+pub enum AttributeChange {
+    Set(GptAttributes),
+    Clear(GptAttributes),
+    Priority(u8),
+    Tries(u8),
+}
+
+/// Comma separated attribute changes, eg. +hidden,-legacy-boot,priority=2
+pub struct AttributeChanges(Vec<AttributeChange>);
+
+impl FromStr for AttributeChanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        let parse_flag = |name: &str| {
+            GptAttributes::from_name(name).ok_or_else(|| {
+                format!(
+                    "Unknown attribute {}, expected one of: {}, priority=N, tries=N",
+                    name,
+                    GptAttributes::names().collect::<Vec<_>>().join(", ")
+                )
+            })
+        };
+        let parse_value = |x: &str| match x.parse::<u8>() {
+            Ok(x) if x <= 15 => Ok(x),
+            _ => Err(format!("invalid value {}, expected 0 to 15", x)),
+        };
+
+        let mut changes = Vec::new();
+        for x in s.split(',') {
+            let change = if let Some(x) = x.strip_prefix("priority=") {
+                AttributeChange::Priority(parse_value(x)?)
+            } else if let Some(x) = x.strip_prefix("tries=") {
+                AttributeChange::Tries(parse_value(x)?)
+            } else if let Some(x) = x.strip_prefix('-') {
+                AttributeChange::Clear(parse_flag(x)?)
+            } else {
+                AttributeChange::Set(parse_flag(x.strip_prefix('+').unwrap_or(x))?)
+            };
+            changes.push(change);
+        }
+
+        Ok(Self(changes))
+    }
+}
+
+impl AttributeChanges {
+    pub fn apply(&self, mut flags: GptAttributes) -> GptAttributes {
+        for change in self.0.iter() {
+            match change {
+                AttributeChange::Set(x) => flags.insert(*x),
+                AttributeChange::Clear(x) => flags.remove(*x),
+                AttributeChange::Priority(x) => flags.set_chromeos_priority(*x),
+                AttributeChange::Tries(x) => flags.set_chromeos_tries(*x),
+            }
+        }
+        flags
+    }
+}
+
+/// GPT partition index with optional MBR partition type, eg. 1:linux
+pub struct HybridPartition {
+    index: u32,
+    partition_type: Option<u8>,
+}
+
+impl FromStr for HybridPartition {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        let (index, partition_type) = match s.split_once(':') {
+            Some((index, t)) => (index, Some(crate::cmd::mbr::parse_partition_type(t)?)),
+            None => (s, None),
+        };
+
+        Ok(Self {
+            index: index.parse::<u32>().map_err(|e| e.to_string())?,
+            partition_type,
+        })
+    }
+}
+
 #[derive(Copy, Clone, ArgEnum)]
 pub enum MbrCreateMode {
     Protective,
@@ -63,7 +151,7 @@ pub struct AddOptions {
     #[clap(short = 'u', long = "guid", help = "Unique GUID")]
     unique_guid: Option<Uuid>,
 
-    #[clap(short = 't', long = "type", parse(try_from_str = parse_partition_type), long_help = "Type GUID or type alias eg. msbasic, msreserved, esp")]
+    #[clap(short = 't', long = "type", parse(try_from_str = parse_partition_type), long_help = "Type GUID or type alias eg. msbasic, msreserved, esp, linux, swap")]
     type_guid: Option<Uuid>,
 }
 
@@ -85,8 +173,47 @@ pub struct ModifyOptions {
     #[clap(short = 'u', long = "guid", help = "Unique GUID")]
     unique_guid: Option<Uuid>,
 
-    #[clap(short = 't', long = "type", parse(try_from_str = parse_partition_type), long_help = "Type GUID or type alias eg. msbasic, msreserved, esp")]
+    #[clap(short = 't', long = "type", parse(try_from_str = parse_partition_type), long_help = "Type GUID or type alias eg. msbasic, msreserved, esp, linux, swap")]
     type_guid: Option<Uuid>,
+
+    #[clap(
+        long = "attr",
+        allow_hyphen_values = true,
+        parse(try_from_str),
+        long_help = "Comma separated attribute changes eg. +hidden,-legacy-boot,priority=2,tries=1"
+    )]
+    attributes: Option<AttributeChanges>,
+}
+
+#[derive(Parser)]
+pub struct HybridOptions {
+    #[clap(
+        required = true,
+        max_values = 3,
+        long_help = "GPT partitions to mirror into MBR as INDEX[:TYPE], MBR type is guessed from GPT type when not specified"
+    )]
+    partitions: Vec<HybridPartition>,
+
+    #[clap(long, help = "Index of GPT partition to mark as active")]
+    active: Option<u32>,
+}
+
+#[derive(Parser)]
+pub struct FixEndOptions {
+    #[clap(long, help = "Grow the last partition into the new space")]
+    grow_last: bool,
+}
+
+#[derive(Parser)]
+pub struct InfoOptions {
+    #[clap(long, help = "Print information as JSON, including list of partitions")]
+    json: bool,
+}
+
+#[derive(Parser)]
+pub struct RepairOptions {
+    #[clap(long, help = "Only report problems, don't modify the disk")]
+    dry_run: bool,
 }
 
 #[derive(Parser)]
@@ -95,7 +222,7 @@ pub enum SubCommand {
     Create(CreateOptions),
 
     #[clap(about = "Print general information about partition table")]
-    Info,
+    Info(InfoOptions),
 
     #[clap(about = "Dump raw contents of partition table")]
     Dump,
@@ -110,6 +237,15 @@ pub enum SubCommand {
     #[clap(about = "Modify things like partition name, type, GUID, etc.")]
     #[clap(alias = "mod")]
     Modify(ModifyOptions),
+
+    #[clap(about = "Create hybrid MBR mirroring up to three GPT partitions")]
+    Hybrid(HybridOptions),
+
+    #[clap(about = "Restore damaged primary or backup GPT from the other copy")]
+    Repair(RepairOptions),
+
+    #[clap(about = "Move backup GPT to the end of disk after disk was enlarged")]
+    FixEnd(FixEndOptions),
 }
 
 #[derive(Parser)]
@@ -129,18 +265,26 @@ pub fn run(command: Command) -> anyhow::Result<()> {
         access::get_access_mode(&command),
     )?;
 
-    if let SubCommand::Create(opt) = command.cmd {
-        return create::create(disk.as_mut(), &opt);
+    match command.cmd {
+        SubCommand::Create(opt) => return create::create(disk.as_mut(), &opt),
+        SubCommand::Repair(opt) => return repair::repair(disk.as_mut(), &opt),
+        _ => (),
     }
 
-    let mut gpt = Gpt::load(disk.as_mut(), ErrorAction::Ignore).context("failed to load GPT")?;
+    let (mut gpt, copy) =
+        Gpt::load_any(disk.as_mut(), ErrorAction::Ignore).context("failed to load GPT")?;
+    if copy == GptCopy::Backup {
+        eprintln!("Primary GPT is damaged, using backup. Use 'gpt repair' to restore it.");
+    }
 
     match command.cmd {
-        SubCommand::Create(_) => unreachable!(),
+        SubCommand::Create(_) | SubCommand::Repair(_) => unreachable!(),
         SubCommand::Dump => dump::dump(disk.as_ref(), &gpt),
-        SubCommand::Info => unimplemented!(),
+        SubCommand::Info(opt) => info::info(disk.as_mut(), &gpt, copy, &opt),
         SubCommand::Add(opt) => add::add(disk.as_mut(), &mut gpt, &opt),
         SubCommand::Delete(opt) => delete::delete(disk.as_mut(), &mut gpt, &opt),
         SubCommand::Modify(opt) => modify::modify(disk.as_mut(), &mut gpt, &opt),
+        SubCommand::Hybrid(opt) => hybrid::hybrid(disk.as_mut(), &gpt, &opt),
+        SubCommand::FixEnd(opt) => fix_end::fix_end(disk.as_mut(), &mut gpt, &opt),
     }
 }
@@ -30,5 +30,9 @@ pub fn modify(disk: &mut dyn Disk, gpt: &mut Gpt, opt: &ModifyOptions) -> anyhow
         part.type_guid = type_guid;
     }
 
+    if let Some(changes) = &opt.attributes {
+        part.set_flags(changes.apply(part.flags()));
+    }
+
     gpt.update(disk).context("failed to update GPT")
 }
@@ -0,0 +1,19 @@
+use super::RepairOptions;
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::gpt::{Gpt, GptCopy};
+
+pub fn repair(disk: &mut dyn Disk, opt: &RepairOptions) -> anyhow::Result<()> {
+    let damaged = Gpt::repair(disk, opt.dry_run).context("failed to repair GPT")?;
+
+    match damaged {
+        None => println!("No problems found"),
+        Some(GptCopy::Primary) => println!("Primary GPT is damaged, restored from backup"),
+        Some(GptCopy::Backup) => println!("Backup GPT is damaged, restored from primary"),
+    }
+    if damaged.is_some() && opt.dry_run {
+        println!("Dry run, nothing was changed");
+    }
+
+    Ok(())
+}
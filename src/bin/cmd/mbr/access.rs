@@ -0,0 +1,11 @@
+use super::{Command, SubCommand};
+use crate::utils::AccessMode;
+
+pub fn get_access_mode(command: &Command) -> AccessMode {
+    match command.cmd {
+        SubCommand::Create | SubCommand::Add(_) | SubCommand::Delete(_) | SubCommand::Modify(_) => {
+            AccessMode::ReadWrite
+        }
+        SubCommand::Dump | SubCommand::Check => AccessMode::ReadOnly,
+    }
+}
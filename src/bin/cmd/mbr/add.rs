@@ -0,0 +1,81 @@
+use std::convert::TryInto;
+
+use super::AddOptions;
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::mbr::{Geometry, Mbr, MbrPartition, TYPE_LINUX};
+use diskutil::region::Region;
+
+const ALIGNMENT: u64 = 1024 * 1024;
+
+/// Finds the first free region which fits partition of given size when aligned,
+/// `reserved` sectors at the start of region are left for EBR
+fn find_free_region(
+    regions: &[Region<u64>],
+    alignment: u64,
+    reserved: u64,
+    size: u64,
+) -> Option<u64> {
+    for region in regions.iter() {
+        let start = (region.start() + reserved + alignment - 1) / alignment * alignment;
+        if start <= region.end() && region.end() - start + 1 >= size {
+            return Some(start);
+        }
+    }
+
+    None
+}
+
+pub fn add(disk: &mut dyn Disk, mbr: &mut Mbr, opt: &AddOptions) -> anyhow::Result<()> {
+    let sector_size = disk.sector_size() as u64;
+    let disk_sectors = disk.disk_size() / sector_size;
+
+    if opt.size % sector_size != 0 {
+        bail!("partition size is not multiple of sector size")
+    }
+    let size = opt.size / sector_size;
+
+    let start = match opt.start {
+        Some(x) => x,
+        None => {
+            let (regions, reserved) = if opt.logical {
+                (mbr.find_free_logical_regions(), 1)
+            } else {
+                (mbr.find_free_regions(disk_sectors), 0)
+            };
+            find_free_region(&regions, ALIGNMENT / sector_size, reserved, size)
+                .ok_or_else(|| anyhow::Error::msg("not enough free space"))?
+        }
+    };
+
+    if size == 0 || !Region::new_with_size(start, size).belongs(&Region::new(0, disk_sectors - 1)) {
+        bail!("new partition does not fit into disk")
+    }
+
+    let partition = MbrPartition::new(
+        opt.partition_type.unwrap_or(TYPE_LINUX),
+        start
+            .try_into()
+            .context("partition start is beyond MBR limit")?,
+        size.try_into()
+            .context("partition size is beyond MBR limit")?,
+        sector_size as u32,
+        &Geometry::default(),
+    );
+    if start + size > u32::MAX as u64 + 1 {
+        bail!("partition end is beyond MBR limit")
+    }
+
+    let index = if opt.logical {
+        mbr.add_logical_partition(partition)?
+    } else {
+        mbr.add_partition(partition)?
+    };
+    if opt.active {
+        mbr.set_active(Some(index))?;
+    }
+
+    println!("Added partition {}", index);
+
+    mbr.update(disk).context("failed to update MBR")
+}
@@ -0,0 +1,25 @@
+use diskutil::disk::Disk;
+use diskutil::part::mbr::Mbr;
+
+fn print_partitions(message: &str, partitions: &[usize]) {
+    if !partitions.is_empty() {
+        println!("{}: {:?}", message, partitions);
+    }
+}
+
+pub fn check(disk: &dyn Disk, mbr: &Mbr) -> anyhow::Result<()> {
+    let report = mbr.validate(disk.disk_size());
+    if report.is_clean() {
+        println!("No problems found");
+        return Ok(());
+    }
+
+    for (a, b) in report.overlapping.iter() {
+        println!("Partitions {} and {} overlap", a, b);
+    }
+    print_partitions("Partitions beyond the end of disk", &report.beyond_disk);
+    print_partitions("Partitions with CHS not matching LBA", &report.chs_mismatch);
+    print_partitions("Multiple active partitions", &report.multiple_active);
+
+    bail!("partition table has problems")
+}
@@ -0,0 +1,9 @@
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::mbr::Mbr;
+
+pub fn create(disk: &mut dyn Disk) -> anyhow::Result<()> {
+    Mbr::create_empty()
+        .update(disk)
+        .context("failed to write MBR")
+}
@@ -0,0 +1,12 @@
+use super::DeleteOptions;
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::mbr::Mbr;
+
+pub fn delete(disk: &mut dyn Disk, mbr: &mut Mbr, opt: &DeleteOptions) -> anyhow::Result<()> {
+    if mbr.remove_partition(opt.index).is_none() {
+        bail!("no such partition");
+    }
+
+    mbr.update(disk).context("failed to update MBR")
+}
@@ -0,0 +1,46 @@
+use crate::utils;
+use diskutil::disk::Disk;
+use diskutil::part::mbr::{partition_type_to_name, Mbr, FIRST_LOGICAL_INDEX};
+
+pub fn dump(disk: &dyn Disk, mbr: &Mbr) -> anyhow::Result<()> {
+    println!(
+        "{:<5} {:<6} {:<22} {:<10} {:<10} {:<8} {:<14} End CHS",
+        "Index", "Active", "Type", "Start", "End", "Size", "Start CHS"
+    );
+
+    let logical = mbr
+        .logical
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (FIRST_LOGICAL_INDEX + i, &x.partition));
+    for (i, p) in mbr
+        .partitions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| x.as_ref().map(|x| (i, x)))
+        .chain(logical)
+    {
+        let size = p.num_sectors as u64 * disk.sector_size() as u64;
+        let end = (p.lba as u64 + p.num_sectors as u64).saturating_sub(1);
+        let partition_type = match partition_type_to_name(p.partition_type) {
+            Some(name) => format!("{:02X} {}", p.partition_type, name),
+            None => format!("{:02X}", p.partition_type),
+        };
+
+        println!(
+            "{:<5} {:<6} {:<22} {:<10} {:<10} {:<8} {:<14} {}/{}/{}",
+            i,
+            if p.is_active() { "*" } else { "" },
+            partition_type,
+            p.lba,
+            end,
+            utils::size_to_string(size),
+            format!("{}/{}/{}", p.start_chs.0, p.start_chs.1, p.start_chs.2),
+            p.end_chs.0,
+            p.end_chs.1,
+            p.end_chs.2
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use crate::{
+    utils::{open_disk, parse_size},
+    CommonDiskOptions,
+};
+use anyhow::Context;
+use clap::Parser;
+use diskutil::part::mbr::{partition_type_from_alias, Mbr};
+
+mod access;
+mod add;
+mod check;
+mod create;
+mod delete;
+mod dump;
+mod modify;
+
+pub(crate) fn parse_partition_type(s: &str) -> ::std::result::Result<u8, String> {
+    if let Some(x) = partition_type_from_alias(s) {
+        return Ok(x);
+    }
+
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    match u8::from_str_radix(hex, 16) {
+        Ok(0) => Err("partition type 0 marks empty slot".to_owned()),
+        Ok(x) => Ok(x),
+        Err(_) => Err("Unknown partition type".to_owned()),
+    }
+}
+
+#[derive(Parser)]
+pub struct AddOptions {
+    #[clap(parse(try_from_str = parse_size))]
+    size: u64,
+
+    #[clap(short, long, help = "Partition first sector")]
+    start: Option<u64>,
+
+    #[clap(short = 't', long = "type", parse(try_from_str = parse_partition_type), long_help = "Type as hex byte or type alias eg. fat32, linux, ntfs, swap, efi, extended")]
+    partition_type: Option<u8>,
+
+    #[clap(long, help = "Mark partition as active (bootable)")]
+    active: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "active",
+        help = "Create logical partition inside of extended partition"
+    )]
+    logical: bool,
+}
+
+#[derive(Parser)]
+pub struct DeleteOptions {
+    #[clap(help = "Partition index, logical partitions start at 5")]
+    index: usize,
+}
+
+#[derive(Parser)]
+pub struct ModifyOptions {
+    #[clap(help = "Partition index, logical partitions start at 5")]
+    index: usize,
+
+    #[clap(short = 't', long = "type", parse(try_from_str = parse_partition_type), long_help = "Type as hex byte or type alias eg. fat32, linux, ntfs, swap, efi")]
+    partition_type: Option<u8>,
+
+    #[clap(long, help = "Mark partition as active (bootable)")]
+    active: bool,
+
+    #[clap(long, conflicts_with = "active", help = "Clear active flag")]
+    inactive: bool,
+}
+
+#[derive(Parser)]
+pub enum SubCommand {
+    #[clap(about = "Create new empty partition table")]
+    Create,
+
+    #[clap(about = "Dump raw contents of partition table")]
+    Dump,
+
+    #[clap(about = "Check partition table for overlapping partitions and other problems")]
+    Check,
+
+    #[clap(about = "Add partition")]
+    Add(AddOptions),
+
+    #[clap(about = "Delete partition")]
+    #[clap(alias = "del")]
+    Delete(DeleteOptions),
+
+    #[clap(about = "Modify partition type or active flag")]
+    #[clap(alias = "mod")]
+    Modify(ModifyOptions),
+}
+
+#[derive(Parser)]
+#[clap(about = "Manipulate MBR partition table")]
+pub struct Command {
+    #[clap(flatten)]
+    disk: CommonDiskOptions,
+
+    #[clap(subcommand)]
+    cmd: SubCommand,
+}
+
+pub fn run(command: Command) -> anyhow::Result<()> {
+    let mut disk = open_disk(
+        command.disk.file.as_path(),
+        command.disk.format,
+        access::get_access_mode(&command),
+    )?;
+
+    if let SubCommand::Create = command.cmd {
+        return create::create(disk.as_mut());
+    }
+
+    let mut mbr = Mbr::load(disk.as_mut()).context("failed to load MBR")?;
+
+    match command.cmd {
+        SubCommand::Create => unreachable!(),
+        SubCommand::Dump => dump::dump(disk.as_ref(), &mbr),
+        SubCommand::Check => check::check(disk.as_ref(), &mbr),
+        SubCommand::Add(opt) => add::add(disk.as_mut(), &mut mbr, &opt),
+        SubCommand::Delete(opt) => delete::delete(disk.as_mut(), &mut mbr, &opt),
+        SubCommand::Modify(opt) => modify::modify(disk.as_mut(), &mut mbr, &opt),
+    }
+}
@@ -0,0 +1,26 @@
+use super::ModifyOptions;
+use anyhow::Context;
+use diskutil::disk::Disk;
+use diskutil::part::mbr::{Mbr, FIRST_LOGICAL_INDEX, FLAG_ACTIVE};
+
+pub fn modify(disk: &mut dyn Disk, mbr: &mut Mbr, opt: &ModifyOptions) -> anyhow::Result<()> {
+    if opt.active && opt.index >= FIRST_LOGICAL_INDEX {
+        bail!("logical partition can't be active");
+    }
+
+    if mbr.get_partition(opt.index).is_none() {
+        bail!("no such partition");
+    }
+
+    if let Some(partition_type) = opt.partition_type {
+        mbr.set_partition_type(opt.index, partition_type)?;
+    }
+
+    if opt.active {
+        mbr.set_active(Some(opt.index))?;
+    } else if opt.inactive {
+        mbr.get_partition_mut(opt.index).unwrap().flags &= !FLAG_ACTIVE;
+    }
+
+    mbr.update(disk).context("failed to update MBR")
+}
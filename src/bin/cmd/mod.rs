@@ -1,5 +1,9 @@
+pub mod apply;
+pub mod convert;
 pub mod create;
 pub mod gpt;
 pub mod hexdump;
+pub mod mbr;
 pub mod read;
+pub mod vhd;
 pub mod write;
@@ -0,0 +1,97 @@
+use std::cmp::min;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::CompactOptions;
+use anyhow::Context;
+use diskutil::disk::vhd::VhdDisk;
+use diskutil::disk::{Argument, ArgumentMap, Disk, FileBackend};
+use diskutil::fs::fat;
+use diskutil::part::load_partition_table;
+
+/// Zeros clusters marked free in FAT volumes, only blocks that are already
+/// allocated are written. Returns number of bytes zeroed.
+fn zero_free_clusters(disk: &mut VhdDisk) -> anyhow::Result<u64> {
+    let mut volumes = Vec::new();
+    if let Ok(pt) = load_partition_table(disk) {
+        for i in 0..128 {
+            if let Some((start, _)) = pt.get_partition_start_end(i) {
+                volumes.push(start * disk.sector_size() as u64);
+            }
+        }
+    }
+    if volumes.is_empty() {
+        // volume without partition table
+        volumes.push(0);
+    }
+
+    let block_size = disk.dynamic_header().block_size as u64;
+    let zeros = vec![0u8; block_size as usize];
+    let mut zeroed = 0;
+    for offset in volumes {
+        let regions = match fat::free_regions(disk, offset) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("no FAT volume at {:#x}: {}", offset, e);
+                continue;
+            }
+        };
+        info!("zeroing free clusters of FAT volume at {:#x}", offset);
+
+        for region in regions {
+            let mut position = region.start();
+            let end = min(region.end() + 1, disk.disk_size());
+            while position < end {
+                let n = min(end - position, block_size - position % block_size);
+                if disk.bat()[(position / block_size) as usize] != 0xFFFFFFFF {
+                    disk.seek(SeekFrom::Start(position))
+                        .context("seek failed")?;
+                    disk.write_all(&zeros[..n as usize])
+                        .context("write failed")?;
+                    zeroed += n;
+                }
+                position += n;
+            }
+        }
+    }
+
+    Ok(zeroed)
+}
+
+pub fn compact(path: &Path, opt: &CompactOptions) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("failed to open file")?;
+    let backend = FileBackend::new(file.try_clone().context("failed to open file")?)
+        .context("failed to initialize backend")?;
+
+    // parent of differencing disk is searched relative to the image
+    let mut args = ArgumentMap::default();
+    args.insert(
+        "path",
+        Argument::String(path.to_string_lossy().into_owned()),
+    );
+    let mut disk = VhdDisk::open_with_argmap(backend, &args).context("failed to open VHD")?;
+    let before = disk.space_usage().context("failed to read file size")?;
+
+    if opt.fat {
+        let n = zero_free_clusters(&mut disk)?;
+        println!("Zeroed {} bytes of free clusters", n);
+    }
+
+    let file_size = disk.compact().context("failed to compact disk")?;
+    let after = disk.space_usage().context("failed to read file size")?;
+    drop(disk);
+    file.set_len(file_size).context("failed to set file size")?;
+
+    println!(
+        "Allocated blocks: {} -> {}",
+        before.allocated_blocks, after.allocated_blocks
+    );
+    println!("File size: {} -> {}", before.file_size, file_size);
+
+    Ok(())
+}
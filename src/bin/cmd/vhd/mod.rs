@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use crate::utils::parse_size;
+use clap::Parser;
+
+mod compact;
+mod repair;
+mod resize;
+
+#[derive(Parser)]
+pub struct RepairOptions {
+    #[clap(
+        short = 'n',
+        long,
+        help = "Only report problems, don't modify the image"
+    )]
+    dry_run: bool,
+}
+
+#[derive(Parser)]
+pub struct CompactOptions {
+    #[clap(
+        long,
+        help = "Zero clusters not used by FAT file systems before compacting"
+    )]
+    fat: bool,
+}
+
+#[derive(Parser)]
+pub struct ResizeOptions {
+    #[clap(parse(try_from_str = parse_size), help = "New virtual disk size")]
+    size: u64,
+
+    #[clap(
+        long,
+        help = "Move backup GPT header and partition table to the new end of disk"
+    )]
+    move_gpt_backup: bool,
+}
+
+#[derive(Parser)]
+pub enum SubCommand {
+    #[clap(
+        about = "Restore damaged footer, drop invalid BAT entries and separate overlapping blocks"
+    )]
+    Repair(RepairOptions),
+
+    #[clap(about = "Unallocate blocks containing only zeros and shrink the image")]
+    Compact(CompactOptions),
+
+    #[clap(about = "Grow or shrink virtual disk size of dynamic VHD")]
+    Resize(ResizeOptions),
+}
+
+#[derive(Parser)]
+#[clap(about = "Maintenance of VHD images")]
+pub struct Command {
+    file: PathBuf,
+
+    #[clap(subcommand)]
+    cmd: SubCommand,
+}
+
+pub fn run(command: Command) -> anyhow::Result<()> {
+    match command.cmd {
+        SubCommand::Repair(opt) => repair::repair(&command.file, &opt),
+        SubCommand::Compact(opt) => compact::compact(&command.file, &opt),
+        SubCommand::Resize(opt) => resize::resize(&command.file, &opt),
+    }
+}
@@ -0,0 +1,49 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use super::RepairOptions;
+use anyhow::Context;
+use diskutil::disk::{vhd, FileBackend};
+
+fn print_blocks(message: &str, blocks: &[usize]) {
+    if !blocks.is_empty() {
+        println!("{}: {:?}", message, blocks);
+    }
+}
+
+pub fn repair(path: &Path, opt: &RepairOptions) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!opt.dry_run)
+        .open(path)
+        .context("failed to open file")?;
+    let mut backend = FileBackend::new(file).context("failed to initialize backend")?;
+
+    let report = vhd::repair(backend.as_mut(), opt.dry_run).context("failed to repair image")?;
+    if report.is_clean() {
+        println!("No problems found");
+        return Ok(());
+    }
+
+    if report.footer_restored {
+        println!("Trailing footer is damaged, restored from copy");
+    }
+    if report.footer_copy_restored {
+        println!("Footer copy is damaged, restored from trailing footer");
+    }
+    print_blocks("Deallocated invalid blocks", &report.invalid_blocks);
+    print_blocks("Blocks truncated by end of file", &report.truncated_blocks);
+    print_blocks("Relocated overlapping blocks", &report.relocated_blocks);
+
+    if opt.dry_run {
+        println!("Dry run, nothing was changed");
+    } else {
+        // footer must be the last thing in file
+        (*backend)
+            .into_inner()
+            .set_len(report.file_size)
+            .context("failed to set file size")?;
+    }
+
+    Ok(())
+}
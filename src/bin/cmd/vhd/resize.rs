@@ -0,0 +1,97 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use super::ResizeOptions;
+use anyhow::Context;
+use diskutil::disk::vhd::VhdDisk;
+use diskutil::disk::{Argument, ArgumentMap, Disk, FileBackend};
+use diskutil::part::gpt::{ErrorAction, Gpt};
+
+/// Fails if some partition would overlap backup GPT at the end of disk of new size
+fn check_partitions_fit(gpt: &Gpt, new_size: u64, sector_size: u64) -> anyhow::Result<()> {
+    let last_usable_lba = gpt.last_usable_lba_for_size(new_size, sector_size);
+    for (i, partition) in gpt.partitions.iter().enumerate() {
+        if let Some(partition) = partition {
+            if partition.end_lba > last_usable_lba {
+                bail!(
+                    "partition {} extends beyond last usable LBA {} of new disk size",
+                    i,
+                    last_usable_lba
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn resize(path: &Path, opt: &ResizeOptions) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("failed to open file")?;
+    let backend = FileBackend::new(file).context("failed to initialize backend")?;
+
+    let mut args = ArgumentMap::default();
+    args.insert(
+        "path",
+        Argument::String(path.to_string_lossy().into_owned()),
+    );
+    let mut disk = VhdDisk::open_with_argmap(backend, &args).context("failed to open VHD")?;
+    let old_size = disk.disk_size();
+    let sector_size = disk.sector_size() as u64;
+
+    let mut gpt = Gpt::load(&mut disk, ErrorAction::Ignore).ok();
+    if let Some(gpt) = &gpt {
+        check_partitions_fit(gpt, opt.size, sector_size)?;
+    }
+
+    disk.resize(opt.size).context("failed to resize disk")?;
+    println!("Disk size: {} -> {}", old_size, disk.disk_size());
+
+    if let Some(gpt) = &mut gpt {
+        if gpt.alternate_lba != disk.disk_size() / sector_size - 1 {
+            if opt.move_gpt_backup {
+                gpt.move_backup_to_end(&mut disk)
+                    .context("failed to move GPT backup")?;
+                disk.flush().context("flush failed")?;
+                println!("Moved GPT backup header to LBA {}", gpt.alternate_lba);
+            } else {
+                println!(
+                    "GPT backup header is no longer at the end of disk, use --move-gpt-backup to move it"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_partitions_fit;
+    use diskutil::part::gpt::{Gpt, GptPartition};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_check_partitions_fit() {
+        const MIB: u64 = 1024 * 1024;
+
+        let mut gpt = Gpt::create_for_size(8 * MIB, 512, 128);
+        gpt.partitions.push(Some(GptPartition::new_ex(
+            Uuid::new_v4(),
+            "a",
+            2048,
+            6143,
+            Uuid::new_v4(),
+        )));
+
+        assert!(check_partitions_fit(&gpt, 8 * MIB, 512).is_ok());
+        // partition ends at the last sector, backup table and header would overwrite it
+        assert!(check_partitions_fit(&gpt, 3 * MIB, 512).is_err());
+        assert!(check_partitions_fit(&gpt, (6144 + 33) * 512, 512).is_ok());
+        assert!(check_partitions_fit(&gpt, (6144 + 32) * 512, 512).is_err());
+    }
+}
@@ -8,13 +8,19 @@ mod utils;
 
 #[macro_use]
 extern crate anyhow;
+#[macro_use]
+extern crate log;
 
 #[derive(Subcommand)]
 enum Command {
+    Apply(cmd::apply::Command),
+    Convert(cmd::convert::Command),
     Create(cmd::create::Command),
     Gpt(cmd::gpt::Command),
     Hexdump(cmd::hexdump::Command),
+    Mbr(cmd::mbr::Command),
     Read(cmd::read::Command),
+    Vhd(cmd::vhd::Command),
     Write(cmd::write::Command),
 }
 
@@ -29,8 +35,12 @@ struct Options {
 
 #[derive(Parser)]
 struct CommonDiskOptions {
-    #[clap(short, long)]
-    format: DiskFormat,
+    #[clap(
+        short,
+        long,
+        help = "Disk format, detected automatically when not specified. Physical devices must be specified explicitly."
+    )]
+    format: Option<DiskFormat>,
     file: PathBuf,
 }
 
@@ -40,10 +50,14 @@ fn main() -> anyhow::Result<()> {
     utils::setup_logging(o.verbose);
 
     match o.command {
+        Command::Apply(c) => cmd::apply::run(c),
+        Command::Convert(c) => cmd::convert::run(c),
         Command::Create(c) => cmd::create::run(c),
         Command::Gpt(c) => cmd::gpt::run(c),
         Command::Hexdump(c) => cmd::hexdump::run(c),
+        Command::Mbr(c) => cmd::mbr::run(c),
         Command::Read(c) => cmd::read::run(c),
+        Command::Vhd(c) => cmd::vhd::run(c),
         Command::Write(c) => cmd::write::run(c),
     }
 }
@@ -6,7 +6,9 @@ mod utils;
 
 use chrono::{DateTime, Local};
 use clap::Parser;
-use diskutil::disk::{open_disk, Backend, DiskFormat, DiskSlice, FileBackend};
+use diskutil::disk::{
+    open_disk, probe_format, Argument, ArgumentMap, Backend, DiskFormat, DiskSlice, FileBackend,
+};
 use diskutil::part::load_partition_table;
 use diskutil::Result;
 use std::cmp::min;
@@ -48,8 +50,13 @@ struct Options {
     #[clap(long, name = "sector_size", parse(try_from_str = parse_sector_size), default_value = "512", long_help = "Set sector size for RAW disks, for other disk formats this is ignored.")]
     pub sector_size: usize,
 
-    #[clap(short = 'f', long, parse(try_from_str))]
-    pub disk_format: DiskFormat,
+    #[clap(
+        short = 'f',
+        long,
+        parse(try_from_str),
+        help = "Disk format, detected automatically when not specified"
+    )]
+    pub disk_format: Option<DiskFormat>,
 
     #[clap(short = 'p', long = "partition", parse(try_from_str))]
     pub partition: Option<utils::PartitionId>,
@@ -114,8 +121,8 @@ macro_rules! u8_vector_uninitialized {
     }};
 }
 
-fn get_backend(path: &Path, format: DiskFormat) -> Result<Box<dyn Backend>> {
-    if format == DiskFormat::Device {
+fn get_backend(path: &Path, format: Option<DiskFormat>) -> Result<Box<dyn Backend>> {
+    if format == Some(DiskFormat::Device) {
         #[cfg(feature = "device")]
         {
             Ok(DeviceBackend::new(path, true)?)
@@ -137,11 +144,17 @@ fn main() -> Result<()> {
     utils::setup_logging(options.verbose);
 
     // TODO: pass sector_size
-    let mut disk = open_disk(
-        options.disk_format,
-        get_backend(options.file.as_path(), options.disk_format)?,
-        Default::default(),
-    )?;
+    let mut args = ArgumentMap::default();
+    args.insert(
+        "path",
+        Argument::String(options.file.to_string_lossy().into_owned()),
+    );
+    let mut backend = get_backend(options.file.as_path(), options.disk_format)?;
+    let format = match options.disk_format {
+        Some(x) => x,
+        None => probe_format(backend.as_mut())?,
+    };
+    let mut disk = open_disk(format, backend, args)?;
 
     let mut slice = if let Some(partition) = options.partition {
         let pt = load_partition_table(disk.as_mut()).unwrap();
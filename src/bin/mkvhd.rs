@@ -4,7 +4,7 @@ extern crate diskutil;
 
 use clap::Parser;
 
-use diskutil::disk::vhd::VhdDisk;
+use diskutil::disk::vhd::{FixedVhdDisk, VhdDisk};
 use diskutil::disk::FileBackend;
 use diskutil::Result;
 use std::convert::TryInto;
@@ -14,6 +14,8 @@ use std::result;
 
 mod utils;
 
+use utils::vhd::VhdCreateArgs;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum VhdType {
     Dynamic,
@@ -42,6 +44,9 @@ struct Options {
 
     #[clap(short = 't', long, parse(try_from_str = VhdType::try_parse), default_value = "dynamic")]
     pub vhd_type: VhdType,
+
+    #[clap(flatten)]
+    pub vhd: VhdCreateArgs,
 }
 
 fn main() -> Result<()> {
@@ -49,11 +54,17 @@ fn main() -> Result<()> {
     let options = Options::parse();
     utils::setup_logging(options.verbose);
 
+    let vhd_options = options.vhd.to_options();
+    vhd_options.validate()?;
     let file = FileBackend::new(File::create(options.file)?)?;
 
     match options.vhd_type {
-        VhdType::Dynamic => VhdDisk::create_dynamic(file, options.size.try_into().unwrap())?,
-        VhdType::Fixed => todo!("Fixed VHD disks are not implemented yet"),
+        VhdType::Dynamic => {
+            VhdDisk::create_dynamic_ex(file, options.size.try_into().unwrap(), &vhd_options)?;
+        }
+        VhdType::Fixed => {
+            FixedVhdDisk::create_ex(file, options.size.try_into().unwrap(), &vhd_options)?;
+        }
     };
 
     Ok(())
@@ -8,6 +8,8 @@ pub use open_disk::*;
 pub use part::*;
 pub use progress::*;
 
+pub mod vhd;
+
 mod open_disk;
 mod part;
 mod progress;
@@ -2,7 +2,7 @@ use std::fs::OpenOptions;
 use std::path::Path;
 
 use anyhow::Context;
-use diskutil::disk::{self, ArgumentMap, Backend, Disk, DiskFormat, FileBackend};
+use diskutil::disk::{self, Argument, ArgumentMap, Backend, Disk, DiskFormat, FileBackend};
 
 #[cfg(feature = "device")]
 use diskutil::disk::DeviceBackend;
@@ -26,10 +26,10 @@ impl AccessMode {
 
 pub fn open_disk(
     path: &Path,
-    format: DiskFormat,
+    format: Option<DiskFormat>,
     access: AccessMode,
 ) -> anyhow::Result<Box<dyn Disk>> {
-    let backend: Box<dyn Backend> = if format == DiskFormat::Device {
+    let mut backend: Box<dyn Backend> = if format == Some(DiskFormat::Device) {
         #[cfg(feature = "device")]
         {
             DeviceBackend::new(
@@ -57,5 +57,17 @@ pub fn open_disk(
         .context("failed to create disk backend (is this a regular file?)")?
     };
 
-    Ok(disk::open_disk(format, backend, ArgumentMap::default())?)
+    let format = match format {
+        Some(x) => x,
+        None => disk::probe_format(backend.as_mut()).context("failed to detect disk format")?,
+    };
+
+    // used to locate parent of differencing disks
+    let mut args = ArgumentMap::default();
+    args.insert(
+        "path",
+        Argument::String(path.to_string_lossy().into_owned()),
+    );
+
+    Ok(disk::open_disk(format, backend, args)?)
 }
@@ -0,0 +1,118 @@
+use std::convert::TryInto;
+use std::result;
+
+use super::parse_size;
+use chrono::{DateTime, TimeZone, Utc};
+use clap::Parser;
+use diskutil::disk::vhd::CreateOptions;
+use uuid::Uuid;
+
+/// VHD creation options shared by commands that create VHD images
+#[derive(Parser, Default)]
+pub struct VhdCreateArgs {
+    #[clap(long, parse(try_from_str = parse_block_size), help = "Block size of dynamic VHD, 2 MiB by default")]
+    pub block_size: Option<u32>,
+
+    #[clap(long, help = "Disk UUID, random by default")]
+    pub uuid: Option<Uuid>,
+
+    #[clap(long, parse(try_from_str = parse_fourcc), help = "Creator application, up to 4 characters")]
+    pub creator_app: Option<[u8; 4]>,
+
+    #[clap(long, parse(try_from_str = parse_creator_version), help = "Creator version as MAJOR.MINOR")]
+    pub creator_version: Option<u32>,
+
+    #[clap(long = "creator-os", parse(try_from_str = parse_fourcc), help = "Creator host OS, eg. Wi2k or Mac")]
+    pub creator_host_os: Option<[u8; 4]>,
+
+    #[clap(long, parse(try_from_str = parse_timestamp), help = "Creation time as RFC 3339 date or seconds since Unix epoch, current time by default")]
+    pub timestamp: Option<DateTime<Utc>>,
+
+    #[clap(long, parse(try_from_str = parse_geometry), help = "Geometry as CYLINDERS/HEADS/SECTORS, computed from size by default")]
+    pub geometry: Option<(u16, u8, u8)>,
+}
+
+impl VhdCreateArgs {
+    /// Returns true if no option was specified
+    pub fn is_empty(&self) -> bool {
+        self.block_size.is_none()
+            && self.uuid.is_none()
+            && self.creator_app.is_none()
+            && self.creator_version.is_none()
+            && self.creator_host_os.is_none()
+            && self.timestamp.is_none()
+            && self.geometry.is_none()
+    }
+
+    pub fn to_options(&self) -> CreateOptions {
+        let default = CreateOptions::default();
+
+        CreateOptions {
+            block_size: self.block_size.unwrap_or(default.block_size),
+            uuid: self.uuid,
+            creator_app: self.creator_app.unwrap_or(default.creator_app),
+            creator_version: self.creator_version.unwrap_or(default.creator_version),
+            creator_host_os: self.creator_host_os.unwrap_or(default.creator_host_os),
+            timestamp: self.timestamp,
+            geometry: self.geometry,
+        }
+    }
+}
+
+fn parse_block_size(s: &str) -> result::Result<u32, String> {
+    let x = parse_size(s)?;
+    if !x.is_power_of_two() {
+        return Err("block size must be power of 2".to_owned());
+    }
+    x.try_into().map_err(|_| "block size too large".to_owned())
+}
+
+/// Parses up to 4 ASCII characters, shorter strings are padded with spaces
+fn parse_fourcc(s: &str) -> result::Result<[u8; 4], String> {
+    if s.is_empty() || s.len() > 4 || !s.is_ascii() {
+        return Err("expected 1 to 4 ASCII characters".to_owned());
+    }
+
+    let mut x = *b"    ";
+    x[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(x)
+}
+
+fn parse_creator_version(s: &str) -> result::Result<u32, String> {
+    let (major, minor) = s.split_once('.').ok_or("expected MAJOR.MINOR")?;
+    let major = major.parse::<u16>().map_err(|e| e.to_string())?;
+    let minor = minor.parse::<u16>().map_err(|e| e.to_string())?;
+    Ok(((major as u32) << 16) | minor as u32)
+}
+
+fn parse_timestamp(s: &str) -> result::Result<DateTime<Utc>, String> {
+    if let Ok(x) = s.parse::<i64>() {
+        return Utc
+            .timestamp_opt(x, 0)
+            .single()
+            .ok_or_else(|| "invalid timestamp".to_owned());
+    }
+
+    DateTime::parse_from_rfc3339(s)
+        .map(|x| x.with_timezone(&Utc))
+        .map_err(|e| e.to_string())
+}
+
+fn parse_geometry(s: &str) -> result::Result<(u16, u8, u8), String> {
+    let parts: Vec<&str> = s.split('/').collect();
+    if parts.len() != 3 {
+        return Err("expected CYLINDERS/HEADS/SECTORS".to_owned());
+    }
+
+    Ok((
+        parts[0]
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())?,
+        parts[1]
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())?,
+        parts[2]
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())?,
+    ))
+}
@@ -6,8 +6,7 @@ use std::env::args;
 use std::fs::File;
 use std::io::{Read, Write};
 
-use diskutil::disk::vhd::VhdDisk;
-use diskutil::disk::{Disk, FileBackend};
+use diskutil::disk::{vhd, Argument, ArgumentMap, FileBackend};
 use diskutil::Result;
 
 fn main() -> Result<()> {
@@ -15,12 +14,13 @@ fn main() -> Result<()> {
     let mut args = args();
     args.next().unwrap();
 
-    let input = FileBackend::new(File::open(
-        args.next().expect("Usage: vhd2bin input output"),
-    )?)?;
+    let input_path = args.next().expect("Usage: vhd2bin input output");
+    let input = FileBackend::new(File::open(&input_path)?)?;
     let mut output = File::create(args.next().expect("Usage: vhd2bin input output"))?;
 
-    let mut disk = VhdDisk::open(input)?;
+    let mut argmap = ArgumentMap::default();
+    argmap.insert("path", Argument::String(input_path));
+    let mut disk = vhd::open_with_argmap(input, &argmap)?;
 
     let mut buf: Vec<u8> = Vec::new();
     buf.reserve(1024 * 1024 * 16);
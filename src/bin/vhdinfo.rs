@@ -1,20 +1,295 @@
 extern crate better_panic;
 extern crate diskutil;
+#[macro_use]
+extern crate log;
+
+mod utils;
 
-use std::env::args;
 use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::str;
+
+use clap::Parser;
+use diskutil::disk::ram::RamDisk;
+use diskutil::disk::vhd::{
+    read_footer, timestamp_to_datetime, DiskType, FixedVhdDisk, Footer, SpaceUsage, VhdDisk,
+};
+use diskutil::disk::{Argument, ArgumentMap, Backend, FileBackend};
+use diskutil::{Error, Result};
+use serde::Serialize;
+
+#[derive(Parser)]
+#[clap(about = "Display information about VHD image")]
+struct Options {
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u32,
+
+    #[clap(long, help = "Print information in JSON format")]
+    json: bool,
+
+    #[clap(name = "file", parse(from_os_str))]
+    file: PathBuf,
+}
+
+struct ParentInfo {
+    name: String,
+    uuid: String,
+    timestamp: u32,
+    found: bool,
+}
+
+fn fourcc(x: &[u8; 4]) -> String {
+    str::from_utf8(x).map_or_else(
+        |_| format!("0x{:08X}", u32::from_be_bytes(*x)),
+        |x| x.to_owned(),
+    )
+}
+
+#[derive(Serialize)]
+struct GeometryInfo {
+    cylinders: u16,
+    heads: u8,
+    sectors: u8,
+}
 
-use diskutil::disk::vhd::VhdDisk;
-use diskutil::disk::FileBackend;
-use diskutil::Result;
+#[derive(Serialize)]
+struct FooterInfo {
+    disk_type: String,
+    features: u32,
+    version: u32,
+    creation_time: String,
+    creator_app: String,
+    creator_version: String,
+    creator_host_os: String,
+    original_size: u64,
+    current_size: u64,
+    geometry: GeometryInfo,
+    uuid: String,
+    saved_state: bool,
+}
+
+impl FooterInfo {
+    fn new(footer: &Footer) -> Self {
+        let (cylinders, heads, sectors) = footer.chs();
+
+        Self {
+            disk_type: format!("{:?}", footer.disk_type),
+            features: footer.features,
+            version: footer.version,
+            creation_time: footer.creation_time().to_rfc3339(),
+            creator_app: fourcc(&footer.creator_app),
+            creator_version: format!(
+                "{}.{}",
+                footer.creator_version >> 16,
+                footer.creator_version & 0xFFFF
+            ),
+            creator_host_os: fourcc(&footer.creator_host_os),
+            original_size: footer.original_size,
+            current_size: footer.current_size,
+            geometry: GeometryInfo {
+                cylinders,
+                heads,
+                sectors,
+            },
+            uuid: footer.uuid.to_string(),
+            saved_state: footer.saved_state != 0,
+        }
+    }
+}
+
+fn print_footer(footer: &Footer) {
+    let (cylinders, heads, sectors) = footer.chs();
+
+    println!("{}", footer);
+    println!(
+        "Geometry (CHS)        : {}/{}/{}",
+        cylinders, heads, sectors
+    );
+    println!(
+        "Saved State           : {}",
+        if footer.saved_state != 0 { "yes" } else { "no" }
+    );
+}
+
+fn print_dynamic(disk: &VhdDisk, usage: &SpaceUsage, parent: &Option<ParentInfo>) {
+    println!("{}", disk.dynamic_header());
+    println!(
+        "Allocated Blocks      : {} / {}",
+        usage.allocated_blocks, usage.total_blocks
+    );
+    println!("File Size             : {}", usage.file_size);
+    println!("Metadata Size         : {}", usage.metadata_size);
+    println!("Data Size             : {}", usage.data_size);
+    println!("Wasted Space          : {}", usage.wasted());
+
+    if let Some(parent) = parent {
+        println!("Parent Name           : {}", parent.name);
+        println!("Parent UUID           : {{{}}}", parent.uuid);
+        println!(
+            "Parent Modified       : {}",
+            timestamp_to_datetime(parent.timestamp).with_timezone(&chrono::Local)
+        );
+        println!(
+            "Parent Found          : {}",
+            if parent.found { "yes" } else { "no" }
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct DynamicHeaderInfo {
+    data_offset: u64,
+    bat_offset: u64,
+    header_version: u32,
+    max_table_entries: u32,
+    block_size: u32,
+}
+
+#[derive(Serialize)]
+struct SpaceUsageInfo {
+    file_size: u64,
+    total_blocks: u32,
+    allocated_blocks: u32,
+    metadata_size: u64,
+    data_size: u64,
+    wasted: u64,
+}
+
+#[derive(Serialize)]
+struct ParentJson {
+    name: String,
+    uuid: String,
+    timestamp: String,
+    found: bool,
+}
+
+#[derive(Serialize)]
+struct DynamicInfo {
+    dynamic_header: DynamicHeaderInfo,
+    space_usage: SpaceUsageInfo,
+    parent: Option<ParentJson>,
+}
+
+impl DynamicInfo {
+    fn new(disk: &VhdDisk, usage: &SpaceUsage, parent: &Option<ParentInfo>) -> Self {
+        let header = disk.dynamic_header();
+
+        Self {
+            dynamic_header: DynamicHeaderInfo {
+                data_offset: header.data_offset,
+                bat_offset: header.bat_offset,
+                header_version: header.header_version,
+                max_table_entries: header.max_table_entries,
+                block_size: header.block_size,
+            },
+            space_usage: SpaceUsageInfo {
+                file_size: usage.file_size,
+                total_blocks: usage.total_blocks,
+                allocated_blocks: usage.allocated_blocks,
+                metadata_size: usage.metadata_size,
+                data_size: usage.data_size,
+                wasted: usage.wasted(),
+            },
+            parent: parent.as_ref().map(|x| ParentJson {
+                name: x.name.clone(),
+                uuid: x.uuid.clone(),
+                timestamp: timestamp_to_datetime(x.timestamp).to_rfc3339(),
+                found: x.found,
+            }),
+        }
+    }
+}
+
+/// Output of --json, dynamic part is present only for dynamic and differencing disks
+#[derive(Serialize)]
+struct VhdInfo {
+    footer: FooterInfo,
+    #[serde(flatten)]
+    dynamic: Option<DynamicInfo>,
+}
+
+fn print_json(info: &VhdInfo) -> Result<()> {
+    let json = serde_json::to_string_pretty(info).map_err(io::Error::from)?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn open_backend(options: &Options) -> Result<Box<dyn Backend>> {
+    Ok(FileBackend::new(File::open(&options.file)?)?)
+}
+
+/// Opens dynamic or differencing disk, differencing disk is opened even if its parent is missing
+fn open_dynamic(options: &Options) -> Result<(VhdDisk, Option<ParentInfo>)> {
+    let mut args = ArgumentMap::default();
+    args.insert(
+        "path",
+        Argument::String(options.file.to_string_lossy().into_owned()),
+    );
+
+    let (disk, found) = match VhdDisk::open_with_argmap(open_backend(options)?, &args) {
+        Ok(disk) => (disk, true),
+        Err(Error::VhdParentNotFound(name)) => {
+            warn!("parent disk {:?} not found", name);
+            // parent is only needed for reading data, not metadata
+            let parent = Box::new(RamDisk::new_zeroed(512, 0));
+            (
+                VhdDisk::open_with_parent(open_backend(options)?, parent)?,
+                false,
+            )
+        }
+        Err(e) => return Err(e),
+    };
+
+    let parent = if disk.footer().disk_type == DiskType::Differencing {
+        let header = disk.dynamic_header();
+        Some(ParentInfo {
+            name: header.parent_name(),
+            uuid: header.parent_unique_id().to_string(),
+            timestamp: header.parent_timestamp,
+            found,
+        })
+    } else {
+        None
+    };
+
+    Ok((disk, parent))
+}
 
 fn main() -> Result<()> {
     better_panic::install();
-    let mut args = args();
-    args.next().unwrap();
+    let options = Options::parse();
+    utils::setup_logging(options.verbose);
+
+    // footer copy is used when trailing footer of dynamic disk is damaged
+    let (_, footer, _) = read_footer(open_backend(&options)?.as_mut())?;
+    let disk_type = footer.disk_type;
+
+    if disk_type == DiskType::Fixed {
+        let disk = FixedVhdDisk::open(open_backend(&options)?)?;
+        if options.json {
+            print_json(&VhdInfo {
+                footer: FooterInfo::new(disk.footer()),
+                dynamic: None,
+            })?;
+        } else {
+            print_footer(disk.footer());
+        }
+        return Ok(());
+    }
+
+    let (mut disk, parent) = open_dynamic(&options)?;
+    let usage = disk.space_usage()?;
 
-    let file = FileBackend::new(File::open(args.next().expect("Usage: vhdinfo file"))?)?;
-    let _disk = VhdDisk::open(file)?;
+    if options.json {
+        print_json(&VhdInfo {
+            footer: FooterInfo::new(disk.footer()),
+            dynamic: Some(DynamicInfo::new(&disk, &usage, &parent)),
+        })?;
+    } else {
+        print_footer(disk.footer());
+        print_dynamic(&disk, &usage, &parent);
+    }
 
-    unimplemented!()
+    Ok(())
 }
@@ -2,7 +2,7 @@ use std::cmp::min;
 use std::convert::TryInto;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use super::{Disk, DiskFormat, MediaType};
+use super::{seek_position, Disk, DiskFormat, MediaType};
 use crate::Result;
 
 // Dummy buffer implementation, currently no caching occurs and code
@@ -16,16 +16,6 @@ pub struct Buffer<T> {
     temp: Vec<u8>,
 }
 
-impl<T> Buffer<T> {
-    fn num_blocks(&self, buf: &[u8]) -> usize {
-        let mut t = buf.len() / self.block_size as usize;
-        if buf.len() % self.block_size as usize != 0 {
-            t += 1;
-        }
-        t
-    }
-}
-
 impl<T> Buffer<T>
 where
     T: Disk,
@@ -63,26 +53,6 @@ where
             ))
         }
     }
-
-    /// Read block into buf
-    /// buf length must equal block size
-    /// stream position must be aligned on block boundary
-    fn read_block_into(&mut self, buf: &mut [u8]) -> io::Result<bool> {
-        debug_assert_eq!(buf.len(), self.block_size as usize);
-
-        let n = self.inner.read(buf)?;
-        if n == 0 {
-            Ok(false)
-        } else if n == self.block_size as usize {
-            Ok(true)
-        } else {
-            // This should not happen, disk size is always multiple of block size
-            Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "EOF in middle of block (read)",
-            ))
-        }
-    }
 }
 
 impl<T> Buffer<T>
@@ -105,26 +75,6 @@ where
             ))
         }
     }
-
-    /// Write contents of buf into block
-    /// buf length must equal block size
-    /// stream position must be aligned on block boundary
-    fn write_block_from(&mut self, buf: &[u8]) -> io::Result<bool> {
-        debug_assert_eq!(buf.len(), self.block_size as usize);
-
-        let n = self.inner.write(buf)?;
-        if n == 0 {
-            Ok(false)
-        } else if n == self.block_size as usize {
-            Ok(true)
-        } else {
-            // This should not happen, since disk size is always multiple of block size
-            Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "EOF in middle of block (write)",
-            ))
-        }
-    }
 }
 
 impl<T> Disk for Buffer<T>
@@ -150,107 +100,88 @@ where
 
 impl<T> Read for Buffer<T>
 where
-    T: Read + Seek,
+    T: Disk,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.position % self.block_size as u64 == 0 && buf.len() % self.block_size as usize == 0
-        {
-            self.inner.read(buf)
-        } else {
-            // offset from block start
-            let offset: u32 = (self.position % self.block_size as u64).try_into().unwrap();
-            let mut blocks_left = self.num_blocks(buf);
-            let mut bytes_read = 0usize;
-            let mut left = buf.len();
-
-            // align on block boundary prior to calling read_block()
-            self.inner
-                .seek(SeekFrom::Start(self.position - offset as u64))?;
-
-            while blocks_left > 0 {
-                let n = min(self.block_size as usize - offset as usize, left);
+        let block_size = self.block_size as usize;
+        let mut bytes_read = 0usize;
 
-                let do_direct_read = n == self.block_size as usize && offset == 0;
-
-                let eof = !if do_direct_read {
-                    self.read_block_into(&mut buf[bytes_read..bytes_read + n])?
-                } else {
-                    self.read_block()?
-                };
-
-                if eof {
-                    // EOF
+        while bytes_read < buf.len() {
+            let left = buf.len() - bytes_read;
+            // offset from block start
+            let offset: usize = (self.position % block_size as u64).try_into().unwrap();
+
+            if offset == 0 && left >= block_size {
+                // whole blocks are passed directly to the disk
+                let n = left / block_size * block_size;
+                self.inner.seek(SeekFrom::Start(self.position))?;
+                let r = self.inner.read(&mut buf[bytes_read..bytes_read + n])?;
+                if r == 0 {
                     break;
                 }
+                bytes_read += r;
+                self.position += r as u64;
+                continue;
+            }
 
-                if !do_direct_read {
-                    buf[bytes_read..bytes_read + n]
-                        .copy_from_slice(&self.temp[offset as usize..offset as usize + n]);
-                }
-
-                left -= n;
-                blocks_left -= 1;
-                bytes_read += n;
-                self.position += n as u64;
+            let n = min(block_size - offset, left);
+            self.inner
+                .seek(SeekFrom::Start(self.position - offset as u64))?;
+            if !self.read_block()? {
+                break;
             }
+            buf[bytes_read..bytes_read + n].copy_from_slice(&self.temp[offset..offset + n]);
 
-            Ok(bytes_read)
+            bytes_read += n;
+            self.position += n as u64;
         }
+
+        Ok(bytes_read)
     }
 }
 
 impl<T> Write for Buffer<T>
 where
-    T: Read + Write + Seek,
+    T: Disk,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if self.position % self.block_size as u64 == 0 && buf.len() % self.block_size as usize == 0
-        {
-            self.inner.write(buf)
-        } else {
-            let offset: u32 = (self.position % self.block_size as u64).try_into().unwrap();
-            let mut blocks_left = self.num_blocks(buf);
-            let mut bytes_written = 0usize;
-            let mut left = buf.len();
-
-            // align on block boundary prior to calling read_block() and write_block()
-            self.inner
-                .seek(SeekFrom::Start(self.position - offset as u64))?;
-
-            while blocks_left > 0 {
-                let n = min(self.block_size as usize - offset as usize, left);
-
-                let do_direct_write = n == self.block_size as usize && offset == 0;
-
-                if do_direct_write {
-                    let eof = !self.write_block_from(&buf[bytes_written..bytes_written + n])?;
-                    if eof {
-                        break;
-                    }
-                } else {
-                    let eof = !self.read_block()?;
-                    if eof {
-                        break;
-                    }
-                    // read_block() advances stream position, need to restore
-                    // previous position to write same sector we just read
-                    self.seek(SeekFrom::Current(-(self.block_size as i64)))?;
-
-                    self.temp[offset as usize..offset as usize + n]
-                        .copy_from_slice(&buf[bytes_written..bytes_written + n]);
-                    if !self.write_block()? {
-                        break;
-                    }
+        let block_size = self.block_size as usize;
+        let mut bytes_written = 0usize;
+
+        while bytes_written < buf.len() {
+            let left = buf.len() - bytes_written;
+            let offset: usize = (self.position % block_size as u64).try_into().unwrap();
+
+            if offset == 0 && left >= block_size {
+                let n = left / block_size * block_size;
+                self.inner.seek(SeekFrom::Start(self.position))?;
+                let w = self.inner.write(&buf[bytes_written..bytes_written + n])?;
+                if w == 0 {
+                    break;
                 }
+                bytes_written += w;
+                self.position += w as u64;
+                continue;
+            }
 
-                left -= n;
-                blocks_left -= 1;
-                bytes_written += n;
-                self.position += n as u64;
+            // partially written block has to be read first
+            let n = min(block_size - offset, left);
+            let block_start = self.position - offset as u64;
+            self.inner.seek(SeekFrom::Start(block_start))?;
+            if !self.read_block()? {
+                break;
+            }
+            self.temp[offset..offset + n].copy_from_slice(&buf[bytes_written..bytes_written + n]);
+            self.inner.seek(SeekFrom::Start(block_start))?;
+            if !self.write_block()? {
+                break;
             }
 
-            Ok(bytes_written)
+            bytes_written += n;
+            self.position += n as u64;
         }
+
+        Ok(bytes_written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -260,10 +191,11 @@ where
 
 impl<T> Seek for Buffer<T>
 where
-    T: Seek,
+    T: Disk,
 {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.position = self.inner.seek(pos)?;
+        // inner disk is positioned on every read or write
+        self.position = seek_position(self.position, self.inner.disk_size(), pos)?;
         Ok(self.position)
     }
 }
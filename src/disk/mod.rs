@@ -10,10 +10,14 @@ use crate::{Error, Result};
 pub use slice::DiskSlice;
 
 pub mod buffer;
+pub mod qcow2;
 pub mod ram;
 pub mod raw;
 mod slice;
+pub mod vdi;
 pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
 
 #[cfg(all(feature = "device", windows))]
 pub use windows_device::DeviceBackend;
@@ -27,10 +31,10 @@ pub enum DiskFormat {
     Device,
     RAW,
     VHD,
-    // TODO:
-    // VHDX
-    // VDI,
-    // VMDK,
+    VHDX,
+    QCOW2,
+    VMDK,
+    VDI,
 }
 
 impl FromStr for DiskFormat {
@@ -41,6 +45,10 @@ impl FromStr for DiskFormat {
             "device" => Ok(Self::Device),
             "raw" => Ok(Self::RAW),
             "vhd" => Ok(Self::VHD),
+            "vhdx" => Ok(Self::VHDX),
+            "qcow2" => Ok(Self::QCOW2),
+            "vmdk" => Ok(Self::VMDK),
+            "vdi" => Ok(Self::VDI),
             _ => Err(Error::UnknownDiskType),
         }
     }
@@ -52,6 +60,10 @@ impl fmt::Display for DiskFormat {
             Self::Device => write!(f, "device"),
             Self::RAW => write!(f, "raw"),
             Self::VHD => write!(f, "vhd"),
+            Self::VHDX => write!(f, "vhdx"),
+            Self::QCOW2 => write!(f, "qcow2"),
+            Self::VMDK => write!(f, "vmdk"),
+            Self::VDI => write!(f, "vdi"),
         }
     }
 }
@@ -110,6 +122,29 @@ pub trait Disk: io::Read + io::Seek + io::Write {
     }
 }
 
+/// Computes new position of a disk cursor, `SeekFrom::End` is relative to disk size.
+/// Positions past the end are allowed, negative or overflowing ones are not.
+pub(crate) fn seek_position(cursor: u64, disk_size: u64, pos: io::SeekFrom) -> io::Result<u64> {
+    let (base, x) = match pos {
+        io::SeekFrom::Start(x) => return Ok(x),
+        io::SeekFrom::Current(x) => (cursor, x),
+        io::SeekFrom::End(x) => (disk_size, x),
+    };
+
+    let new_position = if x >= 0 {
+        base.checked_add(x as u64)
+    } else {
+        base.checked_sub(x.unsigned_abs())
+    };
+
+    new_position.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
 pub trait Backend: io::Read + io::Seek + io::Write {
     fn data_length(&self) -> u64;
 }
@@ -207,6 +242,17 @@ impl ArgumentMap {
     g1!(get_u16, u16);
     g1!(get_u32, u32);
     g1!(get_u64, u64);
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.0.get(key) {
+            Some(Argument::String(x)) => Some(x.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, value: Argument) {
+        self.0.insert(key.to_owned(), value);
+    }
 }
 
 pub fn open_disk(
@@ -221,6 +267,303 @@ pub fn open_disk(
             Box::new(buffer)
         }
         DiskFormat::RAW => Box::new(raw::RawDisk::open_with_argmap(backend, &args)),
-        DiskFormat::VHD => Box::new(vhd::VhdDisk::open_with_argmap(backend, &args)?),
+        DiskFormat::VHD => vhd::open_with_argmap(backend, &args)?,
+        DiskFormat::VHDX => Box::new(vhdx::VhdxDisk::open_with_argmap(backend, &args)?),
+        DiskFormat::QCOW2 => Box::new(qcow2::Qcow2Disk::open_with_argmap(backend, &args)?),
+        DiskFormat::VMDK => Box::new(vmdk::VmdkDisk::open_with_argmap(backend, &args)?),
+        DiskFormat::VDI => Box::new(vdi::VdiDisk::open_with_argmap(backend, &args)?),
     })
 }
+
+/// Reads up to buf.len() bytes at offset, returns number of bytes read
+fn read_at(backend: &mut dyn Backend, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    backend.seek(io::SeekFrom::Start(offset))?;
+
+    let mut total = 0;
+    while total < buf.len() {
+        match backend.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+
+    Ok(total)
+}
+
+/// Detects disk format by looking for signatures of supported formats,
+/// when nothing is found disk is assumed to be RAW.
+/// Physical devices can't be detected, they are reported as RAW too.
+pub fn probe_format(backend: &mut dyn Backend) -> Result<DiskFormat> {
+    let file_size = backend.seek(io::SeekFrom::End(0))?;
+
+    let mut first = [0u8; 520];
+    let n = read_at(backend, 0, &mut first)?;
+    let first = &first[..n];
+
+    let format = if first.starts_with(b"vhdxfile") {
+        DiskFormat::VHDX
+    } else if first.starts_with(b"QFI\xfb") {
+        DiskFormat::QCOW2
+    } else if first.starts_with(b"KDMV") || first.starts_with(b"# Disk DescriptorFile") {
+        DiskFormat::VMDK
+    } else if first.get(64..68) == Some(&0xBEDA107Fu32.to_le_bytes()[..]) {
+        DiskFormat::VDI
+    } else {
+        // VHD footer is at the end of file, older images may have 511 byte footer,
+        // dynamic disks also keep a copy of footer followed by dynamic header
+        let mut cookie = [0u8; 8];
+        let footer = [512u64, 511]
+            .iter()
+            .filter(|x| file_size >= **x)
+            .any(|x| matches!(read_at(backend, file_size - x, &mut cookie), Ok(8) if &cookie == b"conectix"));
+
+        if footer
+            || (first.starts_with(b"conectix") && first.get(512..520) == Some(&b"cxsparse"[..]))
+        {
+            DiskFormat::VHD
+        } else {
+            DiskFormat::RAW
+        }
+    };
+
+    debug!("Detected disk format {}", format);
+    backend.seek(io::SeekFrom::Start(0))?;
+    Ok(format)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::buffer::Buffer;
+    use super::ram::RamDisk;
+    use super::raw::RawDisk;
+    use super::{
+        probe_format, qcow2, vdi, vhd, vhdx, vmdk, Backend, Disk, DiskFormat, DiskSlice, MediaType,
+    };
+    use std::cell::RefCell;
+    use std::cmp::min;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+
+    /// In-memory backend, storage is shared so tests can reopen the same image
+    pub struct MemoryBackend {
+        storage: Rc<RefCell<Vec<u8>>>,
+        position: u64,
+    }
+
+    impl MemoryBackend {
+        pub fn new() -> Self {
+            Self::from_storage(Rc::new(RefCell::new(Vec::new())))
+        }
+
+        pub fn from_storage(storage: Rc<RefCell<Vec<u8>>>) -> Self {
+            Self {
+                storage,
+                position: 0,
+            }
+        }
+
+        pub fn storage(&self) -> Rc<RefCell<Vec<u8>>> {
+            self.storage.clone()
+        }
+    }
+
+    impl Read for MemoryBackend {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let storage = self.storage.borrow();
+            let start = min(self.position, storage.len() as u64) as usize;
+            let n = min(buf.len(), storage.len() - start);
+            buf[..n].copy_from_slice(&storage[start..start + n]);
+            self.position += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Seek for MemoryBackend {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let len = self.storage.borrow().len() as i64;
+            let new_position = match pos {
+                SeekFrom::Start(x) => x as i64,
+                SeekFrom::Current(x) => self.position as i64 + x,
+                SeekFrom::End(x) => len + x,
+            };
+            if new_position < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek to negative position",
+                ));
+            }
+            self.position = new_position as u64;
+            Ok(self.position)
+        }
+    }
+
+    impl Write for MemoryBackend {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut storage = self.storage.borrow_mut();
+            let start = self.position as usize;
+            if storage.len() < start + buf.len() {
+                storage.resize(start + buf.len(), 0);
+            }
+            storage[start..start + buf.len()].copy_from_slice(buf);
+            self.position += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Backend for MemoryBackend {
+        fn data_length(&self) -> u64 {
+            self.storage.borrow().len() as u64
+        }
+    }
+
+    #[test]
+    fn test_probe_format() {
+        crate::tests_init();
+
+        fn probe(storage: Rc<RefCell<Vec<u8>>>) -> DiskFormat {
+            probe_format(&mut MemoryBackend::from_storage(storage)).unwrap()
+        }
+
+        let size = 4 * 1024 * 1024;
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        vhd::FixedVhdDisk::create(Box::new(backend), size as usize).unwrap();
+        assert_eq!(probe(storage), DiskFormat::VHD);
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        vhd::VhdDisk::create_dynamic(Box::new(backend), size as usize).unwrap();
+        assert_eq!(probe(storage.clone()), DiskFormat::VHD);
+        // trailing footer damaged, copy at the beginning is still recognized
+        let len = storage.borrow().len();
+        storage.borrow_mut().truncate(len - 512);
+        assert_eq!(probe(storage), DiskFormat::VHD);
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        vhdx::VhdxDisk::create(Box::new(backend), size).unwrap();
+        assert_eq!(probe(storage), DiskFormat::VHDX);
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        qcow2::Qcow2Disk::create(Box::new(backend), size).unwrap();
+        assert_eq!(probe(storage), DiskFormat::QCOW2);
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        vmdk::VmdkDisk::create_sparse(Box::new(backend), size, "test.vmdk").unwrap();
+        assert_eq!(probe(storage), DiskFormat::VMDK);
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        vdi::VdiDisk::create_dynamic(Box::new(backend), size).unwrap();
+        assert_eq!(probe(storage), DiskFormat::VDI);
+
+        assert_eq!(
+            probe(Rc::new(RefCell::new(vec![0u8; 100]))),
+            DiskFormat::RAW
+        );
+        assert_eq!(probe(Rc::new(RefCell::new(Vec::new()))), DiskFormat::RAW);
+    }
+
+    /// Checks seek, read and write semantics every disk implementation must follow,
+    /// contents of the disk are overwritten
+    pub fn check_conformance(disk: &mut dyn Disk) {
+        let size = disk.disk_size();
+        assert!(size >= 4096);
+        assert_eq!(size % disk.sector_size() as u64, 0);
+
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), size);
+        assert_eq!(disk.seek(SeekFrom::End(-1)).unwrap(), size - 1);
+        assert_eq!(disk.seek(SeekFrom::Current(-1)).unwrap(), size - 2);
+        assert_eq!(disk.seek(SeekFrom::Current(0)).unwrap(), size - 2);
+
+        // negative positions are errors and leave position unchanged
+        assert!(disk.seek(SeekFrom::End(-(size as i64) - 1)).is_err());
+        assert!(disk.seek(SeekFrom::Current(-(size as i64))).is_err());
+        assert_eq!(disk.seek(SeekFrom::Current(0)).unwrap(), size - 2);
+        assert_eq!(disk.seek(SeekFrom::End(-(size as i64))).unwrap(), 0);
+
+        // seeking past the end is allowed, but nothing can be read or written there
+        let mut buf = [0u8; 1000];
+        for position in [size, size + 1, size + 4096].iter().copied() {
+            assert_eq!(disk.seek(SeekFrom::Start(position)).unwrap(), position);
+            assert_eq!(disk.read(&mut buf).unwrap(), 0);
+            assert_eq!(disk.write(&buf).unwrap(), 0);
+        }
+
+        // unaligned writes spanning sectors at both ends of the disk
+        let pattern: Vec<u8> = (0..1500u32).map(|x| (x * 7 + 1) as u8).collect();
+        for position in [3, size - 1500].iter().copied() {
+            disk.seek(SeekFrom::Start(position)).unwrap();
+            disk.write_all(&pattern).unwrap();
+            assert_eq!(disk.seek(SeekFrom::Current(0)).unwrap(), position + 1500);
+
+            let mut data = vec![0u8; 1500];
+            disk.seek(SeekFrom::Current(-1500)).unwrap();
+            disk.read_exact(&mut data).unwrap();
+            assert_eq!(data, pattern);
+        }
+
+        // reads and writes crossing the end are short
+        disk.seek(SeekFrom::End(-3)).unwrap();
+        assert_eq!(disk.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &pattern[1497..]);
+        assert_eq!(disk.seek(SeekFrom::Current(0)).unwrap(), size);
+        disk.seek(SeekFrom::End(-3)).unwrap();
+        assert_eq!(disk.write(&[0xaa; 10]).unwrap(), 3);
+        assert_eq!(disk.seek(SeekFrom::Current(0)).unwrap(), size);
+
+        let mut data = Vec::new();
+        disk.seek(SeekFrom::End(-1024)).unwrap();
+        disk.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 1024);
+        assert_eq!(&data[1021..], &[0xaa; 3]);
+        assert_eq!(disk.disk_size(), size);
+
+        disk.flush().unwrap();
+    }
+
+    #[test]
+    fn test_conformance() {
+        crate::tests_init();
+
+        let size = 4 * 1024 * 1024;
+        let raw = || {
+            let backend = MemoryBackend::from_storage(Rc::new(RefCell::new(vec![0; size])));
+            RawDisk::open(Box::new(backend), 512, MediaType::HDD)
+        };
+
+        check_conformance(&mut RamDisk::new_zeroed(512, size as u32 / 512));
+        check_conformance(&mut raw());
+        check_conformance(&mut Buffer::new(raw()).unwrap());
+        check_conformance(&mut DiskSlice::new(&mut raw(), 3, 100));
+        check_conformance(
+            &mut vhd::FixedVhdDisk::create(Box::new(MemoryBackend::new()), size).unwrap(),
+        );
+        check_conformance(
+            &mut vhd::VhdDisk::create_dynamic(Box::new(MemoryBackend::new()), size).unwrap(),
+        );
+        check_conformance(
+            &mut vhdx::VhdxDisk::create(Box::new(MemoryBackend::new()), size as u64).unwrap(),
+        );
+        check_conformance(
+            &mut qcow2::Qcow2Disk::create(Box::new(MemoryBackend::new()), size as u64).unwrap(),
+        );
+        check_conformance(
+            &mut vmdk::VmdkDisk::create_sparse(
+                Box::new(MemoryBackend::new()),
+                size as u64,
+                "test.vmdk",
+            )
+            .unwrap(),
+        );
+        check_conformance(
+            &mut vdi::VdiDisk::create_dynamic(Box::new(MemoryBackend::new()), size as u64).unwrap(),
+        );
+    }
+}
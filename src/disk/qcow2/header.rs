@@ -0,0 +1,229 @@
+use crate::{Error, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{Cursor, Read};
+
+pub const INCOMPATIBLE_DIRTY: u64 = 1;
+pub const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+pub const INCOMPATIBLE_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+pub const INCOMPATIBLE_COMPRESSION_TYPE: u64 = 1 << 3;
+pub const INCOMPATIBLE_EXTENDED_L2: u64 = 1 << 4;
+
+const EXTENSION_END: u32 = 0;
+const EXTENSION_BACKING_FORMAT: u32 = 0xE2792ACA;
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub crypt_method: u32,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+
+    // version 3 only, for version 2 images defaults are used
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+
+    pub backing_format: Option<String>,
+}
+
+impl Header {
+    pub const MAGIC: &'static [u8; 4] = b"QFI\xfb";
+    pub const V2_SIZE: usize = 72;
+    pub const V3_SIZE: usize = 104;
+
+    /// Decodes header along with header extensions, buf should contain whole first cluster
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::V2_SIZE || &buf[..4] != Self::MAGIC {
+            return Err(Error::InvalidQcow2("invalid magic".to_owned()));
+        }
+
+        let mut reader = Cursor::new(&buf[4..]);
+        let version = reader.read_u32::<BigEndian>()?;
+        if version != 2 && version != 3 {
+            return Err(Error::InvalidQcow2(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+
+        let mut header = Self {
+            version,
+            backing_file_offset: reader.read_u64::<BigEndian>()?,
+            backing_file_size: reader.read_u32::<BigEndian>()?,
+            cluster_bits: reader.read_u32::<BigEndian>()?,
+            size: reader.read_u64::<BigEndian>()?,
+            crypt_method: reader.read_u32::<BigEndian>()?,
+            l1_size: reader.read_u32::<BigEndian>()?,
+            l1_table_offset: reader.read_u64::<BigEndian>()?,
+            refcount_table_offset: reader.read_u64::<BigEndian>()?,
+            refcount_table_clusters: reader.read_u32::<BigEndian>()?,
+            nb_snapshots: reader.read_u32::<BigEndian>()?,
+            snapshots_offset: reader.read_u64::<BigEndian>()?,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: 4,
+            header_length: Self::V2_SIZE as u32,
+            backing_format: None,
+        };
+
+        if version == 3 {
+            header.incompatible_features = reader.read_u64::<BigEndian>()?;
+            header.compatible_features = reader.read_u64::<BigEndian>()?;
+            header.autoclear_features = reader.read_u64::<BigEndian>()?;
+            header.refcount_order = reader.read_u32::<BigEndian>()?;
+            header.header_length = reader.read_u32::<BigEndian>()?;
+        }
+
+        if header.cluster_bits < 9 || header.cluster_bits > 21 {
+            return Err(Error::InvalidQcow2(format!(
+                "invalid cluster bits {}",
+                header.cluster_bits
+            )));
+        }
+        if header.refcount_order < 3 || header.refcount_order > 6 {
+            return Err(Error::InvalidQcow2(format!(
+                "unsupported refcount order {}",
+                header.refcount_order
+            )));
+        }
+        if header.crypt_method != 0 {
+            return Err(Error::InvalidQcow2(
+                "encrypted images are not supported".to_owned(),
+            ));
+        }
+
+        // header extensions follow header in version 3, in version 2 they directly
+        // follow 72 byte header
+        let mut offset = header.header_length as usize;
+        loop {
+            let mut reader =
+                Cursor::new(buf.get(offset..offset + 8).ok_or_else(|| {
+                    Error::InvalidQcow2("header extension out of bounds".to_owned())
+                })?);
+            let extension_type = reader.read_u32::<BigEndian>()?;
+            let length = reader.read_u32::<BigEndian>()? as usize;
+            if extension_type == EXTENSION_END {
+                break;
+            }
+
+            let data = buf
+                .get(offset + 8..offset + 8 + length)
+                .ok_or_else(|| Error::InvalidQcow2("header extension out of bounds".to_owned()))?;
+            if extension_type == EXTENSION_BACKING_FORMAT {
+                header.backing_format = Some(String::from_utf8_lossy(data).into_owned());
+            } else {
+                trace!("ignoring header extension 0x{:08x}", extension_type);
+            }
+
+            offset += 8 + ((length + 7) & !7);
+        }
+
+        Ok(header)
+    }
+
+    /// Encodes header, backing file name is not included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Cursor::new(Vec::new());
+
+        writer.get_mut().extend_from_slice(Self::MAGIC);
+        writer.set_position(4);
+        writer.write_u32::<BigEndian>(self.version).unwrap();
+        writer
+            .write_u64::<BigEndian>(self.backing_file_offset)
+            .unwrap();
+        writer
+            .write_u32::<BigEndian>(self.backing_file_size)
+            .unwrap();
+        writer.write_u32::<BigEndian>(self.cluster_bits).unwrap();
+        writer.write_u64::<BigEndian>(self.size).unwrap();
+        writer.write_u32::<BigEndian>(self.crypt_method).unwrap();
+        writer.write_u32::<BigEndian>(self.l1_size).unwrap();
+        writer.write_u64::<BigEndian>(self.l1_table_offset).unwrap();
+        writer
+            .write_u64::<BigEndian>(self.refcount_table_offset)
+            .unwrap();
+        writer
+            .write_u32::<BigEndian>(self.refcount_table_clusters)
+            .unwrap();
+        writer.write_u32::<BigEndian>(self.nb_snapshots).unwrap();
+        writer
+            .write_u64::<BigEndian>(self.snapshots_offset)
+            .unwrap();
+
+        if self.version == 3 {
+            writer
+                .write_u64::<BigEndian>(self.incompatible_features)
+                .unwrap();
+            writer
+                .write_u64::<BigEndian>(self.compatible_features)
+                .unwrap();
+            writer
+                .write_u64::<BigEndian>(self.autoclear_features)
+                .unwrap();
+            writer.write_u32::<BigEndian>(self.refcount_order).unwrap();
+            writer.write_u32::<BigEndian>(self.header_length).unwrap();
+        }
+
+        writer.into_inner()
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Reads backing file name, buf should contain whole first cluster
+    pub fn backing_file_name(&self, buf: &[u8]) -> Result<Option<String>> {
+        if self.backing_file_offset == 0 {
+            return Ok(None);
+        }
+
+        let start = self.backing_file_offset as usize;
+        let mut name = vec![0u8; self.backing_file_size as usize];
+        buf.get(start..)
+            .unwrap_or(&[])
+            .read_exact(name.as_mut_slice())
+            .map_err(|_| Error::InvalidQcow2("backing file name out of bounds".to_owned()))?;
+
+        Ok(Some(String::from_utf8_lossy(&name).into_owned()))
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Cluster size: {}", self.cluster_size())?;
+        writeln!(f, "Size: {}", self.size)?;
+        writeln!(f, "L1 size: {}", self.l1_size)?;
+        writeln!(f, "L1 table offset: 0x{:x}", self.l1_table_offset)?;
+        writeln!(
+            f,
+            "Refcount table offset: 0x{:x}",
+            self.refcount_table_offset
+        )?;
+        writeln!(
+            f,
+            "Refcount table clusters: {}",
+            self.refcount_table_clusters
+        )?;
+        writeln!(f, "Refcount order: {}", self.refcount_order)?;
+        writeln!(f, "Snapshots: {}", self.nb_snapshots)?;
+        writeln!(
+            f,
+            "Incompatible features: 0x{:x}",
+            self.incompatible_features
+        )?;
+        write!(f, "Backing format: {:?}", self.backing_format)
+    }
+}
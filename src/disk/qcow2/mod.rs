@@ -0,0 +1,838 @@
+mod header;
+
+use crate::disk::{
+    self, seek_position, Argument, ArgumentMap, Backend, Disk, DiskFormat, FileBackend, MediaType,
+};
+use crate::{is_power_of_2, round_up, utils::zero_u8_slice, Error, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use header::{
+    Header, INCOMPATIBLE_COMPRESSION_TYPE, INCOMPATIBLE_CORRUPT, INCOMPATIBLE_DIRTY,
+    INCOMPATIBLE_EXTENDED_L2, INCOMPATIBLE_EXTERNAL_DATA_FILE,
+};
+use std::cmp::min;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+const OFLAG_ZERO: u64 = 1;
+
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+pub struct Qcow2Disk {
+    backend: Box<dyn Backend>,
+    header: Header,
+    cluster_size: u64,
+    l2_entries: u64,
+
+    l1: Vec<u64>,
+    // most recently used L2 table and its offset
+    l2_cache: Option<(u64, Vec<u64>)>,
+    refcount_table: Vec<u64>,
+    // most recently used refcount block and its offset
+    refcount_block_cache: Option<(u64, Vec<u8>)>,
+
+    backing: Option<Box<dyn Disk>>,
+    // offset where next cluster will be allocated
+    free_cluster_offset: u64,
+    cursor: u64,
+}
+
+impl Qcow2Disk {
+    /// Backing file is searched relative to "path" argument
+    pub fn open_with_argmap(backend: Box<dyn Backend>, args: &ArgumentMap) -> Result<Self> {
+        Self::open_ex(backend, args.get_str("path").map(Path::new), None)
+    }
+
+    pub fn open(backend: Box<dyn Backend>) -> Result<Self> {
+        Self::open_ex(backend, None, None)
+    }
+
+    /// Opens image with explicitly provided backing disk,
+    /// backing file name stored in image is ignored.
+    pub fn open_with_backing(backend: Box<dyn Backend>, backing: Box<dyn Disk>) -> Result<Self> {
+        Self::open_ex(backend, None, Some(backing))
+    }
+
+    fn open_ex(
+        mut backend: Box<dyn Backend>,
+        path: Option<&Path>,
+        backing: Option<Box<dyn Disk>>,
+    ) -> Result<Self> {
+        let file_size = backend.seek(SeekFrom::End(0))?;
+
+        // header, its extensions and backing file name must fit in the first cluster
+        let mut first_cluster = vec![0u8; Header::V3_SIZE];
+        backend.seek(SeekFrom::Start(0))?;
+        backend.read_exact(first_cluster.as_mut_slice())?;
+        let cluster_bits = u32::from_be_bytes([
+            first_cluster[20],
+            first_cluster[21],
+            first_cluster[22],
+            first_cluster[23],
+        ]);
+        if cluster_bits <= 21 {
+            first_cluster.resize(min(1 << cluster_bits, file_size) as usize, 0);
+            backend.seek(SeekFrom::Start(0))?;
+            backend.read_exact(first_cluster.as_mut_slice())?;
+        }
+
+        let header = Header::decode(&first_cluster)?;
+        debug!("Header:\n{}\n", header);
+
+        let unknown = !(INCOMPATIBLE_DIRTY
+            | INCOMPATIBLE_CORRUPT
+            | INCOMPATIBLE_EXTERNAL_DATA_FILE
+            | INCOMPATIBLE_COMPRESSION_TYPE
+            | INCOMPATIBLE_EXTENDED_L2);
+        let features = header.incompatible_features;
+        if features & unknown != 0 {
+            return Err(Error::InvalidQcow2(format!(
+                "unknown incompatible features 0x{:x}",
+                features & unknown
+            )));
+        }
+        if features & INCOMPATIBLE_DIRTY != 0 {
+            return Err(Error::InvalidQcow2(
+                "refcounts are dirty, repair image using qemu-img check -r all".to_owned(),
+            ));
+        }
+        if features & INCOMPATIBLE_CORRUPT != 0 {
+            return Err(Error::InvalidQcow2("image is marked corrupt".to_owned()));
+        }
+        if features & INCOMPATIBLE_EXTERNAL_DATA_FILE != 0 {
+            return Err(Error::InvalidQcow2(
+                "external data files are not supported".to_owned(),
+            ));
+        }
+        if features & INCOMPATIBLE_EXTENDED_L2 != 0 {
+            return Err(Error::InvalidQcow2(
+                "extended L2 entries are not supported".to_owned(),
+            ));
+        }
+
+        let cluster_size = header.cluster_size();
+        let l2_entries = cluster_size / 8;
+        let l1_coverage = (header.l1_size as u64)
+            .checked_mul(l2_entries)
+            .and_then(|x| x.checked_mul(cluster_size))
+            .ok_or_else(|| {
+                Error::InvalidQcow2(format!("L1 table too big ({} entries)", header.l1_size))
+            })?;
+        if l1_coverage < header.size {
+            return Err(Error::InvalidQcow2(format!(
+                "L1 table too small ({} entries) for image size {}",
+                header.l1_size, header.size
+            )));
+        }
+
+        let l1 = Self::read_table(
+            backend.as_mut(),
+            header.l1_table_offset,
+            header.l1_size as usize,
+        )?;
+        let refcount_table = Self::read_table(
+            backend.as_mut(),
+            header.refcount_table_offset,
+            (header.refcount_table_clusters as u64 * cluster_size / 8) as usize,
+        )?;
+
+        let backing = match backing {
+            Some(x) => Some(x),
+            None => match header.backing_file_name(&first_cluster)? {
+                Some(name) => Some(Self::open_backing(&header, &name, path)?),
+                None => None,
+            },
+        };
+
+        Ok(Self {
+            backend,
+            header,
+            cluster_size,
+            l2_entries,
+            l1,
+            l2_cache: None,
+            refcount_table,
+            refcount_block_cache: None,
+            backing,
+            free_cluster_offset: round_up!(file_size, cluster_size),
+            cursor: 0,
+        })
+    }
+
+    /// Opens backing file read-only, backing file may have a backing file itself
+    fn open_backing(header: &Header, name: &str, path: Option<&Path>) -> Result<Box<dyn Disk>> {
+        let mut backing_path = PathBuf::from(name);
+        if backing_path.is_relative() {
+            if let Some(dir) = path.and_then(|x| x.parent()) {
+                backing_path = dir.join(backing_path);
+            }
+        }
+        debug!("Opening backing file {}", backing_path.display());
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .open(&backing_path)
+            .map_err(|_| Error::BackingFileNotFound(name.to_owned()))?;
+        let mut backend: Box<dyn Backend> = FileBackend::new(file)?;
+
+        let format = match header.backing_format.as_deref() {
+            Some(x) => DiskFormat::from_str(x)?,
+            None => disk::probe_format(backend.as_mut())?,
+        };
+
+        let mut args = ArgumentMap::default();
+        args.insert(
+            "path",
+            Argument::String(backing_path.to_string_lossy().into_owned()),
+        );
+        disk::open_disk(format, backend, args)
+    }
+
+    fn read_table(backend: &mut dyn Backend, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+        let mut encoded = vec![0u8; entries * 8];
+        backend.seek(SeekFrom::Start(offset))?;
+        backend.read_exact(encoded.as_mut_slice())?;
+
+        let mut reader = io::Cursor::new(encoded);
+        let mut table = Vec::with_capacity(entries);
+        for _ in 0..entries {
+            table.push(reader.read_u64::<BigEndian>()?);
+        }
+
+        Ok(table)
+    }
+
+    pub fn create(backend: Box<dyn Backend>, disk_size: u64) -> Result<Self> {
+        Self::create_ex(backend, disk_size, DEFAULT_CLUSTER_BITS)
+    }
+
+    /// Creates QCOW2 version 3 image with 16 bit refcounts,
+    /// cluster_bits must be between 9 and 21.
+    pub fn create_ex(
+        mut backend: Box<dyn Backend>,
+        disk_size: u64,
+        cluster_bits: u32,
+    ) -> Result<Self> {
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(Error::InvalidQcow2(format!(
+                "invalid cluster bits {}",
+                cluster_bits
+            )));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let disk_size = round_up!(disk_size, 512u64);
+        let l2_coverage = cluster_size / 8 * cluster_size;
+        let l1_size = round_up!(disk_size, l2_coverage) / l2_coverage;
+        let l1_clusters = round_up!(l1_size * 8, cluster_size) / cluster_size;
+
+        // header, L1 table, refcount table and single refcount block
+        let l1_table_offset = cluster_size;
+        let refcount_table_offset = l1_table_offset + l1_clusters * cluster_size;
+        let refcount_block_offset = refcount_table_offset + cluster_size;
+        let clusters = refcount_block_offset / cluster_size + 1;
+        if clusters > cluster_size / 2 {
+            return Err(Error::InvalidQcow2(
+                "disk too big for selected cluster size".to_owned(),
+            ));
+        }
+
+        let header = Header {
+            version: 3,
+            backing_file_offset: 0,
+            backing_file_size: 0,
+            cluster_bits,
+            size: disk_size,
+            crypt_method: 0,
+            l1_size: l1_size as u32,
+            l1_table_offset,
+            refcount_table_offset,
+            refcount_table_clusters: 1,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: 4,
+            header_length: Header::V3_SIZE as u32,
+            backing_format: None,
+        };
+
+        let mut buf = vec![0u8; (clusters * cluster_size) as usize];
+        let header_encoded = header.encode();
+        buf[..header_encoded.len()].copy_from_slice(&header_encoded);
+
+        let o = refcount_table_offset as usize;
+        buf[o..o + 8].copy_from_slice(&refcount_block_offset.to_be_bytes());
+        for i in 0..clusters as usize {
+            let o = refcount_block_offset as usize + i * 2;
+            buf[o..o + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        backend.seek(SeekFrom::Start(0))?;
+        backend.write_all(&buf)?;
+        backend.flush()?;
+
+        Self::open(backend)
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset / (self.l2_entries * self.cluster_size)) as usize
+    }
+
+    fn l2_index(&self, offset: u64) -> usize {
+        ((offset / self.cluster_size) % self.l2_entries) as usize
+    }
+
+    fn load_l2(&mut self, l2_offset: u64) -> io::Result<&mut Vec<u64>> {
+        let cached = matches!(self.l2_cache, Some((x, _)) if x == l2_offset);
+        if !cached {
+            let table =
+                Self::read_table(self.backend.as_mut(), l2_offset, self.l2_entries as usize)?;
+            self.l2_cache = Some((l2_offset, table));
+        }
+
+        Ok(&mut self.l2_cache.as_mut().unwrap().1)
+    }
+
+    /// Returns L2 entry describing cluster containing offset, 0 if L2 table is not allocated
+    fn l2_entry(&mut self, offset: u64) -> io::Result<u64> {
+        let l2_offset = self.l1[self.l1_index(offset)] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+
+        let l2_index = self.l2_index(offset);
+        Ok(self.load_l2(l2_offset)?[l2_index])
+    }
+
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> io::Result<()> {
+        let l1_index = self.l1_index(offset);
+        let l1_entry = self.l1[l1_index];
+
+        let l2_offset = if l1_entry & OFFSET_MASK == 0 {
+            let l2_offset = self.alloc_cluster()?;
+            self.backend.seek(SeekFrom::Start(l2_offset))?;
+            self.backend
+                .write_all(vec![0u8; self.cluster_size as usize].as_slice())?;
+
+            self.l1[l1_index] = l2_offset | OFLAG_COPIED;
+            self.backend.seek(SeekFrom::Start(
+                self.header.l1_table_offset + l1_index as u64 * 8,
+            ))?;
+            self.backend.write_u64::<BigEndian>(self.l1[l1_index])?;
+            l2_offset
+        } else if l1_entry & OFLAG_COPIED == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "writing to L2 tables shared with snapshots is not supported",
+            ));
+        } else {
+            l1_entry & OFFSET_MASK
+        };
+
+        let l2_index = self.l2_index(offset);
+        self.load_l2(l2_offset)?[l2_index] = entry;
+        self.backend
+            .seek(SeekFrom::Start(l2_offset + l2_index as u64 * 8))?;
+        self.backend.write_u64::<BigEndian>(entry)
+    }
+
+    /// Allocates new cluster at the end of file, cluster content is left
+    /// uninitialized and must be written by caller
+    fn alloc_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.free_cluster_offset;
+        self.free_cluster_offset += self.cluster_size;
+        trace!("allocating cluster at 0x{:x}", offset);
+
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn refcount_block_entries(&self) -> u64 {
+        (self.cluster_size * 8) >> self.header.refcount_order
+    }
+
+    fn load_refcount_block(&mut self, block_offset: u64) -> io::Result<&mut Vec<u8>> {
+        let cached = matches!(self.refcount_block_cache, Some((x, _)) if x == block_offset);
+        if !cached {
+            let mut block = vec![0u8; self.cluster_size as usize];
+            self.backend.seek(SeekFrom::Start(block_offset))?;
+            self.backend.read_exact(block.as_mut_slice())?;
+            self.refcount_block_cache = Some((block_offset, block));
+        }
+
+        Ok(&mut self.refcount_block_cache.as_mut().unwrap().1)
+    }
+
+    #[cfg(test)]
+    fn get_refcount(&mut self, offset: u64) -> io::Result<u64> {
+        let cluster_index = offset / self.cluster_size;
+        let table_index = (cluster_index / self.refcount_block_entries()) as usize;
+        let block_index = (cluster_index % self.refcount_block_entries()) as usize;
+
+        let block_offset = self.refcount_table.get(table_index).copied().unwrap_or(0) & OFFSET_MASK;
+        if block_offset == 0 {
+            return Ok(0);
+        }
+
+        let width = 1usize << (self.header.refcount_order - 3);
+        let block = self.load_refcount_block(block_offset)?;
+        let mut value = 0u64;
+        for x in block[block_index * width..(block_index + 1) * width].iter() {
+            value = (value << 8) | *x as u64;
+        }
+
+        Ok(value)
+    }
+
+    fn set_refcount(&mut self, offset: u64, value: u64) -> io::Result<()> {
+        let cluster_index = offset / self.cluster_size;
+        let table_index = (cluster_index / self.refcount_block_entries()) as usize;
+        let block_index = (cluster_index % self.refcount_block_entries()) as usize;
+
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(table_index + 1)?;
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.free_cluster_offset;
+            self.free_cluster_offset += self.cluster_size;
+            trace!("allocating refcount block at 0x{:x}", block_offset);
+
+            self.backend.seek(SeekFrom::Start(block_offset))?;
+            self.backend
+                .write_all(vec![0u8; self.cluster_size as usize].as_slice())?;
+
+            self.refcount_table[table_index] = block_offset;
+            self.backend.seek(SeekFrom::Start(
+                self.header.refcount_table_offset + table_index as u64 * 8,
+            ))?;
+            self.backend.write_u64::<BigEndian>(block_offset)?;
+
+            // refcount block is a cluster too
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let width = 1usize << (self.header.refcount_order - 3);
+        let encoded = &value.to_be_bytes()[8 - width..];
+        let block = self.load_refcount_block(block_offset)?;
+        block[block_index * width..(block_index + 1) * width].copy_from_slice(encoded);
+
+        self.backend
+            .seek(SeekFrom::Start(block_offset + (block_index * width) as u64))?;
+        self.backend.write_all(encoded)
+    }
+
+    /// Moves refcount table to the end of file making it big enough to hold
+    /// at least min_entries entries
+    fn grow_refcount_table(&mut self, min_entries: usize) -> io::Result<()> {
+        let entries_per_cluster = (self.cluster_size / 8) as usize;
+        let old_offset = self.header.refcount_table_offset;
+        let old_clusters = self.header.refcount_table_clusters as u64;
+
+        // table must also cover clusters it occupies
+        let new_clusters = (round_up!(min_entries, entries_per_cluster) / entries_per_cluster) * 2;
+        let new_offset = self.free_cluster_offset;
+        self.free_cluster_offset += new_clusters as u64 * self.cluster_size;
+        debug!(
+            "moving refcount table to 0x{:x}, {} clusters",
+            new_offset, new_clusters
+        );
+
+        self.refcount_table
+            .resize(new_clusters * entries_per_cluster, 0);
+        let mut encoded = Vec::with_capacity(self.refcount_table.len() * 8);
+        for x in self.refcount_table.iter().copied() {
+            encoded.write_u64::<BigEndian>(x)?;
+        }
+        self.backend.seek(SeekFrom::Start(new_offset))?;
+        self.backend.write_all(&encoded)?;
+
+        self.header.refcount_table_offset = new_offset;
+        self.header.refcount_table_clusters = new_clusters as u32;
+        self.backend.seek(SeekFrom::Start(0))?;
+        self.backend.write_all(&self.header.encode())?;
+        self.backend.flush()?;
+
+        for i in 0..new_clusters as u64 {
+            self.set_refcount(new_offset + i * self.cluster_size, 1)?;
+        }
+        for i in 0..old_clusters {
+            self.set_refcount(old_offset + i * self.cluster_size, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Clears autoclear feature bits before the first write, none of the features
+    /// is supported and their metadata (eg. bitmaps) would no longer match the data
+    fn clear_autoclear_features(&mut self) -> io::Result<()> {
+        if self.header.autoclear_features == 0 {
+            return Ok(());
+        }
+
+        debug!(
+            "clearing autoclear features 0x{:x}",
+            self.header.autoclear_features
+        );
+        self.header.autoclear_features = 0;
+        self.backend.seek(SeekFrom::Start(0))?;
+        self.backend.write_all(&self.header.encode())?;
+        self.backend.flush()
+    }
+
+    /// Reads from backing disk, area beyond end of backing disk reads as zeros
+    fn read_backing(&mut self, position: u64, buf: &mut [u8]) -> io::Result<()> {
+        let backing = match self.backing.as_mut() {
+            Some(x) => x,
+            None => {
+                zero_u8_slice(buf);
+                return Ok(());
+            }
+        };
+
+        let available = backing.disk_size().saturating_sub(position);
+        let n = min(available, buf.len() as u64) as usize;
+        if n > 0 {
+            backing.seek(SeekFrom::Start(position))?;
+            backing.read_exact(&mut buf[..n])?;
+        }
+        zero_u8_slice(&mut buf[n..]);
+
+        Ok(())
+    }
+
+    /// Reads data from single cluster
+    fn read_cluster(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.l2_entry(self.cursor)?;
+        let offset_in_cluster = self.cursor % self.cluster_size;
+
+        if entry & OFLAG_COMPRESSED != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compressed clusters are not supported",
+            ));
+        }
+
+        if entry & OFLAG_ZERO != 0 && self.header.version >= 3 {
+            zero_u8_slice(buf);
+        } else if entry & OFFSET_MASK != 0 {
+            self.backend
+                .seek(SeekFrom::Start((entry & OFFSET_MASK) + offset_in_cluster))?;
+            self.backend.read_exact(buf)?;
+        } else {
+            self.read_backing(self.cursor, buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes data into single cluster allocating it if necessary
+    fn write_cluster(&mut self, buf: &[u8]) -> io::Result<()> {
+        let entry = self.l2_entry(self.cursor)?;
+        let offset_in_cluster = self.cursor % self.cluster_size;
+        let host_offset = entry & OFFSET_MASK;
+        let is_zero = entry & OFLAG_ZERO != 0 && self.header.version >= 3;
+
+        if entry & OFLAG_COMPRESSED != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compressed clusters are not supported",
+            ));
+        }
+        if host_offset != 0 && entry & OFLAG_COPIED == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "writing to clusters shared with snapshots is not supported",
+            ));
+        }
+
+        if host_offset != 0 && !is_zero {
+            self.backend
+                .seek(SeekFrom::Start(host_offset + offset_in_cluster))?;
+            return self.backend.write_all(buf);
+        }
+
+        let reads_as_zero = is_zero || self.backing.is_none();
+        if reads_as_zero && buf.iter().all(|x| *x == 0) {
+            return Ok(());
+        }
+
+        // data not covered by this write comes from backing file unless cluster is zeroed
+        let cluster_start = self.cursor - offset_in_cluster;
+        let mut cluster = vec![0u8; self.cluster_size as usize];
+        if !reads_as_zero && buf.len() as u64 != self.cluster_size {
+            self.read_backing(cluster_start, cluster.as_mut_slice())?;
+        }
+        cluster[offset_in_cluster as usize..offset_in_cluster as usize + buf.len()]
+            .copy_from_slice(buf);
+
+        // preallocated zero cluster can be reused
+        let host_offset = if host_offset != 0 {
+            host_offset
+        } else {
+            self.alloc_cluster()?
+        };
+        self.backend.seek(SeekFrom::Start(host_offset))?;
+        self.backend.write_all(cluster.as_slice())?;
+
+        self.set_l2_entry(cluster_start, host_offset | OFLAG_COPIED)
+    }
+}
+
+impl Disk for Qcow2Disk {
+    fn disk_size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn sector_size(&self) -> u32 {
+        512
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::HDD
+    }
+
+    fn disk_format(&self) -> DiskFormat {
+        DiskFormat::QCOW2
+    }
+}
+
+impl Seek for Qcow2Disk {
+    fn seek(&mut self, s: SeekFrom) -> io::Result<u64> {
+        self.cursor = seek_position(self.cursor, self.disk_size(), s)?;
+        Ok(self.cursor)
+    }
+}
+
+impl Read for Qcow2Disk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_read = 0usize;
+
+        while left > 0 {
+            let n = min(
+                left,
+                (self.cluster_size - self.cursor % self.cluster_size) as usize,
+            );
+
+            self.read_cluster(&mut buf[total_read..total_read + n])?;
+
+            left -= n;
+            self.cursor += n as u64;
+            total_read += n;
+        }
+
+        Ok(total_read)
+    }
+}
+
+impl Write for Qcow2Disk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_written = 0usize;
+
+        if left > 0 {
+            self.clear_autoclear_features()?;
+        }
+
+        while left > 0 {
+            let n = min(
+                left,
+                (self.cluster_size - self.cursor % self.cluster_size) as usize,
+            );
+
+            self.write_cluster(&buf[total_written..total_written + n])?;
+
+            left -= n;
+            self.cursor += n as u64;
+            total_written += n;
+        }
+
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::MemoryBackend;
+    use std::collections::HashMap;
+
+    /// Verifies that refcount of every cluster matches number of references
+    fn check_refcounts(disk: &mut Qcow2Disk) {
+        let cs = disk.cluster_size;
+        let mut expected: HashMap<u64, u64> = HashMap::new();
+        let mut add = |offset: u64, len: u64| {
+            for i in 0..round_up!(len, cs) / cs {
+                *expected.entry(offset + i * cs).or_insert(0) += 1;
+            }
+        };
+
+        add(0, cs);
+        add(disk.header.l1_table_offset, disk.header.l1_size as u64 * 8);
+        add(
+            disk.header.refcount_table_offset,
+            disk.header.refcount_table_clusters as u64 * cs,
+        );
+        for x in disk.refcount_table.iter().filter(|x| **x != 0) {
+            add(*x & OFFSET_MASK, cs);
+        }
+        for l1e in disk.l1.clone().iter().filter(|x| **x & OFFSET_MASK != 0) {
+            add(l1e & OFFSET_MASK, cs);
+            for l2e in disk.load_l2(l1e & OFFSET_MASK).unwrap().iter() {
+                if l2e & OFFSET_MASK != 0 {
+                    add(l2e & OFFSET_MASK, cs);
+                }
+            }
+        }
+
+        let mut offset = 0;
+        while offset < disk.free_cluster_offset {
+            assert_eq!(
+                disk.get_refcount(offset).unwrap(),
+                expected.get(&offset).copied().unwrap_or(0),
+                "refcount mismatch at 0x{:x}",
+                offset
+            );
+            offset += cs;
+        }
+    }
+
+    #[test]
+    fn test_create_and_open() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        let mut disk = Qcow2Disk::create(Box::new(backend), 64 * 1024 * 1024).unwrap();
+        disk.seek(SeekFrom::Start(65536 - 2)).unwrap();
+        disk.write_all(b"qcow2").unwrap();
+        check_refcounts(&mut disk);
+        drop(disk);
+
+        // header, L1, refcount table, refcount block, L2 table and 2 data clusters
+        assert_eq!(storage.borrow().len(), 7 * 65536);
+
+        let mut disk = Qcow2Disk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        check_refcounts(&mut disk);
+        let mut buf = [0u8; 5];
+        disk.seek(SeekFrom::Start(65536 - 2)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"qcow2");
+    }
+
+    #[test]
+    fn test_autoclear_features() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        drop(Qcow2Disk::create(Box::new(backend), 1024 * 1024).unwrap());
+        // bitmaps extension
+        storage.borrow_mut()[88..96].copy_from_slice(&1u64.to_be_bytes());
+
+        let mut disk =
+            Qcow2Disk::open(Box::new(MemoryBackend::from_storage(storage.clone()))).unwrap();
+        let mut buf = [0u8; 512];
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(disk.header.autoclear_features, 1);
+        disk.write_all(&[0x55; 512]).unwrap();
+        assert_eq!(disk.header.autoclear_features, 0);
+        drop(disk);
+
+        let disk = Qcow2Disk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        assert_eq!(disk.header.autoclear_features, 0);
+    }
+
+    #[test]
+    fn test_invalid_l1_size() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        drop(Qcow2Disk::create(Box::new(backend), 1024 * 1024).unwrap());
+        // 2 MiB clusters and the largest L1 table overflow covered size
+        storage.borrow_mut()[20..24].copy_from_slice(&21u32.to_be_bytes());
+        storage.borrow_mut()[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        assert!(matches!(
+            Qcow2Disk::open(Box::new(MemoryBackend::from_storage(storage))),
+            Err(Error::InvalidQcow2(x)) if x.contains("too big")
+        ));
+    }
+
+    #[test]
+    fn test_refcount_table_growth() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        // with 512 byte clusters single refcount table cluster covers 8 MiB
+        let mut disk = Qcow2Disk::create_ex(Box::new(backend), 12 * 1024 * 1024, 9).unwrap();
+        let table_offset = disk.header.refcount_table_offset;
+        for i in 0..12 * 1024 * 2u32 {
+            disk.write_all(&[(i % 251) as u8 + 1; 512]).unwrap();
+        }
+        assert_ne!(disk.header.refcount_table_offset, table_offset);
+        check_refcounts(&mut disk);
+        drop(disk);
+
+        let mut disk = Qcow2Disk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        check_refcounts(&mut disk);
+        let mut buf = [0u8; 512];
+        for i in 0..12 * 1024 * 2u32 {
+            disk.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|x| *x == (i % 251) as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn test_backing_file() {
+        crate::tests_init();
+
+        let mut base = Qcow2Disk::create(Box::new(MemoryBackend::new()), 1024 * 1024).unwrap();
+        base.write_all(&[0xaa; 65536 * 2]).unwrap();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        drop(Qcow2Disk::create(Box::new(backend), 1024 * 1024).unwrap());
+        let mut disk = Qcow2Disk::open_with_backing(
+            Box::new(MemoryBackend::from_storage(storage)),
+            Box::new(base),
+        )
+        .unwrap();
+
+        disk.seek(SeekFrom::Start(100)).unwrap();
+        disk.write_all(&[0x55; 100]).unwrap();
+        // zeros must be stored, otherwise backing data would show through
+        disk.seek(SeekFrom::Start(65536)).unwrap();
+        disk.write_all(&[0; 512]).unwrap();
+        check_refcounts(&mut disk);
+
+        let mut buf = vec![0u8; 65536 * 3];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..100].iter().all(|x| *x == 0xaa));
+        assert!(buf[100..200].iter().all(|x| *x == 0x55));
+        assert!(buf[200..65536].iter().all(|x| *x == 0xaa));
+        assert!(buf[65536..65536 + 512].iter().all(|x| *x == 0));
+        assert!(buf[65536 + 512..65536 * 2].iter().all(|x| *x == 0xaa));
+        assert!(buf[65536 * 2..].iter().all(|x| *x == 0));
+    }
+}
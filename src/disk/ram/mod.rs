@@ -1,3 +1,4 @@
+use std::cmp::min;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 use crate::disk::{Disk, DiskFormat, MediaType};
@@ -59,7 +60,14 @@ impl Seek for RamDisk {
 
 impl Write for RamDisk {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.buffer.write(buf)
+        // Cursor<Vec<u8>> would grow the vector, disk size is fixed
+        let available = self.disk_size().saturating_sub(self.buffer.position());
+        let n = min(available, buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        self.buffer.write(&buf[..n])
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -1,12 +1,14 @@
+use std::cmp::min;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use crate::disk::{ArgumentMap, Backend, Disk, DiskFormat, MediaType};
+use crate::disk::{seek_position, ArgumentMap, Backend, Disk, DiskFormat, MediaType};
 
 pub struct RawDisk {
     backend: Box<dyn Backend>,
     disk_size: u64,
     sector_size: u32,
     media_type: MediaType,
+    cursor: u64,
 }
 
 impl RawDisk {
@@ -27,25 +29,48 @@ impl RawDisk {
             sector_size,
             disk_size,
             media_type,
+            cursor: 0,
         }
     }
 }
 
 impl Read for RawDisk {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.backend.read(buf)
+        let available = self.disk_size.saturating_sub(self.cursor);
+        let n = min(available, buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        self.backend.seek(SeekFrom::Start(self.cursor))?;
+        let r = self.backend.read(&mut buf[..n])?;
+        self.cursor += r as u64;
+
+        Ok(r)
     }
 }
 
 impl Seek for RawDisk {
     fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
-        self.backend.seek(seek)
+        self.cursor = seek_position(self.cursor, self.disk_size, seek)?;
+        Ok(self.cursor)
     }
 }
 
 impl Write for RawDisk {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.backend.write(buf)
+        // disk never grows past the size of backing file
+        let available = self.disk_size.saturating_sub(self.cursor);
+        let n = min(available, buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        self.backend.seek(SeekFrom::Start(self.cursor))?;
+        let w = self.backend.write(&buf[..n])?;
+        self.cursor += w as u64;
+
+        Ok(w)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -1,4 +1,4 @@
-use crate::disk::{Disk, DiskFormat, MediaType};
+use crate::disk::{seek_position, Disk, DiskFormat, MediaType};
 use std::cmp::min;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 
@@ -61,12 +61,7 @@ impl<'a> Read for DiskSlice<'a> {
 
 impl<'a> Seek for DiskSlice<'a> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(x) => self.cursor = x,
-            SeekFrom::End(x) => self.cursor = (self.end + 1) - x as u64,
-            SeekFrom::Current(x) => self.cursor = self.cursor.wrapping_add(x as u64),
-        }
-
+        self.cursor = seek_position(self.cursor, self.disk_size(), pos)?;
         Ok(self.cursor)
     }
 }
@@ -192,8 +187,10 @@ mod tests {
         test_seek!(SeekFrom::Current(4), 516);
         test_seek!(SeekFrom::Current(-3), 513);
         test_seek!(SeekFrom::Start(20000), 20512);
-        test_seek!(SeekFrom::End(0), 1536);
-        test_seek!(SeekFrom::Current(2), 1538);
+        test_seek!(SeekFrom::End(0), 1024);
+        test_seek!(SeekFrom::End(-2), 1022);
+        test_seek!(SeekFrom::Current(2), 1024);
+        assert!(slice.seek(SeekFrom::End(-513)).is_err());
     }
 
     #[test]
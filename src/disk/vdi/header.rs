@@ -0,0 +1,261 @@
+use crate::{Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use uuid::Uuid;
+
+pub const IMAGE_TYPE_DYNAMIC: u32 = 1;
+pub const IMAGE_TYPE_FIXED: u32 = 2;
+pub const IMAGE_TYPE_UNDO: u32 = 3;
+pub const IMAGE_TYPE_DIFF: u32 = 4;
+
+/// Preheader and version 1.1 header
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u32,
+    pub image_type: u32,
+    pub flags: u32,
+    pub comment: String,
+    pub blocks_offset: u32,
+    pub data_offset: u32,
+    /// Legacy geometry, cylinders, heads, sectors and sector size
+    pub legacy_geometry: (u32, u32, u32, u32),
+    pub disk_size: u64,
+    pub block_size: u32,
+    pub block_extra_size: u32,
+    pub blocks: u32,
+    pub blocks_allocated: u32,
+    pub uuid_create: Uuid,
+    pub uuid_modify: Uuid,
+    pub uuid_linkage: Uuid,
+    pub uuid_parent_modify: Uuid,
+    pub lchs_geometry: (u32, u32, u32, u32),
+}
+
+impl Header {
+    pub const SIGNATURE: u32 = 0xBEDA107F;
+    pub const VERSION_1_1: u32 = 0x00010001;
+    pub const PREHEADER_SIZE: usize = 72;
+    pub const HEADER_SIZE: usize = 400;
+    pub const SIZE: usize = Self::PREHEADER_SIZE + Self::HEADER_SIZE;
+    const INFO: &'static [u8] = b"<<< Oracle VM VirtualBox Disk Image >>>\n";
+    const COMMENT_SIZE: usize = 256;
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::SIZE {
+            return Err(Error::InvalidVdi("header too short".to_owned()));
+        }
+
+        let mut reader = Cursor::new(&buf[64..]);
+        if reader.read_u32::<LittleEndian>()? != Self::SIGNATURE {
+            return Err(Error::InvalidVdi("invalid signature".to_owned()));
+        }
+        let version = reader.read_u32::<LittleEndian>()?;
+        // header size is part of the header
+        let header_size = reader.read_u32::<LittleEndian>()? as usize;
+        if version >> 16 != 1 || header_size < Self::HEADER_SIZE {
+            return Err(Error::InvalidVdi(format!(
+                "unsupported version 0x{:08x}, header size {}",
+                version, header_size
+            )));
+        }
+
+        let image_type = reader.read_u32::<LittleEndian>()?;
+        let flags = reader.read_u32::<LittleEndian>()?;
+        let mut comment = [0u8; Self::COMMENT_SIZE];
+        reader.read_exact(&mut comment)?;
+        let comment_length = comment
+            .iter()
+            .position(|x| *x == 0)
+            .unwrap_or(comment.len());
+
+        let header = Self {
+            version,
+            image_type,
+            flags,
+            comment: String::from_utf8_lossy(&comment[..comment_length]).into_owned(),
+            blocks_offset: reader.read_u32::<LittleEndian>()?,
+            data_offset: reader.read_u32::<LittleEndian>()?,
+            legacy_geometry: read_geometry(&mut reader)?,
+            disk_size: {
+                let _dummy = reader.read_u32::<LittleEndian>()?;
+                reader.read_u64::<LittleEndian>()?
+            },
+            block_size: reader.read_u32::<LittleEndian>()?,
+            block_extra_size: reader.read_u32::<LittleEndian>()?,
+            blocks: reader.read_u32::<LittleEndian>()?,
+            blocks_allocated: reader.read_u32::<LittleEndian>()?,
+            uuid_create: read_uuid(&mut reader)?,
+            uuid_modify: read_uuid(&mut reader)?,
+            uuid_linkage: read_uuid(&mut reader)?,
+            uuid_parent_modify: read_uuid(&mut reader)?,
+            lchs_geometry: read_geometry(&mut reader)?,
+        };
+
+        if header.block_size == 0 || header.block_size % 512 != 0 {
+            return Err(Error::InvalidVdi(format!(
+                "invalid block size {}",
+                header.block_size
+            )));
+        }
+        if (header.blocks as u64) * (header.block_size as u64) < header.disk_size {
+            return Err(Error::InvalidVdi(format!(
+                "{} blocks don't cover disk size {}",
+                header.blocks, header.disk_size
+            )));
+        }
+
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+        buf[..Self::INFO.len()].copy_from_slice(Self::INFO);
+
+        let mut writer = Cursor::new(&mut buf[64..]);
+        writer.write_u32::<LittleEndian>(Self::SIGNATURE).unwrap();
+        writer.write_u32::<LittleEndian>(self.version).unwrap();
+        writer
+            .write_u32::<LittleEndian>(Self::HEADER_SIZE as u32)
+            .unwrap();
+        writer.write_u32::<LittleEndian>(self.image_type).unwrap();
+        writer.write_u32::<LittleEndian>(self.flags).unwrap();
+        let mut comment = [0u8; Self::COMMENT_SIZE];
+        let comment_length = std::cmp::min(self.comment.len(), Self::COMMENT_SIZE - 1);
+        comment[..comment_length].copy_from_slice(&self.comment.as_bytes()[..comment_length]);
+        writer.write_all(&comment).unwrap();
+        writer
+            .write_u32::<LittleEndian>(self.blocks_offset)
+            .unwrap();
+        writer.write_u32::<LittleEndian>(self.data_offset).unwrap();
+        write_geometry(&mut writer, self.legacy_geometry).unwrap();
+        writer.write_u32::<LittleEndian>(0).unwrap();
+        writer.write_u64::<LittleEndian>(self.disk_size).unwrap();
+        writer.write_u32::<LittleEndian>(self.block_size).unwrap();
+        writer
+            .write_u32::<LittleEndian>(self.block_extra_size)
+            .unwrap();
+        writer.write_u32::<LittleEndian>(self.blocks).unwrap();
+        writer
+            .write_u32::<LittleEndian>(self.blocks_allocated)
+            .unwrap();
+        write_uuid(&mut writer, self.uuid_create).unwrap();
+        write_uuid(&mut writer, self.uuid_modify).unwrap();
+        write_uuid(&mut writer, self.uuid_linkage).unwrap();
+        write_uuid(&mut writer, self.uuid_parent_modify).unwrap();
+        write_geometry(&mut writer, self.lchs_geometry).unwrap();
+
+        buf
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Version: 0x{:08x}", self.version)?;
+        writeln!(f, "Image type: {}", self.image_type)?;
+        writeln!(f, "Comment: {:?}", self.comment)?;
+        writeln!(f, "Blocks offset: 0x{:x}", self.blocks_offset)?;
+        writeln!(f, "Data offset: 0x{:x}", self.data_offset)?;
+        writeln!(f, "Disk size: {}", self.disk_size)?;
+        writeln!(f, "Block size: {}", self.block_size)?;
+        writeln!(f, "Block extra size: {}", self.block_extra_size)?;
+        writeln!(
+            f,
+            "Blocks: {} ({} allocated)",
+            self.blocks, self.blocks_allocated
+        )?;
+        writeln!(f, "UUID: {}", self.uuid_create)?;
+        writeln!(f, "Modification UUID: {}", self.uuid_modify)?;
+        write!(f, "Parent UUID: {}", self.uuid_linkage)
+    }
+}
+
+fn read_geometry<T>(reader: &mut T) -> io::Result<(u32, u32, u32, u32)>
+where
+    T: Read,
+{
+    Ok((
+        reader.read_u32::<LittleEndian>()?,
+        reader.read_u32::<LittleEndian>()?,
+        reader.read_u32::<LittleEndian>()?,
+        reader.read_u32::<LittleEndian>()?,
+    ))
+}
+
+fn write_geometry<T>(writer: &mut T, geometry: (u32, u32, u32, u32)) -> io::Result<()>
+where
+    T: Write,
+{
+    writer.write_u32::<LittleEndian>(geometry.0)?;
+    writer.write_u32::<LittleEndian>(geometry.1)?;
+    writer.write_u32::<LittleEndian>(geometry.2)?;
+    writer.write_u32::<LittleEndian>(geometry.3)
+}
+
+/// UUIDs are stored in little endian, same as GUIDs
+fn read_uuid<T>(reader: &mut T) -> io::Result<Uuid>
+where
+    T: Read,
+{
+    let p0 = reader.read_u32::<LittleEndian>()?;
+    let p1 = reader.read_u16::<LittleEndian>()?;
+    let p2 = reader.read_u16::<LittleEndian>()?;
+    let mut p3 = [0u8; 8];
+    reader.read_exact(&mut p3)?;
+
+    Ok(Uuid::from_fields(p0, p1, p2, &p3).unwrap())
+}
+
+fn write_uuid<T>(writer: &mut T, uuid: Uuid) -> io::Result<()>
+where
+    T: Write,
+{
+    let (p0, p1, p2, p3) = uuid.as_fields();
+
+    writer.write_u32::<LittleEndian>(p0)?;
+    writer.write_u16::<LittleEndian>(p1)?;
+    writer.write_u16::<LittleEndian>(p2)?;
+    writer.write_all(p3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Header;
+
+    #[test]
+    fn test_encode_decode() {
+        crate::tests_init();
+
+        let header = Header {
+            version: Header::VERSION_1_1,
+            image_type: super::IMAGE_TYPE_DYNAMIC,
+            flags: 0,
+            comment: "test".to_owned(),
+            blocks_offset: 0x200,
+            data_offset: 0x100000,
+            legacy_geometry: (0, 0, 0, 512),
+            disk_size: 3 * 1024 * 1024,
+            block_size: 1024 * 1024,
+            block_extra_size: 0,
+            blocks: 3,
+            blocks_allocated: 1,
+            uuid_create: uuid::Uuid::new_v4(),
+            uuid_modify: uuid::Uuid::new_v4(),
+            uuid_linkage: uuid::Uuid::nil(),
+            uuid_parent_modify: uuid::Uuid::nil(),
+            lchs_geometry: (6, 16, 63, 512),
+        };
+
+        let encoded = header.encode();
+        assert_eq!(&encoded[64..68], &[0x7f, 0x10, 0xda, 0xbe]);
+        // disk size offset as defined by VirtualBox
+        assert_eq!(&encoded[0x170..0x178], &(3u64 * 1024 * 1024).to_le_bytes());
+
+        let decoded = Header::decode(&encoded).unwrap();
+        assert_eq!(decoded.comment, "test");
+        assert_eq!(decoded.disk_size, header.disk_size);
+        assert_eq!(decoded.blocks_allocated, 1);
+        assert_eq!(decoded.uuid_create, header.uuid_create);
+        assert_eq!(decoded.lchs_geometry, header.lchs_geometry);
+    }
+}
@@ -0,0 +1,399 @@
+mod header;
+
+pub use header::{Header, IMAGE_TYPE_DIFF, IMAGE_TYPE_DYNAMIC, IMAGE_TYPE_FIXED, IMAGE_TYPE_UNDO};
+
+use crate::disk::{seek_position, ArgumentMap, Backend, Disk, DiskFormat, MediaType};
+use crate::{is_power_of_2, round_up, utils::zero_u8_slice, Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::min;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use uuid::Uuid;
+
+const BLOCK_FREE: u32 = 0xffffffff;
+const BLOCK_ZERO: u32 = 0xfffffffe;
+
+const DEFAULT_BLOCK_SIZE: u32 = 1024 * 1024;
+const BLOCKS_OFFSET: u32 = 0x200;
+// data is aligned to 1 MiB like in images created by VirtualBox
+const DATA_ALIGNMENT: u64 = 1024 * 1024;
+
+pub struct VdiDisk {
+    backend: Box<dyn Backend>,
+    header: Header,
+    block_map: Vec<u32>,
+    header_updated: bool,
+    cursor: u64,
+}
+
+impl VdiDisk {
+    pub fn open_with_argmap(backend: Box<dyn Backend>, _args: &ArgumentMap) -> Result<Self> {
+        Self::open(backend)
+    }
+
+    pub fn open(mut backend: Box<dyn Backend>) -> Result<Self> {
+        let mut buf = [0u8; Header::SIZE];
+        backend.seek(SeekFrom::Start(0))?;
+        backend.read_exact(&mut buf)?;
+        let header = Header::decode(&buf)?;
+        debug!("Header:\n{}\n", header);
+
+        match header.image_type {
+            IMAGE_TYPE_DYNAMIC | IMAGE_TYPE_FIXED => {}
+            IMAGE_TYPE_DIFF | IMAGE_TYPE_UNDO => {
+                return Err(Error::InvalidVdi(
+                    "differencing images are not supported".to_owned(),
+                ))
+            }
+            x => return Err(Error::InvalidVdi(format!("unknown image type {}", x))),
+        }
+
+        let mut encoded = vec![0u8; header.blocks as usize * 4];
+        backend.seek(SeekFrom::Start(header.blocks_offset as u64))?;
+        backend.read_exact(encoded.as_mut_slice())?;
+        let mut reader = Cursor::new(encoded);
+        let mut block_map = Vec::with_capacity(header.blocks as usize);
+        for _ in 0..header.blocks {
+            let entry = reader.read_u32::<LittleEndian>()?;
+            if entry < BLOCK_ZERO && entry >= header.blocks_allocated {
+                return Err(Error::InvalidVdi(format!(
+                    "block map entry {} out of range",
+                    entry
+                )));
+            }
+            block_map.push(entry);
+        }
+
+        Ok(Self {
+            backend,
+            header,
+            block_map,
+            header_updated: false,
+            cursor: 0,
+        })
+    }
+
+    pub fn create_dynamic(backend: Box<dyn Backend>, disk_size: u64) -> Result<Self> {
+        Self::create_ex(backend, disk_size, IMAGE_TYPE_DYNAMIC, DEFAULT_BLOCK_SIZE)
+    }
+
+    pub fn create_fixed(backend: Box<dyn Backend>, disk_size: u64) -> Result<Self> {
+        Self::create_ex(backend, disk_size, IMAGE_TYPE_FIXED, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Creates dynamic or fixed image, block_size must be power of 2 and at least 512
+    pub fn create_ex(
+        mut backend: Box<dyn Backend>,
+        disk_size: u64,
+        image_type: u32,
+        block_size: u32,
+    ) -> Result<Self> {
+        if block_size < 512 || !is_power_of_2!(block_size) {
+            return Err(Error::InvalidVdi(format!(
+                "invalid block size {}",
+                block_size
+            )));
+        }
+        if image_type != IMAGE_TYPE_DYNAMIC && image_type != IMAGE_TYPE_FIXED {
+            return Err(Error::InvalidVdi(format!(
+                "can't create image type {}",
+                image_type
+            )));
+        }
+
+        let disk_size = round_up!(disk_size, 512u64);
+        let blocks = round_up!(disk_size, block_size as u64) / block_size as u64;
+        if blocks >= BLOCK_ZERO as u64 {
+            return Err(Error::InvalidVdi("disk too big for block size".to_owned()));
+        }
+        let data_offset = round_up!(BLOCKS_OFFSET as u64 + blocks * 4, DATA_ALIGNMENT);
+
+        let cylinders = min(disk_size / 512 / (16 * 63), 16383) as u32;
+        let fixed = image_type == IMAGE_TYPE_FIXED;
+        let header = Header {
+            version: Header::VERSION_1_1,
+            image_type,
+            flags: 0,
+            comment: String::new(),
+            blocks_offset: BLOCKS_OFFSET,
+            data_offset: data_offset as u32,
+            legacy_geometry: (0, 0, 0, 512),
+            disk_size,
+            block_size,
+            block_extra_size: 0,
+            blocks: blocks as u32,
+            blocks_allocated: if fixed { blocks as u32 } else { 0 },
+            uuid_create: Uuid::new_v4(),
+            uuid_modify: Uuid::new_v4(),
+            uuid_linkage: Uuid::nil(),
+            uuid_parent_modify: Uuid::nil(),
+            lchs_geometry: (cylinders, 16, 63, 512),
+        };
+
+        let mut buf = vec![0u8; data_offset as usize];
+        buf[..Header::SIZE].copy_from_slice(&header.encode());
+        for i in 0..blocks as usize {
+            let o = BLOCKS_OFFSET as usize + i * 4;
+            let entry = if fixed { i as u32 } else { BLOCK_FREE };
+            buf[o..o + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+
+        backend.seek(SeekFrom::Start(0))?;
+        backend.write_all(&buf)?;
+        if fixed && blocks > 0 {
+            backend.seek(SeekFrom::Start(
+                data_offset + blocks * block_size as u64 - 1,
+            ))?;
+            backend.write_all(&[0])?;
+        }
+        backend.flush()?;
+
+        Self::open(backend)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn block_size(&self) -> u64 {
+        self.header.block_size as u64
+    }
+
+    fn block_offset(&self, index: u32) -> u64 {
+        self.header.data_offset as u64
+            + index as u64 * (self.header.block_size as u64 + self.header.block_extra_size as u64)
+            + self.header.block_extra_size as u64
+    }
+
+    /// Modification UUID has to change whenever image content changes
+    fn update_header_before_write(&mut self) -> io::Result<()> {
+        if !self.header_updated {
+            self.header.uuid_modify = Uuid::new_v4();
+            self.write_header()?;
+            self.header_updated = true;
+        }
+
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.backend.seek(SeekFrom::Start(0))?;
+        self.backend.write_all(&self.header.encode())
+    }
+
+    /// Allocates block at the end of data area, block content is written by caller
+    fn alloc_block(&mut self, block_index: usize, data: &[u8]) -> io::Result<()> {
+        let index = self.header.blocks_allocated;
+        trace!("allocating block {} for block {}", index, block_index);
+
+        let block_offset = self.block_offset(index);
+        if self.header.block_extra_size > 0 {
+            self.backend.seek(SeekFrom::Start(
+                block_offset - self.header.block_extra_size as u64,
+            ))?;
+            self.backend
+                .write_all(vec![0u8; self.header.block_extra_size as usize].as_slice())?;
+        } else {
+            self.backend.seek(SeekFrom::Start(block_offset))?;
+        }
+        self.backend.write_all(data)?;
+
+        self.block_map[block_index] = index;
+        self.backend.seek(SeekFrom::Start(
+            self.header.blocks_offset as u64 + block_index as u64 * 4,
+        ))?;
+        self.backend.write_u32::<LittleEndian>(index)?;
+
+        self.header.blocks_allocated += 1;
+        self.write_header()
+    }
+
+    fn read_block(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let block_index = (self.cursor / self.block_size()) as usize;
+        let offset_in_block = self.cursor % self.block_size();
+
+        match self.block_map[block_index] {
+            BLOCK_FREE | BLOCK_ZERO => zero_u8_slice(buf),
+            index => {
+                self.backend
+                    .seek(SeekFrom::Start(self.block_offset(index) + offset_in_block))?;
+                self.backend.read_exact(buf)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, buf: &[u8]) -> io::Result<()> {
+        let block_index = (self.cursor / self.block_size()) as usize;
+        let offset_in_block = self.cursor % self.block_size();
+
+        match self.block_map[block_index] {
+            BLOCK_FREE | BLOCK_ZERO => {
+                // unallocated blocks read as zeros
+                if buf.iter().all(|x| *x == 0) {
+                    return Ok(());
+                }
+
+                let mut block = vec![0u8; self.block_size() as usize];
+                block[offset_in_block as usize..offset_in_block as usize + buf.len()]
+                    .copy_from_slice(buf);
+                self.alloc_block(block_index, block.as_slice())
+            }
+            index => {
+                self.backend
+                    .seek(SeekFrom::Start(self.block_offset(index) + offset_in_block))?;
+                self.backend.write_all(buf)
+            }
+        }
+    }
+}
+
+impl Disk for VdiDisk {
+    fn disk_size(&self) -> u64 {
+        self.header.disk_size
+    }
+
+    fn sector_size(&self) -> u32 {
+        512
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::HDD
+    }
+
+    fn disk_format(&self) -> DiskFormat {
+        DiskFormat::VDI
+    }
+}
+
+impl Seek for VdiDisk {
+    fn seek(&mut self, s: SeekFrom) -> io::Result<u64> {
+        self.cursor = seek_position(self.cursor, self.disk_size(), s)?;
+        Ok(self.cursor)
+    }
+}
+
+impl Read for VdiDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_read = 0usize;
+
+        while left > 0 {
+            let n = min(
+                left,
+                (self.block_size() - self.cursor % self.block_size()) as usize,
+            );
+
+            self.read_block(&mut buf[total_read..total_read + n])?;
+
+            left -= n;
+            self.cursor += n as u64;
+            total_read += n;
+        }
+
+        Ok(total_read)
+    }
+}
+
+impl Write for VdiDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_written = 0usize;
+
+        if left > 0 {
+            self.update_header_before_write()?;
+        }
+
+        while left > 0 {
+            let n = min(
+                left,
+                (self.block_size() - self.cursor % self.block_size()) as usize,
+            );
+
+            self.write_block(&buf[total_written..total_written + n])?;
+
+            left -= n;
+            self.cursor += n as u64;
+            total_written += n;
+        }
+
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::MemoryBackend;
+
+    #[test]
+    fn test_create_dynamic() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        let mut disk = VdiDisk::create_dynamic(Box::new(backend), 8 * 1024 * 1024).unwrap();
+        let uuid_modify = disk.header().uuid_modify;
+        assert_eq!(storage.borrow().len(), 1024 * 1024);
+        disk.seek(SeekFrom::Start(1024 * 1024 - 1)).unwrap();
+        disk.write_all(b"vdi").unwrap();
+        disk.seek(SeekFrom::Start(7 * 1024 * 1024)).unwrap();
+        disk.write_all(b"vdi").unwrap();
+        drop(disk);
+
+        assert_eq!(storage.borrow().len(), 4 * 1024 * 1024);
+
+        let mut disk = VdiDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        assert_ne!(disk.header().uuid_modify, uuid_modify);
+        assert_eq!(disk.header().blocks_allocated, 3);
+        // blocks are allocated in order of writes
+        assert_eq!(disk.block_map[0], 0);
+        assert_eq!(disk.block_map[1], 1);
+        assert_eq!(disk.block_map[4], BLOCK_FREE);
+        assert_eq!(disk.block_map[7], 2);
+
+        let mut buf = [0u8; 3];
+        disk.seek(SeekFrom::Start(1024 * 1024 - 1)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"vdi");
+    }
+
+    #[test]
+    fn test_zero_blocks() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        let mut disk = VdiDisk::create_fixed(Box::new(backend), 2 * 1024 * 1024).unwrap();
+        assert_eq!(storage.borrow().len(), 3 * 1024 * 1024);
+        disk.write_all(&[0x55; 2 * 1024 * 1024]).unwrap();
+        drop(disk);
+
+        // mark first block as zero block
+        storage.borrow_mut()[0x200..0x204].copy_from_slice(&BLOCK_ZERO.to_le_bytes());
+
+        let mut disk = VdiDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        let mut buf = vec![0u8; 2 * 1024 * 1024];
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..1024 * 1024].iter().all(|x| *x == 0));
+        assert!(buf[1024 * 1024..].iter().all(|x| *x == 0x55));
+
+        // writing into zero block allocates new block
+        disk.seek(SeekFrom::Start(512)).unwrap();
+        disk.write_all(&[0xaa; 512]).unwrap();
+        assert_eq!(disk.block_map[0], 2);
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf[..2048]).unwrap();
+        assert!(buf[..512].iter().all(|x| *x == 0));
+        assert!(buf[512..1024].iter().all(|x| *x == 0xaa));
+        assert!(buf[1024..2048].iter().all(|x| *x == 0));
+    }
+}
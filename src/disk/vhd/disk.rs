@@ -1,11 +1,20 @@
-use crate::disk::vhd::{dynamic_header::DynamicHeader, footer::Footer, DiskType as VhdDiskType};
-use crate::disk::{ArgumentMap, Backend, Disk, DiskFormat, MediaType};
-use crate::{is_power_of_2, round_up, u8_array_uninitialized, utils::zero_u8_slice, Result};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::cmp::min;
+use crate::disk::vhd::{
+    dynamic_header::{DynamicHeader, ParentLocatorEntry},
+    footer::Footer,
+    parent::{create_locators, open_parent, resolve_parent},
+    read_footer,
+    repair::{bitmap_size, find_overlapping_blocks},
+    CreateOptions, DiskType as VhdDiskType,
+};
+use crate::disk::{seek_position, ArgumentMap, Backend, Disk, DiskFormat, MediaType};
+use crate::{is_power_of_2, round_up, u8_array_uninitialized, utils::zero_u8_slice, Error, Result};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use std::cmp::{max, min};
 use std::convert::TryInto;
 use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::slice;
+use uuid::Uuid;
 
 const SECTOR_SIZE: u32 = 512;
 
@@ -17,6 +26,10 @@ pub struct VhdDisk {
     footer_encoded_valid: bool,
     dynamic_header: DynamicHeader,
     bat: Vec<u32>,
+    // parent of differencing disk, sectors not present in child are read from here
+    parent: Option<Box<dyn Disk>>,
+    // most recently used sector bitmap and BAT index of its block
+    bitmap_cache: Option<(usize, Vec<u8>)>,
 
     block_size: u32,
     bitmap_size: u32,
@@ -26,25 +39,67 @@ pub struct VhdDisk {
     free_data_block_offset: u64,
 }
 
+/// Space occupied by dynamic disk image
+#[derive(Debug, Copy, Clone)]
+pub struct SpaceUsage {
+    pub file_size: u64,
+    pub total_blocks: u32,
+    pub allocated_blocks: u32,
+    /// Space occupied by footers, dynamic header, BAT and parent locators
+    pub metadata_size: u64,
+    /// Space occupied by allocated blocks including their bitmaps
+    pub data_size: u64,
+}
+
+impl SpaceUsage {
+    /// Space used neither by metadata nor by allocated blocks
+    pub fn wasted(&self) -> u64 {
+        self.file_size
+            .saturating_sub(self.metadata_size + self.data_size)
+    }
+}
+
 impl VhdDisk {
-    pub fn open_with_argmap(backend: Box<dyn Backend>, _args: &ArgumentMap) -> Result<Self> {
-        Self::open(backend)
+    /// Parent of differencing disk is searched relative to "path" argument
+    pub fn open_with_argmap(backend: Box<dyn Backend>, args: &ArgumentMap) -> Result<Self> {
+        Self::open_ex(backend, args.get_str("path").map(Path::new), None, &[])
     }
 
-    pub fn open(mut backend: Box<dyn Backend>) -> Result<Self> {
-        let _file_size = backend.seek(SeekFrom::End(
-            -TryInto::<i64>::try_into(Footer::SIZE).unwrap(),
-        ))? as usize
-            + Footer::SIZE;
-        let mut reader = BufReader::with_capacity(65536, backend);
-        let mut footer_encoded = u8_array_uninitialized!(Footer::SIZE);
-        reader.read_exact(&mut footer_encoded)?;
-        let footer = Footer::decode(&footer_encoded)?;
-        // TODO: try footer backup in case of failure
+    pub fn open(backend: Box<dyn Backend>) -> Result<Self> {
+        Self::open_ex(backend, None, None, &[])
+    }
+
+    /// Opens differencing disk with explicitly provided parent,
+    /// parent locators stored in disk are ignored.
+    pub fn open_with_parent(backend: Box<dyn Backend>, parent: Box<dyn Disk>) -> Result<Self> {
+        Self::open_ex(backend, None, Some(parent), &[])
+    }
+
+    /// Opens parent of differencing disk, chain holds UUIDs of its children
+    pub(super) fn open_chain(
+        backend: Box<dyn Backend>,
+        path: &Path,
+        chain: &[Uuid],
+    ) -> Result<Self> {
+        Self::open_ex(backend, Some(path), None, chain)
+    }
+
+    fn open_ex(
+        mut backend: Box<dyn Backend>,
+        path: Option<&Path>,
+        parent: Option<Box<dyn Disk>>,
+        chain: &[Uuid],
+    ) -> Result<Self> {
+        let (_file_size, footer, footer_encoded) = read_footer(backend.as_mut())?;
         debug!("Footer:\n{}\n", footer);
 
-        // TODO: support other disk types
-        assert_eq!(footer.disk_type, VhdDiskType::Dynamic);
+        if footer.disk_type == VhdDiskType::Fixed {
+            return Err(Error::InvalidVhdFooter(Some(
+                "Fixed disks are not supported by VhdDisk".to_owned(),
+            )));
+        }
+
+        let mut reader = BufReader::with_capacity(65536, backend);
         reader.seek(SeekFrom::Start(footer.data_offset))?;
         let mut dynamic_header_encoded = u8_array_uninitialized!(DynamicHeader::SIZE);
         reader.read_exact(&mut dynamic_header_encoded[..])?;
@@ -53,15 +108,13 @@ impl VhdDisk {
         debug!("Dynamic header:\n{}", dynamic_header);
 
         let block_size = dynamic_header.block_size;
-        let bitmap_size = ((block_size / (8 * 512)) + 511) & !511;
+        let bitmap_size = bitmap_size(block_size);
         debug!("Bitmap size : {}", bitmap_size);
 
         let mut bat = Vec::with_capacity(dynamic_header.max_table_entries as usize);
         reader.seek(SeekFrom::Start(dynamic_header.bat_offset))?;
-        let mut free_data_block_offset = round_up!(
-            dynamic_header.bat_offset + dynamic_header.max_table_entries as u64 * 4,
-            SECTOR_SIZE as u64
-        );
+        let is_differencing = footer.disk_type == VhdDiskType::Differencing;
+        let mut free_data_block_offset = data_area_start(&dynamic_header, is_differencing);
         for i in 0..dynamic_header.max_table_entries as usize {
             let entry = reader.read_u32::<BigEndian>()?;
             bat.push(entry);
@@ -73,7 +126,6 @@ impl VhdDisk {
                 }
 
                 let offset = entry as u64 * SECTOR_SIZE as u64 + bitmap_size as u64;
-                // TODO: check if regions overlap
                 trace!(
                     "BAT#{:<8} => {:#x}   {{{:#x} - {:#x}}}",
                     i,
@@ -84,18 +136,42 @@ impl VhdDisk {
             }
         }
 
-        // FIXME: use chs for size calculation
-        let max_disk_size =
-            dynamic_header.max_table_entries as usize * dynamic_header.block_size as usize;
+        // writing into one of these would corrupt the other
+        for i in find_overlapping_blocks(&bat, block_size) {
+            warn!("BAT#{} overlaps another block, image should be repaired", i);
+        }
+
+        // BAT may cover more than disk size, last block is usually partially used
+        let max_disk_size = min(
+            footer.current_size,
+            dynamic_header.max_table_entries as u64 * dynamic_header.block_size as u64,
+        ) as usize;
         trace!("max_disk_size = {}", max_disk_size);
 
+        let mut backend = reader.into_inner();
+        let parent = if is_differencing {
+            Some(match (parent, path) {
+                (Some(parent), _) => parent,
+                (None, Some(path)) => {
+                    let mut chain = chain.to_vec();
+                    chain.push(footer.uuid);
+                    resolve_parent(backend.as_mut(), &dynamic_header, path, &chain)?
+                }
+                (None, None) => return Err(Error::VhdParentNotFound(dynamic_header.parent_name())),
+            })
+        } else {
+            None
+        };
+
         Ok(Self {
-            backend: reader.into_inner(),
+            backend,
             footer,
             footer_encoded,
             footer_encoded_valid: true,
             dynamic_header,
             bat,
+            parent,
+            bitmap_cache: None,
             block_size,
             bitmap_size,
             max_disk_size,
@@ -104,39 +180,30 @@ impl VhdDisk {
         })
     }
 
-    fn get_offset(&mut self, offset: u64, write: bool) -> io::Result<Option<Option<u64>>> {
+    /// Returns None if offset is beyond end of BAT, Some(None) if block is not allocated
+    /// or offset in file otherwise
+    fn get_offset(&self, offset: u64) -> Option<Option<u64>> {
         let bat_index = offset as usize / self.block_size as usize;
         let offset_in_block = offset % self.block_size as u64;
 
-        if let Some(e) = self.bat.get(bat_index).copied() {
-            if e == 0xFFFFFFFF {
-                Ok(Some(None))
+        self.bat.get(bat_index).map(|e| {
+            if *e == 0xFFFFFFFF {
+                None
             } else {
-                if write {
-                    self.backend
-                        .seek(SeekFrom::Start(e as u64 * SECTOR_SIZE as u64))?;
-                    let mut bitmap: Vec<u8> = Vec::new();
-                    bitmap.resize_with(self.bitmap_size as usize, || 0xff);
-                    self.backend.write_all(bitmap.as_slice())?;
-                }
-
-                Ok(Some(Some(
-                    e as u64 * SECTOR_SIZE as u64 + self.bitmap_size as u64 + offset_in_block,
-                )))
+                Some(*e as u64 * SECTOR_SIZE as u64 + self.bitmap_size as u64 + offset_in_block)
             }
-        } else {
-            Ok(None)
-        }
+        })
     }
 
-    pub fn create_dynamic(backend: Box<dyn Backend>, max_disk_size: usize) -> io::Result<Self> {
-        Self::create_dynamic_ex(backend, max_disk_size, 1024 * 1024 * 2)
+    pub fn create_dynamic(backend: Box<dyn Backend>, max_disk_size: usize) -> Result<Self> {
+        Self::create_dynamic_ex(backend, max_disk_size, &CreateOptions::default())
     }
     pub fn create_dynamic_ex(
-        mut backend: Box<dyn Backend>,
+        backend: Box<dyn Backend>,
         max_disk_size: usize,
-        block_size: usize,
-    ) -> io::Result<Self> {
+        options: &CreateOptions,
+    ) -> Result<Self> {
+        options.validate()?;
         let max_sectors = {
             let mut t = max_disk_size / 512;
             if max_disk_size % 512 != 0 {
@@ -144,20 +211,319 @@ impl VhdDisk {
             }
             t
         };
+        let block_size = options.block_size as usize;
 
-        // TODO: verify max_sectors and block_size
+        let footer = Footer::create(VhdDiskType::Dynamic, max_sectors, options);
+        let dynamic_header =
+            DynamicHeader::create_dynamic(Self::bat_size(max_sectors, block_size), block_size);
 
-        let footer = Footer::create(VhdDiskType::Dynamic, max_sectors);
-        let mut footer_encoded = u8_array_uninitialized!(Footer::SIZE);
-        footer.encode(&mut footer_encoded);
-        backend.write_all(&footer_encoded)?;
+        Ok(Self::create_sparse(
+            backend,
+            footer,
+            dynamic_header,
+            &[],
+            None,
+        )?)
+    }
+
+    /// Creates differencing disk backed by parent located at parent_path,
+    /// path is the location of the new disk and is used to build relative parent locator.
+    pub fn create_differencing(
+        backend: Box<dyn Backend>,
+        path: &Path,
+        parent_path: &Path,
+    ) -> Result<Self> {
+        Self::create_differencing_ex(backend, path, parent_path, &CreateOptions::default())
+    }
+    pub fn create_differencing_ex(
+        backend: Box<dyn Backend>,
+        path: &Path,
+        parent_path: &Path,
+        options: &CreateOptions,
+    ) -> Result<Self> {
+        options.validate()?;
+        let (parent_footer, parent) = open_parent(parent_path, None)?;
+        let locators = create_locators(path, parent_path)?;
+
+        let max_sectors: usize = (round_up!(parent_footer.current_size, SECTOR_SIZE as u64)
+            / SECTOR_SIZE as u64)
+            .try_into()
+            .unwrap();
+        let block_size = options.block_size as usize;
+        let footer = Footer::create(VhdDiskType::Differencing, max_sectors, options);
+
+        let mut dynamic_header =
+            DynamicHeader::create_dynamic(Self::bat_size(max_sectors, block_size), block_size);
+        dynamic_header.set_parent_unique_id(parent_footer.uuid);
+        dynamic_header.parent_timestamp = parent_footer.time_stamp;
+        dynamic_header.set_parent_name(
+            &parent_path
+                .file_name()
+                .map_or_else(String::new, |x| x.to_string_lossy().into_owned()),
+        );
+
+        Ok(Self::create_sparse(
+            backend,
+            footer,
+            dynamic_header,
+            &locators,
+            Some(parent),
+        )?)
+    }
+
+    pub fn footer(&self) -> &Footer {
+        &self.footer
+    }
+
+    pub fn dynamic_header(&self) -> &DynamicHeader {
+        &self.dynamic_header
+    }
+
+    /// Block allocation table, unallocated blocks are marked with 0xFFFFFFFF
+    pub fn bat(&self) -> &[u32] {
+        &self.bat
+    }
+
+    pub fn parent(&self) -> Option<&dyn Disk> {
+        self.parent.as_deref()
+    }
+
+    /// Computes how much of the image file is occupied by metadata and allocated blocks
+    pub fn space_usage(&mut self) -> io::Result<SpaceUsage> {
+        let file_size = self.backend.seek(SeekFrom::End(0))?;
+        let allocated_blocks = self.bat.iter().filter(|x| **x != 0xFFFFFFFF).count() as u32;
+
+        // footer, its copy, dynamic header, BAT and parent locators
+        let mut metadata_size = 2 * Footer::SIZE as u64
+            + DynamicHeader::SIZE as u64
+            + round_up!(self.bat.len() as u64 * 4, SECTOR_SIZE as u64);
+        if self.footer.disk_type == VhdDiskType::Differencing {
+            for entry in self
+                .dynamic_header
+                .parent_locator_entries()
+                .iter()
+                .filter(|x| x.is_used())
+            {
+                metadata_size += locator_space(entry);
+            }
+        }
+
+        Ok(SpaceUsage {
+            file_size,
+            total_blocks: self.bat.len() as u32,
+            allocated_blocks,
+            metadata_size,
+            data_size: allocated_blocks as u64 * (self.bitmap_size + self.block_size) as u64,
+        })
+    }
 
+    /// Unallocates blocks containing only zeros and moves remaining blocks down to fill
+    /// the gaps. Blocks of differencing disk are unallocated only when none of their
+    /// sectors are present, zeros written to differencing disk hide parent data.
+    /// Returns new file size, backend can't be shrunk so the file has to be truncated
+    /// to this size by caller.
+    pub fn compact(&mut self) -> io::Result<u64> {
+        let span = (self.bitmap_size + self.block_size) as u64;
+        let mut buf = vec![0u8; span as usize];
+        let mut blocks = Vec::new();
+
+        for i in 0..self.bat.len() {
+            if self.bat[i] == 0xFFFFFFFF {
+                continue;
+            }
+
+            let offset = self.bat[i] as u64 * SECTOR_SIZE as u64;
+            self.backend.seek(SeekFrom::Start(offset))?;
+            self.backend.read_exact(&mut buf)?;
+
+            let (bitmap, data) = buf.split_at(self.bitmap_size as usize);
+            let sectors = self.block_size as usize / SECTOR_SIZE as usize;
+            let empty = if self.parent.is_some() {
+                (0..sectors).all(|x| !bitmap_test(bitmap, x))
+            } else {
+                data.chunks(SECTOR_SIZE as usize)
+                    .enumerate()
+                    .all(|(x, sector)| !bitmap_test(bitmap, x) || sector.iter().all(|x| *x == 0))
+            };
+
+            if empty {
+                debug!("unallocating empty block BAT#{}", i);
+                self.bat[i] = 0xFFFFFFFF;
+                self.write_bat_entry(i)?;
+            } else {
+                blocks.push((offset, i));
+            }
+        }
+
+        // blocks are moved in file order so they never overwrite each other
+        blocks.sort_unstable();
+        let mut next = data_area_start(
+            &self.dynamic_header,
+            self.footer.disk_type == VhdDiskType::Differencing,
+        );
+        for (offset, i) in blocks {
+            let new_offset = if offset > next {
+                debug!("moving BAT#{} from {:#x} to {:#x}", i, offset, next);
+                self.backend.seek(SeekFrom::Start(offset))?;
+                self.backend.read_exact(&mut buf)?;
+                self.backend.seek(SeekFrom::Start(next))?;
+                self.backend.write_all(&buf)?;
+
+                self.bat[i] = (next / SECTOR_SIZE as u64) as u32;
+                self.write_bat_entry(i)?;
+                next
+            } else {
+                offset
+            };
+            next = max(next, new_offset + span);
+        }
+
+        self.bitmap_cache = None;
+        self.free_data_block_offset = next;
+        self.backend.seek(SeekFrom::Start(next))?;
+        self.rewrite_footer()?;
+        self.backend.flush()?;
+
+        Ok(next + Footer::SIZE as u64)
+    }
+
+    /// Changes virtual size of dynamic disk. BAT is grown as needed, data blocks
+    /// occupying space needed by BAT are moved to end of file. Shrinking is refused
+    /// if it would drop allocated blocks. Differencing disks can't be resized.
+    pub fn resize(&mut self, new_size: u64) -> io::Result<()> {
+        if self.parent.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "differencing disks can't be resized",
+            ));
+        }
+
+        let new_size = round_up!(new_size, SECTOR_SIZE as u64);
+        let entries = Self::bat_size(
+            (new_size / SECTOR_SIZE as u64) as usize,
+            self.block_size as usize,
+        );
+        if let Some(i) = self.bat[min(entries, self.bat.len())..]
+            .iter()
+            .position(|x| *x != 0xFFFFFFFF)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "block {} is allocated and would be dropped by shrinking",
+                    entries + i
+                ),
+            ));
+        }
+
+        let span = (self.bitmap_size + self.block_size) as u64;
+        let bat_offset = self.dynamic_header.bat_offset;
+        let bat_end = round_up!(bat_offset + entries as u64 * 4, SECTOR_SIZE as u64);
+
+        // blocks in the way of grown BAT are moved to end of file
+        self.free_data_block_offset = max(self.free_data_block_offset, bat_end);
+        let mut buf = vec![0u8; span as usize];
+        for i in 0..self.bat.len() {
+            let offset = self.bat[i] as u64 * SECTOR_SIZE as u64;
+            if self.bat[i] == 0xFFFFFFFF || offset >= bat_end {
+                continue;
+            }
+
+            debug!(
+                "moving BAT#{} from {:#x} to {:#x}",
+                i, offset, self.free_data_block_offset
+            );
+            self.backend.seek(SeekFrom::Start(offset))?;
+            self.backend.read_exact(&mut buf)?;
+            self.backend
+                .seek(SeekFrom::Start(self.free_data_block_offset))?;
+            self.backend.write_all(&buf)?;
+
+            self.bat[i] = (self.free_data_block_offset / SECTOR_SIZE as u64) as u32;
+            self.write_bat_entry(i)?;
+            self.free_data_block_offset += span;
+        }
+
+        self.bat.resize(entries, 0xFFFFFFFF);
+        let mut bat_encoded = vec![0xffu8; (bat_end - bat_offset) as usize];
+        for (entry, x) in self.bat.iter().zip(bat_encoded.chunks_mut(4)) {
+            BigEndian::write_u32(x, *entry);
+        }
+        self.backend.seek(SeekFrom::Start(bat_offset))?;
+        self.backend.write_all(&bat_encoded)?;
+
+        self.dynamic_header.max_table_entries = entries as u32;
+        let mut dynamic_header_encoded = [0u8; DynamicHeader::SIZE];
+        self.dynamic_header.encode(&mut dynamic_header_encoded);
+        self.backend
+            .seek(SeekFrom::Start(self.footer.data_offset))?;
+        self.backend.write_all(&dynamic_header_encoded)?;
+
+        self.footer.set_current_size(new_size);
+        self.footer_encoded_valid = false;
+        self.backend.seek(SeekFrom::Start(0))?;
+        self.rewrite_footer()?;
+        self.backend
+            .seek(SeekFrom::Start(self.free_data_block_offset))?;
+        self.rewrite_footer()?;
+        self.backend.flush()?;
+
+        self.max_disk_size = new_size as usize;
+        self.bitmap_cache = None;
+        self.cursor = min(self.cursor, new_size);
+
+        Ok(())
+    }
+
+    fn write_bat_entry(&mut self, index: usize) -> io::Result<()> {
+        self.backend.seek(SeekFrom::Start(
+            self.dynamic_header.bat_offset + 4 * index as u64,
+        ))?;
+        self.backend.write_u32::<BigEndian>(self.bat[index])
+    }
+
+    fn bat_size(max_sectors: usize, block_size: usize) -> usize {
         let mut bat_size = max_sectors / (block_size / SECTOR_SIZE as usize);
         if max_sectors % (block_size / SECTOR_SIZE as usize) != 0 {
             bat_size += 1;
         }
+        bat_size
+    }
+
+    fn create_sparse(
+        mut backend: Box<dyn Backend>,
+        footer: Footer,
+        mut dynamic_header: DynamicHeader,
+        locators: &[(u32, Vec<u8>)],
+        parent: Option<Box<dyn Disk>>,
+    ) -> io::Result<Self> {
+        let mut footer_encoded = u8_array_uninitialized!(Footer::SIZE);
+        footer.encode(&mut footer_encoded);
+        backend.write_all(&footer_encoded)?;
+
+        let bat_size = dynamic_header.max_table_entries as usize;
+        let block_size = dynamic_header.block_size;
+
+        // parent locator data is stored between BAT and first data block
+        let mut free_data_block_offset = round_up!(
+            dynamic_header.bat_offset + bat_size as u64 * 4,
+            SECTOR_SIZE as u64
+        );
+        for (i, (platform_code, data)) in locators.iter().enumerate() {
+            let space = round_up!(data.len() as u64, SECTOR_SIZE as u64);
+            dynamic_header.set_parent_locator_entry(
+                i,
+                &ParentLocatorEntry {
+                    platform_code: *platform_code,
+                    platform_data_space: space.try_into().unwrap(),
+                    platform_data_length: data.len().try_into().unwrap(),
+                    reserved: 0,
+                    platform_data_offset: free_data_block_offset,
+                },
+            );
+            free_data_block_offset += space;
+        }
 
-        let dynamic_header = DynamicHeader::create_dynamic(bat_size, block_size);
         let mut dynamic_header_encoded = u8_array_uninitialized!(DynamicHeader::SIZE);
         dynamic_header.encode(&mut dynamic_header_encoded);
         backend.write_all(&dynamic_header_encoded)?;
@@ -177,23 +543,28 @@ impl VhdDisk {
             }
         }
 
-        backend.write_all(&footer_encoded)?;
+        for (_, data) in locators.iter() {
+            backend.write_all(data.as_slice())?;
+            if data.len() % SECTOR_SIZE as usize != 0 {
+                let padding_len = ((data.len() + 511) & !511) - data.len();
+                backend.write_all(vec![0u8; padding_len].as_slice())?;
+            }
+        }
 
-        let free_data_block_offset = round_up!(
-            dynamic_header.bat_offset + bat.len() as u64 * 4,
-            SECTOR_SIZE as u64
-        );
+        backend.write_all(&footer_encoded)?;
 
         Ok(Self {
             backend,
+            max_disk_size: footer.current_size as usize,
             footer,
             footer_encoded,
             footer_encoded_valid: true,
             dynamic_header,
             bat,
-            block_size: block_size as u32,
-            bitmap_size: ((block_size as u32 / (8 * 512)) + 511) & !511,
-            max_disk_size: max_sectors * 512,
+            parent,
+            bitmap_cache: None,
+            block_size,
+            bitmap_size: bitmap_size(block_size),
             cursor: 0,
             free_data_block_offset,
         })
@@ -208,24 +579,23 @@ impl VhdDisk {
 
         debug!("allocating block => BAT#{} = {}", bat_index, bat_value);
 
-        let mut bitmap: Vec<u8> = Vec::new();
-        bitmap.resize_with(self.bitmap_size as usize, || 0xff);
+        // sectors are marked present as they are written, until then they read
+        // as zeros or from parent
+        let bitmap = vec![0u8; self.bitmap_size as usize];
         self.backend
             .seek(SeekFrom::Start(self.free_data_block_offset))?;
         self.backend.write_all(bitmap.as_slice())?;
+        self.bitmap_cache = Some((bat_index, bitmap));
 
         let next_offset =
             self.free_data_block_offset + self.block_size as u64 + self.bitmap_size as u64;
         self.backend.seek(SeekFrom::Start(next_offset))?;
         self.rewrite_footer()?;
 
-        self.backend.seek(SeekFrom::Start(
-            self.dynamic_header.bat_offset + 4 * bat_index as u64,
-        ))?;
-        self.backend.write_u32::<BigEndian>(bat_value)?;
+        self.write_bat_entry(bat_index)?;
         self.free_data_block_offset = next_offset;
 
-        Ok(self.get_offset(offset, false)?.unwrap().unwrap())
+        Ok(self.get_offset(offset).unwrap().unwrap())
     }
 
     fn rewrite_footer(&mut self) -> io::Result<()> {
@@ -236,6 +606,192 @@ impl VhdDisk {
 
         self.backend.write_all(&self.footer_encoded)
     }
+
+    /// Takes sector bitmap of block containing offset out of cache, on cache miss
+    /// it's read from file. Block must be allocated.
+    fn take_bitmap(&mut self, offset: u64) -> io::Result<Vec<u8>> {
+        let bat_index = offset as usize / self.block_size as usize;
+        if let Some((i, bitmap)) = self.bitmap_cache.take() {
+            if i == bat_index {
+                return Ok(bitmap);
+            }
+        }
+
+        let e = self.bat[bat_index];
+        debug_assert_ne!(e, 0xFFFFFFFF);
+
+        let mut bitmap = vec![0u8; self.bitmap_size as usize];
+        self.backend
+            .seek(SeekFrom::Start(e as u64 * SECTOR_SIZE as u64))?;
+        self.backend.read_exact(bitmap.as_mut_slice())?;
+
+        Ok(bitmap)
+    }
+
+    fn write_bitmap(&mut self, offset: u64, bitmap: &[u8]) -> io::Result<()> {
+        let e = self.bat[offset as usize / self.block_size as usize];
+        debug_assert_ne!(e, 0xFFFFFFFF);
+
+        self.backend
+            .seek(SeekFrom::Start(e as u64 * SECTOR_SIZE as u64))?;
+        self.backend.write_all(bitmap)
+    }
+
+    /// Reads sectors not present in this disk, these come from parent or are zero.
+    /// Area beyond end of parent reads as zeros.
+    fn read_unallocated(&mut self, position: u64, buf: &mut [u8]) -> io::Result<()> {
+        let n = match self.parent.as_mut() {
+            Some(parent) => {
+                let available = parent.disk_size().saturating_sub(position);
+                let n = min(available, buf.len() as u64) as usize;
+                if n > 0 {
+                    parent.seek(SeekFrom::Start(position))?;
+                    parent.read_exact(&mut buf[..n])?;
+                }
+                n
+            }
+            None => 0,
+        };
+        zero_u8_slice(&mut buf[n..]);
+
+        Ok(())
+    }
+
+    /// Reads data at cursor, buf must not cross block boundary
+    fn read_block(&mut self, offset_in_file: Option<u64>, buf: &mut [u8]) -> io::Result<()> {
+        let offset_in_file = match offset_in_file {
+            Some(x) => x,
+            None => return self.read_unallocated(self.cursor, buf),
+        };
+
+        let offset_in_block = (self.cursor % self.block_size as u64) as usize;
+        let bitmap = self.take_bitmap(self.cursor)?;
+
+        let mut done = 0usize;
+        while done < buf.len() {
+            let sector = (offset_in_block + done) / SECTOR_SIZE as usize;
+            let present = bitmap_test(&bitmap, sector);
+
+            // read all following sectors with the same state at once
+            let mut end = min(
+                buf.len(),
+                (sector + 1) * SECTOR_SIZE as usize - offset_in_block,
+            );
+            while end < buf.len()
+                && bitmap_test(&bitmap, (offset_in_block + end) / SECTOR_SIZE as usize) == present
+            {
+                end = min(buf.len(), end + SECTOR_SIZE as usize);
+            }
+
+            if present {
+                self.backend
+                    .seek(SeekFrom::Start(offset_in_file + done as u64))?;
+                self.backend.read_exact(&mut buf[done..end])?;
+            } else {
+                self.read_unallocated(self.cursor + done as u64, &mut buf[done..end])?;
+            }
+
+            done = end;
+        }
+
+        self.bitmap_cache = Some((self.cursor as usize / self.block_size as usize, bitmap));
+        Ok(())
+    }
+
+    /// Writes data at cursor allocating block if needed, buf must not cross block boundary
+    fn write_block(&mut self, offset_in_file: Option<u64>, buf: &[u8]) -> io::Result<()> {
+        #[allow(clippy::redundant_closure)]
+        let offset_in_file =
+            offset_in_file.map_or_else(|| self.alloc_block(self.cursor), |x| Ok(x))?;
+
+        let offset_in_block = (self.cursor % self.block_size as u64) as usize;
+        let block_start_in_file = offset_in_file - offset_in_block as u64;
+        let block_start = self.cursor - offset_in_block as u64;
+        let mut bitmap = self.take_bitmap(self.cursor)?;
+
+        let first_sector = offset_in_block / SECTOR_SIZE as usize;
+        let last_sector = (offset_in_block + buf.len() - 1) / SECTOR_SIZE as usize;
+
+        // sectors partially covered by this write must be filled from parent or with zeros first
+        let mut sector_buf = [0u8; SECTOR_SIZE as usize];
+        for sector in [first_sector, last_sector].iter().copied() {
+            let sector_start = sector * SECTOR_SIZE as usize;
+            let fully_covered = sector_start >= offset_in_block
+                && sector_start + SECTOR_SIZE as usize <= offset_in_block + buf.len();
+
+            if !fully_covered && !bitmap_test(&bitmap, sector) {
+                self.read_unallocated(block_start + sector_start as u64, &mut sector_buf)?;
+                self.backend
+                    .seek(SeekFrom::Start(block_start_in_file + sector_start as u64))?;
+                self.backend.write_all(&sector_buf)?;
+            }
+        }
+
+        self.backend.seek(SeekFrom::Start(offset_in_file))?;
+        self.backend.write_all(buf)?;
+
+        // bitmap is written only when it changes
+        let mut changed = false;
+        for sector in first_sector..=last_sector {
+            if !bitmap_test(&bitmap, sector) {
+                bitmap_set(&mut bitmap, sector);
+                changed = true;
+            }
+        }
+        if changed {
+            self.write_bitmap(self.cursor, &bitmap)?;
+        }
+
+        self.bitmap_cache = Some((self.cursor as usize / self.block_size as usize, bitmap));
+        Ok(())
+    }
+}
+
+/// Returns offset where data blocks can start, that is after BAT and parent locators
+fn data_area_start(dynamic_header: &DynamicHeader, is_differencing: bool) -> u64 {
+    let mut start = round_up!(
+        dynamic_header.bat_offset + dynamic_header.max_table_entries as u64 * 4,
+        SECTOR_SIZE as u64
+    );
+
+    // parent locator data must not be overwritten by new blocks
+    if is_differencing {
+        for entry in dynamic_header
+            .parent_locator_entries()
+            .iter()
+            .filter(|x| x.is_used())
+        {
+            start = max(start, entry.platform_data_offset + locator_space(entry));
+        }
+    }
+
+    start
+}
+
+/// Returns space reserved for parent locator data
+pub(super) fn locator_space(entry: &ParentLocatorEntry) -> u64 {
+    // some implementations store data space in sectors, others in bytes
+    let space = if entry.platform_data_space < SECTOR_SIZE {
+        entry.platform_data_space as u64 * SECTOR_SIZE as u64
+    } else {
+        entry.platform_data_space as u64
+    };
+
+    max(
+        space,
+        round_up!(entry.platform_data_length as u64, SECTOR_SIZE as u64),
+    )
+}
+
+// Sector bitmaps are stored MSB first, bit 7 of first byte describes first sector of block
+#[inline]
+fn bitmap_test(bitmap: &[u8], sector: usize) -> bool {
+    bitmap[sector / 8] & (0x80 >> (sector % 8)) != 0
+}
+
+#[inline]
+fn bitmap_set(bitmap: &mut [u8], sector: usize) {
+    bitmap[sector / 8] |= 0x80 >> (sector % 8);
 }
 
 impl Disk for VhdDisk {
@@ -258,19 +814,15 @@ impl Disk for VhdDisk {
 
 impl Seek for VhdDisk {
     fn seek(&mut self, s: SeekFrom) -> io::Result<u64> {
-        match s {
-            SeekFrom::Start(x) => self.cursor = x,
-            SeekFrom::Current(x) => self.cursor = self.cursor.wrapping_add(x as u64),
-            _ => unimplemented!(),
-        }
-
+        self.cursor = seek_position(self.cursor, self.disk_size(), s)?;
         Ok(self.cursor)
     }
 }
 
 impl Read for VhdDisk {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut left = buf.len();
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
         let mut total_read = 0usize;
         while left > 0 {
             let n = min(
@@ -278,14 +830,8 @@ impl Read for VhdDisk {
                 self.block_size as usize - (self.cursor as usize % self.block_size as usize),
             );
 
-            if let Some(offset_in_file) = self.get_offset(self.cursor, false)? {
-                if let Some(offset_in_file) = offset_in_file {
-                    self.backend.seek(SeekFrom::Start(offset_in_file))?;
-                    self.backend
-                        .read_exact(&mut buf[total_read..total_read + n])?;
-                } else {
-                    zero_u8_slice(&mut buf[total_read..total_read + n]);
-                }
+            if let Some(offset_in_file) = self.get_offset(self.cursor) {
+                self.read_block(offset_in_file, &mut buf[total_read..total_read + n])?;
             } else {
                 break;
             }
@@ -301,7 +847,8 @@ impl Read for VhdDisk {
 
 impl Write for VhdDisk {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut left = buf.len();
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
         let mut total_written = 0usize;
         while left > 0 {
             let n = min(
@@ -318,21 +865,14 @@ impl Write for VhdDisk {
                 }
             }
 
-            if let Some(offset_in_file) = self.get_offset(self.cursor, true)? {
-                if !is_zero {
-                    #[allow(clippy::redundant_closure)]
-                    let offset_in_file =
-                        offset_in_file.map_or_else(|| self.alloc_block(self.cursor), |x| Ok(x))?;
-                    self.backend.seek(SeekFrom::Start(offset_in_file))?;
-                    self.backend
-                        .write_all(&buf[total_written..total_written + n])?;
-                } else if let Some(offset_in_file) = offset_in_file {
-                    self.backend.seek(SeekFrom::Start(offset_in_file))?;
-                    self.backend
-                        .write_all(&buf[total_written..total_written + n])?;
+            match self.get_offset(self.cursor) {
+                None => break,
+                // zeros have to be stored in differencing disks, otherwise data
+                // from parent would show through
+                Some(None) if is_zero && self.parent.is_none() => (),
+                Some(offset_in_file) => {
+                    self.write_block(offset_in_file, &buf[total_written..total_written + n])?
                 }
-            } else {
-                break;
             }
 
             left -= n;
@@ -346,3 +886,297 @@ impl Write for VhdDisk {
         self.backend.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::VhdDisk;
+    use crate::disk::tests::MemoryBackend;
+    use crate::disk::vhd::{CreateOptions, FixedVhdDisk};
+    use crate::disk::{Argument, ArgumentMap, Disk, FileBackend};
+    use crate::Error;
+    use byteorder::{BigEndian, ByteOrder};
+    use std::fs::{self, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_differencing() {
+        crate::tests_init();
+
+        let dir = std::env::temp_dir().join(format!("diskutil-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let parent_path = dir.join("parent.vhd");
+        let child_path = dir.join("child.vhd");
+
+        let create = |path| {
+            FileBackend::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .unwrap(),
+            )
+            .unwrap()
+        };
+
+        {
+            let mut parent =
+                VhdDisk::create_dynamic(create(&parent_path), 8 * 1024 * 1024).unwrap();
+            parent.write_all(&[0xaa; 4096]).unwrap();
+        }
+
+        {
+            let mut child =
+                VhdDisk::create_differencing(create(&child_path), &child_path, &parent_path)
+                    .unwrap();
+            child.seek(SeekFrom::Start(1000)).unwrap();
+            child.write_all(&[0x55; 100]).unwrap();
+            // zeros must shadow parent data
+            child.seek(SeekFrom::Start(2048)).unwrap();
+            child.write_all(&[0; 512]).unwrap();
+        }
+
+        let mut args = ArgumentMap::default();
+        args.insert(
+            "path",
+            Argument::String(child_path.to_string_lossy().into_owned()),
+        );
+        let mut child = VhdDisk::open_with_argmap(
+            FileBackend::new(OpenOptions::new().read(true).open(&child_path).unwrap()).unwrap(),
+            &args,
+        )
+        .unwrap();
+
+        let mut buf = vec![0u8; 8192];
+        child.read_exact(&mut buf).unwrap();
+        assert!(buf[..1000].iter().all(|x| *x == 0xaa));
+        assert!(buf[1000..1100].iter().all(|x| *x == 0x55));
+        assert!(buf[1100..2048].iter().all(|x| *x == 0xaa));
+        assert!(buf[2048..2560].iter().all(|x| *x == 0));
+        assert!(buf[2560..4096].iter().all(|x| *x == 0xaa));
+        assert!(buf[4096..].iter().all(|x| *x == 0));
+
+        let mut parent = VhdDisk::open(
+            FileBackend::new(OpenOptions::new().read(true).open(&parent_path).unwrap()).unwrap(),
+        )
+        .unwrap();
+        parent.read_exact(&mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|x| *x == 0xaa));
+
+        drop(child);
+        drop(parent);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parent_loop() {
+        crate::tests_init();
+
+        let dir = std::env::temp_dir().join(format!("diskutil-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let base_path = dir.join("base.vhd");
+        let child_path = dir.join("child.vhd");
+        let loop_path = dir.join("loop.vhd");
+
+        let create = |path| {
+            FileBackend::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let base = VhdDisk::create_dynamic(create(&base_path), 1024 * 1024).unwrap();
+        let base_id = base.footer.uuid;
+        drop(base);
+        drop(VhdDisk::create_differencing(create(&child_path), &child_path, &base_path).unwrap());
+
+        // replace base with a child of child that has the same UUID
+        let options = CreateOptions {
+            uuid: Some(base_id),
+            ..CreateOptions::default()
+        };
+        drop(
+            VhdDisk::create_differencing_ex(create(&loop_path), &loop_path, &child_path, &options)
+                .unwrap(),
+        );
+        fs::rename(&loop_path, &base_path).unwrap();
+
+        let mut args = ArgumentMap::default();
+        args.insert(
+            "path",
+            Argument::String(child_path.to_string_lossy().into_owned()),
+        );
+        let result = VhdDisk::open_with_argmap(
+            FileBackend::new(OpenOptions::new().read(true).open(&child_path).unwrap()).unwrap(),
+            &args,
+        );
+        assert!(matches!(result, Err(Error::InvalidVhdFooter(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sector_bitmap() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        let mut disk = VhdDisk::create_dynamic(Box::new(backend), 8 * 1024 * 1024).unwrap();
+        disk.seek(SeekFrom::Start(1000)).unwrap();
+        disk.write_all(&[0x55; 100]).unwrap();
+        disk.seek(SeekFrom::Start(4096)).unwrap();
+        disk.write_all(&[0xaa; 1024]).unwrap();
+        drop(disk);
+
+        // only sectors 1, 2, 8 and 9 are present
+        let block = BigEndian::read_u32(&storage.borrow()[1536..]) as usize * 512;
+        assert_eq!(&storage.borrow()[block..block + 3], &[0x60, 0xc0, 0]);
+
+        // data of sectors not marked present must be ignored
+        storage.borrow_mut()[block + 512 + 2048..block + 512 + 2560].fill(0xee);
+
+        let mut disk = VhdDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        let mut buf = vec![0u8; 8192];
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..1000].iter().all(|x| *x == 0));
+        assert!(buf[1000..1100].iter().all(|x| *x == 0x55));
+        assert!(buf[1100..4096].iter().all(|x| *x == 0));
+        assert!(buf[4096..5120].iter().all(|x| *x == 0xaa));
+        assert!(buf[5120..].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn test_compact() {
+        crate::tests_init();
+
+        const BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        let mut disk = VhdDisk::create_dynamic(Box::new(backend), 8 * 1024 * 1024).unwrap();
+        for block in 0..3 {
+            disk.seek(SeekFrom::Start(block * BLOCK_SIZE + 512))
+                .unwrap();
+            disk.write_all(&[0x11 * (block as u8 + 1); 512]).unwrap();
+        }
+        // second block contains only zeros now
+        disk.seek(SeekFrom::Start(BLOCK_SIZE + 512)).unwrap();
+        disk.write_all(&[0; 512]).unwrap();
+        let size = storage.borrow().len() as u64;
+
+        let new_size = disk.compact().unwrap();
+        assert_eq!(new_size, size - BLOCK_SIZE - 512);
+        assert_eq!(disk.bat()[1], 0xFFFFFFFF);
+        storage.borrow_mut().truncate(new_size as usize);
+        drop(disk);
+
+        let mut disk = VhdDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        let mut buf = vec![0u8; 3 * BLOCK_SIZE as usize];
+        disk.read_exact(&mut buf).unwrap();
+        for (block, fill) in [(0, 0x11), (1, 0), (2, 0x33)].iter().copied() {
+            let start = (block * BLOCK_SIZE) as usize;
+            assert!(buf[start..start + 512].iter().all(|x| *x == 0));
+            assert!(buf[start + 512..start + 1024].iter().all(|x| *x == fill));
+            assert!(buf[start + 1024..start + BLOCK_SIZE as usize]
+                .iter()
+                .all(|x| *x == 0));
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        crate::tests_init();
+
+        const BLOCK_SIZE: usize = 2 * 1024 * 1024;
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        let mut disk = VhdDisk::create_dynamic(Box::new(backend), 2 * BLOCK_SIZE).unwrap();
+        disk.write_all(&vec![0x11; 2 * BLOCK_SIZE]).unwrap();
+
+        // BAT of 512 entries doesn't fit in front of the first block
+        disk.resize(1024 * 1024 * 1024).unwrap();
+        assert_eq!(disk.disk_size(), 1024 * 1024 * 1024);
+        assert_eq!(disk.bat().len(), 512);
+        assert!(disk.bat()[0] as u64 * 512 >= 1536 + 2048);
+        disk.seek(SeekFrom::Start(600 * 1024 * 1024)).unwrap();
+        disk.write_all(&[0x22; 512]).unwrap();
+
+        assert!(disk.resize(BLOCK_SIZE as u64).is_err());
+        disk.resize(700 * 1024 * 1024).unwrap();
+        drop(disk);
+
+        let mut disk = VhdDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        assert_eq!(disk.disk_size(), 700 * 1024 * 1024);
+        assert_eq!(disk.footer().chs(), (1422, 16, 63));
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|x| *x == 0x11));
+        disk.seek(SeekFrom::Start(600 * 1024 * 1024)).unwrap();
+        disk.read_exact(&mut buf[..1024]).unwrap();
+        assert!(buf[..512].iter().all(|x| *x == 0x22));
+        assert!(buf[512..1024].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn test_create_options() {
+        crate::tests_init();
+
+        let options = CreateOptions {
+            block_size: 512 * 1024,
+            uuid: Some(Uuid::from_u128(0x1234)),
+            creator_app: *b"test",
+            creator_version: 0x00020003,
+            creator_host_os: *b"Mac ",
+            timestamp: Some(
+                chrono::TimeZone::timestamp_opt(&chrono::Utc, 1_600_000_000, 0).unwrap(),
+            ),
+            geometry: Some((100, 4, 17)),
+        };
+
+        let create = || {
+            let backend = MemoryBackend::new();
+            let storage = backend.storage();
+            let disk =
+                VhdDisk::create_dynamic_ex(Box::new(backend), 4 * 1024 * 1024, &options).unwrap();
+            (disk, storage)
+        };
+
+        let (disk, storage) = create();
+        assert_eq!(disk.dynamic_header().block_size, 512 * 1024);
+        assert_eq!(disk.bat().len(), 8);
+        let footer = disk.footer();
+        assert_eq!(footer.uuid, Uuid::from_u128(0x1234));
+        assert_eq!(&footer.creator_app, b"test");
+        assert_eq!(footer.creator_version, 0x00020003);
+        assert_eq!(&footer.creator_host_os, b"Mac ");
+        assert_eq!(footer.creation_time().timestamp(), 1_600_000_000);
+        assert_eq!(footer.chs(), (100, 4, 17));
+        drop(disk);
+
+        // same options produce identical images
+        let (_, storage2) = create();
+        assert_eq!(*storage.borrow(), *storage2.borrow());
+
+        let fixed =
+            FixedVhdDisk::create_ex(Box::new(MemoryBackend::new()), 1024 * 1024, &options).unwrap();
+        assert_eq!(fixed.footer().uuid, Uuid::from_u128(0x1234));
+
+        for block_size in [0, 256, 3 * 1024 * 1024, 512 * 1024 * 1024].iter().copied() {
+            let options = CreateOptions {
+                block_size,
+                ..Default::default()
+            };
+            assert!(VhdDisk::create_dynamic_ex(
+                Box::new(MemoryBackend::new()),
+                1024 * 1024,
+                &options
+            )
+            .is_err());
+        }
+    }
+}
@@ -2,6 +2,54 @@ use crate::{u8_array_uninitialized, Error, Result};
 use std::convert::TryInto;
 use std::fmt;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use uuid::Uuid;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ParentLocatorEntry {
+    pub platform_code: u32,
+    pub platform_data_space: u32,
+    pub platform_data_length: u32,
+    pub reserved: u32,
+    pub platform_data_offset: u64,
+}
+
+impl ParentLocatorEntry {
+    pub const SIZE: usize = 24;
+
+    pub const PLATFORM_CODE_NONE: u32 = 0;
+    /// Windows relative path (UTF-16LE)
+    pub const PLATFORM_CODE_W2RU: u32 = u32::from_be_bytes(*b"W2ru");
+    /// Windows absolute path (UTF-16LE)
+    pub const PLATFORM_CODE_W2KU: u32 = u32::from_be_bytes(*b"W2ku");
+    /// Mac OS alias stored as blob
+    pub const PLATFORM_CODE_MAC: u32 = u32::from_be_bytes(*b"Mac ");
+    /// Mac OS X file URL (UTF-8)
+    pub const PLATFORM_CODE_MACX: u32 = u32::from_be_bytes(*b"MacX");
+
+    pub fn decode(buffer: &[u8; Self::SIZE]) -> Self {
+        Self {
+            platform_code: u32::from_be_bytes(buffer[0..4].try_into().unwrap()),
+            platform_data_space: u32::from_be_bytes(buffer[4..8].try_into().unwrap()),
+            platform_data_length: u32::from_be_bytes(buffer[8..12].try_into().unwrap()),
+            reserved: u32::from_be_bytes(buffer[12..16].try_into().unwrap()),
+            platform_data_offset: u64::from_be_bytes(buffer[16..24].try_into().unwrap()),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buffer = [0u8; Self::SIZE];
+        buffer[0..4].copy_from_slice(&self.platform_code.to_be_bytes());
+        buffer[4..8].copy_from_slice(&self.platform_data_space.to_be_bytes());
+        buffer[8..12].copy_from_slice(&self.platform_data_length.to_be_bytes());
+        buffer[12..16].copy_from_slice(&self.reserved.to_be_bytes());
+        buffer[16..24].copy_from_slice(&self.platform_data_offset.to_be_bytes());
+        buffer
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.platform_code != Self::PLATFORM_CODE_NONE
+    }
+}
 
 pub struct DynamicHeader {
     pub data_offset: u64,
@@ -144,6 +192,63 @@ impl DynamicHeader {
         }
     }
 
+    pub fn parent_unique_id(&self) -> Uuid {
+        Uuid::from_bytes(self.parent_unique_id)
+    }
+
+    pub fn set_parent_unique_id(&mut self, uuid: Uuid) {
+        self.parent_unique_id = *uuid.as_bytes();
+    }
+
+    /// Returns parent file name, stored as UTF-16BE
+    pub fn parent_name(&self) -> String {
+        let it = self
+            .parent_unicode_name
+            .chunks_exact(2)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]))
+            .take_while(|x| *x != 0);
+
+        ::std::char::decode_utf16(it)
+            .map(|x| x.unwrap_or(::std::char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    /// Sets parent file name, names longer than 256 UTF-16 characters are truncated
+    pub fn set_parent_name(&mut self, name: &str) {
+        self.parent_unicode_name = [0; 512];
+        for (i, x) in name.encode_utf16().take(256).enumerate() {
+            self.parent_unicode_name[i * 2..i * 2 + 2].copy_from_slice(&x.to_be_bytes());
+        }
+    }
+
+    pub fn parent_locator_entries(&self) -> [ParentLocatorEntry; 8] {
+        [
+            ParentLocatorEntry::decode(&self.parent_locator_entry_1),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_2),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_3),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_4),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_5),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_6),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_7),
+            ParentLocatorEntry::decode(&self.parent_locator_entry_8),
+        ]
+    }
+
+    pub fn set_parent_locator_entry(&mut self, index: usize, entry: &ParentLocatorEntry) {
+        let e = match index {
+            0 => &mut self.parent_locator_entry_1,
+            1 => &mut self.parent_locator_entry_2,
+            2 => &mut self.parent_locator_entry_3,
+            3 => &mut self.parent_locator_entry_4,
+            4 => &mut self.parent_locator_entry_5,
+            5 => &mut self.parent_locator_entry_6,
+            6 => &mut self.parent_locator_entry_7,
+            7 => &mut self.parent_locator_entry_8,
+            _ => panic!("parent locator index out of range"),
+        };
+        *e = entry.encode();
+    }
+
     pub fn encode(&self, buf: &mut [u8]) {
         let mut cursor = Cursor::new(buf);
         let mut checksum: u32 = 0;
@@ -216,7 +321,7 @@ Block Size            : 0x{:08X}",
 
 #[cfg(test)]
 mod tests {
-    use super::DynamicHeader;
+    use super::{DynamicHeader, ParentLocatorEntry};
     static HEADER: [u8; DynamicHeader::SIZE] = [
         0x63, 0x78, 0x73, 0x70, 0x61, 0x72, 0x73, 0x65, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
         0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
@@ -313,6 +418,36 @@ mod tests {
         assert_eq!(dynheader.reserved2, [0; 256]);
     }
 
+    #[test]
+    fn test_parent_fields() {
+        crate::tests_init();
+
+        let mut dynheader = DynamicHeader::decode(&HEADER).unwrap();
+        assert_eq!(dynheader.parent_name(), "");
+        assert!(dynheader
+            .parent_locator_entries()
+            .iter()
+            .all(|x| !x.is_used()));
+
+        dynheader.set_parent_name("base.vhd");
+        let entry = ParentLocatorEntry {
+            platform_code: ParentLocatorEntry::PLATFORM_CODE_W2RU,
+            platform_data_space: 512,
+            platform_data_length: 24,
+            reserved: 0,
+            platform_data_offset: 0x1234,
+        };
+        dynheader.set_parent_locator_entry(3, &entry);
+
+        let mut buffer = [0u8; DynamicHeader::SIZE];
+        dynheader.encode(&mut buffer[..]);
+        let dynheader = DynamicHeader::decode(&buffer).unwrap();
+        assert_eq!(dynheader.parent_name(), "base.vhd");
+        assert_eq!(&dynheader.parent_unicode_name[..4], &[0, b'b', 0, b'a']);
+        assert_eq!(dynheader.parent_locator_entries()[3], entry);
+        assert_eq!(&dynheader.parent_locator_entry_4[..4], b"W2ru");
+    }
+
     #[test]
     fn test_encode() {
         crate::tests_init();
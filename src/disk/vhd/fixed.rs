@@ -0,0 +1,217 @@
+use crate::disk::vhd::{footer::Footer, read_footer, CreateOptions, DiskType as VhdDiskType};
+use crate::disk::{seek_position, ArgumentMap, Backend, Disk, DiskFormat, MediaType, WipePolarity};
+use crate::{u8_array_uninitialized, Error, Result};
+use std::cmp::min;
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const SECTOR_SIZE: u32 = 512;
+
+/// Fixed VHD is a raw disk image with footer appended at the end
+pub struct FixedVhdDisk {
+    backend: Box<dyn Backend>,
+    footer: Footer,
+    disk_size: u64,
+    cursor: u64,
+}
+
+impl FixedVhdDisk {
+    pub fn open_with_argmap(backend: Box<dyn Backend>, _args: &ArgumentMap) -> Result<Self> {
+        Self::open(backend)
+    }
+
+    pub fn open(mut backend: Box<dyn Backend>) -> Result<Self> {
+        let (file_size, footer, _) = read_footer(backend.as_mut())?;
+        debug!("Footer:\n{}\n", footer);
+
+        if footer.disk_type != VhdDiskType::Fixed {
+            return Err(Error::InvalidVhdFooter(Some(format!(
+                "expected fixed disk but got {:?}",
+                footer.disk_type
+            ))));
+        }
+
+        let data_size = file_size - Footer::SIZE as u64;
+        if footer.current_size > data_size {
+            return Err(Error::InvalidVhdFooter(Some(format!(
+                "disk size ({}) exceeds size of data area ({})",
+                footer.current_size, data_size
+            ))));
+        }
+        if footer.current_size % SECTOR_SIZE as u64 != 0 {
+            return Err(Error::InvalidVhdFooter(Some(format!(
+                "disk size ({}) is not multiple of sector size",
+                footer.current_size
+            ))));
+        }
+
+        Ok(Self {
+            backend,
+            disk_size: footer.current_size,
+            footer,
+            cursor: 0,
+        })
+    }
+
+    pub fn footer(&self) -> &Footer {
+        &self.footer
+    }
+
+    /// Creates fixed VHD, whole data area is allocated and zeroed
+    pub fn create(backend: Box<dyn Backend>, disk_size: usize) -> Result<Self> {
+        Self::create_ex(backend, disk_size, &CreateOptions::default())
+    }
+
+    /// Creates fixed disk, block size in options is ignored
+    pub fn create_ex(
+        backend: Box<dyn Backend>,
+        disk_size: usize,
+        options: &CreateOptions,
+    ) -> Result<Self> {
+        options.validate()?;
+        let max_sectors = {
+            let mut t = disk_size / 512;
+            if disk_size % 512 != 0 {
+                t += 1;
+            }
+            t
+        };
+
+        let footer = Footer::create(VhdDiskType::Fixed, max_sectors, options);
+        let mut disk = Self {
+            backend,
+            disk_size: footer.current_size,
+            footer,
+            cursor: 0,
+        };
+
+        disk.wipe(disk.disk_size.try_into().unwrap(), WipePolarity::Low)?;
+        disk.rewrite_footer()?;
+        disk.seek(SeekFrom::Start(0))?;
+
+        Ok(disk)
+    }
+
+    fn rewrite_footer(&mut self) -> io::Result<()> {
+        let mut footer_encoded = u8_array_uninitialized!(Footer::SIZE);
+        self.footer.encode(&mut footer_encoded);
+
+        self.backend.seek(SeekFrom::Start(self.disk_size))?;
+        self.backend.write_all(&footer_encoded)
+    }
+}
+
+impl Disk for FixedVhdDisk {
+    fn disk_size(&self) -> u64 {
+        self.disk_size
+    }
+
+    fn sector_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::HDD
+    }
+
+    fn disk_format(&self) -> DiskFormat {
+        DiskFormat::VHD
+    }
+}
+
+impl Seek for FixedVhdDisk {
+    fn seek(&mut self, s: SeekFrom) -> io::Result<u64> {
+        self.cursor = seek_position(self.cursor, self.disk_size, s)?;
+        Ok(self.cursor)
+    }
+}
+
+impl Read for FixedVhdDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.disk_size.saturating_sub(self.cursor);
+        let n = min(available, buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        self.backend.seek(SeekFrom::Start(self.cursor))?;
+        let r = self.backend.read(&mut buf[..n])?;
+        self.cursor += r as u64;
+
+        Ok(r)
+    }
+}
+
+impl Write for FixedVhdDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // never write past the data area, otherwise we would overwrite footer
+        let available = self.disk_size.saturating_sub(self.cursor);
+        let n = min(available, buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        self.backend.seek(SeekFrom::Start(self.cursor))?;
+        let w = self.backend.write(&buf[..n])?;
+        self.cursor += w as u64;
+
+        Ok(w)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::MemoryBackend;
+
+    #[test]
+    fn test_create_and_open() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        let mut disk = FixedVhdDisk::create(Box::new(backend), 1024 * 1024 + 100).unwrap();
+        assert_eq!(disk.disk_size(), 1024 * 1024 + 512);
+        disk.seek(SeekFrom::Start(4096)).unwrap();
+        disk.write_all(b"fixed").unwrap();
+
+        // writes must never touch the footer
+        disk.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(disk.write(b"overflow").unwrap(), 2);
+        assert_eq!(disk.write(b"overflow").unwrap(), 0);
+
+        assert_eq!(
+            storage.borrow().len() as u64,
+            1024 * 1024 + 512 + Footer::SIZE as u64
+        );
+
+        let mut disk = FixedVhdDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        assert_eq!(disk.disk_size(), 1024 * 1024 + 512);
+
+        let mut buf = [0u8; 5];
+        disk.seek(SeekFrom::Start(4096)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"fixed");
+
+        disk.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(disk.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ov");
+        assert_eq!(disk.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_open_rejects_dynamic() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        crate::disk::vhd::VhdDisk::create_dynamic(Box::new(backend), 1024 * 1024 * 4).unwrap();
+
+        assert!(FixedVhdDisk::open(Box::new(MemoryBackend::from_storage(storage))).is_err());
+    }
+}
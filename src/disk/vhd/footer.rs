@@ -1,9 +1,8 @@
-use crate::disk::vhd::DiskType;
+use crate::disk::vhd::{timestamp_to_datetime, CreateOptions, DiskType};
 use crate::{u8_array_uninitialized, Error, Result};
 use chrono::prelude::*;
 use std::convert::{TryFrom, TryInto};
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::time::SystemTime;
 use std::{fmt, str};
 use uuid::Uuid;
 
@@ -88,14 +87,10 @@ impl Footer {
         let original_size = read!(u64);
         let current_size = read!(u64);
         let disk_geometry = read!(u32);
-        let c = (disk_geometry & 0xffff) as u16;
-        let h = ((disk_geometry >> 16) & 0xff) as u8;
-        let s = ((disk_geometry >> 24) & 0xff) as u8;
-        trace!("CHS: {} {} {}", c, h, s);
 
         let disk_type = DiskType::try_from(read!(u32))?;
         let checksum = read!(u32, nohash);
-        let uuid = Uuid::from_u128(read!(u128, native));
+        let uuid = Uuid::from_u128(read!(u128));
         let saved_state = read!(u8);
         let reserved = read!(427);
 
@@ -128,10 +123,8 @@ impl Footer {
         })
     }
 
-    pub fn create(disk_type: DiskType, max_sectors: usize) -> Self {
+    pub fn create(disk_type: DiskType, max_sectors: usize, options: &CreateOptions) -> Self {
         let total_size = max_sectors as u64 * 512;
-        let (c, h, s) = Self::compute_chs(max_sectors);
-        let disk_geometry = ((s as u32) << 24) | ((h as u32) << 16) | (c as u32);
 
         Self {
             features: 2,
@@ -140,22 +133,47 @@ impl Footer {
                 DiskType::Dynamic | DiskType::Differencing => 512,
                 DiskType::Fixed => 0xFFFFFFFFFFFFFFFFu64,
             },
-            time_stamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map_or(0, |x| (x.as_secs() - 946684800).try_into().unwrap_or(0)),
-            creator_app: *b"rvd ",
-            creator_version: 1u32,
-            creator_host_os: *b"Wi2k",
+            time_stamp: options.vhd_timestamp(),
+            creator_app: options.creator_app,
+            creator_version: options.creator_version,
+            creator_host_os: options.creator_host_os,
             original_size: total_size,
             current_size: total_size,
-            disk_geometry,
+            disk_geometry: match options.geometry {
+                Some((c, h, s)) => ((c as u32) << 16) | ((h as u32) << 8) | (s as u32),
+                None => Self::encode_chs(max_sectors),
+            },
             disk_type,
-            uuid: Uuid::new_v4(),
+            uuid: options.uuid.unwrap_or_else(Uuid::new_v4),
             saved_state: 0,
             reserved: [0; 427],
         }
     }
 
+    /// Returns CHS geometry as (cylinders, heads, sectors per track)
+    pub fn chs(&self) -> (u16, u8, u8) {
+        (
+            (self.disk_geometry >> 16) as u16,
+            (self.disk_geometry >> 8) as u8,
+            self.disk_geometry as u8,
+        )
+    }
+
+    pub fn creation_time(&self) -> DateTime<Utc> {
+        timestamp_to_datetime(self.time_stamp)
+    }
+
+    /// Sets disk size and CHS geometry derived from it
+    pub fn set_current_size(&mut self, size: u64) {
+        self.current_size = size;
+        self.disk_geometry = Self::encode_chs((size / 512) as usize);
+    }
+
+    fn encode_chs(total_sectors: usize) -> u32 {
+        let (c, h, s) = Self::compute_chs(total_sectors);
+        ((c as u32) << 16) | ((h as u32) << 8) | (s as u32)
+    }
+
     fn compute_chs(mut total_sectors: usize) -> (u16, u8, u8) {
         let mut sectors_per_track;
         let mut heads;
@@ -260,7 +278,7 @@ UUID                  : {{{}}}",
             self.features,
             self.version,
             self.data_offset,
-            Local.timestamp(self.time_stamp as i64 + 946684800, 0),
+            self.creation_time().with_timezone(&Local),
             str::from_utf8(&self.creator_app).unwrap_or("<invalid>"),
             self.creator_version,
             str::from_utf8(&self.creator_host_os).map_or_else(
@@ -278,7 +296,7 @@ UUID                  : {{{}}}",
 #[cfg(test)]
 mod tests {
     use super::Footer;
-    use crate::disk::vhd::DiskType;
+    use crate::disk::vhd::{timestamp_to_datetime, CreateOptions, DiskType};
     use std::str::FromStr;
     use uuid::Uuid;
 
@@ -328,7 +346,8 @@ mod tests {
         assert_eq!(footer.features, 2);
         assert_eq!(footer.version, 0x10000);
         assert_eq!(footer.data_offset, 512);
-        // TODO: time stamp check
+        assert_eq!(footer.time_stamp, 0);
+        assert_eq!(footer.creation_time(), timestamp_to_datetime(0));
         assert_eq!(&footer.creator_app, b"rvd ");
         assert_eq!(footer.creator_version, (5u32 << 16) | 3u32);
         assert_eq!(&footer.creator_host_os, b"Wi2k");
@@ -343,6 +362,31 @@ mod tests {
         assert_eq!(footer.reserved, [0; 427]);
     }
 
+    #[test]
+    fn test_create() {
+        crate::tests_init();
+
+        let uuid = Uuid::new_v4();
+        let options = CreateOptions {
+            uuid: Some(uuid),
+            timestamp: Some(timestamp_to_datetime(12345)),
+            geometry: Some((20, 16, 63)),
+            ..CreateOptions::default()
+        };
+        let footer = Footer::create(DiskType::Fixed, 20160, &options);
+        assert_eq!(footer.time_stamp, 12345);
+        assert_eq!(footer.creation_time(), timestamp_to_datetime(12345));
+        assert_eq!(footer.uuid, uuid);
+        assert_eq!(footer.chs(), (20, 16, 63));
+        assert_eq!(footer.current_size, 20160 * 512);
+        assert_eq!(footer.data_offset, 0xFFFFFFFFFFFFFFFF);
+        assert_eq!(&footer.creator_app, b"rvd ");
+
+        let footer = Footer::create(DiskType::Dynamic, 20160, &CreateOptions::default());
+        assert_eq!(footer.data_offset, 512);
+        assert_ne!(footer.uuid, uuid);
+    }
+
     #[test]
     fn test_encode() {
         crate::tests_init();
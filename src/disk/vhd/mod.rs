@@ -1,11 +1,30 @@
 mod disk;
 mod dynamic_header;
+mod fixed;
 mod footer;
+mod options;
+mod parent;
+mod repair;
 
-pub use disk::VhdDisk;
+pub use disk::{SpaceUsage, VhdDisk};
+pub use dynamic_header::{DynamicHeader, ParentLocatorEntry};
+pub use fixed::FixedVhdDisk;
+pub use footer::Footer;
+pub use options::{datetime_to_timestamp, CreateOptions};
+pub use repair::{repair, RepairReport};
 
-use crate::Error;
+use crate::disk::{ArgumentMap, Backend, Disk};
+use crate::{u8_array_uninitialized, Error, Result};
+use chrono::{DateTime, TimeZone, Utc};
 use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom};
+
+/// VHD timestamps are seconds since January 1, 2000 UTC
+pub(crate) const VHD_EPOCH: i64 = 946684800;
+
+pub fn timestamp_to_datetime(timestamp: u32) -> DateTime<Utc> {
+    Utc.timestamp_opt(timestamp as i64 + VHD_EPOCH, 0).unwrap()
+}
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 #[repr(u32)]
@@ -18,7 +37,7 @@ pub enum DiskType {
 impl TryFrom<u32> for DiskType {
     type Error = Error;
 
-    fn try_from(x: u32) -> Result<Self, Self::Error> {
+    fn try_from(x: u32) -> std::result::Result<Self, Self::Error> {
         match x {
             2 => Ok(Self::Fixed),
             3 => Ok(Self::Dynamic),
@@ -30,6 +49,58 @@ impl TryFrom<u32> for DiskType {
     }
 }
 
+/// Opens VHD disk of any supported type
+pub fn open_with_argmap(
+    mut backend: Box<dyn Backend>,
+    args: &ArgumentMap,
+) -> Result<Box<dyn Disk>> {
+    let (_, footer, _) = read_footer(backend.as_mut())?;
+    backend.seek(SeekFrom::Start(0))?;
+
+    Ok(match footer.disk_type {
+        DiskType::Fixed => Box::new(FixedVhdDisk::open_with_argmap(backend, args)?),
+        DiskType::Dynamic | DiskType::Differencing => {
+            Box::new(VhdDisk::open_with_argmap(backend, args)?)
+        }
+    })
+}
+
+/// Opens VHD disk of any supported type, differencing disks can't be opened
+/// this way as there is no path to search for parent
+pub fn open(backend: Box<dyn Backend>) -> Result<Box<dyn Disk>> {
+    open_with_argmap(backend, &ArgumentMap::default())
+}
+
+/// Reads and decodes footer located at the end of file, dynamic disks fall back
+/// to the footer copy at the beginning of file when the trailing one is damaged.
+/// Returns file size, decoded footer and its encoded form.
+pub fn read_footer(backend: &mut dyn Backend) -> Result<(u64, Footer, [u8; Footer::SIZE])> {
+    let file_size = backend.seek(SeekFrom::End(0))?;
+    if file_size < Footer::SIZE as u64 {
+        return Err(Error::InvalidVhdFooter(Some("File too small".to_owned())));
+    }
+
+    match read_footer_at(backend, file_size - Footer::SIZE as u64) {
+        Ok((footer, footer_encoded)) => Ok((file_size, footer, footer_encoded)),
+        Err(e) => match read_footer_at(backend, 0) {
+            Ok((footer, footer_encoded)) if footer.disk_type != DiskType::Fixed => {
+                warn!("trailing VHD footer is damaged ({}), using footer copy", e);
+                Ok((file_size, footer, footer_encoded))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+fn read_footer_at(backend: &mut dyn Backend, offset: u64) -> Result<(Footer, [u8; Footer::SIZE])> {
+    backend.seek(SeekFrom::Start(offset))?;
+    let mut footer_encoded = u8_array_uninitialized!(Footer::SIZE);
+    backend.read_exact(&mut footer_encoded)?;
+    let footer = Footer::decode(&footer_encoded)?;
+
+    Ok((footer, footer_encoded))
+}
+
 /*impl Into<u32> for DiskType {
     fn into(self) -> u32 {
         match self {
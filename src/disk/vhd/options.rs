@@ -0,0 +1,92 @@
+use crate::disk::vhd::VHD_EPOCH;
+use crate::{is_power_of_2, Error, Result};
+use chrono::{DateTime, Utc};
+use std::convert::TryInto;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+const SECTOR_SIZE: u32 = 512;
+const MAX_BLOCK_SIZE: u32 = 256 * 1024 * 1024;
+
+/// Parameters of newly created VHD image, fields left at `None` are generated
+/// during creation. Fixed UUID and timestamp make created images reproducible.
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Block size of dynamic and differencing disks, power of 2 between 512 B and 256 MiB
+    pub block_size: u32,
+    /// Disk UUID, random when not specified
+    pub uuid: Option<Uuid>,
+    pub creator_app: [u8; 4],
+    pub creator_version: u32,
+    pub creator_host_os: [u8; 4],
+    /// Creation time, current time when not specified
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Geometry as (cylinders, heads, sectors per track), computed from disk size
+    /// when not specified
+    pub geometry: Option<(u16, u8, u8)>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 2 * 1024 * 1024,
+            uuid: None,
+            creator_app: *b"rvd ",
+            creator_version: 1,
+            creator_host_os: *b"Wi2k",
+            timestamp: None,
+            geometry: None,
+        }
+    }
+}
+
+impl CreateOptions {
+    pub fn validate(&self) -> Result<()> {
+        if !is_power_of_2!(self.block_size)
+            || self.block_size < SECTOR_SIZE
+            || self.block_size > MAX_BLOCK_SIZE
+        {
+            return Err(Error::InvalidVhdDynamicHeader(Some(format!(
+                "invalid block size {}",
+                self.block_size
+            ))));
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            datetime_to_timestamp(timestamp).ok_or_else(|| {
+                Error::InvalidVhdFooter(Some(format!(
+                    "timestamp {} can't be represented",
+                    timestamp
+                )))
+            })?;
+        }
+
+        if let Some((cylinders, heads, sectors)) = self.geometry {
+            if cylinders == 0 || heads == 0 || heads > 16 || sectors == 0 {
+                return Err(Error::InvalidVhdFooter(Some(format!(
+                    "invalid geometry {}/{}/{}",
+                    cylinders, heads, sectors
+                ))));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns VHD timestamp of creation time
+    pub(crate) fn vhd_timestamp(&self) -> u32 {
+        match self.timestamp {
+            Some(x) => datetime_to_timestamp(x).unwrap(),
+            None => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |x| {
+                    (x.as_secs() as i64 - VHD_EPOCH).try_into().unwrap_or(0)
+                }),
+        }
+    }
+}
+
+/// Converts time to seconds since VHD epoch, fails for times outside of representable range
+pub fn datetime_to_timestamp(time: DateTime<Utc>) -> Option<u32> {
+    (time.timestamp() - VHD_EPOCH).try_into().ok()
+}
@@ -0,0 +1,322 @@
+use crate::disk::vhd::{
+    dynamic_header::{DynamicHeader, ParentLocatorEntry},
+    footer::Footer,
+    read_footer, DiskType, FixedVhdDisk, VhdDisk,
+};
+use crate::disk::{Argument, ArgumentMap, Backend, Disk, FileBackend};
+use crate::{Error, Result};
+use std::char::{decode_utf16, REPLACEMENT_CHARACTER};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::str;
+use uuid::Uuid;
+
+/// Longest supported chain of differencing disks, deeper chains are treated as corruption
+const MAX_CHAIN_LENGTH: usize = 64;
+
+/// Opens disk which is a parent of differencing disk, parent is always opened read-only
+/// and may be a differencing disk itself
+pub(super) fn open_parent(
+    path: &Path,
+    expected_id: Option<Uuid>,
+) -> Result<(Footer, Box<dyn Disk>)> {
+    let (footer, backend) = probe_parent(path, expected_id)?;
+    let disk = open_parent_disk(backend, &footer, path, &[])?;
+
+    Ok((footer, disk))
+}
+
+/// Opens parent file and checks that its footer has expected UUID
+fn probe_parent(path: &Path, expected_id: Option<Uuid>) -> Result<(Footer, Box<dyn Backend>)> {
+    let file = OpenOptions::new().read(true).write(false).open(path)?;
+    let mut backend: Box<dyn Backend> = FileBackend::new(file)?;
+
+    let (_, footer, _) = read_footer(backend.as_mut())?;
+    if let Some(expected_id) = expected_id {
+        if footer.uuid != expected_id {
+            return Err(Error::InvalidVhdFooter(Some(format!(
+                "UUID mismatch, expected {{{}}} but got {{{}}}",
+                expected_id, footer.uuid
+            ))));
+        }
+    }
+    backend.seek(SeekFrom::Start(0))?;
+
+    Ok((footer, backend))
+}
+
+/// Opens probed parent, chain holds UUIDs of its children which are already open
+fn open_parent_disk(
+    backend: Box<dyn Backend>,
+    footer: &Footer,
+    path: &Path,
+    chain: &[Uuid],
+) -> Result<Box<dyn Disk>> {
+    Ok(match footer.disk_type {
+        DiskType::Fixed => {
+            let mut args = ArgumentMap::default();
+            args.insert(
+                "path",
+                Argument::String(path.to_string_lossy().into_owned()),
+            );
+            Box::new(FixedVhdDisk::open_with_argmap(backend, &args)?)
+        }
+        DiskType::Dynamic | DiskType::Differencing => {
+            Box::new(VhdDisk::open_chain(backend, path, chain)?)
+        }
+    })
+}
+
+/// Finds and opens parent of differencing disk, locators are tried first,
+/// then parent name is looked up in directory containing child.
+/// Chain holds UUIDs of the child and all its children.
+pub(super) fn resolve_parent(
+    backend: &mut dyn Backend,
+    header: &DynamicHeader,
+    child_path: &Path,
+    chain: &[Uuid],
+) -> Result<Box<dyn Disk>> {
+    let parent_id = header.parent_unique_id();
+    if chain.contains(&parent_id) {
+        return Err(Error::InvalidVhdFooter(Some(format!(
+            "parent chain loops back to {{{}}}",
+            parent_id
+        ))));
+    }
+    if chain.len() >= MAX_CHAIN_LENGTH {
+        return Err(Error::InvalidVhdFooter(Some(format!(
+            "parent chain is longer than {} disks",
+            MAX_CHAIN_LENGTH
+        ))));
+    }
+
+    let child_dir = child_path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut candidates = Vec::new();
+    for entry in header
+        .parent_locator_entries()
+        .iter()
+        .filter(|x| x.is_used())
+    {
+        match read_locator(backend, entry) {
+            Ok(Some(path)) => candidates.push(child_dir.join(path)),
+            Ok(None) => debug!(
+                "ignoring unsupported parent locator 0x{:08X}",
+                entry.platform_code
+            ),
+            Err(e) => warn!("failed to read parent locator: {}", e),
+        }
+    }
+
+    let name = header.parent_name();
+    if !name.is_empty() {
+        candidates.push(child_dir.join(&name));
+    }
+
+    // once the parent is found, failure to open it isn't retried with other candidates
+    for candidate in candidates.iter() {
+        debug!("Trying parent: {}", candidate.display());
+        match probe_parent(candidate, Some(parent_id)) {
+            Ok((footer, backend)) => return open_parent_disk(backend, &footer, candidate, chain),
+            Err(e) => warn!("{}: {}", candidate.display(), e),
+        }
+    }
+
+    Err(Error::VhdParentNotFound(name))
+}
+
+/// Locator data is only a path, anything larger than this is treated as corruption
+const MAX_LOCATOR_SIZE: u32 = 64 * 1024;
+
+fn read_locator(backend: &mut dyn Backend, entry: &ParentLocatorEntry) -> Result<Option<PathBuf>> {
+    if entry.platform_data_length > MAX_LOCATOR_SIZE {
+        return Err(Error::InvalidVhdDynamicHeader(Some(format!(
+            "parent locator data length ({}) is too large",
+            entry.platform_data_length
+        ))));
+    }
+
+    let mut data = vec![0u8; entry.platform_data_length as usize];
+    backend.seek(SeekFrom::Start(entry.platform_data_offset))?;
+    backend.read_exact(&mut data)?;
+
+    Ok(decode_locator(entry.platform_code, &data))
+}
+
+fn decode_locator(platform_code: u32, data: &[u8]) -> Option<PathBuf> {
+    match platform_code {
+        ParentLocatorEntry::PLATFORM_CODE_W2RU | ParentLocatorEntry::PLATFORM_CODE_W2KU => {
+            let it = data
+                .chunks_exact(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                .take_while(|x| *x != 0);
+            let s: String = decode_utf16(it)
+                .map(|x| x.unwrap_or(REPLACEMENT_CHARACTER))
+                .collect();
+
+            Some(PathBuf::from(from_windows_path(&s)))
+        }
+        ParentLocatorEntry::PLATFORM_CODE_MACX => {
+            let s = str::from_utf8(data).ok()?.trim_end_matches('\0');
+            Some(PathBuf::from(s.strip_prefix("file://").unwrap_or(s)))
+        }
+        // Mac aliases are opaque blobs, we can't do anything with them
+        ParentLocatorEntry::PLATFORM_CODE_MAC => None,
+        x => {
+            warn!("Unknown parent locator platform code 0x{:08x}", x);
+            None
+        }
+    }
+}
+
+/// Builds parent locators for a new differencing disk,
+/// returns list of platform codes and corresponding platform data
+pub(super) fn create_locators(
+    child_path: &Path,
+    parent_path: &Path,
+) -> Result<Vec<(u32, Vec<u8>)>> {
+    let parent_path = parent_path.canonicalize()?;
+    let child_dir = child_path
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .canonicalize()?;
+
+    let mut locators = Vec::new();
+
+    if let Some(relative) = relative_path(&child_dir, &parent_path) {
+        let mut s = to_windows_path(&relative.to_string_lossy());
+        if !s.starts_with('.') {
+            s = format!(".\\{}", s);
+        }
+        locators.push((ParentLocatorEntry::PLATFORM_CODE_W2RU, encode_utf16le(&s)));
+    }
+
+    let absolute = parent_path.to_string_lossy();
+    // strip verbatim prefix added by canonicalize() on Windows
+    let absolute = absolute.strip_prefix(r"\\?\").unwrap_or(&absolute);
+    locators.push((
+        ParentLocatorEntry::PLATFORM_CODE_W2KU,
+        encode_utf16le(&to_windows_path(absolute)),
+    ));
+    locators.push((
+        ParentLocatorEntry::PLATFORM_CODE_MACX,
+        format!("file://{}", absolute).into_bytes(),
+    ));
+
+    Ok(locators)
+}
+
+fn encode_utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn relative_path(from: &Path, to: &Path) -> Option<PathBuf> {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        // eg. different drive letters
+        return None;
+    }
+
+    let mut result = PathBuf::new();
+    for _ in common..from.len() {
+        result.push("..");
+    }
+    for x in to[common..].iter() {
+        result.push(x.as_os_str());
+    }
+
+    Some(result)
+}
+
+#[cfg(windows)]
+fn to_windows_path(s: &str) -> String {
+    s.to_owned()
+}
+
+#[cfg(not(windows))]
+fn to_windows_path(s: &str) -> String {
+    s.replace('/', "\\")
+}
+
+#[cfg(windows)]
+fn from_windows_path(s: &str) -> String {
+    s.to_owned()
+}
+
+#[cfg(not(windows))]
+fn from_windows_path(s: &str) -> String {
+    s.replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_decode_locator() {
+        crate::tests_init();
+
+        let data = encode_utf16le(".\\base.vhd");
+        assert_eq!(
+            decode_locator(ParentLocatorEntry::PLATFORM_CODE_W2RU, &data).unwrap(),
+            PathBuf::from(from_windows_path(".\\base.vhd"))
+        );
+
+        let mut data = b"file:///images/base.vhd".to_vec();
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            decode_locator(ParentLocatorEntry::PLATFORM_CODE_MACX, &data).unwrap(),
+            PathBuf::from("/images/base.vhd")
+        );
+
+        assert!(decode_locator(ParentLocatorEntry::PLATFORM_CODE_MAC, b"alias").is_none());
+    }
+
+    #[test]
+    fn test_read_locator() {
+        crate::tests_init();
+
+        let mut backend = crate::disk::tests::MemoryBackend::new();
+        backend.write_all(&encode_utf16le(".\\base.vhd")).unwrap();
+        let mut entry = ParentLocatorEntry {
+            platform_code: ParentLocatorEntry::PLATFORM_CODE_W2RU,
+            platform_data_space: 512,
+            platform_data_length: 20,
+            reserved: 0,
+            platform_data_offset: 0,
+        };
+        assert!(read_locator(&mut backend, &entry).unwrap().is_some());
+
+        entry.platform_data_length = u32::MAX;
+        assert!(read_locator(&mut backend, &entry).is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_relative_path() {
+        crate::tests_init();
+
+        macro_rules! test {
+            ($from:expr, $to:expr, $expected:expr) => {{
+                assert_eq!(
+                    relative_path(Path::new($from), Path::new($to)).unwrap(),
+                    PathBuf::from($expected)
+                );
+            }};
+        }
+
+        test!("/images", "/images/base.vhd", "base.vhd");
+        test!("/images/children", "/images/base.vhd", "../base.vhd");
+        test!("/a/b", "/c/d/base.vhd", "../../c/d/base.vhd");
+    }
+}
@@ -0,0 +1,325 @@
+use crate::disk::vhd::{disk::locator_space, read_footer_at, DiskType, DynamicHeader, Footer};
+use crate::disk::Backend;
+use crate::{is_power_of_2, round_up, u8_array_uninitialized, utils::zero_u8_slice, Error, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::{max, min};
+use std::io::{self, SeekFrom};
+
+const SECTOR_SIZE: u64 = 512;
+const BAT_UNUSED: u32 = 0xFFFFFFFF;
+
+/// Problems found in dynamic disk image by `repair`
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Trailing footer was missing or damaged and has been restored from copy
+    pub footer_restored: bool,
+    /// Footer copy at the beginning of file was damaged or out of date
+    pub footer_copy_restored: bool,
+    /// BAT entries pointing outside of file or into metadata, these blocks were deallocated
+    pub invalid_blocks: Vec<usize>,
+    /// Blocks cut short by end of file, missing part reads as zeros
+    pub truncated_blocks: Vec<usize>,
+    /// Blocks sharing space with other block, these were copied to end of file
+    pub relocated_blocks: Vec<usize>,
+    /// Size file must be truncated to after repair
+    pub file_size: u64,
+}
+
+impl RepairReport {
+    pub fn is_clean(&self) -> bool {
+        !self.footer_restored
+            && !self.footer_copy_restored
+            && self.invalid_blocks.is_empty()
+            && self.truncated_blocks.is_empty()
+            && self.relocated_blocks.is_empty()
+    }
+}
+
+pub(crate) fn bitmap_size(block_size: u32) -> u32 {
+    ((block_size / (8 * SECTOR_SIZE as u32)) + 511) & !511
+}
+
+/// Returns indexes of BAT entries whose blocks overlap block of some preceding entry
+pub(crate) fn find_overlapping_blocks(bat: &[u32], block_size: u32) -> Vec<usize> {
+    let span = bitmap_size(block_size) as u64 + block_size as u64;
+    let mut blocks: Vec<(u64, usize)> = bat
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| **x != BAT_UNUSED)
+        .map(|(i, x)| (*x as u64 * SECTOR_SIZE, i))
+        .collect();
+    blocks.sort_unstable();
+
+    let mut overlapping = Vec::new();
+    let mut end = 0;
+    for (start, index) in blocks {
+        if start < end {
+            overlapping.push(index);
+        }
+        end = max(end, start + span);
+    }
+    overlapping.sort_unstable();
+
+    overlapping
+}
+
+/// Checks dynamic or differencing disk image and fixes problems that can be fixed
+/// without losing data still present in the file. Footer is taken from the end
+/// of file, or from its copy at the beginning if trailing footer is damaged.
+/// BAT entries are validated against file length, blocks sharing space with
+/// other blocks are copied to end of file. When dry_run is set nothing is written.
+///
+/// Backend can't be shrunk, file should be truncated to `file_size` from returned report.
+pub fn repair(backend: &mut dyn Backend, dry_run: bool) -> Result<RepairReport> {
+    let file_size = backend.seek(SeekFrom::End(0))?;
+    if file_size < Footer::SIZE as u64 {
+        return Err(Error::InvalidVhdFooter(Some("File too small".to_owned())));
+    }
+
+    let mut report = RepairReport::default();
+    let trailing = read_footer_at(backend, file_size - Footer::SIZE as u64);
+    let copy = read_footer_at(backend, 0);
+    let (footer, footer_encoded) = match (trailing, copy) {
+        (Ok(trailing), copy) => {
+            report.footer_copy_restored = copy.map_or(true, |x| x.1[..] != trailing.1[..]);
+            trailing
+        }
+        (Err(_), Ok(copy)) => {
+            report.footer_restored = true;
+            copy
+        }
+        (Err(e), Err(_)) => return Err(e),
+    };
+    if footer.disk_type == DiskType::Fixed {
+        return Err(Error::InvalidVhdFooter(Some(
+            "fixed disks have no metadata to repair".to_owned(),
+        )));
+    }
+
+    // area usable by blocks, trailing footer is excluded when present
+    let data_end = if report.footer_restored {
+        file_size
+    } else {
+        file_size - Footer::SIZE as u64
+    };
+
+    backend.seek(SeekFrom::Start(footer.data_offset))?;
+    let mut dynamic_header_encoded = u8_array_uninitialized!(DynamicHeader::SIZE);
+    backend.read_exact(&mut dynamic_header_encoded)?;
+    let dynamic_header = DynamicHeader::decode(&dynamic_header_encoded)?;
+
+    let block_size = dynamic_header.block_size;
+    let bitmap_size = bitmap_size(block_size) as u64;
+    let span = bitmap_size + block_size as u64;
+
+    let mut bat = Vec::with_capacity(dynamic_header.max_table_entries as usize);
+    backend.seek(SeekFrom::Start(dynamic_header.bat_offset))?;
+    for _ in 0..dynamic_header.max_table_entries {
+        bat.push(backend.read_u32::<BigEndian>()?);
+    }
+    let bat_end = round_up!(
+        dynamic_header.bat_offset + bat.len() as u64 * 4,
+        SECTOR_SIZE
+    );
+
+    // footer copy, dynamic header, BAT and parent locators must not be touched by blocks
+    let mut metadata = vec![
+        (0, Footer::SIZE as u64),
+        (
+            footer.data_offset,
+            footer.data_offset + DynamicHeader::SIZE as u64,
+        ),
+        (dynamic_header.bat_offset, bat_end),
+    ];
+    if footer.disk_type == DiskType::Differencing {
+        for entry in dynamic_header
+            .parent_locator_entries()
+            .iter()
+            .filter(|x| x.is_used())
+        {
+            metadata.push((
+                entry.platform_data_offset,
+                entry.platform_data_offset + locator_space(entry),
+            ));
+        }
+    }
+    let mut end = metadata.iter().map(|x| x.1).max().unwrap();
+
+    for (i, entry) in bat.iter_mut().enumerate() {
+        if *entry == BAT_UNUSED {
+            continue;
+        }
+
+        let start = *entry as u64 * SECTOR_SIZE;
+        if start + bitmap_size > data_end
+            || metadata
+                .iter()
+                .any(|(s, e)| start < *e && start + span > *s)
+        {
+            warn!("BAT#{} points to invalid offset {:#x}", i, start);
+            report.invalid_blocks.push(i);
+            *entry = BAT_UNUSED;
+            continue;
+        }
+
+        if start + span > data_end {
+            warn!("BAT#{} at {:#x} is truncated by end of file", i, start);
+            report.truncated_blocks.push(i);
+        }
+        end = max(end, start + span);
+    }
+
+    // footer being moved is now part of truncated block and must read as zeros
+    if !dry_run && data_end < file_size && !report.truncated_blocks.is_empty() {
+        backend.seek(SeekFrom::Start(data_end))?;
+        backend.write_all(&[0u8; Footer::SIZE])?;
+    }
+
+    end = round_up!(end, SECTOR_SIZE);
+    let mut buf = vec![0u8; span as usize];
+    for i in find_overlapping_blocks(&bat, block_size) {
+        warn!("BAT#{} overlaps another block", i);
+        report.relocated_blocks.push(i);
+
+        if !dry_run {
+            // part beyond end of file reads as zeros, same as it would from the disk
+            let start = bat[i] as u64 * SECTOR_SIZE;
+            let n = min(span, data_end - start) as usize;
+            backend.seek(SeekFrom::Start(start))?;
+            backend.read_exact(&mut buf[..n])?;
+            zero_u8_slice(&mut buf[n..]);
+
+            backend.seek(SeekFrom::Start(end))?;
+            backend.write_all(&buf)?;
+        }
+        bat[i] = (end / SECTOR_SIZE) as u32;
+        end += span;
+    }
+
+    report.file_size = end + Footer::SIZE as u64;
+    if dry_run || report.is_clean() {
+        return Ok(report);
+    }
+
+    let mut bat_encoded = vec![0xffu8; (bat_end - dynamic_header.bat_offset) as usize];
+    let mut writer = io::Cursor::new(&mut bat_encoded[..]);
+    for entry in bat.iter() {
+        writer.write_u32::<BigEndian>(*entry)?;
+    }
+    backend.seek(SeekFrom::Start(dynamic_header.bat_offset))?;
+    backend.write_all(&bat_encoded)?;
+
+    backend.seek(SeekFrom::Start(0))?;
+    backend.write_all(&footer_encoded)?;
+    backend.seek(SeekFrom::Start(end))?;
+    backend.write_all(&footer_encoded)?;
+    backend.flush()?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repair;
+    use crate::disk::tests::MemoryBackend;
+    use crate::disk::vhd::VhdDisk;
+    use byteorder::{BigEndian, ByteOrder};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    const BLOCK_SIZE: usize = 2 * 1024 * 1024;
+    const BAT_OFFSET: usize = 1536;
+
+    fn read_all(storage: &std::rc::Rc<std::cell::RefCell<Vec<u8>>>) -> Vec<u8> {
+        let mut disk =
+            VhdDisk::open(Box::new(MemoryBackend::from_storage(storage.clone()))).unwrap();
+        let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_truncated() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        {
+            let mut disk = VhdDisk::create_dynamic(Box::new(backend), 3 * BLOCK_SIZE).unwrap();
+            disk.write_all(&[0xaa; 4096]).unwrap();
+            disk.seek(SeekFrom::Start(2 * BLOCK_SIZE as u64)).unwrap();
+            disk.write_all(&[0x55; 4096]).unwrap();
+        }
+
+        // interrupted copy, second block is only partially present
+        let len = storage.borrow().len();
+        storage.borrow_mut().truncate(len - BLOCK_SIZE + 4096);
+
+        let mut backend = MemoryBackend::from_storage(storage.clone());
+        let report = repair(&mut backend, false).unwrap();
+        assert!(report.footer_restored);
+        assert!(!report.footer_copy_restored);
+        assert_eq!(report.truncated_blocks, vec![2]);
+        assert!(report.invalid_blocks.is_empty());
+        assert_eq!(report.file_size, len as u64);
+        assert_eq!(storage.borrow().len(), len);
+
+        let buf = read_all(&storage);
+        assert!(buf[..4096].iter().all(|x| *x == 0xaa));
+        assert!(buf[4096..2 * BLOCK_SIZE].iter().all(|x| *x == 0));
+        assert!(buf[2 * BLOCK_SIZE..2 * BLOCK_SIZE + 4096]
+            .iter()
+            .all(|x| *x == 0x55));
+
+        let report = repair(&mut MemoryBackend::from_storage(storage), true).unwrap();
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn test_invalid_and_overlapping() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        {
+            let mut disk = VhdDisk::create_dynamic(Box::new(backend), 3 * BLOCK_SIZE).unwrap();
+            disk.write_all(&[0xff; 4096]).unwrap();
+        }
+
+        {
+            let mut storage = storage.borrow_mut();
+            let first = BigEndian::read_u32(&storage[BAT_OFFSET..]);
+            // second block shares space with the first one, third points past end of file
+            BigEndian::write_u32(&mut storage[BAT_OFFSET + 4..], first + 1);
+            BigEndian::write_u32(&mut storage[BAT_OFFSET + 8..], 0x00ffffff);
+            storage[0] ^= 1;
+        }
+
+        let expected = {
+            let mut buf = vec![0u8; 3 * BLOCK_SIZE];
+            let mut backend = MemoryBackend::from_storage(storage.clone());
+            let first = BigEndian::read_u32(&storage.borrow()[BAT_OFFSET..]) as u64 * 512;
+            backend.seek(SeekFrom::Start(first + 512)).unwrap();
+            backend.read_exact(&mut buf[..BLOCK_SIZE]).unwrap();
+            buf.copy_within(512..BLOCK_SIZE, BLOCK_SIZE);
+            buf
+        };
+
+        let mut backend = MemoryBackend::from_storage(storage.clone());
+        let report = repair(&mut backend, true).unwrap();
+        assert_eq!(report.relocated_blocks, vec![1]);
+        assert_eq!(report.invalid_blocks, vec![2]);
+        // second block reaches into trailing footer
+        assert_eq!(report.truncated_blocks, vec![1]);
+        assert!(report.footer_copy_restored);
+
+        let report = repair(&mut backend, false).unwrap();
+        assert_eq!(report.relocated_blocks, vec![1]);
+        assert_eq!(report.invalid_blocks, vec![2]);
+        let len = report.file_size as usize;
+        storage.borrow_mut().truncate(len);
+
+        assert!(read_all(&storage) == expected);
+        let report = repair(&mut MemoryBackend::from_storage(storage), true).unwrap();
+        assert!(report.is_clean());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::disk::vhdx::{checksum, read_guid, write_guid};
+use crate::{Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::Cursor;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub sequence_number: u64,
+    pub file_write_guid: Uuid,
+    pub data_write_guid: Uuid,
+    pub log_guid: Uuid,
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+}
+
+impl Header {
+    pub const SIZE: usize = 4096;
+    const SIGNATURE: &'static [u8; 4] = b"head";
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        debug_assert_eq!(buf.len(), Self::SIZE);
+
+        if &buf[..4] != Self::SIGNATURE {
+            return Err(Error::InvalidVhdx("invalid header signature".to_owned()));
+        }
+
+        let mut reader = Cursor::new(&buf[4..]);
+        let stored_checksum = reader.read_u32::<LittleEndian>()?;
+        let computed_checksum = checksum(buf, 4);
+        if stored_checksum != computed_checksum {
+            return Err(Error::InvalidVhdx(format!(
+                "header checksum mismatch, computed 0x{:08X} but the checksum is 0x{:08X}",
+                computed_checksum, stored_checksum
+            )));
+        }
+
+        Ok(Self {
+            sequence_number: reader.read_u64::<LittleEndian>()?,
+            file_write_guid: read_guid(&mut reader)?,
+            data_write_guid: read_guid(&mut reader)?,
+            log_guid: read_guid(&mut reader)?,
+            log_version: reader.read_u16::<LittleEndian>()?,
+            version: reader.read_u16::<LittleEndian>()?,
+            log_length: reader.read_u32::<LittleEndian>()?,
+            log_offset: reader.read_u64::<LittleEndian>()?,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; Self::SIZE];
+
+        {
+            let mut writer = Cursor::new(&mut buf[..]);
+            writer.get_mut()[..4].copy_from_slice(Self::SIGNATURE);
+            writer.set_position(8);
+            writer
+                .write_u64::<LittleEndian>(self.sequence_number)
+                .unwrap();
+            write_guid(&mut writer, self.file_write_guid).unwrap();
+            write_guid(&mut writer, self.data_write_guid).unwrap();
+            write_guid(&mut writer, self.log_guid).unwrap();
+            writer.write_u16::<LittleEndian>(self.log_version).unwrap();
+            writer.write_u16::<LittleEndian>(self.version).unwrap();
+            writer.write_u32::<LittleEndian>(self.log_length).unwrap();
+            writer.write_u64::<LittleEndian>(self.log_offset).unwrap();
+        }
+
+        let c = checksum(&buf, 4);
+        buf[4..8].copy_from_slice(&c.to_le_bytes());
+
+        buf
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Sequence number: {}", self.sequence_number)?;
+        writeln!(f, "File write GUID: {}", self.file_write_guid)?;
+        writeln!(f, "Data write GUID: {}", self.data_write_guid)?;
+        writeln!(f, "Log GUID: {}", self.log_guid)?;
+        writeln!(f, "Log version: {}", self.log_version)?;
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Log length: {}", self.log_length)?;
+        write!(f, "Log offset: {}", self.log_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Header;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_encode_decode() {
+        crate::tests_init();
+
+        let header = Header {
+            sequence_number: 5,
+            file_write_guid: Uuid::new_v4(),
+            data_write_guid: Uuid::new_v4(),
+            log_guid: Uuid::nil(),
+            log_version: 0,
+            version: 1,
+            log_length: 1024 * 1024,
+            log_offset: 1024 * 1024,
+        };
+
+        let mut encoded = header.encode();
+        let decoded = Header::decode(&encoded).unwrap();
+        assert_eq!(decoded.sequence_number, 5);
+        assert_eq!(decoded.file_write_guid, header.file_write_guid);
+        assert_eq!(decoded.data_write_guid, header.data_write_guid);
+        assert_eq!(decoded.log_offset, header.log_offset);
+
+        encoded[100] ^= 1;
+        assert!(Header::decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::disk::vhdx::{checksum, header::Header, read_guid};
+use crate::disk::Backend;
+use crate::{is_power_of_2, round_up, Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{Cursor, SeekFrom};
+
+const ENTRY_SIGNATURE: &[u8; 4] = b"loge";
+const ZERO_DESCRIPTOR_SIGNATURE: &[u8; 4] = b"zero";
+const DATA_DESCRIPTOR_SIGNATURE: &[u8; 4] = b"desc";
+const DATA_SECTOR_SIGNATURE: &[u8; 4] = b"data";
+
+const SECTOR_SIZE: usize = 4096;
+const ENTRY_HEADER_SIZE: usize = 64;
+const DESCRIPTOR_SIZE: usize = 32;
+
+enum Descriptor {
+    Zero {
+        file_offset: u64,
+        length: u64,
+    },
+    Data {
+        file_offset: u64,
+        leading_bytes: [u8; 8],
+        trailing_bytes: [u8; 4],
+    },
+}
+
+struct LogEntry {
+    offset: usize,
+    length: usize,
+    tail: usize,
+    sequence_number: u64,
+    flushed_file_offset: u64,
+    descriptors: Vec<Descriptor>,
+}
+
+impl LogEntry {
+    /// Decodes log entry located at offset, returns None if there is no valid entry
+    fn decode(log: &[u8], offset: usize, log_guid: uuid::Uuid) -> Option<Self> {
+        let header = log.get(offset..offset + ENTRY_HEADER_SIZE)?;
+        if &header[..4] != ENTRY_SIGNATURE {
+            return None;
+        }
+
+        let mut reader = Cursor::new(&header[4..]);
+        let stored_checksum = reader.read_u32::<LittleEndian>().ok()?;
+        let length = reader.read_u32::<LittleEndian>().ok()? as usize;
+        let tail = reader.read_u32::<LittleEndian>().ok()? as usize;
+        let sequence_number = reader.read_u64::<LittleEndian>().ok()?;
+        let descriptor_count = reader.read_u32::<LittleEndian>().ok()? as usize;
+        let _reserved = reader.read_u32::<LittleEndian>().ok()?;
+        let guid = read_guid(&mut reader).ok()?;
+        let flushed_file_offset = reader.read_u64::<LittleEndian>().ok()?;
+        let _last_file_offset = reader.read_u64::<LittleEndian>().ok()?;
+
+        if guid != log_guid
+            || length == 0
+            || length % SECTOR_SIZE != 0
+            || tail % SECTOR_SIZE != 0
+            || tail >= log.len()
+        {
+            return None;
+        }
+
+        let entry = log.get(offset..offset + length)?;
+        if checksum(entry, 4) != stored_checksum {
+            return None;
+        }
+
+        let descriptors_size = round_up!(
+            ENTRY_HEADER_SIZE + descriptor_count * DESCRIPTOR_SIZE,
+            SECTOR_SIZE
+        );
+        if descriptors_size > length {
+            return None;
+        }
+
+        let mut descriptors = Vec::with_capacity(descriptor_count);
+        let mut data_sector = descriptors_size;
+        for i in 0..descriptor_count {
+            let o = ENTRY_HEADER_SIZE + i * DESCRIPTOR_SIZE;
+            let d = &entry[o..o + DESCRIPTOR_SIZE];
+            let mut reader = Cursor::new(&d[4..]);
+
+            if &d[..4] == ZERO_DESCRIPTOR_SIGNATURE {
+                let _reserved = reader.read_u32::<LittleEndian>().ok()?;
+                let length = reader.read_u64::<LittleEndian>().ok()?;
+                let file_offset = reader.read_u64::<LittleEndian>().ok()?;
+                if reader.read_u64::<LittleEndian>().ok()? != sequence_number {
+                    return None;
+                }
+
+                descriptors.push(Descriptor::Zero {
+                    file_offset,
+                    length,
+                });
+            } else if &d[..4] == DATA_DESCRIPTOR_SIGNATURE {
+                let mut trailing_bytes = [0u8; 4];
+                let mut leading_bytes = [0u8; 8];
+                trailing_bytes.copy_from_slice(&d[4..8]);
+                leading_bytes.copy_from_slice(&d[8..16]);
+                reader.set_position(12);
+                let file_offset = reader.read_u64::<LittleEndian>().ok()?;
+                if reader.read_u64::<LittleEndian>().ok()? != sequence_number {
+                    return None;
+                }
+
+                // data sector must belong to the same entry
+                let s = entry.get(data_sector..data_sector + SECTOR_SIZE)?;
+                let sequence_high = u32::from_le_bytes([s[4], s[5], s[6], s[7]]);
+                let sequence_low = u32::from_le_bytes([s[4092], s[4093], s[4094], s[4095]]);
+                if &s[..4] != DATA_SECTOR_SIGNATURE
+                    || ((sequence_high as u64) << 32 | sequence_low as u64) != sequence_number
+                {
+                    return None;
+                }
+                data_sector += SECTOR_SIZE;
+
+                descriptors.push(Descriptor::Data {
+                    file_offset,
+                    leading_bytes,
+                    trailing_bytes,
+                });
+            } else {
+                return None;
+            }
+        }
+
+        Some(Self {
+            offset,
+            length,
+            tail,
+            sequence_number,
+            flushed_file_offset,
+            descriptors,
+        })
+    }
+
+    fn data_sectors_offset(&self) -> usize {
+        round_up!(
+            ENTRY_HEADER_SIZE + self.descriptors.len() * DESCRIPTOR_SIZE,
+            SECTOR_SIZE
+        )
+    }
+}
+
+/// Finds the active log sequence, that is the valid sequence with the highest
+/// sequence number, entries are returned in the order they have to be replayed.
+fn find_active_sequence(log: &[u8], log_guid: uuid::Uuid) -> Vec<LogEntry> {
+    let mut best: Vec<LogEntry> = Vec::new();
+
+    for start in (0..log.len()).step_by(SECTOR_SIZE) {
+        let mut sequence: Vec<LogEntry> = Vec::new();
+        let mut offset = start;
+
+        while let Some(entry) = LogEntry::decode(log, offset, log_guid) {
+            if let Some(last) = sequence.last() {
+                if entry.sequence_number != last.sequence_number + 1 {
+                    break;
+                }
+            }
+
+            offset = (entry.offset + entry.length) % log.len();
+            sequence.push(entry);
+            if offset == start {
+                break;
+            }
+        }
+
+        // head entry's tail must point at an entry from this sequence,
+        // everything before the tail has already been flushed
+        let head = match sequence.last() {
+            Some(x) => x,
+            None => continue,
+        };
+        let tail = match sequence.iter().position(|x| x.offset == head.tail) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let newer = match best.last() {
+            Some(x) => x.sequence_number < head.sequence_number,
+            None => true,
+        };
+        if newer {
+            best = sequence.split_off(tail);
+        }
+    }
+
+    best
+}
+
+/// Replays log into backend, returns true if anything has been replayed
+pub fn replay(backend: &mut dyn Backend, header: &Header) -> Result<bool> {
+    if header.log_guid.is_nil() {
+        return Ok(false);
+    }
+
+    if header.log_version != 0 {
+        return Err(Error::InvalidVhdx(format!(
+            "unsupported log version {}",
+            header.log_version
+        )));
+    }
+
+    let mut log = vec![0u8; header.log_length as usize];
+    backend.seek(SeekFrom::Start(header.log_offset))?;
+    backend.read_exact(log.as_mut_slice())?;
+
+    let sequence = find_active_sequence(&log, header.log_guid);
+    if sequence.is_empty() {
+        return Err(Error::InvalidVhdx(
+            "log is not empty but no valid log sequence found".to_owned(),
+        ));
+    }
+
+    debug!(
+        "Replaying {} log entries, sequence {}-{}",
+        sequence.len(),
+        sequence.first().unwrap().sequence_number,
+        sequence.last().unwrap().sequence_number
+    );
+
+    for entry in sequence.iter() {
+        let mut data_sector = entry.offset + entry.data_sectors_offset();
+
+        for descriptor in entry.descriptors.iter() {
+            match descriptor {
+                Descriptor::Zero {
+                    file_offset,
+                    length,
+                } => {
+                    trace!("zero {} bytes at 0x{:x}", length, file_offset);
+                    backend.seek(SeekFrom::Start(*file_offset))?;
+                    let zero = [0u8; SECTOR_SIZE];
+                    let mut left = *length as usize;
+                    while left > 0 {
+                        let n = std::cmp::min(left, zero.len());
+                        backend.write_all(&zero[..n])?;
+                        left -= n;
+                    }
+                }
+                Descriptor::Data {
+                    file_offset,
+                    leading_bytes,
+                    trailing_bytes,
+                } => {
+                    trace!("write sector at 0x{:x}", file_offset);
+                    let s = &log[data_sector..data_sector + SECTOR_SIZE];
+                    backend.seek(SeekFrom::Start(*file_offset))?;
+                    backend.write_all(leading_bytes)?;
+                    backend.write_all(&s[8..SECTOR_SIZE - 4])?;
+                    backend.write_all(trailing_bytes)?;
+                    data_sector += SECTOR_SIZE;
+                }
+            }
+        }
+    }
+
+    // file must be at least as long as it was when the log was written
+    let flushed_file_offset = sequence.last().unwrap().flushed_file_offset;
+    let file_size = backend.seek(SeekFrom::End(0))?;
+    if file_size < flushed_file_offset {
+        backend.seek(SeekFrom::Start(flushed_file_offset - 1))?;
+        backend.write_all(&[0])?;
+    }
+    backend.flush()?;
+
+    Ok(true)
+}
@@ -0,0 +1,209 @@
+use crate::disk::vhdx::{read_guid, write_guid};
+use crate::{is_power_of_2, Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryInto;
+use std::io::Cursor;
+use uuid::Uuid;
+use uuid_macros::uuid;
+
+const FILE_PARAMETERS: Uuid = uuid! {"CAA16737-FA36-4D43-B3B6-33F0AA44E76B"};
+const VIRTUAL_DISK_SIZE: Uuid = uuid! {"2FA54224-CD1B-4876-B211-5DBED83BF4B8"};
+const VIRTUAL_DISK_ID: Uuid = uuid! {"BECA12AB-B2E6-4523-93EF-C309E000C746"};
+const LOGICAL_SECTOR_SIZE: Uuid = uuid! {"8141BF1D-A96F-4709-BA47-F233A8FAAB5F"};
+const PHYSICAL_SECTOR_SIZE: Uuid = uuid! {"CDA348C7-445D-4471-9CC9-E9885251C556"};
+const PARENT_LOCATOR: Uuid = uuid! {"A8D35F2D-B30B-454D-ABF7-D3D84834AB0C"};
+
+const FLAG_IS_VIRTUAL_DISK: u32 = 2;
+const FLAG_IS_REQUIRED: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub block_size: u32,
+    pub leave_blocks_allocated: bool,
+    pub has_parent: bool,
+    pub virtual_disk_size: u64,
+    pub virtual_disk_id: Uuid,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+}
+
+impl Metadata {
+    const SIGNATURE: &'static [u8; 8] = b"metadata";
+    const TABLE_SIZE: usize = 65536;
+    const MAX_ENTRIES: u16 = 2047;
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::TABLE_SIZE || &buf[..8] != Self::SIGNATURE {
+            return Err(Error::InvalidVhdx(
+                "invalid metadata table signature".to_owned(),
+            ));
+        }
+
+        let mut reader = Cursor::new(&buf[8..]);
+        let _reserved = reader.read_u16::<LittleEndian>()?;
+        let entry_count = reader.read_u16::<LittleEndian>()?;
+        if entry_count > Self::MAX_ENTRIES {
+            return Err(Error::InvalidVhdx(format!(
+                "too many metadata entries ({})",
+                entry_count
+            )));
+        }
+        reader.set_position(24);
+
+        let mut file_parameters = None;
+        let mut virtual_disk_size = None;
+        let mut virtual_disk_id = None;
+        let mut logical_sector_size = None;
+        let mut physical_sector_size = None;
+
+        for _ in 0..entry_count {
+            let item_id = read_guid(&mut reader)?;
+            let offset = reader.read_u32::<LittleEndian>()? as usize;
+            let length = reader.read_u32::<LittleEndian>()? as usize;
+            let flags = reader.read_u32::<LittleEndian>()?;
+            let _reserved = reader.read_u32::<LittleEndian>()?;
+
+            let data = buf.get(offset..offset + length).ok_or_else(|| {
+                Error::InvalidVhdx(format!("metadata item {} out of bounds", item_id))
+            })?;
+
+            macro_rules! item {
+                ($type:ty) => {{
+                    if data.len() < ::std::mem::size_of::<$type>() {
+                        return Err(Error::InvalidVhdx(format!(
+                            "metadata item {} too short",
+                            item_id
+                        )));
+                    }
+                    <$type>::from_le_bytes(
+                        data[..::std::mem::size_of::<$type>()].try_into().unwrap(),
+                    )
+                }};
+            }
+
+            match item_id {
+                FILE_PARAMETERS => file_parameters = Some((item!(u32), item!(u64) >> 32)),
+                VIRTUAL_DISK_SIZE => virtual_disk_size = Some(item!(u64)),
+                VIRTUAL_DISK_ID => {
+                    virtual_disk_id = Some(read_guid(&mut Cursor::new(data))?);
+                }
+                LOGICAL_SECTOR_SIZE => logical_sector_size = Some(item!(u32)),
+                PHYSICAL_SECTOR_SIZE => physical_sector_size = Some(item!(u32)),
+                PARENT_LOCATOR => {}
+                x if flags & FLAG_IS_REQUIRED != 0 => {
+                    return Err(Error::InvalidVhdx(format!(
+                        "unsupported required metadata item {}",
+                        x
+                    )))
+                }
+                x => warn!("Ignoring unknown metadata item {}", x),
+            }
+        }
+
+        macro_rules! required {
+            ($x:expr, $name:expr) => {
+                $x.ok_or_else(|| Error::InvalidVhdx(format!("{} metadata item missing", $name)))?
+            };
+        }
+
+        let (block_size, file_flags) = required!(file_parameters, "file parameters");
+        let metadata = Self {
+            block_size,
+            leave_blocks_allocated: file_flags & 1 != 0,
+            has_parent: file_flags & 2 != 0,
+            virtual_disk_size: required!(virtual_disk_size, "virtual disk size"),
+            virtual_disk_id: required!(virtual_disk_id, "virtual disk ID"),
+            logical_sector_size: required!(logical_sector_size, "logical sector size"),
+            physical_sector_size: required!(physical_sector_size, "physical sector size"),
+        };
+
+        if !is_power_of_2!(metadata.block_size)
+            || metadata.block_size < 1024 * 1024
+            || metadata.block_size > 256 * 1024 * 1024
+        {
+            return Err(Error::InvalidVhdx(format!(
+                "invalid block size {}",
+                metadata.block_size
+            )));
+        }
+        if metadata.logical_sector_size != 512 && metadata.logical_sector_size != 4096 {
+            return Err(Error::InvalidVhdx(format!(
+                "invalid logical sector size {}",
+                metadata.logical_sector_size
+            )));
+        }
+        if metadata.virtual_disk_size % metadata.logical_sector_size as u64 != 0 {
+            return Err(Error::InvalidVhdx(format!(
+                "virtual disk size {} is not multiple of sector size",
+                metadata.virtual_disk_size
+            )));
+        }
+
+        Ok(metadata)
+    }
+
+    /// Encodes metadata region, returned buffer has size of the whole region
+    pub fn encode(&self, region_size: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; region_size];
+        let mut data_offset = Self::TABLE_SIZE;
+
+        let mut file_flags = 0u32;
+        if self.leave_blocks_allocated {
+            file_flags |= 1;
+        }
+        if self.has_parent {
+            file_flags |= 2;
+        }
+        let mut file_parameters = [0u8; 8];
+        file_parameters[..4].copy_from_slice(&self.block_size.to_le_bytes());
+        file_parameters[4..].copy_from_slice(&file_flags.to_le_bytes());
+        let mut virtual_disk_id = Cursor::new([0u8; 16]);
+        write_guid(&mut virtual_disk_id, self.virtual_disk_id).unwrap();
+
+        let items: [(Uuid, u32, &[u8]); 5] = [
+            (FILE_PARAMETERS, FLAG_IS_REQUIRED, &file_parameters),
+            (
+                VIRTUAL_DISK_SIZE,
+                FLAG_IS_VIRTUAL_DISK | FLAG_IS_REQUIRED,
+                &self.virtual_disk_size.to_le_bytes(),
+            ),
+            (
+                VIRTUAL_DISK_ID,
+                FLAG_IS_VIRTUAL_DISK | FLAG_IS_REQUIRED,
+                virtual_disk_id.get_ref(),
+            ),
+            (
+                LOGICAL_SECTOR_SIZE,
+                FLAG_IS_VIRTUAL_DISK | FLAG_IS_REQUIRED,
+                &self.logical_sector_size.to_le_bytes(),
+            ),
+            (
+                PHYSICAL_SECTOR_SIZE,
+                FLAG_IS_VIRTUAL_DISK | FLAG_IS_REQUIRED,
+                &self.physical_sector_size.to_le_bytes(),
+            ),
+        ];
+
+        buf[..8].copy_from_slice(Self::SIGNATURE);
+        buf[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+
+        let (table, data_area) = buf.split_at_mut(Self::TABLE_SIZE);
+        let mut writer = Cursor::new(table);
+        writer.set_position(32);
+        for (item_id, flags, data) in items.iter() {
+            write_guid(&mut writer, *item_id).unwrap();
+            writer
+                .write_u32::<LittleEndian>(data_offset as u32)
+                .unwrap();
+            writer.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            writer.write_u32::<LittleEndian>(*flags).unwrap();
+            writer.write_u32::<LittleEndian>(0).unwrap();
+
+            let o = data_offset - Self::TABLE_SIZE;
+            data_area[o..o + data.len()].copy_from_slice(data);
+            data_offset += data.len();
+        }
+
+        buf
+    }
+}
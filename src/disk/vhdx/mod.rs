@@ -0,0 +1,641 @@
+mod header;
+mod log;
+mod metadata;
+mod region;
+
+use crate::disk::{seek_position, ArgumentMap, Backend, Disk, DiskFormat, MediaType};
+use crate::{is_power_of_2, round_up, utils::zero_u8_slice, Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use header::Header;
+use metadata::Metadata;
+use region::{RegionTable, RegionTableEntry, BAT_REGION, METADATA_REGION};
+use std::cmp::min;
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use uuid::Uuid;
+
+const FILE_IDENTIFIER_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * 1024, 256 * 1024];
+const MIB: u64 = 1024 * 1024;
+
+const DEFAULT_BLOCK_SIZE: u32 = 32 * 1024 * 1024;
+const LOG_OFFSET: u64 = MIB;
+const LOG_LENGTH: u32 = MIB as u32;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_LENGTH: u32 = MIB as u32;
+const BAT_OFFSET: u64 = 3 * MIB;
+
+const PAYLOAD_BLOCK_NOT_PRESENT: u64 = 0;
+const PAYLOAD_BLOCK_UNDEFINED: u64 = 1;
+const PAYLOAD_BLOCK_ZERO: u64 = 2;
+const PAYLOAD_BLOCK_UNMAPPED: u64 = 3;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+
+pub struct VhdxDisk {
+    backend: Box<dyn Backend>,
+    header: Header,
+    // index of slot current header was read from
+    header_index: usize,
+    headers_updated: bool,
+    metadata: Metadata,
+
+    bat_offset: u64,
+    bat: Vec<u64>,
+    chunk_ratio: u64,
+
+    // offset where next payload block will be allocated
+    free_data_block_offset: u64,
+    cursor: u64,
+}
+
+impl VhdxDisk {
+    pub fn open_with_argmap(backend: Box<dyn Backend>, _args: &ArgumentMap) -> Result<Self> {
+        Self::open(backend)
+    }
+
+    pub fn open(mut backend: Box<dyn Backend>) -> Result<Self> {
+        let mut signature = [0u8; 8];
+        backend.seek(SeekFrom::Start(0))?;
+        backend.read_exact(&mut signature)?;
+        if &signature != FILE_IDENTIFIER_SIGNATURE {
+            return Err(Error::InvalidVhdx("invalid file signature".to_owned()));
+        }
+
+        let (header_index, header) = Self::read_header(backend.as_mut())?;
+        debug!("Header:\n{}\n", header);
+
+        if header.version != 1 {
+            return Err(Error::InvalidVhdx(format!(
+                "unsupported version {}",
+                header.version
+            )));
+        }
+
+        let mut disk_header = header;
+        let mut disk_header_index = header_index;
+        if log::replay(backend.as_mut(), &disk_header)? {
+            // log has been applied, mark it empty so it won't be replayed again
+            disk_header.log_guid = Uuid::nil();
+            Self::write_header_twice(backend.as_mut(), &mut disk_header, &mut disk_header_index)?;
+        }
+
+        let region_table = Self::read_region_table(backend.as_mut())?;
+        for entry in region_table.entries.iter() {
+            if entry.required && entry.guid != BAT_REGION && entry.guid != METADATA_REGION {
+                return Err(Error::InvalidVhdx(format!(
+                    "unsupported required region {}",
+                    entry.guid
+                )));
+            }
+        }
+        let missing = |name| Error::InvalidVhdx(format!("{} region missing", name));
+        let bat_region = region_table
+            .find(BAT_REGION)
+            .ok_or_else(|| missing("BAT"))?;
+        let metadata_region = region_table
+            .find(METADATA_REGION)
+            .ok_or_else(|| missing("metadata"))?;
+
+        let mut metadata_encoded = vec![0u8; metadata_region.length as usize];
+        backend.seek(SeekFrom::Start(metadata_region.file_offset))?;
+        backend.read_exact(metadata_encoded.as_mut_slice())?;
+        let metadata = Metadata::decode(&metadata_encoded)?;
+        debug!("Metadata: {:?}", metadata);
+
+        if metadata.has_parent {
+            // TODO: support differencing disks
+            return Err(Error::InvalidVhdx(
+                "differencing VHDX disks are not supported".to_owned(),
+            ));
+        }
+
+        let chunk_ratio = Self::chunk_ratio(&metadata);
+        let total_entries = Self::bat_entries(&metadata);
+        if bat_region.length as u64 / 8 < total_entries {
+            return Err(Error::InvalidVhdx(format!(
+                "BAT region too small, expected at least {} entries",
+                total_entries
+            )));
+        }
+
+        let mut bat: Vec<u64> = Vec::with_capacity(total_entries as usize);
+        {
+            let mut bat_encoded = vec![0u8; total_entries as usize * 8];
+            backend.seek(SeekFrom::Start(bat_region.file_offset))?;
+            backend.read_exact(bat_encoded.as_mut_slice())?;
+            let mut reader = io::Cursor::new(bat_encoded);
+            for _ in 0..total_entries {
+                bat.push(reader.read_u64::<LittleEndian>()?);
+            }
+        }
+
+        let file_size = backend.seek(SeekFrom::End(0))?;
+        // new blocks must be 1 MiB aligned
+        let free_data_block_offset = round_up!(file_size, MIB);
+
+        Ok(Self {
+            backend,
+            header: disk_header,
+            header_index: disk_header_index,
+            headers_updated: false,
+            metadata,
+            bat_offset: bat_region.file_offset,
+            bat,
+            chunk_ratio,
+            free_data_block_offset,
+            cursor: 0,
+        })
+    }
+
+    pub fn create(backend: Box<dyn Backend>, disk_size: u64) -> Result<Self> {
+        Self::create_ex(backend, disk_size, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Creates dynamically sized VHDX disk, block_size must be power of 2
+    /// between 1 MiB and 256 MiB.
+    pub fn create_ex(
+        mut backend: Box<dyn Backend>,
+        disk_size: u64,
+        block_size: u32,
+    ) -> Result<Self> {
+        if !is_power_of_2!(block_size) || block_size < MIB as u32 || block_size > 256 * MIB as u32 {
+            return Err(Error::InvalidVhdx(format!(
+                "invalid block size {}",
+                block_size
+            )));
+        }
+
+        let metadata = Metadata {
+            block_size,
+            leave_blocks_allocated: false,
+            has_parent: false,
+            virtual_disk_size: round_up!(disk_size, 512u64),
+            virtual_disk_id: Uuid::new_v4(),
+            logical_sector_size: 512,
+            physical_sector_size: 4096,
+        };
+
+        let chunk_ratio = Self::chunk_ratio(&metadata);
+        let total_entries = Self::bat_entries(&metadata);
+        let bat_length = round_up!(total_entries * 8, MIB);
+
+        // file type identifier, creator is an UTF-16 string
+        let mut file_identifier = vec![0u8; HEADER_OFFSETS[0] as usize];
+        file_identifier[..8].copy_from_slice(FILE_IDENTIFIER_SIGNATURE);
+        for (i, c) in "diskutil".encode_utf16().enumerate() {
+            file_identifier[8 + i * 2..10 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        backend.seek(SeekFrom::Start(0))?;
+        backend.write_all(&file_identifier)?;
+
+        let mut header = Header {
+            sequence_number: 0,
+            file_write_guid: Uuid::new_v4(),
+            data_write_guid: Uuid::new_v4(),
+            log_guid: Uuid::nil(),
+            log_version: 0,
+            version: 1,
+            log_length: LOG_LENGTH,
+            log_offset: LOG_OFFSET,
+        };
+        let mut header_index = 1;
+        Self::write_header_twice(backend.as_mut(), &mut header, &mut header_index)?;
+
+        let region_table = RegionTable {
+            entries: vec![
+                RegionTableEntry {
+                    guid: BAT_REGION,
+                    file_offset: BAT_OFFSET,
+                    length: bat_length.try_into().unwrap(),
+                    required: true,
+                },
+                RegionTableEntry {
+                    guid: METADATA_REGION,
+                    file_offset: METADATA_OFFSET,
+                    length: METADATA_LENGTH,
+                    required: true,
+                },
+            ],
+        };
+        let region_table_encoded = region_table.encode();
+        for offset in REGION_TABLE_OFFSETS.iter().copied() {
+            backend.seek(SeekFrom::Start(offset))?;
+            backend.write_all(&region_table_encoded)?;
+        }
+
+        // log region must be zeroed
+        backend.seek(SeekFrom::Start(LOG_OFFSET))?;
+        backend.write_all(vec![0u8; LOG_LENGTH as usize].as_slice())?;
+
+        backend.seek(SeekFrom::Start(METADATA_OFFSET))?;
+        backend.write_all(&metadata.encode(METADATA_LENGTH as usize))?;
+
+        backend.seek(SeekFrom::Start(BAT_OFFSET))?;
+        backend.write_all(vec![0u8; bat_length as usize].as_slice())?;
+        backend.flush()?;
+
+        Ok(Self {
+            backend,
+            header,
+            header_index,
+            headers_updated: true,
+            metadata,
+            bat_offset: BAT_OFFSET,
+            bat: vec![PAYLOAD_BLOCK_NOT_PRESENT; total_entries as usize],
+            chunk_ratio,
+            free_data_block_offset: BAT_OFFSET + bat_length,
+            cursor: 0,
+        })
+    }
+
+    fn chunk_ratio(metadata: &Metadata) -> u64 {
+        ((1u64 << 23) * metadata.logical_sector_size as u64) / metadata.block_size as u64
+    }
+
+    fn bat_entries(metadata: &Metadata) -> u64 {
+        let data_blocks = round_up!(metadata.virtual_disk_size, metadata.block_size as u64)
+            / metadata.block_size as u64;
+        let chunk_ratio = Self::chunk_ratio(metadata);
+
+        // every chunk_ratio payload entries are followed by sector bitmap entry
+        data_blocks + data_blocks.saturating_sub(1) / chunk_ratio
+    }
+
+    /// Reads both headers, returns the valid one with the highest sequence number
+    fn read_header(backend: &mut dyn Backend) -> Result<(usize, Header)> {
+        let mut current: Option<(usize, Header)> = None;
+
+        for (i, offset) in HEADER_OFFSETS.iter().copied().enumerate() {
+            let mut buf = vec![0u8; Header::SIZE];
+            backend.seek(SeekFrom::Start(offset))?;
+            backend.read_exact(buf.as_mut_slice())?;
+
+            match Header::decode(&buf) {
+                Ok(header) => {
+                    let newer = match current {
+                        Some((_, ref x)) => header.sequence_number > x.sequence_number,
+                        None => true,
+                    };
+                    if newer {
+                        current = Some((i, header));
+                    }
+                }
+                Err(e) => warn!("Header {} is invalid: {}", i + 1, e),
+            }
+        }
+
+        current.ok_or_else(|| Error::InvalidVhdx("no valid header found".to_owned()))
+    }
+
+    /// Writes header into both slots, so that both copies are valid and up to date
+    fn write_header_twice(
+        backend: &mut dyn Backend,
+        header: &mut Header,
+        header_index: &mut usize,
+    ) -> io::Result<()> {
+        for _ in 0..2 {
+            header.sequence_number += 1;
+            *header_index ^= 1;
+
+            backend.seek(SeekFrom::Start(HEADER_OFFSETS[*header_index]))?;
+            backend.write_all(&header.encode())?;
+            backend.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn read_region_table(backend: &mut dyn Backend) -> Result<RegionTable> {
+        let mut last_error = None;
+
+        for (i, offset) in REGION_TABLE_OFFSETS.iter().copied().enumerate() {
+            let mut buf = vec![0u8; RegionTable::SIZE];
+            backend.seek(SeekFrom::Start(offset))?;
+            backend.read_exact(buf.as_mut_slice())?;
+
+            match RegionTable::decode(&buf) {
+                Ok(x) => return Ok(x),
+                Err(e) => {
+                    warn!("Region table {} is invalid: {}", i + 1, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap())
+    }
+
+    /// Header must be updated with new write GUIDs before the first modification
+    fn update_headers_before_write(&mut self) -> io::Result<()> {
+        if !self.headers_updated {
+            self.header.file_write_guid = Uuid::new_v4();
+            self.header.data_write_guid = Uuid::new_v4();
+            Self::write_header_twice(
+                self.backend.as_mut(),
+                &mut self.header,
+                &mut self.header_index,
+            )?;
+            self.headers_updated = true;
+        }
+
+        Ok(())
+    }
+
+    fn bat_index(&self, offset: u64) -> usize {
+        let block = offset / self.metadata.block_size as u64;
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    /// Returns offset in file or None if block is not allocated
+    fn get_offset(&self, offset: u64) -> io::Result<Option<u64>> {
+        let e = self.bat[self.bat_index(offset)];
+        let offset_in_block = offset % self.metadata.block_size as u64;
+
+        match e & 7 {
+            PAYLOAD_BLOCK_NOT_PRESENT
+            | PAYLOAD_BLOCK_UNDEFINED
+            | PAYLOAD_BLOCK_ZERO
+            | PAYLOAD_BLOCK_UNMAPPED => Ok(None),
+            PAYLOAD_BLOCK_FULLY_PRESENT => Ok(Some((e >> 20 << 20) + offset_in_block)),
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "partially present block in non-differencing disk",
+            )),
+            x => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid BAT entry state {}", x),
+            )),
+        }
+    }
+
+    fn alloc_block(&mut self, offset: u64) -> io::Result<u64> {
+        let bat_index = self.bat_index(offset);
+        let block_offset = self.free_data_block_offset;
+        let block_size = self.metadata.block_size as u64;
+        trace!("allocating block {} at 0x{:x}", bat_index, block_offset);
+
+        // extend file, new area reads as zeros
+        self.backend
+            .seek(SeekFrom::Start(block_offset + block_size - 1))?;
+        self.backend.write_all(&[0])?;
+
+        // TODO: BAT updates should go through the log
+        let e = block_offset | PAYLOAD_BLOCK_FULLY_PRESENT;
+        self.backend
+            .seek(SeekFrom::Start(self.bat_offset + bat_index as u64 * 8))?;
+        self.backend.write_u64::<LittleEndian>(e)?;
+
+        self.bat[bat_index] = e;
+        self.free_data_block_offset += block_size;
+
+        Ok(block_offset + offset % block_size)
+    }
+}
+
+impl Disk for VhdxDisk {
+    fn disk_size(&self) -> u64 {
+        self.metadata.virtual_disk_size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.metadata.logical_sector_size
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::HDD
+    }
+
+    fn disk_format(&self) -> DiskFormat {
+        DiskFormat::VHDX
+    }
+}
+
+impl Seek for VhdxDisk {
+    fn seek(&mut self, s: SeekFrom) -> io::Result<u64> {
+        self.cursor = seek_position(self.cursor, self.disk_size(), s)?;
+        Ok(self.cursor)
+    }
+}
+
+impl Read for VhdxDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = self.metadata.block_size as u64;
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_read = 0usize;
+
+        while left > 0 {
+            let n = min(left, (block_size - self.cursor % block_size) as usize);
+
+            if let Some(offset_in_file) = self.get_offset(self.cursor)? {
+                self.backend.seek(SeekFrom::Start(offset_in_file))?;
+                self.backend
+                    .read_exact(&mut buf[total_read..total_read + n])?;
+            } else {
+                zero_u8_slice(&mut buf[total_read..total_read + n]);
+            }
+
+            left -= n;
+            self.cursor += n as u64;
+            total_read += n;
+        }
+
+        Ok(total_read)
+    }
+}
+
+impl Write for VhdxDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let block_size = self.metadata.block_size as u64;
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_written = 0usize;
+
+        if left > 0 {
+            self.update_headers_before_write()?;
+        }
+
+        while left > 0 {
+            let n = min(left, (block_size - self.cursor % block_size) as usize);
+            let data = &buf[total_written..total_written + n];
+
+            let offset_in_file = match self.get_offset(self.cursor)? {
+                Some(x) => Some(x),
+                // unallocated blocks already read as zeros
+                None if data.iter().all(|x| *x == 0) => None,
+                None => Some(self.alloc_block(self.cursor)?),
+            };
+
+            if let Some(offset_in_file) = offset_in_file {
+                self.backend.seek(SeekFrom::Start(offset_in_file))?;
+                self.backend.write_all(data)?;
+            }
+
+            left -= n;
+            self.cursor += n as u64;
+            total_written += n;
+        }
+
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
+
+/// Computes CRC-32C of buf assuming 4 byte checksum field at checksum_offset is zero
+fn checksum(buf: &[u8], checksum_offset: usize) -> u32 {
+    let mut c = crc32::update(0, &crc32::CASTAGNOLI_TABLE, &buf[..checksum_offset]);
+    c = crc32::update(c, &crc32::CASTAGNOLI_TABLE, &[0u8; 4]);
+    crc32::update(c, &crc32::CASTAGNOLI_TABLE, &buf[checksum_offset + 4..])
+}
+
+fn read_guid<T>(reader: &mut T) -> io::Result<Uuid>
+where
+    T: Read,
+{
+    let p0 = reader.read_u32::<LittleEndian>()?;
+    let p1 = reader.read_u16::<LittleEndian>()?;
+    let p2 = reader.read_u16::<LittleEndian>()?;
+    let mut p3 = [0u8; 8];
+    reader.read_exact(&mut p3)?;
+
+    Ok(Uuid::from_fields(p0, p1, p2, &p3).unwrap())
+}
+
+fn write_guid<T>(writer: &mut T, uuid: Uuid) -> io::Result<()>
+where
+    T: Write,
+{
+    let (p0, p1, p2, p3) = uuid.as_fields();
+
+    writer.write_u32::<LittleEndian>(p0)?;
+    writer.write_u16::<LittleEndian>(p1)?;
+    writer.write_u16::<LittleEndian>(p2)?;
+    writer.write_all(p3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::MemoryBackend;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn open(storage: &Rc<RefCell<Vec<u8>>>) -> VhdxDisk {
+        VhdxDisk::open(Box::new(MemoryBackend::from_storage(storage.clone()))).unwrap()
+    }
+
+    #[test]
+    fn test_create_and_open() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        let mut disk = VhdxDisk::create_ex(Box::new(backend), 8 * MIB, MIB as u32).unwrap();
+        disk.seek(SeekFrom::Start(5 * MIB)).unwrap();
+        disk.write_all(b"block").unwrap();
+        disk.seek(SeekFrom::Start(MIB - 3)).unwrap();
+        disk.write_all(b"vhdx block").unwrap();
+        drop(disk);
+
+        // payload blocks are appended after the BAT in allocation order
+        let file_size = storage.borrow().len() as u64;
+        assert_eq!(file_size, BAT_OFFSET + MIB + 3 * MIB);
+
+        let mut disk = open(&storage);
+        assert_eq!(disk.disk_size(), 8 * MIB);
+        assert_eq!(disk.sector_size(), 512);
+        assert_eq!(disk.get_offset(5 * MIB).unwrap(), Some(BAT_OFFSET + MIB));
+        assert_eq!(disk.get_offset(0).unwrap(), Some(BAT_OFFSET + 2 * MIB));
+        assert_eq!(disk.get_offset(MIB).unwrap(), Some(BAT_OFFSET + 3 * MIB));
+        assert_eq!(disk.get_offset(3 * MIB).unwrap(), None);
+
+        let mut buf = [0u8; 10];
+        disk.seek(SeekFrom::Start(MIB - 3)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"vhdx block");
+    }
+
+    #[test]
+    fn test_log_replay() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+
+        let mut disk = VhdxDisk::create_ex(Box::new(backend), 4 * MIB, MIB as u32).unwrap();
+        disk.write_all(&[0x11; 8192]).unwrap();
+        let block_offset = disk.get_offset(0).unwrap().unwrap();
+        drop(disk);
+
+        let log_guid = Uuid::new_v4();
+        let sequence_number = 0x1_0000_0002u64;
+        let file_size = storage.borrow().len() as u64;
+
+        // single entry with one data descriptor overwriting second sector of block
+        let mut entry = vec![0u8; 8192];
+        {
+            let mut writer = io::Cursor::new(&mut entry[..]);
+            writer.write_all(b"loge").unwrap();
+            writer.write_u32::<LittleEndian>(0).unwrap();
+            writer.write_u32::<LittleEndian>(8192).unwrap();
+            writer.write_u32::<LittleEndian>(0).unwrap();
+            writer.write_u64::<LittleEndian>(sequence_number).unwrap();
+            writer.write_u32::<LittleEndian>(1).unwrap();
+            writer.write_u32::<LittleEndian>(0).unwrap();
+            write_guid(&mut writer, log_guid).unwrap();
+            writer.write_u64::<LittleEndian>(file_size).unwrap();
+            writer.write_u64::<LittleEndian>(file_size).unwrap();
+
+            writer.write_all(b"desc").unwrap();
+            writer.write_all(b"TAIL").unwrap();
+            writer.write_all(b"LEADING!").unwrap();
+            writer
+                .write_u64::<LittleEndian>(block_offset + 4096)
+                .unwrap();
+            writer.write_u64::<LittleEndian>(sequence_number).unwrap();
+
+            writer.set_position(4096);
+            writer.write_all(b"data").unwrap();
+            writer
+                .write_u32::<LittleEndian>((sequence_number >> 32) as u32)
+                .unwrap();
+            writer.write_all(&[0x22; 4084]).unwrap();
+            writer
+                .write_u32::<LittleEndian>(sequence_number as u32)
+                .unwrap();
+        }
+        let c = checksum(&entry, 4);
+        entry[4..8].copy_from_slice(&c.to_le_bytes());
+
+        {
+            let mut backend = MemoryBackend::from_storage(storage.clone());
+            backend.seek(SeekFrom::Start(LOG_OFFSET)).unwrap();
+            backend.write_all(&entry).unwrap();
+
+            let (mut header_index, mut header) = VhdxDisk::read_header(&mut backend).unwrap();
+            header.log_guid = log_guid;
+            VhdxDisk::write_header_twice(&mut backend, &mut header, &mut header_index).unwrap();
+        }
+
+        let mut disk = open(&storage);
+        assert!(disk.header.log_guid.is_nil());
+
+        let mut buf = vec![0u8; 8192];
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|x| *x == 0x11));
+        assert_eq!(&buf[4096..4104], b"LEADING!");
+        assert!(buf[4104..8188].iter().all(|x| *x == 0x22));
+        assert_eq!(&buf[8188..], b"TAIL");
+
+        // log must not be replayed again
+        let (_, header) = VhdxDisk::read_header(&mut MemoryBackend::from_storage(storage)).unwrap();
+        assert!(header.log_guid.is_nil());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::disk::vhdx::{checksum, read_guid, write_guid};
+use crate::{Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use uuid::Uuid;
+use uuid_macros::uuid;
+
+pub const BAT_REGION: Uuid = uuid! {"2DC27766-F623-4200-9D64-115E9BFD4A08"};
+pub const METADATA_REGION: Uuid = uuid! {"8B7CA206-4790-4B9A-B8FE-575F050F886E"};
+
+#[derive(Debug, Clone)]
+pub struct RegionTableEntry {
+    pub guid: Uuid,
+    pub file_offset: u64,
+    pub length: u32,
+    pub required: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionTable {
+    pub entries: Vec<RegionTableEntry>,
+}
+
+impl RegionTable {
+    pub const SIZE: usize = 65536;
+    const SIGNATURE: &'static [u8; 4] = b"regi";
+    const MAX_ENTRIES: u32 = 2047;
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        debug_assert_eq!(buf.len(), Self::SIZE);
+
+        if &buf[..4] != Self::SIGNATURE {
+            return Err(Error::InvalidVhdx(
+                "invalid region table signature".to_owned(),
+            ));
+        }
+
+        let mut reader = Cursor::new(&buf[4..]);
+        let stored_checksum = reader.read_u32::<LittleEndian>()?;
+        let computed_checksum = checksum(buf, 4);
+        if stored_checksum != computed_checksum {
+            return Err(Error::InvalidVhdx(format!(
+                "region table checksum mismatch, computed 0x{:08X} but the checksum is 0x{:08X}",
+                computed_checksum, stored_checksum
+            )));
+        }
+
+        let entry_count = reader.read_u32::<LittleEndian>()?;
+        if entry_count > Self::MAX_ENTRIES {
+            return Err(Error::InvalidVhdx(format!(
+                "too many region table entries ({})",
+                entry_count
+            )));
+        }
+        let _reserved = reader.read_u32::<LittleEndian>()?;
+
+        let mut entries = Vec::with_capacity(entry_count as usize);
+        for _ in 0..entry_count {
+            let guid = read_guid(&mut reader)?;
+            let file_offset = reader.read_u64::<LittleEndian>()?;
+            let length = reader.read_u32::<LittleEndian>()?;
+            let required = reader.read_u32::<LittleEndian>()? & 1 != 0;
+
+            entries.push(RegionTableEntry {
+                guid,
+                file_offset,
+                length,
+                required,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; Self::SIZE];
+
+        {
+            let mut writer = Cursor::new(&mut buf[..]);
+            writer.get_mut()[..4].copy_from_slice(Self::SIGNATURE);
+            writer.set_position(8);
+            writer
+                .write_u32::<LittleEndian>(self.entries.len() as u32)
+                .unwrap();
+            writer.write_u32::<LittleEndian>(0).unwrap();
+
+            for entry in self.entries.iter() {
+                write_guid(&mut writer, entry.guid).unwrap();
+                writer.write_u64::<LittleEndian>(entry.file_offset).unwrap();
+                writer.write_u32::<LittleEndian>(entry.length).unwrap();
+                writer
+                    .write_u32::<LittleEndian>(entry.required as u32)
+                    .unwrap();
+            }
+        }
+
+        let c = checksum(&buf, 4);
+        buf[4..8].copy_from_slice(&c.to_le_bytes());
+
+        buf
+    }
+
+    pub fn find(&self, guid: Uuid) -> Option<&RegionTableEntry> {
+        self.entries.iter().find(|x| x.guid == guid)
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{Error, Result};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtentAccess {
+    ReadWrite,
+    ReadOnly,
+    NoAccess,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExtentType {
+    Flat,
+    Sparse,
+    Zero,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtentDescriptor {
+    pub access: ExtentAccess,
+    /// Extent size in sectors
+    pub sectors: u64,
+    pub extent_type: ExtentType,
+    pub file_name: Option<String>,
+    /// Offset of data in flat extent file, in sectors
+    pub offset: u64,
+}
+
+/// Text descriptor, either embedded in sparse extent or stored in separate file
+#[derive(Debug, Clone)]
+pub struct Descriptor {
+    pub version: u32,
+    pub cid: u32,
+    pub parent_cid: u32,
+    pub create_type: String,
+    pub extents: Vec<ExtentDescriptor>,
+    /// Disk database entries, stored in order of appearance
+    pub ddb: Vec<(String, String)>,
+}
+
+impl Descriptor {
+    pub const NO_PARENT_CID: u32 = 0xffffffff;
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut version = 1;
+        let mut cid = 0;
+        let mut parent_cid = Self::NO_PARENT_CID;
+        let mut create_type = None;
+        let mut extents = Vec::new();
+        let mut ddb = Vec::new();
+
+        let invalid =
+            |line: &str| Error::InvalidVmdk(format!("invalid descriptor line {:?}", line));
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with("RW ")
+                || line.starts_with("RDONLY ")
+                || line.starts_with("NOACCESS ")
+            {
+                extents.push(Self::parse_extent(line).ok_or_else(|| invalid(line))?);
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim().trim_matches('"')),
+                None => return Err(invalid(line)),
+            };
+
+            match key {
+                "version" => version = value.parse().map_err(|_| invalid(line))?,
+                "CID" => cid = u32::from_str_radix(value, 16).map_err(|_| invalid(line))?,
+                "parentCID" => {
+                    parent_cid = u32::from_str_radix(value, 16).map_err(|_| invalid(line))?
+                }
+                "createType" => create_type = Some(value.to_owned()),
+                x if x.starts_with("ddb.") => ddb.push((x.to_owned(), value.to_owned())),
+                x => trace!("ignoring descriptor key {}", x),
+            }
+        }
+
+        Ok(Self {
+            version,
+            cid,
+            parent_cid,
+            create_type: create_type
+                .ok_or_else(|| Error::InvalidVmdk("createType missing".to_owned()))?,
+            extents,
+            ddb,
+        })
+    }
+
+    fn parse_extent(line: &str) -> Option<ExtentDescriptor> {
+        let mut tokens = line.split_whitespace();
+        let access = match tokens.next()? {
+            "RW" => ExtentAccess::ReadWrite,
+            "RDONLY" => ExtentAccess::ReadOnly,
+            "NOACCESS" => ExtentAccess::NoAccess,
+            _ => return None,
+        };
+        let sectors = tokens.next()?.parse().ok()?;
+        let extent_type = match tokens.next()? {
+            "FLAT" | "VMFS" => ExtentType::Flat,
+            "SPARSE" => ExtentType::Sparse,
+            "ZERO" => ExtentType::Zero,
+            _ => return None,
+        };
+
+        // file name is quoted and may contain spaces
+        let (file_name, rest) = match (line.find('"'), line.rfind('"')) {
+            (Some(s), Some(e)) if s < e => (Some(line[s + 1..e].to_owned()), &line[e + 1..]),
+            _ => (None, ""),
+        };
+        let offset = match rest.trim() {
+            "" => 0,
+            x => x.parse().ok()?,
+        };
+
+        if file_name.is_none() && extent_type != ExtentType::Zero {
+            return None;
+        }
+
+        Some(ExtentDescriptor {
+            access,
+            sectors,
+            extent_type,
+            file_name,
+            offset,
+        })
+    }
+
+    pub fn ddb_get(&self, key: &str) -> Option<&str> {
+        self.ddb
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# Disk DescriptorFile")?;
+        writeln!(f, "version={}", self.version)?;
+        writeln!(f, "CID={:08x}", self.cid)?;
+        writeln!(f, "parentCID={:08x}", self.parent_cid)?;
+        writeln!(f, "createType=\"{}\"", self.create_type)?;
+        writeln!(f)?;
+        writeln!(f, "# Extent description")?;
+        for extent in self.extents.iter() {
+            let access = match extent.access {
+                ExtentAccess::ReadWrite => "RW",
+                ExtentAccess::ReadOnly => "RDONLY",
+                ExtentAccess::NoAccess => "NOACCESS",
+            };
+            let extent_type = match extent.extent_type {
+                ExtentType::Flat => "FLAT",
+                ExtentType::Sparse => "SPARSE",
+                ExtentType::Zero => "ZERO",
+            };
+            write!(f, "{} {} {}", access, extent.sectors, extent_type)?;
+            if let Some(file_name) = extent.file_name.as_ref() {
+                write!(f, " \"{}\"", file_name)?;
+            }
+            if extent.extent_type == ExtentType::Flat {
+                write!(f, " {}", extent.offset)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "# The Disk Data Base")?;
+        writeln!(f, "#DDB")?;
+        writeln!(f)?;
+        for (key, value) in self.ddb.iter() {
+            writeln!(f, "{} = \"{}\"", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Descriptor, ExtentAccess, ExtentType};
+
+    #[test]
+    fn test_parse() {
+        crate::tests_init();
+
+        let text = "# Disk DescriptorFile\n\
+                    version=1\n\
+                    encoding=\"UTF-8\"\n\
+                    CID=7e5f0a1b\n\
+                    parentCID=ffffffff\n\
+                    createType=\"twoGbMaxExtentFlat\"\n\
+                    \n\
+                    # Extent description\n\
+                    RW 4192256 FLAT \"my disk-f001.vmdk\" 0\n\
+                    RDONLY 2048 FLAT \"my disk-f002.vmdk\" 16\n\
+                    RW 1024 ZERO\n\
+                    \n\
+                    ddb.adapterType = \"lsilogic\"\n\
+                    ddb.geometry.heads = \"255\"\n";
+
+        let descriptor = Descriptor::parse(text).unwrap();
+        assert_eq!(descriptor.cid, 0x7e5f0a1b);
+        assert_eq!(descriptor.parent_cid, Descriptor::NO_PARENT_CID);
+        assert_eq!(descriptor.create_type, "twoGbMaxExtentFlat");
+        assert_eq!(descriptor.extents.len(), 3);
+        assert_eq!(descriptor.extents[0].sectors, 4192256);
+        assert_eq!(
+            descriptor.extents[0].file_name.as_deref(),
+            Some("my disk-f001.vmdk")
+        );
+        assert_eq!(descriptor.extents[1].access, ExtentAccess::ReadOnly);
+        assert_eq!(descriptor.extents[1].offset, 16);
+        assert_eq!(descriptor.extents[2].extent_type, ExtentType::Zero);
+        assert_eq!(descriptor.ddb_get("ddb.geometry.heads"), Some("255"));
+
+        // encoded descriptor must parse back to the same thing
+        let reparsed = Descriptor::parse(&descriptor.to_string()).unwrap();
+        assert_eq!(reparsed.extents.len(), 3);
+        assert_eq!(reparsed.extents[1].offset, 16);
+        assert_eq!(reparsed.ddb, descriptor.ddb);
+
+        assert!(Descriptor::parse("RW abc FLAT \"x\"\ncreateType=\"x\"").is_err());
+    }
+}
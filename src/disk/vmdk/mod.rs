@@ -0,0 +1,612 @@
+mod descriptor;
+mod sparse;
+
+pub use descriptor::{Descriptor, ExtentAccess, ExtentDescriptor, ExtentType};
+pub use sparse::SparseExtentHeader;
+
+use crate::disk::{seek_position, ArgumentMap, Backend, Disk, DiskFormat, FileBackend, MediaType};
+use crate::{is_power_of_2, round_up, utils::zero_u8_slice, Error, Result};
+use sparse::{SparseExtent, FLAG_REDUNDANT_GRAIN_TABLE, FLAG_VALID_NEWLINE_TEST, SECTOR_SIZE};
+use std::cmp::min;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// 64 KiB grains, same as VMware
+const DEFAULT_GRAIN_SIZE: u64 = 128;
+const NUM_GTES_PER_GT: u32 = 512;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+// text descriptors are small, anything bigger is most likely not a descriptor
+const MAX_DESCRIPTOR_SIZE: u64 = 1024 * 1024;
+
+enum ExtentData {
+    Flat {
+        backend: Box<dyn Backend>,
+        offset: u64,
+    },
+    Sparse(SparseExtent),
+    Zero,
+}
+
+struct Extent {
+    /// Position of extent in virtual disk, in bytes
+    start: u64,
+    size: u64,
+    access: ExtentAccess,
+    data: ExtentData,
+}
+
+pub struct VmdkDisk {
+    descriptor: Descriptor,
+    extents: Vec<Extent>,
+    disk_size: u64,
+    cursor: u64,
+}
+
+impl VmdkDisk {
+    /// Extent files are searched relative to "path" argument
+    pub fn open_with_argmap(backend: Box<dyn Backend>, args: &ArgumentMap) -> Result<Self> {
+        Self::open_ex(backend, args.get_str("path").map(Path::new))
+    }
+
+    pub fn open(backend: Box<dyn Backend>) -> Result<Self> {
+        Self::open_ex(backend, None)
+    }
+
+    fn open_ex(mut backend: Box<dyn Backend>, path: Option<&Path>) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        backend.seek(SeekFrom::Start(0))?;
+        let n = backend.read(&mut magic)?;
+        backend.seek(SeekFrom::Start(0))?;
+
+        if n == magic.len() && &magic == SparseExtentHeader::MAGIC {
+            Self::open_monolithic_sparse(backend)
+        } else {
+            let length = backend.seek(SeekFrom::End(0))?;
+            if length > MAX_DESCRIPTOR_SIZE {
+                return Err(Error::InvalidVmdk("descriptor file too big".to_owned()));
+            }
+
+            let mut text = vec![0u8; length as usize];
+            backend.seek(SeekFrom::Start(0))?;
+            backend.read_exact(text.as_mut_slice())?;
+            let text = String::from_utf8(text)
+                .map_err(|_| Error::InvalidVmdk("descriptor is not valid UTF-8".to_owned()))?;
+
+            let descriptor = Descriptor::parse(&text)?;
+            Self::from_descriptor(descriptor, |extent| {
+                let file_name = extent.file_name.as_deref().unwrap();
+                Self::open_extent_file(file_name, extent.access, path)
+            })
+        }
+    }
+
+    /// Opens sparse extent with embedded descriptor
+    fn open_monolithic_sparse(backend: Box<dyn Backend>) -> Result<Self> {
+        let mut extent = SparseExtent::open(backend)?;
+        let text = extent.read_descriptor()?.ok_or_else(|| {
+            Error::InvalidVmdk(
+                "sparse extent has no embedded descriptor, open descriptor file instead".to_owned(),
+            )
+        })?;
+
+        let descriptor = Descriptor::parse(&text)?;
+        if descriptor
+            .extents
+            .iter()
+            .any(|x| x.extent_type == ExtentType::Flat)
+        {
+            return Err(Error::InvalidVmdk(
+                "embedded descriptor can't describe flat extents".to_owned(),
+            ));
+        }
+        let sparse_extents = descriptor
+            .extents
+            .iter()
+            .filter(|x| x.extent_type == ExtentType::Sparse)
+            .count();
+        if sparse_extents != 1 {
+            return Err(Error::InvalidVmdk(format!(
+                "embedded descriptor must describe exactly one sparse extent, found {}",
+                sparse_extents
+            )));
+        }
+
+        let mut extent = Some(extent);
+        Self::from_descriptor(descriptor, |_| {
+            extent.take().map(|x| x.into_backend()).ok_or_else(|| {
+                Error::InvalidVmdk("embedded descriptor describes another extent file".to_owned())
+            })
+        })
+    }
+
+    /// Opens extent file, read-only extents and extents which can't be opened
+    /// for writing are opened read-only
+    fn open_extent_file(
+        name: &str,
+        access: ExtentAccess,
+        path: Option<&Path>,
+    ) -> Result<Box<dyn Backend>> {
+        let mut extent_path = PathBuf::from(name);
+        if extent_path.is_relative() {
+            if let Some(dir) = path.and_then(|x| x.parent()) {
+                extent_path = dir.join(extent_path);
+            }
+        }
+        debug!("Opening extent {}", extent_path.display());
+
+        let file = if access == ExtentAccess::ReadWrite {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&extent_path)
+                .or_else(|_| OpenOptions::new().read(true).open(&extent_path))
+        } else {
+            OpenOptions::new().read(true).open(&extent_path)
+        }
+        .map_err(|_| Error::InvalidVmdk(format!("extent file {:?} not found", name)))?;
+
+        Ok(FileBackend::new(file)?)
+    }
+
+    /// Builds disk from descriptor, open is called for every extent backed by a file
+    fn from_descriptor<F>(descriptor: Descriptor, mut open: F) -> Result<Self>
+    where
+        F: FnMut(&ExtentDescriptor) -> Result<Box<dyn Backend>>,
+    {
+        debug!("Descriptor:\n{}", descriptor);
+
+        if descriptor.parent_cid != Descriptor::NO_PARENT_CID {
+            return Err(Error::InvalidVmdk(
+                "child (delta) disks are not supported".to_owned(),
+            ));
+        }
+        if descriptor.extents.is_empty() {
+            return Err(Error::InvalidVmdk("descriptor has no extents".to_owned()));
+        }
+
+        let mut extents = Vec::with_capacity(descriptor.extents.len());
+        let mut start = 0u64;
+        for x in descriptor.extents.iter() {
+            let size = x.sectors * SECTOR_SIZE;
+            let data = match x.extent_type {
+                ExtentType::Flat => ExtentData::Flat {
+                    backend: open(x)?,
+                    offset: x.offset * SECTOR_SIZE,
+                },
+                ExtentType::Sparse => {
+                    let extent = SparseExtent::open(open(x)?)?;
+                    if extent.header().capacity < x.sectors {
+                        return Err(Error::InvalidVmdk(format!(
+                            "sparse extent {:?} smaller than described",
+                            x.file_name.as_deref().unwrap_or_default()
+                        )));
+                    }
+                    ExtentData::Sparse(extent)
+                }
+                ExtentType::Zero => ExtentData::Zero,
+            };
+
+            extents.push(Extent {
+                start,
+                size,
+                access: x.access,
+                data,
+            });
+            start += size;
+        }
+
+        Ok(Self {
+            descriptor,
+            extents,
+            disk_size: start,
+            cursor: 0,
+        })
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    fn new_descriptor(
+        create_type: &str,
+        extents: Vec<ExtentDescriptor>,
+        disk_size: u64,
+    ) -> Descriptor {
+        // geometry is only informational, use the same one as VMware for IDE disks
+        let cylinders = min(disk_size / SECTOR_SIZE / (16 * 63), 16383);
+
+        Descriptor {
+            version: 1,
+            cid: uuid::Uuid::new_v4().as_fields().0,
+            parent_cid: Descriptor::NO_PARENT_CID,
+            create_type: create_type.to_owned(),
+            extents,
+            ddb: vec![
+                ("ddb.virtualHWVersion".to_owned(), "4".to_owned()),
+                ("ddb.geometry.cylinders".to_owned(), cylinders.to_string()),
+                ("ddb.geometry.heads".to_owned(), "16".to_owned()),
+                ("ddb.geometry.sectors".to_owned(), "63".to_owned()),
+                ("ddb.adapterType".to_owned(), "ide".to_owned()),
+            ],
+        }
+    }
+
+    /// Creates monolithicSparse image, file_name is the name of the image file
+    /// as recorded in embedded descriptor
+    pub fn create_sparse(
+        mut backend: Box<dyn Backend>,
+        disk_size: u64,
+        file_name: &str,
+    ) -> Result<Self> {
+        let capacity = round_up!(disk_size, SECTOR_SIZE) / SECTOR_SIZE;
+        let descriptor = Self::new_descriptor(
+            "monolithicSparse",
+            vec![ExtentDescriptor {
+                access: ExtentAccess::ReadWrite,
+                sectors: capacity,
+                extent_type: ExtentType::Sparse,
+                file_name: Some(file_name.to_owned()),
+                offset: 0,
+            }],
+            disk_size,
+        );
+        let text = descriptor.to_string();
+        if text.len() as u64 > DESCRIPTOR_SECTORS * SECTOR_SIZE {
+            return Err(Error::InvalidVmdk("descriptor too long".to_owned()));
+        }
+
+        let mut header = SparseExtentHeader {
+            version: 1,
+            flags: FLAG_VALID_NEWLINE_TEST | FLAG_REDUNDANT_GRAIN_TABLE,
+            capacity,
+            grain_size: DEFAULT_GRAIN_SIZE,
+            descriptor_offset: DESCRIPTOR_OFFSET,
+            descriptor_size: DESCRIPTOR_SECTORS,
+            num_gtes_per_gt: NUM_GTES_PER_GT,
+            rgd_offset: 0,
+            gd_offset: 0,
+            over_head: 0,
+            unclean_shutdown: false,
+            compress_algorithm: 0,
+        };
+
+        // redundant grain directory and tables followed by primary ones,
+        // all grain tables are preallocated
+        let num_gts = header.num_gts();
+        let gd_sectors = round_up!(num_gts * 4, SECTOR_SIZE) / SECTOR_SIZE;
+        let gt_sectors = NUM_GTES_PER_GT as u64 * 4 / SECTOR_SIZE;
+        header.rgd_offset = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+        header.gd_offset = header.rgd_offset + gd_sectors + num_gts * gt_sectors;
+        let end = header.gd_offset + gd_sectors + num_gts * gt_sectors;
+        header.over_head = round_up!(end, DEFAULT_GRAIN_SIZE);
+
+        let mut buf = vec![0u8; (header.over_head * SECTOR_SIZE) as usize];
+        buf[..SparseExtentHeader::SIZE].copy_from_slice(&header.encode());
+        let o = (DESCRIPTOR_OFFSET * SECTOR_SIZE) as usize;
+        buf[o..o + text.len()].copy_from_slice(text.as_bytes());
+        for gd_offset in [header.rgd_offset, header.gd_offset].iter().copied() {
+            for i in 0..num_gts {
+                let gt = (gd_offset + gd_sectors + i * gt_sectors) as u32;
+                let o = (gd_offset * SECTOR_SIZE + i * 4) as usize;
+                buf[o..o + 4].copy_from_slice(&gt.to_le_bytes());
+            }
+        }
+
+        backend.seek(SeekFrom::Start(0))?;
+        backend.write_all(&buf)?;
+        backend.flush()?;
+
+        Self::open(backend)
+    }
+
+    /// Creates monolithicFlat image consisting of descriptor and single flat extent,
+    /// extent_name is the name of extent file relative to descriptor.
+    pub fn create_flat(
+        mut descriptor_backend: Box<dyn Backend>,
+        mut extent_backend: Box<dyn Backend>,
+        extent_name: &str,
+        disk_size: u64,
+    ) -> Result<Self> {
+        let sectors = round_up!(disk_size, SECTOR_SIZE) / SECTOR_SIZE;
+        let descriptor = Self::new_descriptor(
+            "monolithicFlat",
+            vec![ExtentDescriptor {
+                access: ExtentAccess::ReadWrite,
+                sectors,
+                extent_type: ExtentType::Flat,
+                file_name: Some(extent_name.to_owned()),
+                offset: 0,
+            }],
+            disk_size,
+        );
+
+        descriptor_backend.seek(SeekFrom::Start(0))?;
+        descriptor_backend.write_all(descriptor.to_string().as_bytes())?;
+        descriptor_backend.flush()?;
+
+        if sectors > 0 {
+            extent_backend.seek(SeekFrom::Start(sectors * SECTOR_SIZE - 1))?;
+            extent_backend.write_all(&[0])?;
+            extent_backend.flush()?;
+        }
+
+        let mut extent_backend = Some(extent_backend);
+        Self::from_descriptor(descriptor, |_| Ok(extent_backend.take().unwrap()))
+    }
+
+    fn extent_index(&self, position: u64) -> usize {
+        self.extents
+            .iter()
+            .position(|x| position < x.start + x.size)
+            .unwrap()
+    }
+}
+
+impl Disk for VmdkDisk {
+    fn disk_size(&self) -> u64 {
+        self.disk_size
+    }
+
+    fn sector_size(&self) -> u32 {
+        SECTOR_SIZE as u32
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::HDD
+    }
+
+    fn disk_format(&self) -> DiskFormat {
+        DiskFormat::VMDK
+    }
+}
+
+impl Seek for VmdkDisk {
+    fn seek(&mut self, s: SeekFrom) -> io::Result<u64> {
+        self.cursor = seek_position(self.cursor, self.disk_size(), s)?;
+        Ok(self.cursor)
+    }
+}
+
+impl Read for VmdkDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_read = 0usize;
+
+        while left > 0 {
+            let index = self.extent_index(self.cursor);
+            let extent = &mut self.extents[index];
+            let offset = self.cursor - extent.start;
+            let mut n = min(left as u64, extent.size - offset) as usize;
+            let buf = &mut buf[total_read..total_read + n];
+
+            if extent.access == ExtentAccess::NoAccess {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "extent is not accessible",
+                ));
+            }
+
+            match &mut extent.data {
+                ExtentData::Flat { backend, offset: o } => {
+                    backend.seek(SeekFrom::Start(*o + offset))?;
+                    backend.read_exact(buf)?;
+                }
+                ExtentData::Sparse(sparse) => {
+                    n = min(n as u64, sparse.grain_size() - offset % sparse.grain_size()) as usize;
+                    sparse.read_grain(offset, &mut buf[..n])?;
+                }
+                ExtentData::Zero => zero_u8_slice(buf),
+            }
+
+            left -= n;
+            self.cursor += n as u64;
+            total_read += n;
+        }
+
+        Ok(total_read)
+    }
+}
+
+impl Write for VmdkDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let available = self.disk_size().saturating_sub(self.cursor);
+        let mut left = min(available, buf.len() as u64) as usize;
+        let mut total_written = 0usize;
+
+        while left > 0 {
+            let index = self.extent_index(self.cursor);
+            let extent = &mut self.extents[index];
+            let offset = self.cursor - extent.start;
+            let mut n = min(left as u64, extent.size - offset) as usize;
+            let buf = &buf[total_written..total_written + n];
+
+            if extent.access != ExtentAccess::ReadWrite {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "extent is read-only",
+                ));
+            }
+
+            match &mut extent.data {
+                ExtentData::Flat { backend, offset: o } => {
+                    backend.seek(SeekFrom::Start(*o + offset))?;
+                    backend.write_all(buf)?;
+                }
+                ExtentData::Sparse(sparse) => {
+                    n = min(n as u64, sparse.grain_size() - offset % sparse.grain_size()) as usize;
+                    sparse.write_grain(offset, &buf[..n])?;
+                }
+                ExtentData::Zero => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "zero extent can't be written",
+                    ))
+                }
+            }
+
+            left -= n;
+            self.cursor += n as u64;
+            total_written += n;
+        }
+
+        Ok(total_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for extent in self.extents.iter_mut() {
+            match &mut extent.data {
+                ExtentData::Flat { backend, .. } => backend.flush()?,
+                ExtentData::Sparse(sparse) => sparse.flush()?,
+                ExtentData::Zero => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::MemoryBackend;
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_create_sparse() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        let mut disk =
+            VmdkDisk::create_sparse(Box::new(backend), 64 * 1024 * 1024, "test.vmdk").unwrap();
+
+        // redundant grain directory and tables come first, followed by primary ones
+        let header =
+            SparseExtentHeader::decode(&storage.borrow()[..SparseExtentHeader::SIZE]).unwrap();
+        let gt_sectors = NUM_GTES_PER_GT as u64 * 4 / SECTOR_SIZE;
+        assert_eq!(header.num_gts(), 2);
+        assert_eq!(header.rgd_offset, DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS);
+        assert_eq!(header.gd_offset, header.rgd_offset + 1 + 2 * gt_sectors);
+        assert_eq!(header.over_head % DEFAULT_GRAIN_SIZE, 0);
+        assert_eq!(
+            storage.borrow().len() as u64,
+            header.over_head * SECTOR_SIZE
+        );
+
+        // second grain of the second grain table
+        let offset = (NUM_GTES_PER_GT as u64 + 1) * DEFAULT_GRAIN_SIZE * SECTOR_SIZE;
+        disk.seek(SeekFrom::Start(offset + 512)).unwrap();
+        disk.write_all(b"vmdk").unwrap();
+        drop(disk);
+
+        let gte = |gd_offset: u64| {
+            let storage = storage.borrow();
+            let u32_at = |o: u64| {
+                let o = o as usize;
+                u32::from_le_bytes([storage[o], storage[o + 1], storage[o + 2], storage[o + 3]])
+            };
+            let gt = u32_at(gd_offset * SECTOR_SIZE + 4) as u64;
+            u32_at(gt * SECTOR_SIZE + 4) as u64
+        };
+        let grain = gte(header.gd_offset);
+        assert_eq!(grain, header.over_head);
+        assert_eq!(gte(header.rgd_offset), grain);
+        let o = ((grain + 1) * SECTOR_SIZE) as usize;
+        assert_eq!(&storage.borrow()[o..o + 4], b"vmdk");
+
+        let mut disk = VmdkDisk::open(Box::new(MemoryBackend::from_storage(storage))).unwrap();
+        assert_eq!(disk.descriptor().create_type, "monolithicSparse");
+        let mut buf = [0u8; 4];
+        disk.seek(SeekFrom::Start(offset + 512)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"vmdk");
+    }
+
+    #[test]
+    fn test_embedded_flat_extent() {
+        crate::tests_init();
+
+        let backend = MemoryBackend::new();
+        let storage = backend.storage();
+        let mut disk =
+            VmdkDisk::create_sparse(Box::new(backend), 1024 * 1024, "test.vmdk").unwrap();
+
+        let mut descriptor = disk.descriptor().clone();
+        descriptor.extents.insert(
+            0,
+            ExtentDescriptor {
+                access: ExtentAccess::ReadWrite,
+                sectors: 2048,
+                extent_type: ExtentType::Flat,
+                file_name: Some("test-flat.vmdk".to_owned()),
+                offset: 0,
+            },
+        );
+        disk.flush().unwrap();
+        drop(disk);
+
+        let text = descriptor.to_string();
+        let o = (DESCRIPTOR_OFFSET * SECTOR_SIZE) as usize;
+        storage.borrow_mut()[o..o + text.len()].copy_from_slice(text.as_bytes());
+        assert!(matches!(
+            VmdkDisk::open(Box::new(MemoryBackend::from_storage(storage))),
+            Err(Error::InvalidVmdk(_))
+        ));
+    }
+
+    #[test]
+    fn test_split_flat() {
+        crate::tests_init();
+
+        let dir = std::env::temp_dir().join(format!("diskutil-vmdk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("split.vmdk");
+
+        fs::write(dir.join("split-f001.vmdk"), vec![0x11u8; 4096]).unwrap();
+        fs::write(dir.join("split-f002.vmdk"), vec![0x22u8; 1024 + 4096]).unwrap();
+        fs::write(
+            &path,
+            "# Disk DescriptorFile\n\
+             version=1\n\
+             CID=fffffffe\n\
+             parentCID=ffffffff\n\
+             createType=\"twoGbMaxExtentFlat\"\n\
+             RW 8 FLAT \"split-f001.vmdk\" 0\n\
+             RW 8 FLAT \"split-f002.vmdk\" 2\n\
+             RW 8 ZERO\n",
+        )
+        .unwrap();
+
+        let mut args = ArgumentMap::default();
+        args.insert(
+            "path",
+            crate::disk::Argument::String(path.to_string_lossy().into_owned()),
+        );
+        let backend = FileBackend::new(File::open(&path).unwrap()).unwrap();
+        let mut disk = VmdkDisk::open_with_argmap(backend, &args).unwrap();
+        assert_eq!(disk.disk_size(), 24 * 512);
+
+        // write across the extent boundary
+        disk.seek(SeekFrom::Start(4096 - 2)).unwrap();
+        disk.write_all(&[0x33; 4]).unwrap();
+        disk.seek(SeekFrom::Start(8192)).unwrap();
+        assert!(disk.write(&[0x33; 512]).is_err());
+
+        let mut buf = vec![0u8; 24 * 512];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..4094].iter().all(|x| *x == 0x11));
+        assert!(buf[4094..4098].iter().all(|x| *x == 0x33));
+        assert!(buf[4098..8192].iter().all(|x| *x == 0x22));
+        assert!(buf[8192..].iter().all(|x| *x == 0));
+        drop(disk);
+
+        let extent = fs::read(dir.join("split-f002.vmdk")).unwrap();
+        assert_eq!(&extent[1022..1026], &[0x22, 0x22, 0x33, 0x33]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
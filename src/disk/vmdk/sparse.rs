@@ -0,0 +1,359 @@
+use crate::disk::Backend;
+use crate::{is_power_of_2, round_up, utils::zero_u8_slice, Error, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+pub const FLAG_VALID_NEWLINE_TEST: u32 = 1;
+pub const FLAG_REDUNDANT_GRAIN_TABLE: u32 = 1 << 1;
+pub const FLAG_ZERO_GRAIN_GTE: u32 = 1 << 2;
+pub const FLAG_COMPRESSED: u32 = 1 << 16;
+pub const FLAG_MARKERS: u32 = 1 << 17;
+
+// grain table entry describing grain which reads as zeros
+const GTE_ZERO: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct SparseExtentHeader {
+    pub version: u32,
+    pub flags: u32,
+    /// All sizes and offsets are in sectors
+    pub capacity: u64,
+    pub grain_size: u64,
+    pub descriptor_offset: u64,
+    pub descriptor_size: u64,
+    pub num_gtes_per_gt: u32,
+    pub rgd_offset: u64,
+    pub gd_offset: u64,
+    pub over_head: u64,
+    pub unclean_shutdown: bool,
+    pub compress_algorithm: u16,
+}
+
+impl SparseExtentHeader {
+    pub const SIZE: usize = 512;
+    pub const MAGIC: &'static [u8; 4] = b"KDMV";
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::SIZE || &buf[..4] != Self::MAGIC {
+            return Err(Error::InvalidVmdk("invalid sparse extent magic".to_owned()));
+        }
+
+        let mut reader = Cursor::new(&buf[4..]);
+        let header = Self {
+            version: reader.read_u32::<LittleEndian>()?,
+            flags: reader.read_u32::<LittleEndian>()?,
+            capacity: reader.read_u64::<LittleEndian>()?,
+            grain_size: reader.read_u64::<LittleEndian>()?,
+            descriptor_offset: reader.read_u64::<LittleEndian>()?,
+            descriptor_size: reader.read_u64::<LittleEndian>()?,
+            num_gtes_per_gt: reader.read_u32::<LittleEndian>()?,
+            rgd_offset: reader.read_u64::<LittleEndian>()?,
+            gd_offset: reader.read_u64::<LittleEndian>()?,
+            over_head: reader.read_u64::<LittleEndian>()?,
+            unclean_shutdown: reader.read_u8()? != 0,
+            compress_algorithm: {
+                // skip newline detection characters
+                reader.set_position(reader.position() + 4);
+                reader.read_u16::<LittleEndian>()?
+            },
+        };
+
+        if header.version == 0 || header.version > 3 {
+            return Err(Error::InvalidVmdk(format!(
+                "unsupported sparse extent version {}",
+                header.version
+            )));
+        }
+        if header.flags & FLAG_VALID_NEWLINE_TEST != 0 && &buf[73..77] != b"\n \r\n" {
+            return Err(Error::InvalidVmdk(
+                "file corrupted by text mode transfer".to_owned(),
+            ));
+        }
+        if header.flags & (FLAG_COMPRESSED | FLAG_MARKERS) != 0 {
+            return Err(Error::InvalidVmdk(
+                "compressed (stream optimized) extents are not supported".to_owned(),
+            ));
+        }
+        if header.grain_size < 8 || !is_power_of_2!(header.grain_size) {
+            return Err(Error::InvalidVmdk(format!(
+                "invalid grain size {}",
+                header.grain_size
+            )));
+        }
+        if header.num_gtes_per_gt == 0 {
+            return Err(Error::InvalidVmdk(
+                "invalid number of grain table entries".to_owned(),
+            ));
+        }
+
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0u8; Self::SIZE];
+
+        let mut writer = Cursor::new(&mut buf[..]);
+        writer.write_all(Self::MAGIC).unwrap();
+        writer.write_u32::<LittleEndian>(self.version).unwrap();
+        writer.write_u32::<LittleEndian>(self.flags).unwrap();
+        writer.write_u64::<LittleEndian>(self.capacity).unwrap();
+        writer.write_u64::<LittleEndian>(self.grain_size).unwrap();
+        writer
+            .write_u64::<LittleEndian>(self.descriptor_offset)
+            .unwrap();
+        writer
+            .write_u64::<LittleEndian>(self.descriptor_size)
+            .unwrap();
+        writer
+            .write_u32::<LittleEndian>(self.num_gtes_per_gt)
+            .unwrap();
+        writer.write_u64::<LittleEndian>(self.rgd_offset).unwrap();
+        writer.write_u64::<LittleEndian>(self.gd_offset).unwrap();
+        writer.write_u64::<LittleEndian>(self.over_head).unwrap();
+        writer.write_u8(self.unclean_shutdown as u8).unwrap();
+        writer.write_all(b"\n \r\n").unwrap();
+        writer
+            .write_u16::<LittleEndian>(self.compress_algorithm)
+            .unwrap();
+
+        buf
+    }
+
+    /// Returns number of grain tables needed to cover whole capacity
+    pub fn num_gts(&self) -> u64 {
+        let gt_coverage = self.num_gtes_per_gt as u64 * self.grain_size;
+        round_up!(self.capacity, gt_coverage) / gt_coverage
+    }
+}
+
+/// Hosted sparse extent, data is stored in grains allocated on demand
+pub struct SparseExtent {
+    backend: Box<dyn Backend>,
+    header: SparseExtentHeader,
+    gd: Vec<u32>,
+    rgd: Option<Vec<u32>>,
+    // most recently used grain table and its index in grain directory
+    gt_cache: Option<(usize, Vec<u32>)>,
+    // sector where next grain or grain table will be allocated
+    free_sector: u64,
+}
+
+impl SparseExtent {
+    pub fn open(mut backend: Box<dyn Backend>) -> Result<Self> {
+        let mut buf = [0u8; SparseExtentHeader::SIZE];
+        backend.seek(SeekFrom::Start(0))?;
+        backend.read_exact(&mut buf)?;
+        let header = SparseExtentHeader::decode(&buf)?;
+        debug!("Sparse extent header: {:?}", header);
+
+        let num_gts = header.num_gts() as usize;
+        let gd = Self::read_directory(backend.as_mut(), header.gd_offset, num_gts)?;
+        let rgd = if header.flags & FLAG_REDUNDANT_GRAIN_TABLE != 0 && header.rgd_offset != 0 {
+            Some(Self::read_directory(
+                backend.as_mut(),
+                header.rgd_offset,
+                num_gts,
+            )?)
+        } else {
+            None
+        };
+
+        let file_size = backend.seek(SeekFrom::End(0))?;
+        let free_sector = round_up!(file_size, SECTOR_SIZE) / SECTOR_SIZE;
+
+        Ok(Self {
+            backend,
+            header,
+            gd,
+            rgd,
+            gt_cache: None,
+            free_sector,
+        })
+    }
+
+    fn read_directory(
+        backend: &mut dyn Backend,
+        sector: u64,
+        entries: usize,
+    ) -> io::Result<Vec<u32>> {
+        let mut encoded = vec![0u8; entries * 4];
+        backend.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        backend.read_exact(encoded.as_mut_slice())?;
+
+        let mut reader = Cursor::new(encoded);
+        let mut directory = Vec::with_capacity(entries);
+        for _ in 0..entries {
+            directory.push(reader.read_u32::<LittleEndian>()?);
+        }
+
+        Ok(directory)
+    }
+
+    pub fn header(&self) -> &SparseExtentHeader {
+        &self.header
+    }
+
+    /// Reads embedded descriptor, trailing zeros are stripped
+    pub fn read_descriptor(&mut self) -> Result<Option<String>> {
+        if self.header.descriptor_offset == 0 || self.header.descriptor_size == 0 {
+            return Ok(None);
+        }
+
+        let mut text = vec![0u8; (self.header.descriptor_size * SECTOR_SIZE) as usize];
+        self.backend
+            .seek(SeekFrom::Start(self.header.descriptor_offset * SECTOR_SIZE))?;
+        self.backend.read_exact(text.as_mut_slice())?;
+        let length = text.iter().position(|x| *x == 0).unwrap_or(text.len());
+        text.truncate(length);
+
+        String::from_utf8(text)
+            .map(Some)
+            .map_err(|_| Error::InvalidVmdk("descriptor is not valid UTF-8".to_owned()))
+    }
+
+    pub fn grain_size(&self) -> u64 {
+        self.header.grain_size * SECTOR_SIZE
+    }
+
+    pub fn into_backend(self) -> Box<dyn Backend> {
+        self.backend
+    }
+
+    fn load_gt(&mut self, gt_index: usize) -> io::Result<&mut Vec<u32>> {
+        let cached = matches!(self.gt_cache, Some((x, _)) if x == gt_index);
+        if !cached {
+            let gt = Self::read_directory(
+                self.backend.as_mut(),
+                self.gd[gt_index] as u64,
+                self.header.num_gtes_per_gt as usize,
+            )?;
+            self.gt_cache = Some((gt_index, gt));
+        }
+
+        Ok(&mut self.gt_cache.as_mut().unwrap().1)
+    }
+
+    /// Returns grain table entry for grain containing offset, 0 if grain table is not allocated
+    fn gte(&mut self, offset: u64) -> io::Result<u32> {
+        let grain = offset / self.grain_size();
+        let gt_index = (grain / self.header.num_gtes_per_gt as u64) as usize;
+        let gte_index = (grain % self.header.num_gtes_per_gt as u64) as usize;
+
+        if self.gd[gt_index] == 0 {
+            return Ok(0);
+        }
+
+        Ok(self.load_gt(gt_index)?[gte_index])
+    }
+
+    fn alloc_sectors(&mut self, sectors: u64) -> io::Result<u32> {
+        let sector = self.free_sector;
+        self.free_sector += sectors;
+
+        // extend file, newly allocated area must read as zeros
+        self.backend
+            .seek(SeekFrom::Start(self.free_sector * SECTOR_SIZE - 1))?;
+        self.backend.write_all(&[0])?;
+
+        if sector > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sparse extent too big",
+            ));
+        }
+        Ok(sector as u32)
+    }
+
+    /// Allocates grain table, both primary and redundant table are allocated
+    fn alloc_gt(&mut self, gt_index: usize) -> io::Result<()> {
+        let gt_sectors =
+            round_up!(self.header.num_gtes_per_gt as u64 * 4, SECTOR_SIZE) / SECTOR_SIZE;
+
+        if let Some(rgd_offset) = self.rgd.as_ref().map(|_| self.header.rgd_offset) {
+            let sector = self.alloc_sectors(gt_sectors)?;
+            self.rgd.as_mut().unwrap()[gt_index] = sector;
+            self.backend.seek(SeekFrom::Start(
+                rgd_offset * SECTOR_SIZE + gt_index as u64 * 4,
+            ))?;
+            self.backend.write_u32::<LittleEndian>(sector)?;
+        }
+
+        let sector = self.alloc_sectors(gt_sectors)?;
+        self.gd[gt_index] = sector;
+        self.backend.seek(SeekFrom::Start(
+            self.header.gd_offset * SECTOR_SIZE + gt_index as u64 * 4,
+        ))?;
+        self.backend.write_u32::<LittleEndian>(sector)
+    }
+
+    fn set_gte(&mut self, offset: u64, value: u32) -> io::Result<()> {
+        let grain = offset / self.grain_size();
+        let gt_index = (grain / self.header.num_gtes_per_gt as u64) as usize;
+        let gte_index = (grain % self.header.num_gtes_per_gt as u64) as usize;
+
+        if self.gd[gt_index] == 0 {
+            self.alloc_gt(gt_index)?;
+        }
+
+        self.load_gt(gt_index)?[gte_index] = value;
+        self.backend.seek(SeekFrom::Start(
+            self.gd[gt_index] as u64 * SECTOR_SIZE + gte_index as u64 * 4,
+        ))?;
+        self.backend.write_u32::<LittleEndian>(value)?;
+
+        if let Some(rgd) = self.rgd.as_ref() {
+            self.backend.seek(SeekFrom::Start(
+                rgd[gt_index] as u64 * SECTOR_SIZE + gte_index as u64 * 4,
+            ))?;
+            self.backend.write_u32::<LittleEndian>(value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads data from single grain
+    pub fn read_grain(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let gte = self.gte(offset)?;
+
+        if gte == 0 || (gte == GTE_ZERO && self.header.flags & FLAG_ZERO_GRAIN_GTE != 0) {
+            zero_u8_slice(buf);
+            Ok(())
+        } else {
+            let offset_in_grain = offset % self.grain_size();
+            self.backend
+                .seek(SeekFrom::Start(gte as u64 * SECTOR_SIZE + offset_in_grain))?;
+            self.backend.read_exact(buf)
+        }
+    }
+
+    /// Writes data into single grain allocating it if necessary
+    pub fn write_grain(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let gte = self.gte(offset)?;
+        let offset_in_grain = offset % self.grain_size();
+
+        if gte != 0 && !(gte == GTE_ZERO && self.header.flags & FLAG_ZERO_GRAIN_GTE != 0) {
+            self.backend
+                .seek(SeekFrom::Start(gte as u64 * SECTOR_SIZE + offset_in_grain))?;
+            return self.backend.write_all(buf);
+        }
+
+        // unallocated grains read as zeros
+        if buf.iter().all(|x| *x == 0) {
+            return Ok(());
+        }
+
+        let grain = self.alloc_sectors(self.header.grain_size)?;
+        self.backend.seek(SeekFrom::Start(
+            grain as u64 * SECTOR_SIZE + offset_in_grain,
+        ))?;
+        self.backend.write_all(buf)?;
+
+        self.set_gte(offset, grain)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.backend.flush()
+    }
+}
@@ -12,8 +12,22 @@ pub enum Error {
     InvalidVhdFooter(Option<String>),
     #[error("invalid VHD dynamic header {0:#?}")]
     InvalidVhdDynamicHeader(Option<String>),
+    #[error("parent disk {0:?} not found")]
+    VhdParentNotFound(String),
+    #[error("invalid VHDX: {0}")]
+    InvalidVhdx(String),
+    #[error("invalid QCOW2: {0}")]
+    InvalidQcow2(String),
+    #[error("invalid VMDK: {0}")]
+    InvalidVmdk(String),
+    #[error("invalid VDI: {0}")]
+    InvalidVdi(String),
+    #[error("backing file {0:?} not found")]
+    BackingFileNotFound(String),
     #[error("MBR is missing")]
     MbrMissing,
+    #[error("{0}")]
+    InvalidMbr(String),
     #[error("GPT is missing")]
     GptMissing,
     #[error("{0}")]
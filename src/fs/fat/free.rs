@@ -0,0 +1,132 @@
+use crate::region::Region;
+use crate::{Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+
+/// Returns byte regions of clusters marked free in the first FAT of FAT12/16/32
+/// volume starting at offset, regions are absolute and sorted
+pub fn free_regions<T>(device: &mut T, offset: u64) -> Result<Vec<Region<u64>>>
+where
+    T: Read + Seek,
+{
+    let mut bs = [0u8; 512];
+    device.seek(SeekFrom::Start(offset))?;
+    device.read_exact(&mut bs)?;
+
+    if LittleEndian::read_u16(&bs[510..]) != 0xAA55 {
+        return Err(Error::InvalidBpb);
+    }
+    let bytes_per_sector = LittleEndian::read_u16(&bs[11..]) as u64;
+    let sectors_per_cluster = bs[13] as u64;
+    let reserved_sectors = LittleEndian::read_u16(&bs[14..]) as u64;
+    let number_of_fats = bs[16] as u64;
+    let root_entries = LittleEndian::read_u16(&bs[17..]) as u64;
+    let sectors_total = match LittleEndian::read_u16(&bs[19..]) {
+        0 => LittleEndian::read_u32(&bs[32..]) as u64,
+        x => x as u64,
+    };
+    let sectors_per_fat = match LittleEndian::read_u16(&bs[22..]) {
+        0 => LittleEndian::read_u32(&bs[36..]) as u64,
+        x => x as u64,
+    };
+
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        || !sectors_per_cluster.is_power_of_two()
+        || number_of_fats == 0
+        || sectors_per_fat == 0
+    {
+        return Err(Error::InvalidBpb);
+    }
+
+    let root_dir_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+    let first_data_sector = reserved_sectors + number_of_fats * sectors_per_fat + root_dir_sectors;
+    if first_data_sector >= sectors_total {
+        return Err(Error::InvalidBpb);
+    }
+    let clusters = (sectors_total - first_data_sector) / sectors_per_cluster;
+
+    // cluster count is the only thing that determines FAT type
+    let entry_bits = match clusters {
+        x if x < 4085 => 12,
+        x if x < 65525 => 16,
+        _ => 32,
+    };
+    let fat_size = ((clusters + 2) * entry_bits + 7) / 8;
+    if fat_size > sectors_per_fat * bytes_per_sector {
+        return Err(Error::InvalidBpb);
+    }
+
+    let mut fat = vec![0u8; fat_size as usize];
+    device.seek(SeekFrom::Start(
+        offset + reserved_sectors * bytes_per_sector,
+    ))?;
+    device.read_exact(&mut fat)?;
+
+    let is_free = |cluster: usize| match entry_bits {
+        12 => {
+            let x = LittleEndian::read_u16(&fat[cluster * 3 / 2..]);
+            (if cluster % 2 == 0 { x & 0xfff } else { x >> 4 }) == 0
+        }
+        16 => LittleEndian::read_u16(&fat[cluster * 2..]) == 0,
+        _ => LittleEndian::read_u32(&fat[cluster * 4..]) & 0x0fff_ffff == 0,
+    };
+
+    let cluster_size = sectors_per_cluster * bytes_per_sector;
+    let data_start = offset + first_data_sector * bytes_per_sector;
+    let mut regions: Vec<Region<u64>> = Vec::new();
+    let mut run_start = None;
+    for cluster in 2..clusters as usize + 3 {
+        let free = cluster < clusters as usize + 2 && is_free(cluster);
+        match (free, run_start) {
+            (true, None) => run_start = Some(cluster),
+            (false, Some(start)) => {
+                regions.push(Region::new_with_size(
+                    data_start + (start as u64 - 2) * cluster_size,
+                    (cluster - start) as u64 * cluster_size,
+                ));
+                run_start = None;
+            }
+            _ => (),
+        }
+    }
+
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::free_regions;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_fat16() {
+        crate::tests_init();
+
+        // 4 sectors per cluster, 1 reserved sector, 2 FATs of 32 sectors, 512 root entries
+        let mut volume = vec![0u8; 20000 * 512];
+        volume[11..13].copy_from_slice(&512u16.to_le_bytes());
+        volume[13] = 4;
+        volume[14..16].copy_from_slice(&1u16.to_le_bytes());
+        volume[16] = 2;
+        volume[17..19].copy_from_slice(&512u16.to_le_bytes());
+        volume[19..21].copy_from_slice(&20000u16.to_le_bytes());
+        volume[22..24].copy_from_slice(&32u16.to_le_bytes());
+        volume[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        // clusters 2 and 5 are used
+        let fat = 512;
+        volume[fat..fat + 4].copy_from_slice(&[0xf8, 0xff, 0xff, 0xff]);
+        volume[fat + 4..fat + 6].copy_from_slice(&[0xff, 0xff]);
+        volume[fat + 10..fat + 12].copy_from_slice(&[0xff, 0xff]);
+
+        let regions = free_regions(&mut Cursor::new(&volume), 0).unwrap();
+        // data area starts after 1 + 64 + 32 sectors, there are 4975 clusters
+        let data_start = 97 * 512;
+        let cluster_size = 2048;
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].start(), data_start + cluster_size);
+        assert_eq!(regions[0].size(), 2 * cluster_size);
+        assert_eq!(regions[1].start(), data_start + 4 * cluster_size);
+        assert_eq!(regions[1].end(), data_start + 4975 * cluster_size - 1);
+    }
+}
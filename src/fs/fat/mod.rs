@@ -1,4 +1,7 @@
 mod bpb;
+mod free;
+
+pub use free::free_regions;
 
 use crate::disk::Disk;
 use crate::Result;
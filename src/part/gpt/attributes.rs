@@ -0,0 +1,123 @@
+use bitflags::bitflags;
+use std::fmt;
+
+bitflags! {
+    /// GPT partition attributes, bits 48 to 63 are specific to partition type
+    pub struct GptAttributes: u64 {
+        /// Partition is required for platform to function
+        const REQUIRED = 1 << 0;
+        /// Firmware must not produce block IO protocol for partition
+        const NO_BLOCK_IO = 1 << 1;
+        /// Partition is bootable by legacy BIOS
+        const LEGACY_BIOS_BOOTABLE = 1 << 2;
+
+        /// ChromeOS kernel boot priority, 0 means not bootable
+        const CHROMEOS_PRIORITY = 0xF << 48;
+        /// ChromeOS kernel remaining boot attempts
+        const CHROMEOS_TRIES = 0xF << 52;
+        /// ChromeOS kernel booted successfully
+        const CHROMEOS_SUCCESSFUL = 1 << 56;
+
+        /// Microsoft basic data partition is read-only
+        const MS_READ_ONLY = 1 << 60;
+        /// Microsoft basic data partition is shadow copy
+        const MS_SHADOW_COPY = 1 << 61;
+        /// Microsoft basic data partition is hidden
+        const MS_HIDDEN = 1 << 62;
+        /// Microsoft basic data partition doesn't get drive letter
+        const MS_NO_AUTOMOUNT = 1 << 63;
+    }
+}
+
+// single bit flags and their names used by command line tools
+const FLAG_NAMES: &[(GptAttributes, &str)] = &[
+    (GptAttributes::REQUIRED, "required"),
+    (GptAttributes::NO_BLOCK_IO, "no-block-io"),
+    (GptAttributes::LEGACY_BIOS_BOOTABLE, "legacy-boot"),
+    (GptAttributes::CHROMEOS_SUCCESSFUL, "successful"),
+    (GptAttributes::MS_READ_ONLY, "read-only"),
+    (GptAttributes::MS_SHADOW_COPY, "shadow-copy"),
+    (GptAttributes::MS_HIDDEN, "hidden"),
+    (GptAttributes::MS_NO_AUTOMOUNT, "no-automount"),
+];
+
+impl GptAttributes {
+    /// Looks up single bit flag by name such as hidden or legacy-boot
+    pub fn from_name(name: &str) -> Option<Self> {
+        FLAG_NAMES
+            .iter()
+            .find(|x| x.1.eq_ignore_ascii_case(name))
+            .map(|x| x.0)
+    }
+
+    /// Names of all single bit flags
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        FLAG_NAMES.iter().map(|x| x.1)
+    }
+
+    pub fn chromeos_priority(&self) -> u8 {
+        ((self.bits & Self::CHROMEOS_PRIORITY.bits) >> 48) as u8
+    }
+
+    /// Sets ChromeOS priority, values above 15 are truncated
+    pub fn set_chromeos_priority(&mut self, priority: u8) {
+        self.remove(Self::CHROMEOS_PRIORITY);
+        self.bits |= ((priority & 0xF) as u64) << 48;
+    }
+
+    pub fn chromeos_tries(&self) -> u8 {
+        ((self.bits & Self::CHROMEOS_TRIES.bits) >> 52) as u8
+    }
+
+    /// Sets ChromeOS remaining tries, values above 15 are truncated
+    pub fn set_chromeos_tries(&mut self, tries: u8) {
+        self.remove(Self::CHROMEOS_TRIES);
+        self.bits |= ((tries & 0xF) as u64) << 52;
+    }
+}
+
+impl fmt::Display for GptAttributes {
+    /// Formats flags as comma separated names, eg. legacy-boot,priority=2
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = FLAG_NAMES
+            .iter()
+            .filter(|x| self.contains(x.0))
+            .map(|x| x.1.to_owned())
+            .collect();
+        if self.chromeos_priority() != 0 {
+            parts.push(format!("priority={}", self.chromeos_priority()));
+        }
+        if self.chromeos_tries() != 0 {
+            parts.push(format!("tries={}", self.chromeos_tries()));
+        }
+
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GptAttributes;
+
+    #[test]
+    fn test_attributes() {
+        crate::tests_init();
+
+        let mut attributes = GptAttributes::from_name("Hidden").unwrap();
+        attributes.insert(GptAttributes::LEGACY_BIOS_BOOTABLE);
+        attributes.set_chromeos_priority(2);
+        attributes.set_chromeos_tries(15);
+        assert_eq!(attributes.bits(), 1 << 62 | 1 << 2 | 2 << 48 | 15 << 52);
+        assert_eq!(
+            attributes.to_string(),
+            "legacy-boot,hidden,priority=2,tries=15"
+        );
+
+        attributes.set_chromeos_priority(1);
+        attributes.set_chromeos_tries(0);
+        assert_eq!(attributes.chromeos_priority(), 1);
+        assert_eq!(attributes.chromeos_tries(), 0);
+        assert!(GptAttributes::from_name("unknown").is_none());
+        assert_eq!(GptAttributes::empty().to_string(), "");
+    }
+}
@@ -1,4 +1,6 @@
+mod attributes;
 mod partition_type;
+pub use attributes::*;
 pub use partition_type::*;
 
 use super::{Partition, PartitionTable};
@@ -16,6 +18,7 @@ use uuid::Uuid;
 mod util;
 
 const GPT_HEADER_SIZE: usize = 0x5C;
+const GPT_SIGNATURE: u64 = 0x5452415020494645;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ErrorAction {
@@ -23,6 +26,56 @@ pub enum ErrorAction {
     Ignore,
 }
 
+/// Copy of GPT header and partition array
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GptCopy {
+    /// Header at LBA 1
+    Primary,
+    /// Header at the last LBA of disk
+    Backup,
+}
+
+/// Header fields of single GPT copy as stored on disk, with checksum validity
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GptHeaderInfo {
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc32: u32,
+    pub header_crc32_valid: bool,
+    pub current_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Uuid,
+    pub partition_table_start: u64,
+    pub partition_table_entries_num: u32,
+    pub partition_table_entry_size: u32,
+    pub partition_table_crc32: u32,
+    pub partition_table_crc32_valid: bool,
+}
+
+impl GptHeaderInfo {
+    pub fn is_valid(&self) -> bool {
+        self.header_crc32_valid && self.partition_table_crc32_valid
+    }
+
+    /// Checks that both copies are valid, point to each other and describe
+    /// the same partition table
+    pub fn is_consistent_with(&self, other: &Self) -> bool {
+        self.is_valid()
+            && other.is_valid()
+            && self.current_lba == other.alternate_lba
+            && self.alternate_lba == other.current_lba
+            && self.revision == other.revision
+            && self.disk_guid == other.disk_guid
+            && self.first_usable_lba == other.first_usable_lba
+            && self.last_usable_lba == other.last_usable_lba
+            && self.partition_table_entries_num == other.partition_table_entries_num
+            && self.partition_table_entry_size == other.partition_table_entry_size
+            && self.partition_table_crc32 == other.partition_table_crc32
+    }
+}
+
 pub struct Gpt {
     pub partitions: Vec<Option<GptPartition>>,
     //
@@ -45,8 +98,150 @@ impl Gpt {
     const ENTRY_SIZE: u32 = 128;
     const REVISION: u32 = 0x00010000;
 
-    // TODO: support loading backup
+    /// Loads primary GPT
     pub fn load(disk: &mut dyn Disk, error_action: ErrorAction) -> Result<Self> {
+        Self::load_at(disk, 1, error_action)
+    }
+
+    /// Loads backup GPT from the last sector of disk, returned GPT describes primary
+    /// copy so that `update` restores both copies
+    pub fn load_backup(disk: &mut dyn Disk, error_action: ErrorAction) -> Result<Self> {
+        let last_lba = disk.disk_size() / disk.sector_size() as u64 - 1;
+        Self::load_at(disk, last_lba, error_action)
+    }
+
+    /// Loads valid copy of GPT, backup is used only if primary is damaged.
+    /// With `ErrorAction::Ignore` damaged primary is loaded if none of copies is valid.
+    pub fn load_any(disk: &mut dyn Disk, error_action: ErrorAction) -> Result<(Self, GptCopy)> {
+        let primary_error = match Self::load(disk, ErrorAction::Abort) {
+            Ok(gpt) => return Ok((gpt, GptCopy::Primary)),
+            Err(e) => e,
+        };
+
+        match Self::load_backup(disk, ErrorAction::Abort) {
+            Ok(gpt) => {
+                warn!("primary GPT is damaged ({}), using backup", primary_error);
+                Ok((gpt, GptCopy::Backup))
+            }
+            Err(_) if error_action == ErrorAction::Ignore => {
+                Ok((Self::load(disk, error_action)?, GptCopy::Primary))
+            }
+            Err(_) => Err(primary_error),
+        }
+    }
+
+    /// Checks both copies of GPT and rewrites damaged one from the valid one.
+    /// Returns restored copy or None if both copies are valid, when dry_run is set
+    /// nothing is written.
+    pub fn repair(disk: &mut dyn Disk, dry_run: bool) -> Result<Option<GptCopy>> {
+        let (mut gpt, damaged) = match Self::load(disk, ErrorAction::Abort) {
+            Ok(gpt) => {
+                let damaged = match Self::load_at(disk, gpt.alternate_lba, ErrorAction::Abort) {
+                    Ok(_) => {
+                        // backup with valid checksums can still be stale
+                        let primary = Self::read_header_info(disk, gpt.current_lba)?;
+                        let backup = Self::read_header_info(disk, gpt.alternate_lba)?;
+                        let consistent = primary.is_consistent_with(&backup);
+                        if !consistent {
+                            warn!("backup GPT doesn't match primary GPT");
+                        }
+                        !consistent
+                    }
+                    Err(e) => {
+                        warn!("backup GPT is damaged: {}", e);
+                        true
+                    }
+                };
+                (gpt, if damaged { Some(GptCopy::Backup) } else { None })
+            }
+            Err(e) => {
+                warn!("primary GPT is damaged: {}", e);
+                (
+                    Self::load_backup(disk, ErrorAction::Abort).map_err(|_| e)?,
+                    Some(GptCopy::Primary),
+                )
+            }
+        };
+
+        if damaged.is_some() && !dry_run {
+            gpt.update(disk)?;
+        }
+
+        Ok(damaged)
+    }
+
+    /// Reads GPT header at given LBA and verifies checksums, unlike `load`
+    /// damaged copy is not treated as an error
+    pub fn read_header_info(disk: &mut dyn Disk, lba: u64) -> Result<GptHeaderInfo> {
+        let sector_size = disk.sector_size();
+        let mut sector = allocate_u8_vector_uninitialized(sector_size as usize);
+        disk.seek(SeekFrom::Start(lba * sector_size as u64))?;
+        disk.read_exact(sector.as_mut_slice())?;
+
+        let mut reader = Cursor::new(sector.as_slice());
+        if reader.read_u64::<LittleEndian>()? != GPT_SIGNATURE {
+            return Err(Error::GptMissing);
+        }
+        let revision = reader.read_u32::<LittleEndian>()?;
+        let header_size = reader.read_u32::<LittleEndian>()?;
+        if header_size < GPT_HEADER_SIZE as u32 || header_size > sector_size {
+            return Err(Error::InvalidGpt(format!(
+                "invalid header size ({})",
+                header_size
+            )));
+        }
+        let header_crc32 = reader.read_u32::<LittleEndian>()?;
+        let _reserved = reader.read_u32::<LittleEndian>()?;
+        let current_lba = reader.read_u64::<LittleEndian>()?;
+        let alternate_lba = reader.read_u64::<LittleEndian>()?;
+        let first_usable_lba = reader.read_u64::<LittleEndian>()?;
+        let last_usable_lba = reader.read_u64::<LittleEndian>()?;
+        let disk_guid = read_guid(&mut reader)?;
+        let partition_table_start = reader.read_u64::<LittleEndian>()?;
+        let partition_table_entries_num = reader.read_u32::<LittleEndian>()?;
+        let partition_table_entry_size = reader.read_u32::<LittleEndian>()?;
+        let partition_table_crc32 = reader.read_u32::<LittleEndian>()?;
+
+        // checksum is computed with the header_crc32 field zeroed
+        zero_u8_slice(&mut sector[16..20]);
+        let header_crc32_valid =
+            crc32::checksum_ieee(&sector[..header_size as usize]) == header_crc32;
+
+        let table_size = partition_table_entries_num as u64 * partition_table_entry_size as u64;
+        let table_offset = partition_table_start.saturating_mul(sector_size as u64);
+        let partition_table_crc32_valid =
+            if table_offset.saturating_add(table_size) <= disk.disk_size() {
+                let mut buf = allocate_u8_vector_uninitialized(table_size as usize);
+                disk.seek(SeekFrom::Start(table_offset))?;
+                disk.read_exact(buf.as_mut_slice())?;
+                crc32::checksum_ieee(buf.as_slice()) == partition_table_crc32
+            } else {
+                warn!(
+                    "partition table at LBA {} is beyond end of disk",
+                    partition_table_start
+                );
+                false
+            };
+
+        Ok(GptHeaderInfo {
+            revision,
+            header_size,
+            header_crc32,
+            header_crc32_valid,
+            current_lba,
+            alternate_lba,
+            first_usable_lba,
+            last_usable_lba,
+            disk_guid,
+            partition_table_start,
+            partition_table_entries_num,
+            partition_table_entry_size,
+            partition_table_crc32,
+            partition_table_crc32_valid,
+        })
+    }
+
+    fn load_at(disk: &mut dyn Disk, gpt_start_lba: u64, error_action: ErrorAction) -> Result<Self> {
         let sector_size = disk.sector_size();
         let mut reader = BufReader::with_capacity(sector_size as usize, disk);
         let mut crc32 = crc32::Digest::new(crc32::IEEE);
@@ -72,10 +267,9 @@ impl Gpt {
             }};
         }
 
-        let gpt_start_lba = 1u64;
         reader.seek(SeekFrom::Start(gpt_start_lba * sector_size as u64))?;
         let signature = read_hash!(u64)?;
-        if signature != 0x5452415020494645u64 {
+        if signature != GPT_SIGNATURE {
             return Err(Error::GptMissing);
         }
 
@@ -87,6 +281,12 @@ impl Gpt {
                 header_size
             )));
         }
+        if header_size > sector_size {
+            return Err(Error::InvalidGpt(format!(
+                "header size ({}) is larger than sector",
+                header_size
+            )));
+        }
         let header_crc32 = read_hash!(u32, 0)?;
         let reserved = read_hash!(u32)?;
         let mut current_lba = read_hash!(u64)?;
@@ -113,16 +313,33 @@ impl Gpt {
         let partition_table_entry_size = read_hash!(u32)?;
         let partition_table_crc32 = read_hash!(u32)?;
 
-        let partition_table_last_lba = partition_table_start
-            + (round_up!(
-                partition_table_entry_size as u64 * partition_table_entries_num as u64,
-                sector_size as u64
-            ) / sector_size as u64)
-            - 1;
-        if first_usable_lba <= partition_table_last_lba {
+        if partition_table_entries_num == 0 || partition_table_entry_size < 128 {
+            return Err(Error::InvalidGpt(format!(
+                "invalid partition table size ({} entries of {} bytes)",
+                partition_table_entries_num, partition_table_entry_size
+            )));
+        }
+        let partition_table_sectors = round_up!(
+            partition_table_entry_size as u64 * partition_table_entries_num as u64,
+            sector_size as u64
+        ) / sector_size as u64;
+        let partition_table_last_lba =
+            partition_table_start.saturating_add(partition_table_sectors - 1);
+        let overlap = if gpt_start_lba == 1 && first_usable_lba <= partition_table_last_lba {
+            Some((
+                "first_usable_lba",
+                first_usable_lba,
+                partition_table_last_lba,
+            ))
+        } else if gpt_start_lba != 1 && last_usable_lba >= partition_table_start {
+            Some(("last_usable_lba", last_usable_lba, partition_table_start))
+        } else {
+            None
+        };
+        if let Some((name, usable_lba, table_lba)) = overlap {
             let msg = format!(
-                "first_usable_lba overlaps with partition table ({} <= {})",
-                first_usable_lba, partition_table_last_lba
+                "{} overlaps with partition table ({} vs {})",
+                name, usable_lba, table_lba
             );
             match error_action {
                 ErrorAction::Abort => return Err(Error::InvalidGpt(msg)),
@@ -130,7 +347,7 @@ impl Gpt {
             };
         }
 
-        let total_read = reader.seek(SeekFrom::Current(0))? - sector_size as u64;
+        let total_read = reader.seek(SeekFrom::Current(0))? - gpt_start_lba * sector_size as u64;
         assert_eq!(total_read, GPT_HEADER_SIZE as u64);
 
         let header_additional_data = if total_read != header_size as u64 {
@@ -280,7 +497,7 @@ impl Gpt {
             }
         }
 
-        Ok(Self {
+        let mut gpt = Self {
             partitions,
             revision,
             reserved,
@@ -293,15 +510,38 @@ impl Gpt {
             partition_table_entries_num,
             partition_table_entry_size,
             header_additional_data,
-        })
+        };
+
+        // backup header has current and alternate LBA swapped, primary partition
+        // array is expected right after primary header
+        if gpt_start_lba != 1 {
+            gpt.current_lba = 1;
+            gpt.alternate_lba = gpt_start_lba;
+            gpt.partition_table_start = 2;
+            if first_usable_lba <= 1 + partition_table_sectors {
+                return Err(Error::InvalidGpt(
+                    "primary partition table would overlap with first_usable_lba".to_owned(),
+                ));
+            }
+        }
+
+        Ok(gpt)
     }
 
     pub fn create(disk: &mut dyn Disk) -> Result<Self> {
         Self::create_ex(disk, 128)
     }
     pub fn create_ex(disk: &mut dyn Disk, max_entries: u32) -> Result<Self> {
-        let sector_size = disk.sector_size();
+        Ok(Self::create_for_size(
+            disk.disk_size(),
+            disk.sector_size(),
+            max_entries,
+        ))
+    }
 
+    /// Creates empty GPT for disk of given geometry without accessing the disk,
+    /// nothing is written until `update` is called
+    pub fn create_for_size(disk_size: u64, sector_size: u32, max_entries: u32) -> Self {
         let partition_table_start = 2;
         let partition_table_entries_num = max_entries;
         let partition_table_entry_size = Self::ENTRY_SIZE;
@@ -312,7 +552,6 @@ impl Gpt {
         ) / sector_size as u64;
         let first_usable_lba = partition_table_start + partition_table_size_in_sectors;
 
-        let disk_size = disk.disk_size();
         assert_eq!(disk_size % sector_size as u64, 0);
         let alternate_lba = disk_size / sector_size as u64 - 1;
         let last_usable_lba = alternate_lba - partition_table_size_in_sectors - 1;
@@ -320,7 +559,7 @@ impl Gpt {
         let disk_guid = Uuid::new_v4();
         info!("Created new GPT with GUID: {}", disk_guid);
 
-        Ok(Self {
+        Self {
             partitions: Vec::new(),
             revision: Self::REVISION,
             reserved: 0,
@@ -333,7 +572,7 @@ impl Gpt {
             partition_table_entries_num,
             partition_table_entry_size,
             header_additional_data: Vec::new(),
-        })
+        }
     }
 
     pub fn update(&mut self, disk: &mut dyn Disk) -> Result<()> {
@@ -462,11 +701,8 @@ impl Gpt {
         disk.write_all(partition_table_buffer.as_slice())?;
 
         // Write backup partition table
-        let partition_table_size_in_sectors = round_up!(
-            self.partition_table_entry_size as u64 * self.partition_table_entries_num as u64,
-            sector_size as u64
-        ) / sector_size as u64;
-        let backup_partition_table_lba = self.alternate_lba - partition_table_size_in_sectors;
+        let backup_partition_table_lba =
+            self.alternate_lba - self.partition_table_sectors(sector_size as u64);
         disk.seek(SeekFrom::Start(
             backup_partition_table_lba * sector_size as u64,
         ))?;
@@ -514,6 +750,70 @@ impl Gpt {
         Ok(())
     }
 
+    /// Moves backup header and partition array to the end of disk, used after disk
+    /// size changed. Stale backup header is zeroed and changes are written to disk.
+    pub fn move_backup_to_end(&mut self, disk: &mut dyn Disk) -> Result<()> {
+        let sector_size = disk.sector_size() as u64;
+        let alternate_lba = disk.disk_size() / sector_size - 1;
+        let last_usable_lba = self.last_usable_lba_for_size(disk.disk_size(), sector_size);
+
+        if let Some(i) = self
+            .partitions
+            .iter()
+            .position(|x| matches!(x, Some(x) if x.end_lba > last_usable_lba))
+        {
+            return Err(Error::InvalidGpt(format!(
+                "partition {} extends beyond last usable LBA {}",
+                i, last_usable_lba
+            )));
+        }
+
+        // stale backup header left in the middle of grown disk could confuse tools
+        let old_alternate_lba = self.alternate_lba;
+        if old_alternate_lba < alternate_lba {
+            disk.seek(SeekFrom::Start(old_alternate_lba * sector_size))?;
+            disk.write_all(&vec![0u8; sector_size as usize])?;
+        }
+
+        self.alternate_lba = alternate_lba;
+        self.last_usable_lba = last_usable_lba;
+        self.update(disk)
+    }
+
+    /// Extends partition with the highest end LBA up to the last usable LBA, returns
+    /// its index or None if there is nothing to grow
+    pub fn grow_last_partition(&mut self) -> Option<u32> {
+        let last_usable_lba = self.last_usable_lba;
+        let (i, partition) = self
+            .partitions
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, x)| x.as_mut().map(|x| (i, x)))
+            .max_by_key(|(_, x)| x.end_lba)?;
+
+        if partition.end_lba >= last_usable_lba {
+            return None;
+        }
+        partition.end_lba = last_usable_lba;
+
+        Some(i as u32)
+    }
+
+    /// Last usable LBA of this GPT with backup moved to the end of disk of given size
+    pub fn last_usable_lba_for_size(&self, disk_size: u64, sector_size: u64) -> u64 {
+        (disk_size / sector_size)
+            .saturating_sub(1)
+            .saturating_sub(self.partition_table_sectors(sector_size))
+            .saturating_sub(1)
+    }
+
+    fn partition_table_sectors(&self, sector_size: u64) -> u64 {
+        round_up!(
+            self.partition_table_entry_size as u64 * self.partition_table_entries_num as u64,
+            sector_size
+        ) / sector_size
+    }
+
     pub fn find_partition_by_guid(&self, guid: Uuid) -> Result<(u32, &GptPartition)> {
         for (i, x) in self
             .partitions
@@ -592,6 +892,7 @@ impl PartitionTable for Gpt {
     }
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct GptPartition {
     pub type_guid: Uuid,
     pub unique_guid: Uuid,
@@ -616,6 +917,16 @@ impl GptPartition {
             partition_name: name.to_owned(),
         }
     }
+
+    /// Returns known attribute flags, reserved bits are ignored
+    pub fn flags(&self) -> GptAttributes {
+        GptAttributes::from_bits_truncate(self.attributes)
+    }
+
+    /// Replaces known attribute flags, reserved bits are kept
+    pub fn set_flags(&mut self, flags: GptAttributes) {
+        self.attributes = (self.attributes & !GptAttributes::all().bits()) | flags.bits();
+    }
 }
 
 impl Partition for GptPartition {
@@ -678,3 +989,178 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorAction, Gpt, GptCopy, GptPartition, GptPartitionType};
+    use crate::disk::ram::RamDisk;
+    use crate::disk::Disk;
+    use crate::part::{load_partition_table, mbr::Mbr};
+    use crc::crc32;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    fn corrupt_sector(disk: &mut dyn Disk, lba: u64) {
+        disk.seek(SeekFrom::Start(lba * 512)).unwrap();
+        disk.write_all(&[0xAA; 512]).unwrap();
+    }
+
+    #[test]
+    fn test_backup() {
+        crate::tests_init();
+
+        let mut disk = RamDisk::new_zeroed(512, 20000);
+        Mbr::create_protective(&mut disk).update(&mut disk).unwrap();
+        let mut gpt = Gpt::create(&mut disk).unwrap();
+        gpt.partitions.push(Some(GptPartition::new(
+            GptPartitionType::LinuxFilesystem,
+            "test",
+            2048,
+            4095,
+        )));
+        gpt.update(&mut disk).unwrap();
+        assert_eq!(Gpt::repair(&mut disk, false).unwrap(), None);
+
+        // damaged primary header
+        corrupt_sector(&mut disk, 1);
+        assert!(Gpt::load(&mut disk, ErrorAction::Abort).is_err());
+        let (gpt, copy) = Gpt::load_any(&mut disk, ErrorAction::Abort).unwrap();
+        assert_eq!(copy, GptCopy::Backup);
+        assert_eq!((gpt.current_lba, gpt.alternate_lba), (1, 19999));
+        assert_eq!(gpt.partition_table_start, 2);
+        assert_eq!(gpt.get_partition(0).unwrap().partition_name, "test");
+        let pt = load_partition_table(&mut disk).unwrap();
+        assert_eq!(pt.get_partition_start_end(0), Some((2048, 4095)));
+
+        assert_eq!(
+            Gpt::repair(&mut disk, true).unwrap(),
+            Some(GptCopy::Primary)
+        );
+        assert!(Gpt::load(&mut disk, ErrorAction::Abort).is_err());
+        assert_eq!(
+            Gpt::repair(&mut disk, false).unwrap(),
+            Some(GptCopy::Primary)
+        );
+        let gpt = Gpt::load(&mut disk, ErrorAction::Abort).unwrap();
+        assert_eq!(gpt.get_partition(0).unwrap().partition_name, "test");
+
+        // damaged partition arrays
+        corrupt_sector(&mut disk, 19998);
+        assert_eq!(
+            Gpt::repair(&mut disk, false).unwrap(),
+            Some(GptCopy::Backup)
+        );
+        assert_eq!(Gpt::repair(&mut disk, false).unwrap(), None);
+        corrupt_sector(&mut disk, 2);
+        assert_eq!(
+            Gpt::repair(&mut disk, false).unwrap(),
+            Some(GptCopy::Primary)
+        );
+        assert_eq!(Gpt::repair(&mut disk, false).unwrap(), None);
+
+        // backup with valid checksums that doesn't match primary
+        let mut primary = vec![0u8; 33 * 512];
+        disk.seek(SeekFrom::Start(512)).unwrap();
+        disk.read_exact(&mut primary).unwrap();
+        let mut gpt = Gpt::load(&mut disk, ErrorAction::Abort).unwrap();
+        gpt.partitions[0].as_mut().unwrap().partition_name = "stale".to_owned();
+        gpt.update(&mut disk).unwrap();
+        disk.seek(SeekFrom::Start(512)).unwrap();
+        disk.write_all(&primary).unwrap();
+        assert_eq!(Gpt::repair(&mut disk, true).unwrap(), Some(GptCopy::Backup));
+        assert_eq!(
+            Gpt::repair(&mut disk, false).unwrap(),
+            Some(GptCopy::Backup)
+        );
+        assert_eq!(Gpt::repair(&mut disk, false).unwrap(), None);
+        let gpt = Gpt::load_backup(&mut disk, ErrorAction::Abort).unwrap();
+        assert_eq!(gpt.get_partition(0).unwrap().partition_name, "test");
+
+        corrupt_sector(&mut disk, 1);
+        corrupt_sector(&mut disk, 19999);
+        assert!(Gpt::repair(&mut disk, false).is_err());
+        assert!(Gpt::load_any(&mut disk, ErrorAction::Abort).is_err());
+    }
+
+    #[test]
+    fn test_empty_partition_table() {
+        crate::tests_init();
+
+        let mut disk = RamDisk::new_zeroed(512, 20000);
+        Mbr::create_protective(&mut disk).update(&mut disk).unwrap();
+        Gpt::create(&mut disk).unwrap().update(&mut disk).unwrap();
+        corrupt_sector(&mut disk, 1);
+
+        // backup header with zero entries and otherwise valid checksums
+        let mut header = [0u8; 512];
+        disk.seek(SeekFrom::Start(19999 * 512)).unwrap();
+        disk.read_exact(&mut header).unwrap();
+        header[80..84].copy_from_slice(&0u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32::checksum_ieee(&[]).to_le_bytes());
+        header[16..20].copy_from_slice(&[0; 4]);
+        let crc = crc32::checksum_ieee(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.seek(SeekFrom::Start(19999 * 512)).unwrap();
+        disk.write_all(&header).unwrap();
+
+        assert!(Gpt::load_backup(&mut disk, ErrorAction::Abort).is_err());
+        assert!(Gpt::load_any(&mut disk, ErrorAction::Ignore).is_err());
+        // falls back to protective MBR
+        assert!(load_partition_table(&mut disk).is_ok());
+    }
+
+    #[test]
+    fn test_read_header_info() {
+        crate::tests_init();
+
+        let mut disk = RamDisk::new_zeroed(512, 20000);
+        let mut gpt = Gpt::create(&mut disk).unwrap();
+        gpt.update(&mut disk).unwrap();
+
+        let primary = Gpt::read_header_info(&mut disk, 1).unwrap();
+        let backup = Gpt::read_header_info(&mut disk, 19999).unwrap();
+        assert!(primary.is_valid() && backup.is_valid());
+        assert_eq!((primary.current_lba, primary.alternate_lba), (1, 19999));
+        assert_eq!((backup.current_lba, backup.alternate_lba), (19999, 1));
+        assert_eq!(primary.disk_guid, gpt.disk_guid);
+        assert_eq!(primary.header_size, 92);
+        assert!(primary.is_consistent_with(&backup));
+
+        corrupt_sector(&mut disk, 2);
+        let primary = Gpt::read_header_info(&mut disk, 1).unwrap();
+        assert!(primary.header_crc32_valid);
+        assert!(!primary.partition_table_crc32_valid);
+        assert!(!primary.is_consistent_with(&backup));
+
+        corrupt_sector(&mut disk, 1);
+        assert!(Gpt::read_header_info(&mut disk, 1).is_err());
+    }
+
+    #[test]
+    fn test_move_backup_to_end() {
+        crate::tests_init();
+
+        // GPT created for smaller disk simulates enlarged disk
+        let mut small = RamDisk::new_zeroed(512, 20000);
+        let mut disk = RamDisk::new_zeroed(512, 30000);
+        let mut gpt = Gpt::create(&mut small).unwrap();
+        gpt.partitions.push(Some(GptPartition::new(
+            GptPartitionType::LinuxFilesystem,
+            "",
+            2048,
+            4095,
+        )));
+        gpt.update(&mut disk).unwrap();
+
+        gpt.move_backup_to_end(&mut disk).unwrap();
+        assert_eq!((gpt.alternate_lba, gpt.last_usable_lba), (29999, 29966));
+        assert!(Gpt::load_at(&mut disk, 19999, ErrorAction::Abort).is_err());
+        assert_eq!(Gpt::repair(&mut disk, false).unwrap(), None);
+
+        assert_eq!(gpt.grow_last_partition(), Some(0));
+        assert_eq!(gpt.get_partition(0).unwrap().end_lba, 29966);
+        assert_eq!(gpt.grow_last_partition(), None);
+
+        // partitions must fit when disk shrinks
+        assert!(gpt.move_backup_to_end(&mut small).is_err());
+    }
+}
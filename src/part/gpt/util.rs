@@ -1,7 +1,5 @@
-use std::iter::once;
-
 use super::Gpt;
-use crate::region::Region;
+use crate::region::{find_free_regions, Region};
 
 impl Gpt {
     pub fn find_free_regions(&self) -> Vec<Region<u64>> {
@@ -15,32 +13,6 @@ impl Gpt {
     }
 }
 
-fn find_free_regions(
-    usable_region: Region<u64>,
-    region_it: &mut dyn Iterator<Item = Region<u64>>,
-) -> Vec<Region<u64>> {
-    let mut usable_regions = once(Some(usable_region)).collect::<Vec<_>>();
-
-    for used_region in region_it {
-        for i in 0..usable_regions.len() {
-            if let Some(usable) = &usable_regions[i] {
-                let (first, second) = usable.substract(&used_region);
-                if let Some(first) = first {
-                    usable_regions[i] = Some(first);
-
-                    if let Some(second) = second {
-                        usable_regions.push(Some(second));
-                    }
-                } else {
-                    usable_regions[i] = None;
-                }
-            }
-        }
-    }
-
-    usable_regions.iter().copied().flatten().collect()
-}
-
 #[cfg(test)]
 #[test]
 fn test_find_free_regions() {
@@ -1,10 +1,14 @@
 use std::convert::TryInto;
 use std::io::{self, Cursor, SeekFrom, Write};
 
+use super::gpt::Gpt;
 use super::PartitionTable;
 use crate::disk::{Disk, MediaType};
+use crate::region::{find_free_regions, Region};
 use crate::{u8_array_uninitialized, Error, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::min;
+use std::iter::once;
 
 #[rustfmt::skip]
 pub const CODE_NONBOOTABLE: [u8; 446] = 
@@ -43,6 +47,102 @@ pub const CODE_NONBOOTABLE: [u8; 446] =
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
+pub const TYPE_EXTENDED: u8 = 0x05;
+pub const TYPE_FAT32_LBA: u8 = 0x0C;
+pub const TYPE_EXTENDED_LBA: u8 = 0x0F;
+pub const TYPE_LINUX_SWAP: u8 = 0x82;
+pub const TYPE_LINUX: u8 = 0x83;
+pub const TYPE_NTFS: u8 = 0x07;
+pub const TYPE_PROTECTIVE: u8 = 0xEE;
+pub const TYPE_EXTENDED_LINUX: u8 = 0x85;
+pub const TYPE_EFI: u8 = 0xEF;
+
+/// Index of the first logical partition, primary partitions have indices 0 to 3
+pub const FIRST_LOGICAL_INDEX: usize = 5;
+
+/// Bit of partition flags marking partition as bootable
+pub const FLAG_ACTIVE: u8 = 0x80;
+
+// type, name, aliases
+const PARTITION_TYPES: &[(u8, &str, &[&str])] = &[
+    (0x01, "FAT12", &["fat12"]),
+    (0x04, "FAT16 <32M", &[]),
+    (TYPE_EXTENDED, "Extended", &[]),
+    (0x06, "FAT16", &[]),
+    (TYPE_NTFS, "NTFS/exFAT", &["ntfs", "exfat"]),
+    (0x0B, "FAT32", &[]),
+    (TYPE_FAT32_LBA, "FAT32 LBA", &["fat32", "vfat"]),
+    (0x0E, "FAT16 LBA", &["fat16"]),
+    (TYPE_EXTENDED_LBA, "Extended LBA", &["extended"]),
+    (TYPE_LINUX_SWAP, "Linux swap", &["swap"]),
+    (TYPE_LINUX, "Linux", &["linux"]),
+    (TYPE_EXTENDED_LINUX, "Linux extended", &[]),
+    (0x8E, "Linux LVM", &["lvm"]),
+    (TYPE_PROTECTIVE, "GPT protective", &[]),
+    (TYPE_EFI, "EFI System", &["efi", "esp"]),
+];
+
+pub fn partition_type_to_name(partition_type: u8) -> Option<&'static str> {
+    PARTITION_TYPES
+        .iter()
+        .find(|x| x.0 == partition_type)
+        .map(|x| x.1)
+}
+
+pub fn is_extended_type(partition_type: u8) -> bool {
+    matches!(
+        partition_type,
+        TYPE_EXTENDED | TYPE_EXTENDED_LBA | TYPE_EXTENDED_LINUX
+    )
+}
+
+/// Looks up partition type by alias such as linux, ntfs or fat32
+pub fn partition_type_from_alias(alias: &str) -> Option<u8> {
+    let alias = alias.to_lowercase();
+    PARTITION_TYPES
+        .iter()
+        .find(|x| x.2.contains(&alias.as_str()))
+        .map(|x| x.0)
+}
+
+/// Geometry used to translate LBA into CHS address
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Geometry {
+    pub heads: u8,
+    pub sectors_per_track: u8,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            heads: 255,
+            sectors_per_track: 63,
+        }
+    }
+}
+
+impl Geometry {
+    /// Returns (cylinder, head, sector), addresses beyond cylinder 1023 can't be
+    /// represented and are clamped to the last CHS address
+    pub fn lba_to_chs(&self, lba: u64) -> (u16, u8, u8) {
+        let heads = self.heads as u64;
+        let sectors = self.sectors_per_track as u64;
+        debug_assert!(heads > 0 && sectors > 0 && sectors <= 63);
+
+        let cylinder = lba / (heads * sectors);
+        if cylinder > 1023 {
+            return (1023, self.heads - 1, self.sectors_per_track);
+        }
+
+        (
+            cylinder as u16,
+            (lba / sectors % heads) as u8,
+            (lba % sectors + 1) as u8,
+        )
+    }
+}
+
+#[derive(Clone)]
 pub struct MbrPartition {
     pub flags: u8,
     pub start_chs: (u16, u8, u8),
@@ -53,14 +153,74 @@ pub struct MbrPartition {
     pub sector_size: u32,
 }
 impl MbrPartition {
-    pub fn decode(sector_size: u32, buf: &[u8; 16]) -> Option<Self> {
-        let mut cursor = Cursor::new(buf);
+    /// Creates inactive partition with CHS addresses computed from geometry
+    pub fn new(
+        partition_type: u8,
+        lba: u32,
+        num_sectors: u32,
+        sector_size: u32,
+        geometry: &Geometry,
+    ) -> Self {
+        let mut partition = Self {
+            flags: 0,
+            start_chs: (0, 0, 0),
+            partition_type,
+            end_chs: (0, 0, 0),
+            lba,
+            num_sectors,
+            sector_size,
+        };
+        partition.update_chs(geometry);
+        partition
+    }
 
-        let flags = cursor.read_u8().unwrap();
-        if flags == 0 {
+    /// Recomputes CHS addresses from LBA and size
+    pub fn update_chs(&mut self, geometry: &Geometry) {
+        self.start_chs = geometry.lba_to_chs(self.lba as u64);
+        self.end_chs =
+            geometry.lba_to_chs((self.lba as u64 + self.num_sectors as u64).saturating_sub(1));
+    }
+
+    /// Entries with zero type or size don't describe any partition
+    pub fn is_empty(&self) -> bool {
+        self.partition_type == 0 || self.num_sectors == 0
+    }
+
+    /// Checks that CHS addresses match LBA and size, addresses which can't be
+    /// represented in CHS only need to have the maximal cylinder
+    pub fn chs_matches(&self, geometry: &Geometry) -> bool {
+        let end = (self.lba as u64 + self.num_sectors as u64).saturating_sub(1);
+        let matches = |chs: (u16, u8, u8), expected: (u16, u8, u8)| {
+            chs == expected || (expected.0 == 1023 && chs.0 == 1023)
+        };
+
+        matches(self.start_chs, geometry.lba_to_chs(self.lba as u64))
+            && matches(self.end_chs, geometry.lba_to_chs(end))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.flags & FLAG_ACTIVE != 0
+    }
+
+    /// Returns sectors occupied by partition, partition must not be empty
+    pub fn region(&self) -> Region<u64> {
+        Region::new_with_size(self.lba as u64, self.num_sectors as u64)
+    }
+
+    /// Decodes partition entry, returns None for empty slot
+    pub fn decode(sector_size: u32, buf: &[u8; 16]) -> Option<Self> {
+        let partition = Self::decode_entry(sector_size, buf);
+        if partition.is_empty() {
             return None;
         }
 
+        Some(partition)
+    }
+
+    fn decode_entry(sector_size: u32, buf: &[u8; 16]) -> Self {
+        let mut cursor = Cursor::new(buf);
+
+        let flags = cursor.read_u8().unwrap();
         let start_chs = Self::decode_chs(&mut cursor);
         let partition_type = cursor.read_u8().unwrap();
         let end_chs = Self::decode_chs(&mut cursor);
@@ -70,7 +230,7 @@ impl MbrPartition {
 
         debug_assert_eq!(cursor.position(), 16);
 
-        Some(Self {
+        Self {
             flags,
             start_chs,
             partition_type,
@@ -78,7 +238,7 @@ impl MbrPartition {
             lba,
             num_sectors,
             sector_size,
-        })
+        }
     }
 
     fn decode_chs<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> (u16, u8, u8) {
@@ -119,8 +279,53 @@ impl MbrPartition {
     }
 }
 
+/// Logical partition described by EBR inside of extended partition
+#[derive(Clone)]
+pub struct LogicalPartition {
+    /// Sector of EBR describing this partition
+    pub ebr_lba: u32,
+    /// Partition with LBA relative to the start of disk
+    pub partition: MbrPartition,
+}
+
+/// GPT partition mirrored into hybrid MBR
+#[derive(Debug, Clone)]
+pub struct HybridEntry {
+    /// Index of GPT partition
+    pub gpt_index: u32,
+    /// MBR partition type
+    pub partition_type: u8,
+    pub active: bool,
+}
+
+/// Problems found in partition table by `Mbr::validate`, partitions are
+/// identified by index
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// Pairs of partitions sharing some sectors
+    pub overlapping: Vec<(usize, usize)>,
+    /// Partitions ending beyond the end of disk
+    pub beyond_disk: Vec<usize>,
+    /// Partitions whose CHS addresses don't match LBA
+    pub chs_mismatch: Vec<usize>,
+    /// Active partitions, reported only if there is more than one
+    pub multiple_active: Vec<usize>,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.overlapping.is_empty()
+            && self.beyond_disk.is_empty()
+            && self.chs_mismatch.is_empty()
+            && self.multiple_active.is_empty()
+    }
+}
+
 pub struct Mbr {
     pub partitions: [Option<MbrPartition>; 4],
+    /// Logical partitions in order of EBR chain, they have indices starting at
+    /// `FIRST_LOGICAL_INDEX`
+    pub logical: Vec<LogicalPartition>,
     pub code: [u8; 446],
 }
 impl Mbr {
@@ -145,10 +350,78 @@ impl Mbr {
             );
         }
 
-        Ok(Self {
+        let mut mbr = Self {
             partitions,
+            logical: Vec::new(),
             code: buf[..446].try_into().unwrap(),
-        })
+        };
+        if let Some(extended) = mbr.extended_partition() {
+            mbr.logical = Self::load_logical(disk, extended)?;
+        }
+
+        Ok(mbr)
+    }
+
+    /// Walks EBR chain of extended partition, invalid links end the chain
+    fn load_logical(disk: &mut dyn Disk, extended: &MbrPartition) -> Result<Vec<LogicalPartition>> {
+        let sector_size = disk.sector_size() as u64;
+        let extended_region = extended.region();
+        let mut logical = Vec::new();
+        let mut visited = Vec::new();
+        let mut ebr_lba = extended.lba as u64;
+        let mut buf = [0u8; 512];
+
+        loop {
+            visited.push(ebr_lba);
+            disk.seek(SeekFrom::Start(ebr_lba * sector_size))?;
+            disk.read_exact(&mut buf)?;
+
+            if buf[0x1FE] != 0x55 || buf[0x1FF] != 0xAA {
+                warn!("EBR at sector {} has invalid signature", ebr_lba);
+                break;
+            }
+
+            let mut partition = MbrPartition::decode_entry(
+                sector_size as u32,
+                &buf[0x1BE..0x1CE].try_into().unwrap(),
+            );
+            let link = MbrPartition::decode_entry(
+                sector_size as u32,
+                &buf[0x1CE..0x1DE].try_into().unwrap(),
+            );
+
+            if !partition.is_empty() {
+                let lba = ebr_lba + partition.lba as u64;
+                let region = Region::new_with_size(lba, partition.num_sectors as u64);
+                if !region.belongs(&extended_region) {
+                    warn!(
+                        "logical partition in EBR at sector {} is outside of extended partition",
+                        ebr_lba
+                    );
+                    break;
+                }
+
+                partition.lba = lba as u32;
+                logical.push(LogicalPartition {
+                    ebr_lba: ebr_lba as u32,
+                    partition,
+                });
+            }
+
+            if link.is_empty() {
+                break;
+            }
+
+            // links are relative to the start of extended partition
+            let next = extended.lba as u64 + link.lba as u64;
+            if next > extended_region.end() || visited.contains(&next) {
+                warn!("EBR at sector {} has invalid link", ebr_lba);
+                break;
+            }
+            ebr_lba = next;
+        }
+
+        Ok(logical)
     }
 
     pub fn update(&mut self, disk: &mut dyn Disk) -> Result<()> {
@@ -171,28 +444,472 @@ impl Mbr {
         disk.seek(SeekFrom::Start(0))?;
         disk.write_all(&buffer[..])?;
 
+        if let Some(extended) = self.extended_partition() {
+            self.write_logical(disk, extended)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes EBR chain, chain always starts at the first sector of extended partition
+    /// even if there is no logical partition
+    fn write_logical(&self, disk: &mut dyn Disk, extended: &MbrPartition) -> Result<()> {
+        let geometry = Geometry::default();
+
+        let mut chain: Vec<(u32, Option<&MbrPartition>)> = self
+            .logical
+            .iter()
+            .map(|x| (x.ebr_lba, Some(&x.partition)))
+            .collect();
+        if chain.first().map(|x| x.0) != Some(extended.lba) {
+            chain.insert(0, (extended.lba, None));
+        }
+
+        for (i, (ebr_lba, partition)) in chain.iter().enumerate() {
+            let entry = partition.map(|x| {
+                let mut entry = x.clone();
+                entry.lba -= ebr_lba;
+                entry
+            });
+            let link = chain.get(i + 1).map(|(next_ebr_lba, next)| {
+                let end = next.map_or(*next_ebr_lba, |x| x.lba + (x.num_sectors - 1));
+                let mut link = MbrPartition::new(
+                    TYPE_EXTENDED,
+                    *next_ebr_lba,
+                    end - next_ebr_lba + 1,
+                    extended.sector_size,
+                    &geometry,
+                );
+                link.lba -= extended.lba;
+                link
+            });
+
+            Self::write_ebr(disk, *ebr_lba, entry.as_ref(), link.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    fn write_ebr(
+        disk: &mut dyn Disk,
+        ebr_lba: u32,
+        entry: Option<&MbrPartition>,
+        link: Option<&MbrPartition>,
+    ) -> Result<()> {
+        let mut buffer = [0u8; 512];
+        let mut cursor = Cursor::new(&mut buffer[0x1BE..]);
+        for p in [entry, link].iter() {
+            if let Some(p) = p {
+                p.write(&mut cursor).unwrap();
+            } else {
+                cursor.write_all(&[0u8; 16]).unwrap();
+            }
+        }
+        buffer[0x1FE] = 0x55;
+        buffer[0x1FF] = 0xAA;
+
+        disk.seek(SeekFrom::Start(ebr_lba as u64 * disk.sector_size() as u64))?;
+        disk.write_all(&buffer)?;
+
         Ok(())
     }
 
+    pub fn extended_partition(&self) -> Option<&MbrPartition> {
+        self.partitions
+            .iter()
+            .flatten()
+            .find(|x| is_extended_type(x.partition_type) && x.num_sectors != 0)
+    }
+
+    /// Returns primary partition for indices 0 to 3 and logical partition for
+    /// indices starting at `FIRST_LOGICAL_INDEX`
+    pub fn get_partition(&self, index: usize) -> Option<&MbrPartition> {
+        if index >= FIRST_LOGICAL_INDEX {
+            self.logical
+                .get(index - FIRST_LOGICAL_INDEX)
+                .map(|x| &x.partition)
+        } else {
+            self.partitions.get(index)?.as_ref()
+        }
+    }
+
+    pub fn get_partition_mut(&mut self, index: usize) -> Option<&mut MbrPartition> {
+        if index >= FIRST_LOGICAL_INDEX {
+            self.logical
+                .get_mut(index - FIRST_LOGICAL_INDEX)
+                .map(|x| &mut x.partition)
+        } else {
+            self.partitions.get_mut(index)?.as_mut()
+        }
+    }
+
+    /// Creates MBR without any partitions
+    pub fn create_empty() -> Self {
+        Self {
+            partitions: [None, None, None, None],
+            logical: Vec::new(),
+            code: CODE_NONBOOTABLE,
+        }
+    }
+
+    /// Adds partition into the first free slot and returns index of that slot
+    pub fn add_partition(&mut self, partition: MbrPartition) -> Result<usize> {
+        if partition.num_sectors == 0 {
+            return Err(Error::InvalidMbr("partition is empty".to_owned()));
+        }
+        if partition.lba == 0 {
+            return Err(Error::InvalidMbr(
+                "partition would overwrite MBR".to_owned(),
+            ));
+        }
+
+        if is_extended_type(partition.partition_type) && self.extended_partition().is_some() {
+            return Err(Error::InvalidMbr(
+                "there is already an extended partition".to_owned(),
+            ));
+        }
+
+        let region = partition.region();
+        for (i, p) in self.partitions.iter().enumerate() {
+            if let Some(p) = p {
+                if p.num_sectors != 0 && p.region().overlaps(&region) {
+                    return Err(Error::InvalidMbr(format!(
+                        "partition would overlap with #{}",
+                        i
+                    )));
+                }
+            }
+        }
+
+        let slot = self
+            .partitions
+            .iter()
+            .position(|x| x.is_none())
+            .ok_or_else(|| Error::InvalidMbr("partition table is full".to_owned()))?;
+        self.partitions[slot] = Some(partition);
+
+        Ok(slot)
+    }
+
+    /// Adds logical partition into extended partition and returns its index,
+    /// EBR is placed right before the partition
+    pub fn add_logical_partition(&mut self, partition: MbrPartition) -> Result<usize> {
+        let extended = self
+            .extended_partition()
+            .ok_or_else(|| Error::InvalidMbr("there is no extended partition".to_owned()))?
+            .region();
+
+        if partition.num_sectors == 0 {
+            return Err(Error::InvalidMbr("partition is empty".to_owned()));
+        }
+        // the first sector of extended partition is always used by EBR
+        let region = partition.region();
+        if region.start() <= extended.start() || !region.belongs(&extended) {
+            return Err(Error::InvalidMbr(
+                "logical partition must be inside of extended partition".to_owned(),
+            ));
+        }
+
+        let position = self
+            .logical
+            .iter()
+            .position(|x| x.partition.lba > partition.lba)
+            .unwrap_or(self.logical.len());
+        let mut logical = self.logical.clone();
+        logical.insert(
+            position,
+            LogicalPartition {
+                ebr_lba: partition.lba - 1,
+                partition,
+            },
+        );
+        // chain has to start at the beginning of extended partition
+        if position == 0 {
+            logical[0].ebr_lba = extended.start() as u32;
+            if let Some(x) = logical.get_mut(1) {
+                if x.ebr_lba == extended.start() as u32 {
+                    x.ebr_lba = x.partition.lba - 1;
+                }
+            }
+        }
+
+        if logical
+            .windows(2)
+            .any(|x| x[0].partition.region().end() >= x[1].ebr_lba as u64)
+        {
+            return Err(Error::InvalidMbr(
+                "partition would overlap with another logical partition".to_owned(),
+            ));
+        }
+
+        self.logical = logical;
+
+        Ok(FIRST_LOGICAL_INDEX + position)
+    }
+
+    /// Removes primary or logical partition, removing extended partition removes
+    /// all logical partitions
+    pub fn remove_partition(&mut self, index: usize) -> Option<MbrPartition> {
+        if index >= FIRST_LOGICAL_INDEX {
+            let position = index - FIRST_LOGICAL_INDEX;
+            if position >= self.logical.len() {
+                return None;
+            }
+
+            let removed = self.logical.remove(position);
+            if position == 0 {
+                if let Some(x) = self.logical.first_mut() {
+                    x.ebr_lba = removed.ebr_lba;
+                }
+            }
+
+            return Some(removed.partition);
+        }
+
+        let removed = self.partitions.get_mut(index).and_then(|x| x.take());
+        if matches!(&removed, Some(x) if is_extended_type(x.partition_type)) {
+            self.logical.clear();
+        }
+
+        removed
+    }
+
+    /// Changes type of primary or logical partition, keeps at most one extended partition
+    /// and refuses to turn extended partition that still holds logical partitions into
+    /// a regular one
+    pub fn set_partition_type(&mut self, index: usize, partition_type: u8) -> Result<()> {
+        let current = self
+            .get_partition(index)
+            .ok_or(Error::NotFound)?
+            .partition_type;
+
+        if is_extended_type(partition_type) {
+            if index >= FIRST_LOGICAL_INDEX {
+                return Err(Error::InvalidMbr(
+                    "logical partition can't be extended".to_owned(),
+                ));
+            }
+            if !is_extended_type(current) && self.extended_partition().is_some() {
+                return Err(Error::InvalidMbr(
+                    "there is already an extended partition".to_owned(),
+                ));
+            }
+        } else if is_extended_type(current)
+            && index < FIRST_LOGICAL_INDEX
+            && !self.logical.is_empty()
+        {
+            return Err(Error::InvalidMbr(
+                "extended partition contains logical partitions, remove them first".to_owned(),
+            ));
+        }
+
+        self.get_partition_mut(index).unwrap().partition_type = partition_type;
+
+        Ok(())
+    }
+
+    /// Makes partition the only active one, None clears active flag of all partitions
+    pub fn set_active(&mut self, index: Option<usize>) -> Result<()> {
+        if let Some(index) = index {
+            if !matches!(self.partitions.get(index), Some(Some(_))) {
+                return Err(Error::NotFound);
+            }
+        }
+
+        for (i, p) in self.partitions.iter_mut().enumerate() {
+            if let Some(p) = p {
+                if Some(i) == index {
+                    p.flags |= FLAG_ACTIVE;
+                } else {
+                    p.flags &= !FLAG_ACTIVE;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns regions not used by any partition on disk with given number of sectors,
+    /// regions past the 32 bit LBA limit are not included
+    pub fn find_free_regions(&self, num_sectors: u64) -> Vec<Region<u64>> {
+        let last_sector = min(num_sectors, u32::MAX as u64 + 1) - 1;
+        if last_sector < 1 {
+            return Vec::new();
+        }
+
+        let mut it = self
+            .partitions
+            .iter()
+            .flatten()
+            .filter(|x| x.num_sectors != 0)
+            .map(|x| x.region());
+        let mut regions = find_free_regions(Region::new(1, last_sector), &mut it);
+        regions.sort_by_key(|x| x.start());
+        regions
+    }
+
+    /// Returns regions of extended partition not used by logical partitions or their EBRs
+    pub fn find_free_logical_regions(&self) -> Vec<Region<u64>> {
+        let extended = match self.extended_partition() {
+            Some(x) => x.region(),
+            None => return Vec::new(),
+        };
+
+        let mut it = once(Region::new(extended.start(), extended.start())).chain(
+            self.logical
+                .iter()
+                .map(|x| Region::new(x.ebr_lba as u64, x.partition.region().end())),
+        );
+        let mut regions = find_free_regions(extended, &mut it);
+        regions.sort_by_key(|x| x.start());
+        regions
+    }
+
+    /// Checks partition table of disk with given size in bytes, CHS addresses are
+    /// checked against the default geometry
+    pub fn validate(&self, disk_size: u64) -> ValidationReport {
+        let geometry = Geometry::default();
+        let mut report = ValidationReport::default();
+
+        let logical = self
+            .logical
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (FIRST_LOGICAL_INDEX + i, &x.partition));
+        let partitions: Vec<(usize, &MbrPartition)> = self
+            .partitions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| x.as_ref().map(|x| (i, x)))
+            .chain(logical)
+            .collect();
+
+        for (n, (i, p)) in partitions.iter().enumerate() {
+            let region = p.region();
+            for (j, other) in partitions[n + 1..].iter() {
+                // logical partitions are supposed to be inside of extended partition
+                let nested = *j >= FIRST_LOGICAL_INDEX && is_extended_type(p.partition_type);
+                if !nested && region.overlaps(&other.region()) {
+                    report.overlapping.push((*i, *j));
+                }
+            }
+
+            if (region.end() + 1) * p.sector_size as u64 > disk_size {
+                report.beyond_disk.push(*i);
+            }
+            if !p.chs_matches(&geometry) {
+                report.chs_mismatch.push(*i);
+            }
+            if p.is_active() {
+                report.multiple_active.push(*i);
+            }
+        }
+
+        if report.multiple_active.len() < 2 {
+            report.multiple_active.clear();
+        }
+
+        report
+    }
+
+    /// Creates hybrid MBR mirroring up to three GPT partitions, the rest of slots
+    /// is taken by protective partition covering GPT structures before the first
+    /// mirrored partition. All mirrored partitions must end below 2^32 sectors.
+    pub fn create_hybrid(disk: &dyn Disk, gpt: &Gpt, entries: &[HybridEntry]) -> Result<Self> {
+        if entries.is_empty() || entries.len() > 3 {
+            return Err(Error::InvalidMbr(
+                "hybrid MBR needs 1 to 3 partitions".to_owned(),
+            ));
+        }
+        if entries.iter().filter(|x| x.active).count() > 1 {
+            return Err(Error::InvalidMbr(
+                "only one partition can be active".to_owned(),
+            ));
+        }
+
+        let sector_size = disk.sector_size();
+        let geometry = Geometry::default();
+        let mut mbr = Self::create_empty();
+
+        let mut first_lba = u64::MAX;
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.partition_type == 0
+                || entry.partition_type == TYPE_PROTECTIVE
+                || is_extended_type(entry.partition_type)
+            {
+                return Err(Error::InvalidMbr(format!(
+                    "partition type {:02X} can't be used in hybrid MBR",
+                    entry.partition_type
+                )));
+            }
+            if entries[..i].iter().any(|x| x.gpt_index == entry.gpt_index) {
+                return Err(Error::InvalidMbr(format!(
+                    "GPT partition {} is used more than once",
+                    entry.gpt_index
+                )));
+            }
+
+            let p = gpt.get_partition(entry.gpt_index).ok_or_else(|| {
+                Error::InvalidMbr(format!("GPT partition {} not found", entry.gpt_index))
+            })?;
+            if p.start_lba < gpt.first_usable_lba {
+                return Err(Error::InvalidMbr(format!(
+                    "GPT partition {} overlaps GPT structures",
+                    entry.gpt_index
+                )));
+            }
+            if p.end_lba > u32::MAX as u64 || p.end_lba < p.start_lba {
+                return Err(Error::InvalidMbr(format!(
+                    "GPT partition {} is beyond MBR limit",
+                    entry.gpt_index
+                )));
+            }
+
+            let mut partition = MbrPartition::new(
+                entry.partition_type,
+                p.start_lba as u32,
+                (p.end_lba - p.start_lba + 1) as u32,
+                sector_size,
+                &geometry,
+            );
+            if entry.active {
+                partition.flags = FLAG_ACTIVE;
+            }
+            mbr.partitions[i + 1] = Some(partition);
+            first_lba = min(first_lba, p.start_lba);
+        }
+
+        // GPT header and entries are always below the first usable LBA
+        mbr.partitions[0] = Some(MbrPartition::new(
+            TYPE_PROTECTIVE,
+            1,
+            (first_lba - 1) as u32,
+            sector_size,
+            &geometry,
+        ));
+
+        Ok(mbr)
+    }
+
     pub fn create_protective(disk: &mut dyn Disk) -> Self {
         let sector_size = disk.sector_size();
         let size = disk.disk_size();
         let num_sectors = size / sector_size as u64;
+        let mut partition = MbrPartition::new(
+            TYPE_PROTECTIVE,
+            1,
+            (num_sectors - 1).try_into().unwrap_or(u32::MAX),
+            sector_size,
+            &Geometry::default(),
+        );
+        // UEFI requires 0xFFFFFF for addresses that can't be represented
+        if partition.end_chs.0 == 1023 {
+            partition.end_chs = (1023, 255, 63);
+        }
+
         Self {
-            partitions: [
-                Some(MbrPartition {
-                    flags: 0,
-                    start_chs: (0, 0, 1),
-                    end_chs: (1023, 255, 63),
-                    partition_type: 0xEE,
-                    lba: 1,
-                    num_sectors: (num_sectors).try_into().unwrap_or(u32::MAX),
-                    sector_size,
-                }),
-                None,
-                None,
-                None,
-            ],
+            partitions: [Some(partition), None, None, None],
+            logical: Vec::new(),
             code: CODE_NONBOOTABLE,
         }
     }
@@ -200,10 +917,12 @@ impl Mbr {
 
 impl PartitionTable for Mbr {
     fn get_partition_start_end(&self, index: u32) -> Option<(u64, u64)> {
-        if let Some(Some(part)) = self.partitions.get(index as usize) {
-            Some((part.lba as u64, part.lba as u64 + part.num_sectors as u64))
-        } else {
-            None
+        match self.get_partition(index as usize) {
+            Some(part) if part.num_sectors != 0 => {
+                let region = part.region();
+                Some((region.start(), region.end()))
+            }
+            _ => None,
         }
     }
 
@@ -212,3 +931,269 @@ impl PartitionTable for Mbr {
         Err(Error::NotSupported)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::HybridEntry;
+    use super::{
+        partition_type_from_alias, partition_type_to_name, Geometry, Mbr, MbrPartition,
+        FIRST_LOGICAL_INDEX, FLAG_ACTIVE, TYPE_EXTENDED, TYPE_EXTENDED_LBA, TYPE_FAT32_LBA,
+        TYPE_LINUX, TYPE_NTFS, TYPE_PROTECTIVE,
+    };
+    use crate::disk::ram::RamDisk;
+    use crate::part::gpt::{Gpt, GptPartition, GptPartitionType};
+    use crate::part::PartitionTable;
+
+    #[test]
+    fn test_lba_to_chs() {
+        crate::tests_init();
+
+        let geometry = Geometry::default();
+        assert_eq!(geometry.lba_to_chs(0), (0, 0, 1));
+        assert_eq!(geometry.lba_to_chs(62), (0, 0, 63));
+        assert_eq!(geometry.lba_to_chs(63), (0, 1, 1));
+        assert_eq!(geometry.lba_to_chs(2048), (0, 32, 33));
+        assert_eq!(geometry.lba_to_chs(255 * 63), (1, 0, 1));
+        assert_eq!(geometry.lba_to_chs(u32::MAX as u64), (1023, 254, 63));
+    }
+
+    #[test]
+    fn test_partition_types() {
+        crate::tests_init();
+
+        assert_eq!(partition_type_from_alias("FAT32"), Some(TYPE_FAT32_LBA));
+        assert_eq!(partition_type_from_alias("linux"), Some(TYPE_LINUX));
+        assert_eq!(partition_type_from_alias("ntfs"), Some(TYPE_NTFS));
+        assert_eq!(partition_type_from_alias("unknown"), None);
+        assert_eq!(partition_type_to_name(TYPE_LINUX), Some("Linux"));
+    }
+
+    #[test]
+    fn test_edit() {
+        crate::tests_init();
+
+        let geometry = Geometry::default();
+        let mut mbr = Mbr::create_empty();
+        let p = MbrPartition::new(TYPE_FAT32_LBA, 2048, 2048, 512, &geometry);
+        assert_eq!(p.start_chs, (0, 32, 33));
+        assert_eq!(p.end_chs, (0, 65, 1));
+        assert_eq!(mbr.add_partition(p).unwrap(), 0);
+
+        // overlapping, empty and MBR sector partitions are refused
+        let p = MbrPartition::new(TYPE_LINUX, 4095, 100, 512, &geometry);
+        assert!(mbr.add_partition(p).is_err());
+        let p = MbrPartition::new(TYPE_LINUX, 4096, 0, 512, &geometry);
+        assert!(mbr.add_partition(p).is_err());
+        let p = MbrPartition::new(TYPE_LINUX, 0, 1, 512, &geometry);
+        assert!(mbr.add_partition(p).is_err());
+
+        let p = MbrPartition::new(TYPE_LINUX, 4096, 4096, 512, &geometry);
+        assert_eq!(mbr.add_partition(p).unwrap(), 1);
+        assert_eq!(mbr.get_partition_start_end(1), Some((4096, 8191)));
+
+        let free = mbr.find_free_regions(10000);
+        assert_eq!(free.len(), 2);
+        assert_eq!((free[0].start(), free[0].end()), (1, 2047));
+        assert_eq!((free[1].start(), free[1].end()), (8192, 9999));
+
+        mbr.set_active(Some(1)).unwrap();
+        mbr.set_active(Some(0)).unwrap();
+        assert!(mbr.partitions[0].as_ref().unwrap().is_active());
+        assert!(!mbr.partitions[1].as_ref().unwrap().is_active());
+        assert!(mbr.set_active(Some(2)).is_err());
+
+        assert!(mbr.remove_partition(0).is_some());
+        assert!(mbr.remove_partition(0).is_none());
+        assert_eq!(mbr.find_free_regions(10000)[0].end(), 4095);
+    }
+
+    #[test]
+    fn test_logical() {
+        crate::tests_init();
+
+        let geometry = Geometry::default();
+        let mut disk = RamDisk::new_zeroed(512, 20000);
+        let mut mbr = Mbr::create_empty();
+        let p = MbrPartition::new(TYPE_FAT32_LBA, 2048, 2048, 512, &geometry);
+        mbr.add_partition(p).unwrap();
+        let p = MbrPartition::new(TYPE_EXTENDED_LBA, 4096, 15904, 512, &geometry);
+        assert_eq!(mbr.add_partition(p).unwrap(), 1);
+
+        // logical partitions are kept in order of their position
+        let p = MbrPartition::new(TYPE_LINUX, 10240, 4096, 512, &geometry);
+        assert_eq!(mbr.add_logical_partition(p).unwrap(), FIRST_LOGICAL_INDEX);
+        let p = MbrPartition::new(TYPE_NTFS, 6144, 2048, 512, &geometry);
+        assert_eq!(mbr.add_logical_partition(p).unwrap(), FIRST_LOGICAL_INDEX);
+        let p = MbrPartition::new(TYPE_LINUX, 8192, 2049, 512, &geometry);
+        assert!(mbr.add_logical_partition(p).is_err());
+        let p = MbrPartition::new(TYPE_LINUX, 16384, 8192, 512, &geometry);
+        assert!(mbr.add_logical_partition(p).is_err());
+        assert_eq!(mbr.logical[0].ebr_lba, 4096);
+        assert_eq!(mbr.logical[1].ebr_lba, 10239);
+
+        let free = mbr.find_free_logical_regions();
+        assert_eq!(free.len(), 2);
+        assert_eq!((free[0].start(), free[0].end()), (8192, 10238));
+        assert_eq!((free[1].start(), free[1].end()), (14336, 19999));
+
+        mbr.update(&mut disk).unwrap();
+        let mut mbr = Mbr::load(&mut disk).unwrap();
+        assert_eq!(mbr.logical.len(), 2);
+        assert_eq!(mbr.get_partition_start_end(5), Some((6144, 8191)));
+        assert_eq!(mbr.get_partition_start_end(6), Some((10240, 14335)));
+        assert_eq!(mbr.get_partition(6).unwrap().partition_type, TYPE_LINUX);
+        assert_eq!(mbr.get_partition_start_end(7), None);
+
+        // removing the first logical partition keeps the chain at extended partition start
+        assert_eq!(mbr.remove_partition(5).unwrap().lba, 6144);
+        assert_eq!(mbr.logical[0].ebr_lba, 4096);
+        mbr.update(&mut disk).unwrap();
+        let mut mbr = Mbr::load(&mut disk).unwrap();
+        assert_eq!(mbr.logical.len(), 1);
+        assert_eq!(mbr.get_partition_start_end(5), Some((10240, 14335)));
+
+        // extended partition can't lose its type while it holds logical partitions
+        assert!(mbr.set_partition_type(1, TYPE_LINUX).is_err());
+        assert!(mbr.set_partition_type(0, TYPE_EXTENDED_LBA).is_err());
+        assert!(mbr.set_partition_type(5, TYPE_EXTENDED_LBA).is_err());
+        mbr.set_partition_type(5, TYPE_NTFS).unwrap();
+        mbr.set_partition_type(1, TYPE_EXTENDED_LBA).unwrap();
+        assert_eq!(mbr.get_partition(5).unwrap().partition_type, TYPE_NTFS);
+
+        mbr.remove_partition(1).unwrap();
+        assert!(mbr.logical.is_empty());
+        mbr.set_partition_type(0, TYPE_EXTENDED_LBA).unwrap();
+    }
+
+    #[test]
+    fn test_decode() {
+        crate::tests_init();
+
+        let mut entry = [0u8; 16];
+        assert!(MbrPartition::decode(512, &entry).is_none());
+
+        // inactive partition
+        entry[4] = TYPE_LINUX;
+        entry[8..12].copy_from_slice(&2048u32.to_le_bytes());
+        assert!(MbrPartition::decode(512, &entry).is_none());
+        entry[12..16].copy_from_slice(&4096u32.to_le_bytes());
+        let p = MbrPartition::decode(512, &entry).unwrap();
+        assert!(!p.is_active());
+        assert_eq!((p.lba, p.num_sectors), (2048, 4096));
+
+        // type 0 marks empty slot even if the rest is filled
+        entry[0] = FLAG_ACTIVE;
+        entry[4] = 0;
+        assert!(MbrPartition::decode(512, &entry).is_none());
+    }
+
+    #[test]
+    fn test_protective() {
+        crate::tests_init();
+
+        let mut disk = RamDisk::new_zeroed(512, 20000);
+        Mbr::create_protective(&mut disk).update(&mut disk).unwrap();
+
+        let mbr = Mbr::load(&mut disk).unwrap();
+        let p = mbr.partitions[0].as_ref().unwrap();
+        assert_eq!(p.partition_type, TYPE_PROTECTIVE);
+        assert_eq!(mbr.get_partition_start_end(0), Some((1, 19999)));
+        assert!(mbr.validate(20000 * 512).is_clean());
+    }
+
+    #[test]
+    fn test_validate() {
+        crate::tests_init();
+
+        let geometry = Geometry::default();
+        let mut mbr = Mbr::create_empty();
+        mbr.partitions[0] = Some(MbrPartition::new(TYPE_LINUX, 2048, 4096, 512, &geometry));
+        mbr.partitions[1] = Some(MbrPartition::new(TYPE_LINUX, 4096, 4096, 512, &geometry));
+        mbr.partitions[2] = Some(MbrPartition::new(
+            TYPE_EXTENDED,
+            8192,
+            12000,
+            512,
+            &geometry,
+        ));
+        mbr.add_logical_partition(MbrPartition::new(TYPE_LINUX, 10240, 2048, 512, &geometry))
+            .unwrap();
+        mbr.partitions[0].as_mut().unwrap().flags = FLAG_ACTIVE;
+        let report = mbr.validate(20000 * 512);
+        assert_eq!(report.overlapping, vec![(0, 1)]);
+        assert_eq!(report.beyond_disk, vec![2]);
+        assert!(report.chs_mismatch.is_empty());
+        assert!(report.multiple_active.is_empty());
+
+        mbr.partitions[1].as_mut().unwrap().flags = FLAG_ACTIVE;
+        mbr.partitions[2].as_mut().unwrap().end_chs = (0, 0, 1);
+        let report = mbr.validate(20000 * 512);
+        assert_eq!(report.chs_mismatch, vec![2]);
+        assert_eq!(report.multiple_active, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_hybrid() {
+        crate::tests_init();
+
+        let mut disk = RamDisk::new_zeroed(512, 20000);
+        let mut gpt = Gpt::create(&mut disk).unwrap();
+        gpt.partitions = vec![
+            Some(GptPartition::new(
+                GptPartitionType::EFISystemPartition,
+                "",
+                2048,
+                4095,
+            )),
+            Some(GptPartition::new(
+                GptPartitionType::LinuxFilesystem,
+                "",
+                4096,
+                8191,
+            )),
+            Some(GptPartition::new(
+                GptPartitionType::LinuxFilesystem,
+                "",
+                8192,
+                u32::MAX as u64 + 1,
+            )),
+        ];
+
+        let entry = |gpt_index, partition_type, active| HybridEntry {
+            gpt_index,
+            partition_type,
+            active,
+        };
+        let mbr = Mbr::create_hybrid(
+            &disk,
+            &gpt,
+            &[entry(1, TYPE_LINUX, true), entry(0, 0xEF, false)],
+        )
+        .unwrap();
+        assert_eq!(
+            mbr.partitions[0].as_ref().unwrap().partition_type,
+            TYPE_PROTECTIVE
+        );
+        assert_eq!(mbr.get_partition_start_end(0), Some((1, 2047)));
+        assert_eq!(mbr.get_partition_start_end(1), Some((4096, 8191)));
+        assert_eq!(mbr.get_partition_start_end(2), Some((2048, 4095)));
+        assert!(mbr.partitions[1].as_ref().unwrap().is_active());
+        assert!(mbr.partitions[3].is_none());
+        assert!(mbr.validate(20000 * 512).is_clean());
+
+        // beyond 2 TiB, unknown partition, duplicates, too many active partitions
+        assert!(Mbr::create_hybrid(&disk, &gpt, &[entry(2, TYPE_LINUX, false)]).is_err());
+        assert!(Mbr::create_hybrid(&disk, &gpt, &[entry(3, TYPE_LINUX, false)]).is_err());
+        assert!(
+            Mbr::create_hybrid(&disk, &gpt, &[entry(0, 0xEF, false), entry(0, 0xEF, false)])
+                .is_err()
+        );
+        assert!(Mbr::create_hybrid(
+            &disk,
+            &gpt,
+            &[entry(0, 0xEF, true), entry(1, TYPE_LINUX, true)]
+        )
+        .is_err());
+        assert!(Mbr::create_hybrid(&disk, &gpt, &[entry(0, TYPE_PROTECTIVE, false)]).is_err());
+    }
+}
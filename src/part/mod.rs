@@ -15,8 +15,23 @@ pub trait PartitionTable {
 }
 
 pub fn load_partition_table(disk: &mut dyn Disk) -> Result<Box<dyn PartitionTable>> {
-    match gpt::Gpt::load(disk, gpt::ErrorAction::Abort) {
-        Ok(gpt) => Ok(Box::new(gpt)),
-        Err(_) => Ok(Box::new(mbr::Mbr::load(disk)?)),
+    if let Ok(gpt) = gpt::Gpt::load(disk, gpt::ErrorAction::Abort) {
+        return Ok(Box::new(gpt));
     }
+
+    let mbr = mbr::Mbr::load(disk)?;
+    // backup GPT is trusted only when protective MBR says the disk uses GPT
+    let protective = mbr
+        .partitions
+        .iter()
+        .flatten()
+        .any(|x| x.partition_type == mbr::TYPE_PROTECTIVE);
+    if protective {
+        if let Ok(gpt) = gpt::Gpt::load_backup(disk, gpt::ErrorAction::Abort) {
+            warn!("primary GPT is damaged, using backup");
+            return Ok(Box::new(gpt));
+        }
+    }
+
+    Ok(Box::new(mbr))
 }
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::iter::once;
 use std::ops::{Add, Sub};
 
 #[derive(Debug, Copy, Clone)]
@@ -83,6 +84,33 @@ where
     }
 }
 
+/// Returns parts of usable region not covered by any of used regions
+pub(crate) fn find_free_regions(
+    usable_region: Region<u64>,
+    region_it: &mut dyn Iterator<Item = Region<u64>>,
+) -> Vec<Region<u64>> {
+    let mut usable_regions = once(Some(usable_region)).collect::<Vec<_>>();
+
+    for used_region in region_it {
+        for i in 0..usable_regions.len() {
+            if let Some(usable) = &usable_regions[i] {
+                let (first, second) = usable.substract(&used_region);
+                if let Some(first) = first {
+                    usable_regions[i] = Some(first);
+
+                    if let Some(second) = second {
+                        usable_regions.push(Some(second));
+                    }
+                } else {
+                    usable_regions[i] = None;
+                }
+            }
+        }
+    }
+
+    usable_regions.iter().copied().flatten().collect()
+}
+
 #[cfg(test)]
 #[test]
 fn test_region_overlap() {